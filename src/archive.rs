@@ -0,0 +1,142 @@
+use std::fs;
+use std::io::{Cursor, Read};
+use zip::result::ZipError;
+use zip::ZipArchive;
+
+/// Signature starting every zip archive (the first local file header).
+pub const SIGNATURE: [u8; 4] = [b'P', b'K', 0x03, 0x04];
+
+/// A zip archive read in memory. Only stored and deflated files are
+/// supported, which covers the archives rom collections are distributed in.
+pub struct Archive {
+    zip: ZipArchive<Cursor<Vec<u8>>>
+}
+
+impl Archive {
+    pub fn open(path: &str) -> Result<Archive, String> {
+        let data = fs::read(path).map_err(|e| format!("Unable to read {}: {}", path, e))?;
+        Archive::from_bytes(data).map_err(|e| format!("Unable to read archive {}: {}", path, e))
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> Result<Archive, String> {
+        let zip = ZipArchive::new(Cursor::new(data)).map_err(|e| e.to_string())?;
+        Ok(Archive { zip })
+    }
+
+    /// Paths of the files in the archive. Directories are left out.
+    pub fn file_names(&self) -> Vec<String> {
+        self.zip.file_names()
+            .filter(|name| !name.ends_with('/'))
+            .map(String::from)
+            .collect()
+    }
+
+    /// Extracts a file of at most max_size bytes, checking its contents
+    /// against the stored checksum. Larger files are rejected before they
+    /// are decompressed.
+    pub fn read(&mut self, name: &str, max_size: usize) -> Result<Vec<u8>, String> {
+        let file = match self.zip.by_name(name) {
+            Ok(file) => file,
+            Err(ZipError::FileNotFound) => return Err(format!("{} not found in archive", name)),
+            Err(e) => return Err(format!("Unable to read {}: {}", name, e))
+        };
+        let size = file.size();
+        if size > max_size as u64 {
+            return Err(format!("{} is too large ({} bytes, at most {})", name, size, max_size));
+        }
+
+        // Decompressing more than the size in the directory means the file is
+        // corrupt. The checksum is checked once the whole file is read.
+        let mut contents = Vec::new();
+        file.take(size + 1).read_to_end(&mut contents).map_err(|e| format!("{} is corrupt ({})", name, e))?;
+        if contents.len() as u64 != size {
+            return Err(format!("{} is corrupt (size mismatch)", name));
+        }
+        Ok(contents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::write::SimpleFileOptions;
+    use zip::{CompressionMethod, ZipWriter};
+
+    /// Builds an archive of files, deflated or stored.
+    fn archive(files: &[(&str, &[u8], bool)]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, contents, deflated) in files {
+            let method = if *deflated { CompressionMethod::Deflated } else { CompressionMethod::Stored };
+            let options = SimpleFileOptions::default().compression_method(method);
+            if name.ends_with('/') {
+                writer.add_directory(*name, options).unwrap();
+            } else {
+                writer.start_file(*name, options).unwrap();
+                writer.write_all(contents).unwrap();
+            }
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    /// Size of a local file header before the name of the file.
+    const LOCAL_HEADER_SIZE: usize = 30;
+    const ROM: &[u8] = &[0x00, 0xE0, 0xA2, 0x2A, 0x60, 0x0C, 0x61, 0x08, 0xD0, 0x1F, 0x12, 0x0A];
+
+    #[test]
+    fn reads_stored_and_deflated_files() {
+        let text = b"PONG by Paul Vervalin. PONG by Paul Vervalin.".repeat(10);
+        let data = archive(&[("games/", b"", false), ("games/pong.ch8", ROM, false), ("readme.txt", &text, true)]);
+        assert!(data.starts_with(&SIGNATURE));
+        let mut zip = Archive::from_bytes(data).unwrap();
+        assert_eq!(zip.file_names(), vec!["games/pong.ch8", "readme.txt"]);
+        assert_eq!(zip.read("games/pong.ch8", 4096), Ok(ROM.to_vec()));
+        assert_eq!(zip.read("readme.txt", 4096), Ok(text));
+        assert!(zip.read("missing.ch8", 4096).unwrap_err().contains("not found"));
+    }
+
+    #[test]
+    fn rejects_files_over_the_size_limit_before_reading_them() {
+        let zeros = vec![0; 1 << 20];
+        let data = archive(&[("zeros.ch8", &zeros, true)]);
+        assert!(data.len() < 8192);
+        let mut zip = Archive::from_bytes(data).unwrap();
+        let error = zip.read("zeros.ch8", 3584).unwrap_err();
+        assert!(error.contains("too large"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_corrupt_files() {
+        let mut data = archive(&[("pong.ch8", ROM, false)]);
+        let start = LOCAL_HEADER_SIZE + "pong.ch8".len();
+        assert_eq!(&data[start..start + ROM.len()], ROM);
+        data[start] ^= 0xFF;
+        let mut zip = Archive::from_bytes(data).unwrap();
+        assert!(zip.read("pong.ch8", 4096).is_err());
+    }
+
+    #[test]
+    fn rejects_files_larger_than_their_directory_entry() {
+        let mut data = archive(&[("pong.ch8", &[0; 64], true)]);
+        // Uncompressed size in the central directory, which is the only
+        // entry, followed by the end of directory record
+        let directory = data.len() - 22 - (46 + "pong.ch8".len());
+        data[directory + 24..directory + 28].copy_from_slice(&16u32.to_le_bytes());
+        let mut zip = Archive::from_bytes(data).unwrap();
+        assert!(zip.read("pong.ch8", 4096).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_archives() {
+        let data = archive(&[("pong.ch8", ROM, false), ("tetris.ch8", &[0x12; 300], true)]);
+        for length in 0..data.len() {
+            let truncated = data[..length].to_vec();
+            if let Ok(mut zip) = Archive::from_bytes(truncated) {
+                for name in ["pong.ch8", "tetris.ch8"] {
+                    assert!(zip.read(name, 4096).is_err(), "{} read from {} bytes", name, length);
+                }
+            }
+        }
+        assert!(Archive::from_bytes(b"PK\x03\x04 not an archive".to_vec()).is_err());
+    }
+}
@@ -0,0 +1,89 @@
+use crate::menu::Menu;
+use crate::rom;
+
+/// Number of items skipped by page up and page down.
+pub const PAGE_SIZE: isize = 10;
+
+/// Picks the rom to run, among the recently played ones and the ones in the
+/// rom directory, or among the roms of an archive or directory.
+pub struct RomBrowser {
+    /// Path opened by each menu item, None for headers.
+    paths: Vec<Option<String>>,
+    menu: Menu
+}
+
+impl RomBrowser {
+    /// Lists the recent roms that still exist, most recent first, followed by
+    /// the roms and archives in rom_dir, sorted by title.
+    pub fn new(recent: &[String], rom_dir: Option<&str>) -> RomBrowser {
+        let mut browser = RomBrowser {
+            paths: Vec::new(),
+            menu: Menu::new("Open rom")
+        };
+
+        let recent: Vec<&String> = recent.iter().filter(|p| rom::exists(p)).collect();
+        if !recent.is_empty() {
+            browser.add_header("Recent");
+            for path in recent {
+                browser.add_path(path);
+            }
+        }
+
+        match rom_dir {
+            Some(dir) => match rom::list_directory(dir) {
+                Ok(paths) if paths.is_empty() => browser.add_header(&format!("No roms found in {}", dir)),
+                Ok(paths) => {
+                    browser.add_header(dir);
+                    for path in paths.iter() {
+                        browser.add_path(path);
+                    }
+                },
+                Err(e) => browser.add_header(&e)
+            },
+            None => browser.add_header("Set rom_dir in the config file to list your roms")
+        }
+
+        browser
+    }
+
+    /// Lists the roms found in an archive or a directory.
+    pub fn with_choice(location: &str, paths: &[String]) -> RomBrowser {
+        let mut browser = RomBrowser {
+            paths: Vec::new(),
+            menu: Menu::new("Choose a rom")
+        };
+        browser.add_header(location);
+        for path in paths {
+            browser.add_path(path);
+        }
+        browser
+    }
+
+    fn add_header(&mut self, text: &str) {
+        self.menu.add_header(text);
+        self.paths.push(None);
+    }
+
+    fn add_path(&mut self, path: &str) {
+        let title = rom::title(path);
+        if rom::is_archive_name(path) {
+            self.menu.add_item(&format!("{} (zip)", title));
+        } else {
+            self.menu.add_item(&title);
+        }
+        self.paths.push(Some(String::from(path)));
+    }
+
+    pub fn menu(&self) -> &Menu {
+        &self.menu
+    }
+
+    pub fn move_selection(&mut self, offset: isize) {
+        self.menu.move_selection(offset);
+    }
+
+    /// Path of the selected rom or archive.
+    pub fn selected_path(&self) -> Option<&str> {
+        self.paths.get(self.menu.selected?)?.as_deref()
+    }
+}
@@ -0,0 +1,45 @@
+use crate::chip8::{Chip8, Register, MEMSIZE};
+use crate::utils;
+
+/// Kind of memory access made by an instruction.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    /// Fetch of an instruction to run.
+    Execute
+}
+
+/// Address an access lands on: addresses past the end of memory wrap
+/// around to its start, so roms can't access outside of it.
+fn wrap(address: u16) -> u16 {
+    (address as usize % MEMSIZE) as u16
+}
+
+/// Memory accesses of the instructions go through these functions, which
+/// let the debugger watch them. Debuggers and the rom loader access the
+/// memory directly, without being watched.
+impl Chip8 {
+    pub fn read_byte(&mut self, address: u16) -> u8 {
+        let address = wrap(address);
+        let value = self.main_memory[address as usize];
+        self.watch_access(Access::Read, address, value);
+        value
+    }
+
+    pub fn write_byte(&mut self, address: u16, value: u8) {
+        let address = wrap(address);
+        let previous = self.main_memory[address as usize];
+        self.main_memory[address as usize] = value;
+        self.history.note_write(address, previous, self.frame_count);
+        self.watch_access(Access::Write, address, value);
+    }
+
+    /// Reads the instruction at PC, to run it.
+    pub fn fetch_instruction(&mut self) -> u16 {
+        let pc = wrap(self.get_register(Register::PC));
+        let high = self.main_memory[pc as usize];
+        self.watch_access(Access::Execute, pc, high);
+        utils::concat_u8_to_u16(high, self.main_memory[wrap(pc + 1) as usize])
+    }
+}
@@ -1,6 +1,14 @@
+use std::collections::BTreeMap;
 use std::time::{ Duration, Instant };
+use crate::debug::DebugState;
 use crate::input::{ KeyboardState };
+use crate::trace::Tracer;
+use crate::profile::Profiler;
+use crate::history::{self, History};
+use crate::symbols::Symbols;
+use toml::Table;
 use crate::utils;
+use crate::log_warn;
 
 pub const DISPLAYW: u32 = 64;
 pub const DISPLAYH: u32 = 32;
@@ -8,6 +16,60 @@ pub const MEMSIZE: usize = 0xFFF;
 pub const PROGRAM_START: usize = 0x200;
 pub const STACK_START: usize = PROGRAM_START;
 pub const STACK_SIZE: u32 = 32; // Stack can store 16 u16
+/// Duration of a frame. Timers and the display are updated at 60 hz.
+pub const FRAME_TIME: Duration = Duration::from_nanos(16_666_667);
+
+/// Behaviors that differ between Chip-8 interpreters, which roms rely on.
+/// Quirks are only set by cartridges and the config file; unset ones keep
+/// the behavior the emulator always had, given with each quirk.
+#[derive(Clone, Copy, Default)]
+pub struct Quirks {
+    /// 8XY6 and 8XYE shift VX when set, or put VY shifted in VX when unset
+    /// to false. Unset, VX is shifted.
+    pub shift: Option<bool>,
+    /// FX55 and FX65 leave I unchanged when set, or move it past the last
+    /// register when unset to false. Unset, only FX55 moves it.
+    pub load_store: Option<bool>,
+    /// BNNN jumps to NNN plus VX, X being the first digit of NNN, when set,
+    /// or to NNN plus V0 when unset to false. Unset, BNNN isn't executed.
+    pub jump: Option<bool>,
+    /// 8XY1, 8XY2 and 8XY3 reset VF when set. Unset, VF is left unchanged.
+    pub logic: Option<bool>,
+    /// Sprites are clipped at the edges of the screen when set. Unset, they
+    /// wrap around.
+    pub clip: Option<bool>,
+    /// Drawing waits for the next frame when set, so a single sprite is drawn
+    /// per frame. Unset, drawing doesn't wait.
+    pub vblank: Option<bool>
+}
+
+impl Quirks {
+    pub fn new() -> Quirks {
+        Quirks::default()
+    }
+
+    /// Overrides the quirks present in a `[quirks]` config section.
+    pub fn apply(&mut self, table: &Table) {
+        for (key, value) in table {
+            let quirk = match key.as_str() {
+                "shift" => &mut self.shift,
+                "load_store" => &mut self.load_store,
+                "jump" => &mut self.jump,
+                "logic" => &mut self.logic,
+                "clip" => &mut self.clip,
+                "vblank" => &mut self.vblank,
+                _ => {
+                    log_warn!(Config, "Unknown quirk '{}'.", key);
+                    continue;
+                }
+            };
+            match value.as_bool() {
+                Some(enabled) => *quirk = Some(enabled),
+                None => log_warn!(Config, "Invalid value for quirk '{}'.", key)
+            }
+        }
+    }
+}
 
 pub enum KeyboardKey {
     Zero = 0,
@@ -66,11 +128,36 @@ pub struct Chip8 {
     pub registers: [u16; Register::Total as usize],
     pub keyboard_state: KeyboardState,
     pub previous_tick: Instant,
-    pub previous_vsync: Instant,
-    pub timer_accumulator: Duration,
+    /// Time emulated since the last frame started.
+    pub frame_accumulator: Duration,
+    /// Number of frames emulated since the rom started.
+    pub frame_count: u64,
+    /// Number of instructions executed since the rom started.
+    pub instruction_count: u64,
+    /// Whether the beeper sounded during the last frame emulated.
+    pub last_frame_beeped: bool,
     pub time_since_last_op: Duration,
     pub halted_for_keypress: bool,
-    pub halted_keypress_store_reg: usize
+    pub halted_keypress_store_reg: usize,
+    pub instruction_time_ns: u32,
+    pub quirks: Quirks,
+    /// Set by drawing with the vblank quirk. No instructions run until the
+    /// next frame.
+    pub waiting_for_vblank: bool,
+    /// Undo information of the last instructions, and the last write to
+    /// each address.
+    pub history: History,
+    /// Addresses in I when sprites were drawn, with the most rows drawn from
+    /// each, for the sprite viewer.
+    pub sprites_drawn: BTreeMap<u16, u8>,
+    /// Breakpoints, watchpoints and whether a debugger stopped the emulation.
+    pub debug: DebugState,
+    /// Writes the instructions executed to a file, when tracing.
+    pub tracer: Option<Tracer>,
+    /// Counts the instructions executed, when profiling.
+    pub profiler: Option<Profiler>,
+    /// Labels of the rom, shown next to the addresses printed.
+    pub symbols: Symbols
 }
 
 pub const FONTS: [u8; 5 * 16] = [0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
@@ -99,11 +186,22 @@ impl Chip8 {
             keyboard_state: KeyboardState::new(),
 
             previous_tick: Instant::now(),
-            previous_vsync: Instant::now(),
-            timer_accumulator: Duration::new(0, 0),
+            frame_accumulator: Duration::new(0, 0),
+            frame_count: 0,
+            instruction_count: 0,
+            last_frame_beeped: false,
             time_since_last_op: Duration::new(0, 0),
             halted_for_keypress: false,
-            halted_keypress_store_reg: 0
+            halted_keypress_store_reg: 0,
+            instruction_time_ns: 1400000,
+            quirks: Quirks::new(),
+            waiting_for_vblank: false,
+            history: History::new(history::DEFAULT_CAPACITY),
+            sprites_drawn: BTreeMap::new(),
+            debug: DebugState::default(),
+            tracer: None,
+            profiler: None,
+            symbols: Symbols::default()
         }
     }
 
@@ -120,9 +218,14 @@ impl Chip8 {
     pub fn get_register(&self, register: Register) -> u16 {
         self.registers[register as usize]
     }
+
+    /// Whether the beeper sounds, which is while the sound timer is not zero.
+    pub fn is_beeping(&self) -> bool {
+        self.get_register(Register::Sound) > 0
+    }
     
     pub fn advance_pc(&mut self) -> u16 {
-        self.registers[Register::PC as usize] += 2;
+        self.registers[Register::PC as usize] = self.registers[Register::PC as usize].wrapping_add(2);
         self.registers[Register::PC as usize]
     }
     
@@ -131,10 +234,9 @@ impl Chip8 {
     /// The stack is composed of 16 bit values, and since the interpreter
     /// area is used (which is a [u8]), each unit advances the SP by 2.
     pub fn advance_sp(&mut self, n: i32) -> u16 {
-        assert!((self.registers[Register::SP as usize] as i32) > (STACK_START as i32 - STACK_SIZE as i32));
         let mut sp_v = self.get_register(Register::SP) as i32;
         sp_v -= n * 2; // Advance n * 2 bytes
-        assert!(sp_v >= 0); // Sanity check
+        assert!(sp_v >= STACK_START as i32 - STACK_SIZE as i32 && sp_v <= STACK_START as i32);
         self.set_register(Register::SP, sp_v as u16);
         self.registers[Register::SP as usize]
     }
@@ -145,15 +247,16 @@ impl Chip8 {
     }
 
     pub fn pop_stack(&mut self) -> u16 {
-        let top_stack = self.peek_stack();
+        let stack_top = self.get_register(Register::SP);
+        let top_stack = utils::concat_u8_to_u16(self.read_byte(stack_top - 1), self.read_byte(stack_top));
         self.advance_sp(-1);
         top_stack
     }
 
     pub fn push_stack(&mut self, value: u16) {
-        let stack_addr = self.advance_sp(1) as usize;
+        let stack_addr = self.advance_sp(1);
         let split = utils::split_u16_to_u8(value);
-        self.main_memory[stack_addr] = split.1;
-        self.main_memory[stack_addr - 1] = split.0;
+        self.write_byte(stack_addr, split.1);
+        self.write_byte(stack_addr - 1, split.0);
     }
 }
\ No newline at end of file
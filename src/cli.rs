@@ -0,0 +1,279 @@
+use crate::recorder::RecordingFormat;
+use std::env;
+use crate::disasm::OpcodeClass;
+use crate::history;
+use crate::logger::{self, LogFilter};
+use crate::renderer::Backend;
+use crate::sprites::{self, SpriteOptions};
+use crate::trace::TraceOptions;
+use crate::watch::Watchpoint;
+
+pub const USAGE: &str = "Usage: rusty-chip8 [OPTIONS] [ROM]
+       rusty-chip8 sprites [SPRITE OPTIONS] ROM
+
+Options:
+  --renderer <opengl|software>  Renderer used to draw the screen
+  --headless                    Run without a window, using the software renderer
+  --frames <N>                  Number of frames to run in headless mode (default: 600)
+  --screenshot                  Save a screenshot at the end of a headless run
+  --record <gif|raw>            Record every frame from the start, as a GIF or Y4M video and WAV audio
+  --tui                         Run in the terminal, drawing the screen with text characters
+  --gdb <PORT>                  Wait for a debugger speaking the GDB remote protocol on a local port
+  --dap <PORT>                  Wait for an editor speaking the debug adapter protocol on a local port
+  --watch <WATCHPOINT>          Stop when the rom accesses memory or changes a register, e.g. \"write to 0x3f0\"
+  --trace <FILE>                Write every instruction executed to a file
+  --trace-range <START-END>     Only trace the instructions in this address range, e.g. 0x200-0x2ff
+  --trace-ops <CLASSES>         Only trace these classes of instructions, e.g. flow,display
+  --trace-last <N>              Only write the last N instructions when an error occurs
+  --profile <FILE>              Count the instructions executed, and write a report and an annotated disassembly (FILE.lst)
+  --symbols <FILE>              Label and source line map of the rom (Octo .sym or JSON), shown next to addresses
+  --history <N>                 Instructions the debugger can step back through (default: 100000, 0 to disable)
+  --log <LEVELS>                Log levels, for every category or per category, e.g. warn,cpu=debug
+  --log-file <FILE>             Write the log to a file instead of stderr
+  -h, --help                    Show this message
+
+The sprites command draws memory as 8 pixel wide sprites in a PNG image, framing the ones the rom draws.
+Sprite options:
+  --range <START-END>           Memory drawn, e.g. 0x000-0x04f for the font (default: the rom)
+  --height <N>                  Rows of each sprite, from 1 to 15 (default: 8)
+  --frames <N>                  Frames the rom runs first, to find the sprites it draws (default: 600)
+  --scale <N>                   Size of the sprite pixels in the image (default: 4)
+  --output <FILE>               PNG file written (default: <rom>-sprites.png)";
+
+/// Parses an address range, e.g. "0x200-0x2ff".
+fn parse_range(text: &str) -> Option<(u16, u16)> {
+    let parse = |address: &str| {
+        let address = address.trim();
+        match address.strip_prefix("0x") {
+            Some(hex) => u16::from_str_radix(hex, 16).ok(),
+            None => address.parse().ok()
+        }
+    };
+    let (start, end) = text.split_once('-')?;
+    let (start, end) = (parse(start)?, parse(end)?);
+    (start <= end).then_some((start, end))
+}
+
+/// Options given in the command line. They take precedence over the config file.
+pub struct Options {
+    pub rom_path: Option<String>,
+    pub renderer: Option<Backend>,
+    pub headless: bool,
+    pub frames: u64,
+    pub tui: bool,
+    pub screenshot: bool,
+    pub record: Option<RecordingFormat>,
+    /// Local port a GDB remote protocol server listens on.
+    pub gdb_port: Option<u16>,
+    /// Local port a debug adapter protocol server listens on.
+    pub dap_port: Option<u16>,
+    pub watchpoints: Vec<Watchpoint>,
+    pub trace: Option<TraceOptions>,
+    /// File the profile is written to.
+    pub profile: Option<String>,
+    /// Symbols of the rom given in the command line, instead of the ones of
+    /// the config file.
+    pub symbols: Option<String>,
+    /// Instructions remembered to step backwards.
+    pub history: usize,
+    /// Log levels, from `--log` or the environment.
+    pub log_filter: LogFilter,
+    pub log_file: Option<String>,
+    /// Set by the `sprites` command, which runs instead of the emulator.
+    pub sprites: Option<SpriteOptions>,
+    pub show_help: bool
+}
+
+/// Parses the arguments of the `sprites` command.
+fn parse_sprites(args: &[String]) -> Result<SpriteOptions, String> {
+    let mut rom_path = None;
+    let mut options = SpriteOptions { rom_path: String::new(), range: None, height: 8, frames: 600, scale: 4, output: None };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next()
+            .ok_or_else(|| format!("Missing value for {}.", name));
+
+        match arg.as_str() {
+            "--range" => {
+                let range = value(arg)?;
+                options.range = Some(parse_range(range).ok_or_else(|| format!("Invalid address range: {}.", range))?);
+            },
+            "--height" => {
+                let height = value(arg)?;
+                options.height = height.parse().ok().filter(|h| (1..=sprites::MAX_HEIGHT).contains(h))
+                    .ok_or_else(|| format!("Invalid sprite height: {}.", height))?;
+            },
+            "--frames" => {
+                let frames = value(arg)?;
+                options.frames = frames.parse()
+                    .map_err(|_| format!("Invalid number of frames: {}.", frames))?;
+            },
+            "--scale" => {
+                let scale = value(arg)?;
+                options.scale = scale.parse().ok().filter(|s| *s > 0)
+                    .ok_or_else(|| format!("Invalid scale: {}.", scale))?;
+            },
+            "--output" => options.output = Some(value(arg)?.clone()),
+            _ if arg.starts_with('-') => return Err(format!("Unknown sprite option: {}.", arg)),
+            _ => {
+                if rom_path.is_some() {
+                    return Err(String::from("Only one rom can be provided."));
+                }
+                rom_path = Some(arg.clone());
+            }
+        }
+    }
+    options.rom_path = rom_path.ok_or("The sprites command needs a rom.")?;
+    Ok(options)
+}
+
+impl Options {
+    /// Parses the arguments, not including the program name.
+    pub fn parse(args: &[String]) -> Result<Options, String> {
+        let mut options = Options {
+            rom_path: None,
+            renderer: None,
+            headless: false,
+            frames: 600,
+            tui: false,
+            screenshot: false,
+            record: None,
+            gdb_port: None,
+            dap_port: None,
+            watchpoints: Vec::new(),
+            trace: None,
+            profile: None,
+            symbols: None,
+            history: history::DEFAULT_CAPACITY,
+            log_filter: LogFilter::default(),
+            log_file: None,
+            sprites: None,
+            show_help: false
+        };
+
+        if args.first().is_some_and(|arg| arg == "sprites") {
+            if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+                options.show_help = true;
+            } else {
+                options.sprites = Some(parse_sprites(&args[1..])?);
+            }
+            return Ok(options);
+        }
+
+        let mut log_filter = None;
+        // Trace filters can be given before the trace file
+        let mut trace_range = None;
+        let mut trace_classes = Vec::new();
+        let mut trace_last = None;
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| args.next()
+                .ok_or_else(|| format!("Missing value for {}.", name));
+
+            match arg.as_str() {
+                "-h" | "--help" => options.show_help = true,
+                "--headless" => options.headless = true,
+                "--tui" => options.tui = true,
+                "--screenshot" => options.screenshot = true,
+                "--renderer" => {
+                    let name = value(arg)?;
+                    options.renderer = Some(Backend::from_name(name)
+                        .ok_or_else(|| format!("Unknown renderer: {}.", name))?);
+                },
+                "--record" => {
+                    let name = value(arg)?;
+                    options.record = Some(RecordingFormat::from_name(name)
+                        .ok_or_else(|| format!("Unknown recording format: {}.", name))?);
+                },
+                "--gdb" => {
+                    let port = value(arg)?;
+                    options.gdb_port = Some(port.parse()
+                        .map_err(|_| format!("Invalid port: {}.", port))?);
+                },
+                "--dap" => {
+                    let port = value(arg)?;
+                    options.dap_port = Some(port.parse()
+                        .map_err(|_| format!("Invalid port: {}.", port))?);
+                },
+                "--watch" => {
+                    let text = value(arg)?;
+                    let watchpoint = Watchpoint::parse(text)
+                        .map_err(|e| format!("Invalid watchpoint '{}': {}.", text, e))?;
+                    options.watchpoints.push(watchpoint);
+                },
+                "--log" => {
+                    log_filter = Some(LogFilter::parse(value(arg)?).map_err(|e| format!("Invalid log levels: {}.", e))?);
+                },
+                "--log-file" => options.log_file = Some(value(arg)?.clone()),
+                "--profile" => options.profile = Some(value(arg)?.clone()),
+                "--symbols" => options.symbols = Some(value(arg)?.clone()),
+                "--trace" => {
+                    let path = value(arg)?;
+                    options.trace = Some(TraceOptions { path: path.clone(), range: None, classes: Vec::new(), last: None });
+                },
+                "--trace-range" => {
+                    let range = value(arg)?;
+                    trace_range = Some(parse_range(range).ok_or_else(|| format!("Invalid address range: {}.", range))?);
+                },
+                "--trace-ops" => {
+                    for name in value(arg)?.split(',') {
+                        let class = OpcodeClass::from_name(name.trim())
+                            .ok_or_else(|| format!("Unknown instruction class: {}. Classes are {}.", name, OpcodeClass::names().join(", ")))?;
+                        trace_classes.push(class);
+                    }
+                },
+                "--trace-last" => {
+                    let last = value(arg)?;
+                    trace_last = Some(last.parse().ok().filter(|n| *n > 0)
+                        .ok_or_else(|| format!("Invalid number of instructions: {}.", last))?);
+                },
+                "--history" => {
+                    let history = value(arg)?;
+                    options.history = history.parse()
+                        .map_err(|_| format!("Invalid number of instructions: {}.", history))?;
+                },
+                "--frames" => {
+                    let frames = value(arg)?;
+                    options.frames = frames.parse()
+                        .map_err(|_| format!("Invalid number of frames: {}.", frames))?;
+                },
+                _ if arg.starts_with('-') => return Err(format!("Unknown option: {}.", arg)),
+                _ => {
+                    if options.rom_path.is_some() {
+                        return Err(String::from("Only one rom can be provided."));
+                    }
+                    options.rom_path = Some(arg.clone());
+                }
+            }
+        }
+
+        // The command line takes precedence over the environment
+        if log_filter.is_none() {
+            if let Ok(text) = env::var(logger::LOG_ENV) {
+                log_filter = Some(LogFilter::parse(&text).map_err(|e| format!("Invalid {}: {}.", logger::LOG_ENV, e))?);
+            }
+        }
+        options.log_filter = log_filter.unwrap_or_default();
+
+        let filtered = trace_range.is_some() || !trace_classes.is_empty() || trace_last.is_some();
+        match &mut options.trace {
+            Some(trace) => {
+                trace.range = trace_range;
+                trace.classes = trace_classes;
+                trace.last = trace_last;
+            },
+            None if filtered => return Err(String::from("The trace options need a trace file (--trace).")),
+            None => { }
+        }
+
+        let debugger = options.gdb_port.is_some() || options.dap_port.is_some();
+        if options.headless && debugger {
+            return Err(String::from("The debugger can't be used in headless mode."));
+        }
+        if options.gdb_port.is_some() && options.dap_port.is_some() {
+            return Err(String::from("Only one debugger can be used at a time."));
+        }
+        Ok(options)
+    }
+}
@@ -0,0 +1,484 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use crate::chip8::Quirks;
+use crate::palette::{self, Palette};
+use crate::postfx::Effects;
+use crate::phosphor::Ghosting;
+use crate::display::ColorMode;
+use crate::recorder::RecordingFormat;
+use crate::rom::RomFile;
+use crate::renderer::Backend;
+use crate::shader::GlVersion;
+use toml::{Table, Value};
+use toml_edit::{DocumentMut, Item, TableLike};
+use crate::utils;
+use crate::{log_error, log_warn};
+
+const CONFIG_DIR_NAME: &str = "rusty-chip8";
+const CONFIG_FILE_NAME: &str = "config.toml";
+const ROMS_TABLE: &str = "roms";
+/// Paths of the roms played last, most recent first. Kept in the global
+/// section, next to the settings.
+const RECENT_ROMS: &str = "recent_roms";
+const MAX_RECENT_ROMS: usize = 10;
+/// Palette made of the colors of the settings replaced by palettes,
+/// `background_color` and `foreground_color`.
+const LEGACY_PALETTE: &str = "config";
+
+/// Identifies a rom for per-rom overrides. Sections in the config file can
+/// be keyed either by the rom file name or by the hash of its contents:
+///
+/// ```toml
+/// [roms."tetris.ch8"]
+/// instruction_time_ns = 1000000
+///
+/// [roms."4f8cf2a3e3b2a0d1"]
+/// palette = "teal"
+/// ```
+pub struct RomId {
+    pub file_name: String,
+    pub hash: String,
+    /// Settings that come with the rom, applied before the rom sections.
+    pub settings: Table
+}
+
+impl RomId {
+    pub fn new(file_path: &str, rom_bytes: &[u8]) -> RomId {
+        let file_name = Path::new(file_path)
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_else(|| String::from(file_path));
+
+        RomId {
+            file_name,
+            hash: format!("{:016x}", utils::fnv1a_64(rom_bytes)),
+            settings: Table::new()
+        }
+    }
+
+    pub fn of(rom: &RomFile) -> RomId {
+        RomId { settings: rom.settings.clone(), ..RomId::new(&rom.path, &rom.data) }
+    }
+}
+
+/// Settings resolved from the global section of the config file, with the
+/// current rom overrides applied on top.
+#[derive(Clone)]
+pub struct Settings {
+    pub renderer: Backend,
+    /// OpenGL version to create the context with. When None, the most capable
+    /// version available is used.
+    pub gl_version: Option<GlVersion>,
+    pub window_width: u32,
+    pub window_height: u32,
+    /// Color of the window area around the screen.
+    pub border_color: [f32; 3],
+    /// Restricts the screen scale to whole numbers, for crisp pixels.
+    pub integer_scaling: bool,
+    pub fullscreen: bool,
+    /// Name of the active palette.
+    pub palette: String,
+    /// Built-in palettes followed by the ones defined in the config file.
+    pub palettes: Vec<Palette>,
+    /// Custom shader pair used to draw the screen, instead of the built-in one.
+    pub vertex_shader: Option<String>,
+    pub fragment_shader: Option<String>,
+    pub effects: Effects,
+    pub ghosting: Ghosting,
+    /// Name of the host key mapped to each Chip-8 key, indexed by key value.
+    pub key_map: [String; 16],
+    /// Colors used by the terminal frontend. When None, they are detected
+    /// from the environment.
+    pub terminal_colors: Option<ColorMode>,
+    /// Directory screenshots are saved to.
+    pub screenshot_dir: String,
+    /// Scale of the upscaled screenshot, in window pixels per Chip-8 pixel.
+    pub screenshot_scale: u32,
+    /// Also save the screen as displayed, with shaders and effects applied.
+    pub screenshot_display: bool,
+    pub recording_format: RecordingFormat,
+    /// Directory recordings are saved to.
+    pub recording_dir: String,
+    /// Scale of the recorded video, in pixels per Chip-8 pixel.
+    pub recording_scale: u32,
+    /// Directory listed by the rom browser.
+    pub rom_dir: Option<String>,
+    /// Symbol file of the rom, usually set in its `[roms."<name>"]` table.
+    pub symbols: Option<String>,
+    /// Load the last rom played when none is given in the command line.
+    pub resume_last_rom: bool,
+    /// How many times faster than normal fast-forward runs.
+    pub fast_forward_speed: u32,
+    /// Show notifications on screen.
+    pub osd_messages: bool,
+    /// Show the frames and instructions per second counter.
+    pub osd_fps: bool,
+    /// Show the pause and fast-forward indicators.
+    pub osd_indicators: bool,
+    /// Scale of the on-screen display text, in window pixels per font pixel.
+    pub osd_scale: u32,
+    pub instruction_time_ns: u32,
+    pub quirks: Quirks
+}
+
+impl Settings {
+    pub fn new() -> Settings {
+        const DEFAULT_KEY_MAP: [&str; 16] = [
+            "E", "R", "T", "Y", "F", "G", "H", "V",
+            "B", "N", "W", "S", "U", "J", "M", "D"
+        ];
+
+        Settings {
+            renderer: Backend::OpenGL,
+            gl_version: None,
+            window_width: 1200,
+            window_height: 600,
+            border_color: [0.2, 0.2, 0.2],
+            integer_scaling: false,
+            fullscreen: false,
+            palette: String::from("default"),
+            palettes: palette::builtin_palettes(),
+            vertex_shader: None,
+            fragment_shader: None,
+            effects: Effects::new(),
+            ghosting: Ghosting::Decay { factor: 0.8 },
+            key_map: DEFAULT_KEY_MAP.map(String::from),
+            terminal_colors: None,
+            screenshot_dir: String::from("screenshots"),
+            screenshot_scale: 10,
+            screenshot_display: true,
+            recording_format: RecordingFormat::Gif,
+            recording_dir: String::from("recordings"),
+            recording_scale: 4,
+            rom_dir: None,
+            symbols: None,
+            resume_last_rom: false,
+            fast_forward_speed: 4,
+            osd_messages: true,
+            osd_fps: false,
+            osd_indicators: true,
+            osd_scale: 2,
+            instruction_time_ns: 1400000,
+            quirks: Quirks::new()
+        }
+    }
+
+    /// Overrides the settings present in table. Unknown keys and values of the
+    /// wrong type are reported and ignored.
+    pub fn apply(&mut self, table: &Table) {
+        let mut legacy_colors = [None, None];
+        for (key, value) in table {
+            let applied = match key.as_str() {
+                "renderer" => Backend::from_config(value).map(|v| self.renderer = v),
+                "gl_version" => value.as_str().and_then(|v| match v {
+                    "auto" => Some(None),
+                    _ => GlVersion::from_name(v).map(Some)
+                }).map(|v| self.gl_version = v),
+                "window_width" => read_u32(value).map(|v| self.window_width = v),
+                "window_height" => read_u32(value).map(|v| self.window_height = v),
+                "border_color" => parse_color(value).map(|v| self.border_color = v),
+                "clear_color" => parse_color(value).map(|v| {
+                    log_warn!(Config, "'clear_color' is deprecated, use 'border_color' instead.");
+                    self.border_color = v;
+                }),
+                "background_color" => parse_color(value).map(|v| legacy_colors[0] = Some(v)),
+                "foreground_color" => parse_color(value).map(|v| legacy_colors[1] = Some(v)),
+                "integer_scaling" => value.as_bool().map(|v| self.integer_scaling = v),
+                "fullscreen" => value.as_bool().map(|v| self.fullscreen = v),
+                "palette" => value.as_str().map(|v| self.palette = String::from(v)),
+                "palettes" => value.as_table().map(|palettes| self.apply_palettes(palettes)),
+                "instruction_time_ns" => read_u32(value).filter(|v| *v > 0).map(|v| self.instruction_time_ns = v),
+                "quirks" => value.as_table().map(|quirks| self.quirks.apply(quirks)),
+                "vertex_shader" => value.as_str().map(|v| self.vertex_shader = Some(String::from(v))),
+                "fragment_shader" => value.as_str().map(|v| self.fragment_shader = Some(String::from(v))),
+                "effects" => value.as_table().map(|effects| self.effects.apply(effects)),
+                "ghosting" => value.as_table()
+                    .and_then(|g| Ghosting::from_config(g, self.ghosting))
+                    .map(|g| self.ghosting = g),
+                "keys" => value.as_table().map(|keys| self.apply_key_map(keys)),
+                "screenshot_dir" => value.as_str().map(|v| self.screenshot_dir = String::from(v)),
+                "screenshot_scale" => read_u32(value).filter(|v| (1..=64).contains(v)).map(|v| self.screenshot_scale = v),
+                "screenshot_display" => value.as_bool().map(|v| self.screenshot_display = v),
+                "recording_format" => value.as_str().and_then(RecordingFormat::from_name).map(|v| self.recording_format = v),
+                "recording_dir" => value.as_str().map(|v| self.recording_dir = String::from(v)),
+                "recording_scale" => read_u32(value).filter(|v| (1..=16).contains(v)).map(|v| self.recording_scale = v),
+                "rom_dir" => value.as_str().map(|v| self.rom_dir = Some(String::from(v))),
+                "symbols" => value.as_str().map(|v| self.symbols = Some(String::from(v))),
+                "resume_last_rom" => value.as_bool().map(|v| self.resume_last_rom = v),
+                "fast_forward_speed" => read_u32(value).filter(|v| (2..=16).contains(v)).map(|v| self.fast_forward_speed = v),
+                "osd_messages" => value.as_bool().map(|v| self.osd_messages = v),
+                "osd_fps" => value.as_bool().map(|v| self.osd_fps = v),
+                "osd_indicators" => value.as_bool().map(|v| self.osd_indicators = v),
+                "osd_scale" => read_u32(value).filter(|v| (1..=8).contains(v)).map(|v| self.osd_scale = v),
+                "terminal_colors" => ColorMode::from_config(value).map(|v| self.terminal_colors = v),
+                ROMS_TABLE | RECENT_ROMS => Some(()),
+                _ => {
+                    log_warn!(Config, "Unknown setting '{}'.", key);
+                    Some(())
+                }
+            };
+
+            if applied.is_none() {
+                log_warn!(Config, "Invalid value for '{}'.", key);
+            }
+        }
+        if legacy_colors.iter().any(Option::is_some) {
+            // A palette picked in the same section, e.g. with F3, wins over the old colors
+            self.apply_legacy_colors(legacy_colors, !table.contains_key("palette"));
+        }
+    }
+
+    /// Replaces the background or foreground color of the active palette,
+    /// with the settings used before palettes. The result becomes the
+    /// `config` palette, selected when select is set.
+    fn apply_legacy_colors(&mut self, [background, foreground]: [Option<[f32; 3]>; 2], select: bool) {
+        log_warn!(Config, "'background_color' and 'foreground_color' are deprecated, use a palette instead (e.g. [palettes.mine] colors = [\"#393646\", \"#F4EEE0\"]).");
+        let current = self.current_palette().colors;
+        let palette = Palette::from_two_colors(LEGACY_PALETTE, background.unwrap_or(current[0]), foreground.unwrap_or(current[1]));
+        self.palettes.retain(|p| p.name != LEGACY_PALETTE);
+        self.palettes.push(palette);
+        if select {
+            self.palette = String::from(LEGACY_PALETTE);
+        }
+    }
+
+    fn apply_palettes(&mut self, palettes: &Table) {
+        for (name, value) in palettes {
+            match value.as_table().and_then(|t| Palette::from_config(name, t)) {
+                Some(palette) => {
+                    self.palettes.retain(|p| p.name != palette.name);
+                    self.palettes.push(palette);
+                },
+                None => log_warn!(Config, "Invalid palette '{}'. Palettes need 2 or 4 colors.", name)
+            }
+        }
+    }
+
+    /// The active palette. Falls back to the first palette if the configured
+    /// name is unknown.
+    pub fn current_palette(&self) -> &Palette {
+        self.palettes.iter()
+            .find(|p| p.name == self.palette)
+            .unwrap_or(&self.palettes[0])
+    }
+
+    /// Selects the palette offset positions away from the active one, wrapping
+    /// around the palette list.
+    pub fn cycle_palette(&mut self, offset: isize) -> &Palette {
+        let count = self.palettes.len() as isize;
+        let current = self.palettes.iter().position(|p| p.name == self.palette).unwrap_or(0) as isize;
+        let next = (current + offset).rem_euclid(count) as usize;
+        self.palette = self.palettes[next].name.clone();
+        &self.palettes[next]
+    }
+
+    fn apply_key_map(&mut self, keys: &Table) {
+        for (chip8_key, host_key) in keys {
+            match (u8::from_str_radix(chip8_key, 16), host_key.as_str()) {
+                (Ok(k), Some(name)) if k < 16 => self.key_map[k as usize] = String::from(name),
+                _ => log_warn!(Config, "Invalid key mapping '{}'.", chip8_key)
+            }
+        }
+    }
+}
+
+pub struct Config {
+    path: Option<PathBuf>,
+    table: Table,
+    /// The file as written, edited in place so saving keeps its comments and
+    /// key order.
+    document: DocumentMut,
+    /// Set when the file exists but failed to load, so it isn't saved over.
+    read_only: bool
+}
+
+impl Config {
+    /// Location of the config file: `$XDG_CONFIG_HOME/rusty-chip8/config.toml`,
+    /// falling back to `~/.config/rusty-chip8/config.toml`.
+    pub fn default_path() -> Option<PathBuf> {
+        let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(env::var_os("HOME")?).join(".config")
+        };
+        Some(config_dir.join(CONFIG_DIR_NAME).join(CONFIG_FILE_NAME))
+    }
+
+    pub fn load() -> Config {
+        match Config::default_path() {
+            Some(path) => Config::load_from(path),
+            None => {
+                log_warn!(Config, "Unable to locate config directory. Using defaults.");
+                Config { path: None, table: Table::new(), document: DocumentMut::new(), read_only: false }
+            }
+        }
+    }
+
+    /// Loads the config file at path. A missing file results in an empty
+    /// config. A malformed or unreadable file also results in an empty config,
+    /// but is then never saved over.
+    pub fn load_from(path: PathBuf) -> Config {
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                log_error!(Config, "Unable to read {}: {}. Using defaults.", path.display(), e);
+                return Config { path: Some(path), table: Table::new(), document: DocumentMut::new(), read_only: true };
+            }
+        };
+
+        let parsed = text.parse::<Table>().map_err(|e| e.to_string())
+            .and_then(|table| Ok((table, text.parse::<DocumentMut>().map_err(|e| e.to_string())?)));
+        match parsed {
+            Ok((table, document)) => Config { path: Some(path), table, document, read_only: false },
+            Err(e) => {
+                log_error!(Config, "Unable to parse {}: {}. Using defaults.", path.display(), e);
+                Config { path: Some(path), table: Table::new(), document: DocumentMut::new(), read_only: true }
+            }
+        }
+    }
+
+    pub fn settings(&self, rom: Option<&RomId>) -> Settings {
+        let mut settings = Settings::new();
+        settings.apply(&self.table);
+
+        if let Some(rom) = rom {
+            settings.apply(&rom.settings);
+            // Hash sections are applied last, since they are the most specific
+            for key in [&rom.file_name, &rom.hash] {
+                if let Some(overrides) = self.rom_table(key) {
+                    settings.apply(overrides);
+                }
+            }
+        }
+
+        settings
+    }
+
+    fn rom_table(&self, key: &str) -> Option<&Table> {
+        self.table.get(ROMS_TABLE)?.as_table()?.get(key)?.as_table()
+    }
+
+    /// Sets a setting in the global section, or in the rom section when rom
+    /// is provided. An existing hash section is preferred over the file name.
+    pub fn set(&mut self, rom: Option<&RomId>, key: &str, value: Value) {
+        let section = rom.map(|rom| if self.rom_table(&rom.hash).is_some() { &rom.hash } else { &rom.file_name });
+
+        let mut document: &mut dyn TableLike = self.document.as_table_mut();
+        if let Some(section) = section {
+            let Some(table) = document_section(document, ROMS_TABLE).and_then(|roms| document_section(roms, section)) else { return; };
+            document = table;
+        }
+        let mut edited = document_value(&value);
+        // Keep comments written next to the old value
+        if let Some(old) = document.get(key).and_then(Item::as_value) {
+            *edited.decor_mut() = old.decor().clone();
+        }
+        document.insert(key, Item::Value(edited));
+
+        let table = match section {
+            None => &mut self.table,
+            Some(section) => {
+                let roms = self.table.entry(ROMS_TABLE).or_insert_with(|| Value::Table(Table::new()));
+                let Value::Table(roms) = roms else { return; };
+                match roms.entry(section.clone()).or_insert_with(|| Value::Table(Table::new())) {
+                    Value::Table(t) => t,
+                    _ => return
+                }
+            }
+        };
+
+        table.insert(String::from(key), value);
+    }
+
+    /// Paths of the roms played last, most recent first.
+    pub fn recent_roms(&self) -> Vec<String> {
+        self.table.get(RECENT_ROMS)
+            .and_then(|v| v.as_array())
+            .map(|paths| paths.iter().filter_map(|p| p.as_str()).map(String::from).collect())
+            .unwrap_or_default()
+    }
+
+    /// Moves a rom to the top of the recent roms.
+    pub fn add_recent_rom(&mut self, path: &str) {
+        let mut recent = self.recent_roms();
+        recent.retain(|p| p != path);
+        recent.insert(0, String::from(path));
+        recent.truncate(MAX_RECENT_ROMS);
+        let recent = recent.into_iter().map(Value::String).collect();
+        self.set(None, RECENT_ROMS, Value::Array(recent));
+    }
+
+    /// Writes the config back to disk, creating the config directory if needed.
+    /// Only the settings changed at runtime differ from the file as written.
+    pub fn save(&self) -> io::Result<()> {
+        if self.read_only {
+            return Err(io::Error::other("It failed to load, so it is left as is."));
+        }
+        let path = self.path.as_ref().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no config path"))?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, self.document.to_string())
+    }
+}
+
+/// The table at key in the config document, added if missing.
+fn document_section<'a>(table: &'a mut dyn TableLike, key: &str) -> Option<&'a mut dyn TableLike> {
+    if table.get(key).is_none() {
+        let mut section = toml_edit::Table::new();
+        section.set_implicit(true);
+        table.insert(key, Item::Table(section));
+    }
+    table.get_mut(key)?.as_table_like_mut()
+}
+
+fn document_value(value: &Value) -> toml_edit::Value {
+    match value {
+        Value::String(s) => s.as_str().into(),
+        Value::Integer(i) => (*i).into(),
+        Value::Float(f) => (*f).into(),
+        Value::Boolean(b) => (*b).into(),
+        Value::Datetime(d) => (*d).into(),
+        Value::Array(a) => a.iter().map(document_value).collect::<toml_edit::Array>().into(),
+        Value::Table(t) => t.iter().map(|(k, v)| (k.as_str(), document_value(v))).collect::<toml_edit::InlineTable>().into()
+    }
+}
+
+fn read_u32(value: &Value) -> Option<u32> {
+    value.as_integer().and_then(|v| u32::try_from(v).ok())
+}
+
+/// Integers are accepted wherever a float is expected.
+pub fn read_float(value: &Value) -> Option<f64> {
+    match value {
+        Value::Float(f) => Some(*f),
+        Value::Integer(i) => Some(*i as f64),
+        _ => None
+    }
+}
+
+/// Colors can be written either as "#RRGGBB" or as an array of floats in [0, 1].
+pub fn parse_color(value: &Value) -> Option<[f32; 3]> {
+    match value {
+        Value::String(s) => {
+            let hex = s.strip_prefix('#').unwrap_or(s);
+            if hex.len() != 6 { return None; }
+            let rgb = u32::from_str_radix(hex, 16).ok()?;
+            Some([
+                ((rgb >> 16) & 0xFF) as f32 / 255.0,
+                ((rgb >> 8) & 0xFF) as f32 / 255.0,
+                (rgb & 0xFF) as f32 / 255.0
+            ])
+        },
+        Value::Array(a) if a.len() == 3 => {
+            let mut color = [0.0; 3];
+            for (c, v) in color.iter_mut().zip(a) {
+                *c = read_float(v)? as f32;
+            }
+            Some(color)
+        },
+        _ => None
+    }
+}
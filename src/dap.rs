@@ -0,0 +1,525 @@
+use std::collections::BTreeMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use serde_json::{json, Value};
+use crate::chip8::{Chip8, Register, MEMSIZE};
+use crate::debug::StopReason;
+use crate::history;
+use crate::symbols::LineMap;
+use crate::watch::Watchpoint;
+
+/// The Chip-8 is shown to the editor as a single thread.
+const THREAD_ID: i64 = 1;
+const REGISTERS_REFERENCE: i64 = 1;
+const MEMORY_REFERENCE: i64 = 2;
+/// References of the memory pages start here, one page after the other.
+const FIRST_PAGE_REFERENCE: i64 = 16;
+/// Memory is shown in pages, split in rows of bytes.
+const PAGE_SIZE: usize = 0x100;
+const ROW_SIZE: usize = 16;
+const REGISTER_NAMES: [&str; Register::Total as usize] = [
+    "V0", "V1", "V2", "V3", "V4", "V5", "V6", "V7", "V8", "V9", "VA", "VB", "VC", "VD", "VE", "VF",
+    "I", "PC", "SP", "DT", "ST"
+];
+
+/// Debugs the running rom from an editor, with the debug adapter protocol
+/// over a local TCP port. Addresses are mapped to source lines with the line
+/// map given when launching. The server is polled from the main loop, and
+/// never blocks it.
+pub struct DapServer {
+    listener: TcpListener,
+    client: Option<TcpStream>,
+    /// Bytes received that don't make a whole message yet.
+    input: Vec<u8>,
+    /// Sequence number of the next message sent.
+    seq: i64,
+    /// Events to send after the response to the current request.
+    events: Vec<(&'static str, Value)>,
+    /// Line map given when launching, used instead of the one of the symbols.
+    line_map: Option<LineMap>,
+    /// Breakpoint addresses of each source file.
+    source_breakpoints: BTreeMap<String, Vec<u16>>,
+    /// Breakpoints and watchpoints set by the editor, removed when it
+    /// replaces them or disconnects; those set otherwise are left alone.
+    breakpoints: Vec<u16>,
+    watchpoints: Vec<Watchpoint>,
+    /// Whether the editor waits for the emulation to stop.
+    running: bool,
+    stop_on_entry: bool,
+    /// Rom to open, requested when launching.
+    launch: Option<String>,
+    /// Set when the editor disconnects, once the response is sent.
+    closing: bool
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, b)| bits | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn parse_address(text: &str) -> Option<usize> {
+    let hex = text.strip_prefix("0x")?;
+    let start = hex.split('-').next()?;
+    usize::from_str_radix(start, 16).ok()
+}
+
+fn stop_reason_name(reason: Option<StopReason>) -> &'static str {
+    match reason {
+        Some(StopReason::Breakpoint) => "breakpoint",
+        Some(StopReason::Watchpoint { .. }) => "data breakpoint",
+        Some(StopReason::Step) | Some(StopReason::HistoryStart) => "step",
+        Some(StopReason::Paused) | None => "pause"
+    }
+}
+
+fn variable(name: &str, value: String, variables_reference: i64) -> Value {
+    json!({ "name": name, "value": value, "variablesReference": variables_reference })
+}
+
+impl DapServer {
+    pub fn bind(port: u16) -> Result<DapServer, String> {
+        let listener = TcpListener::bind(("127.0.0.1", port))
+            .and_then(|listener| listener.set_nonblocking(true).map(|_| listener))
+            .map_err(|e| format!("Unable to listen for an editor on port {}: {}", port, e))?;
+        Ok(DapServer {
+            listener,
+            client: None,
+            input: Vec::new(),
+            seq: 1,
+            events: Vec::new(),
+            line_map: None,
+            source_breakpoints: BTreeMap::new(),
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            running: false,
+            stop_on_entry: false,
+            launch: None,
+            closing: false
+        })
+    }
+
+    /// Accepts an editor, handles the requests received, and tells the
+    /// editor when the emulation stops. Returns a message to show when an
+    /// editor connects or disconnects.
+    pub fn poll(&mut self, chip8: &mut Chip8) -> Option<String> {
+        if self.client.is_none() {
+            let (stream, address) = self.listener.accept().ok()?;
+            if stream.set_nonblocking(true).is_err() {
+                return None;
+            }
+            let _ = stream.set_nodelay(true);
+            self.client = Some(stream);
+            self.input.clear();
+            self.running = false;
+            self.closing = false;
+            // Breakpoints are set before the rom starts running
+            chip8.stop(StopReason::Paused);
+            return Some(format!("Editor attached from {}", address));
+        }
+
+        if !self.receive() || !self.handle_input(chip8) {
+            return Some(self.detach(chip8));
+        }
+
+        if self.running && chip8.is_stopped() {
+            self.running = false;
+            let body = json!({
+                "reason": stop_reason_name(chip8.debug.stop_reason),
+                "threadId": THREAD_ID,
+                "allThreadsStopped": true
+            });
+            if !self.send_event("stopped", body) {
+                return Some(self.detach(chip8));
+            }
+        }
+        None
+    }
+
+    /// Rom the editor asked to launch, to be opened by the caller.
+    pub fn take_launch(&mut self) -> Option<String> {
+        self.launch.take()
+    }
+
+    /// Reads the bytes available. Returns false when the editor is gone.
+    fn receive(&mut self) -> bool {
+        let Some(client) = &mut self.client else { return false; };
+        let mut buffer = [0u8; 4096];
+        loop {
+            match client.read(&mut buffer) {
+                Ok(0) => return false,
+                Ok(read) => self.input.extend_from_slice(&buffer[..read]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return true,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => return false
+            }
+        }
+    }
+
+    /// Handles the whole messages received, each a JSON object after a
+    /// Content-Length header. Returns false when the editor disconnected,
+    /// sent a malformed message, or can't be written to.
+    fn handle_input(&mut self, chip8: &mut Chip8) -> bool {
+        loop {
+            let Some(header_end) = self.input.windows(4).position(|w| w == b"\r\n\r\n") else { return true; };
+            let header = String::from_utf8_lossy(&self.input[..header_end]).into_owned();
+            let length = header.lines()
+                .find_map(|line| line.strip_prefix("Content-Length:"))
+                .and_then(|length| length.trim().parse::<usize>().ok());
+            let Some(length) = length else { return false; };
+            let start = header_end + 4;
+            if self.input.len() < start + length {
+                return true;
+            }
+            let body: Vec<u8> = self.input.drain(..start + length).skip(start).collect();
+            let Ok(request) = serde_json::from_slice::<Value>(&body) else { return false; };
+            if request["type"].as_str() != Some("request") {
+                continue;
+            }
+
+            let command = request["command"].as_str().unwrap_or("").to_string();
+            let result = self.handle_request(&command, &request["arguments"], chip8);
+            let mut response = json!({
+                "type": "response",
+                "request_seq": request["seq"],
+                "command": command,
+                "success": result.is_ok()
+            });
+            match result {
+                Ok(Value::Null) => { },
+                Ok(body) => response["body"] = body,
+                Err(message) => response["message"] = Value::from(message)
+            }
+            if !self.send(response) {
+                return false;
+            }
+            for (event, body) in std::mem::take(&mut self.events) {
+                if !self.send_event(event, body) {
+                    return false;
+                }
+            }
+            if self.closing {
+                return false;
+            }
+        }
+    }
+
+    /// Returns the body of the response to a request.
+    fn handle_request(&mut self, command: &str, arguments: &Value, chip8: &mut Chip8) -> Result<Value, String> {
+        match command {
+            "initialize" => {
+                self.events.push(("initialized", Value::Null));
+                Ok(json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsReadMemoryRequest": true,
+                    "supportsDataBreakpoints": true,
+                    "supportsStepBack": true
+                }))
+            },
+            "launch" | "attach" => {
+                if command == "launch" {
+                    let program = arguments["program"].as_str().ok_or("The rom to launch is missing (\"program\").")?;
+                    self.launch = Some(String::from(program));
+                }
+                self.line_map = match arguments["lineMap"].as_str() {
+                    Some(path) => Some(LineMap::load(path)?),
+                    None => None
+                };
+                self.stop_on_entry = arguments["stopOnEntry"].as_bool().unwrap_or(false);
+                Ok(Value::Null)
+            },
+            "setBreakpoints" => Ok(self.set_breakpoints(arguments, chip8)),
+            "dataBreakpointInfo" => Ok(DapServer::data_breakpoint_info(arguments)),
+            "setDataBreakpoints" => Ok(self.set_data_breakpoints(arguments, chip8)),
+            "setExceptionBreakpoints" => Ok(json!({ "breakpoints": [] })),
+            "configurationDone" => {
+                if self.stop_on_entry {
+                    let body = json!({ "reason": "entry", "threadId": THREAD_ID, "allThreadsStopped": true });
+                    self.events.push(("stopped", body));
+                } else {
+                    chip8.resume();
+                    self.running = true;
+                }
+                Ok(Value::Null)
+            },
+            "threads" => {
+                Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "Chip-8" }] }))
+            },
+            "stackTrace" => Ok(self.stack_trace(chip8)),
+            "scopes" => {
+                Ok(json!({ "scopes": [
+                    { "name": "Registers", "variablesReference": REGISTERS_REFERENCE, "expensive": false },
+                    { "name": "Memory", "variablesReference": MEMORY_REFERENCE, "expensive": true }
+                ] }))
+            },
+            "variables" => {
+                let reference = arguments["variablesReference"].as_i64().unwrap_or(0);
+                Ok(json!({ "variables": self.variables(reference, chip8)? }))
+            },
+            "continue" | "next" | "stepIn" | "stepOut" | "stepBack" | "reverseContinue" | "pause" => {
+                match command {
+                    "continue" => chip8.resume(),
+                    "next" => chip8.step_over(),
+                    "stepIn" => chip8.single_step(),
+                    "stepOut" => chip8.step_out(),
+                    "stepBack" => chip8.step_back(),
+                    "reverseContinue" => chip8.reverse_continue(),
+                    _ => chip8.stop(StopReason::Paused)
+                }
+                // The stop is reported once it happens
+                self.running = true;
+                if command == "continue" {
+                    Ok(json!({ "allThreadsContinued": true }))
+                } else {
+                    Ok(Value::Null)
+                }
+            },
+            "evaluate" => {
+                let expression = arguments["expression"].as_str().unwrap_or("");
+                let result = history::writer_command(chip8, expression)
+                    .ok_or("Only `writer <address>` can be evaluated, it tells which instruction last wrote an address.")?;
+                Ok(json!({ "result": result, "variablesReference": 0 }))
+            },
+            "readMemory" => {
+                let reference = arguments["memoryReference"].as_str().and_then(parse_address).ok_or("Invalid memory reference.")?;
+                let offset = arguments["offset"].as_i64().unwrap_or(0);
+                // Bytes past the end of memory are reported as unreadable,
+                // counting no more of them than the size of memory
+                let count = arguments["count"].as_i64().unwrap_or(0).clamp(0, MEMSIZE as i64) as usize;
+                let start = offset.checked_add(reference as i64)
+                    .and_then(|start| usize::try_from(start).ok())
+                    .filter(|start| *start < MEMSIZE)
+                    .ok_or("Address outside of memory.")?;
+                let end = start.saturating_add(count).min(MEMSIZE);
+                Ok(json!({
+                    "address": format!("{:#x}", start),
+                    "data": base64(&chip8.main_memory[start..end]),
+                    "unreadableBytes": count - (end - start)
+                }))
+            },
+            "disconnect" | "terminate" => {
+                self.closing = true;
+                Ok(Value::Null)
+            },
+            _ => Err(format!("Unsupported request: {}", command))
+        }
+    }
+
+    /// Line map given when launching, or else the one of the symbols loaded.
+    fn line_map<'a>(&'a self, chip8: &'a Chip8) -> Option<&'a LineMap> {
+        self.line_map.as_ref().or(chip8.symbols.line_map.as_ref())
+    }
+
+    /// Replaces the breakpoints of a source file, placing them at the
+    /// address of each line.
+    fn set_breakpoints(&mut self, arguments: &Value, chip8: &mut Chip8) -> Value {
+        let path = arguments["source"]["path"].as_str().unwrap_or("");
+        let requested = arguments["breakpoints"].as_array().cloned().unwrap_or_default();
+        let mut addresses = Vec::new();
+        let mut breakpoints = Vec::new();
+
+        for breakpoint in requested.iter() {
+            let line = breakpoint["line"].as_i64().unwrap_or(0);
+            let address = self.line_map(chip8).and_then(|map| map.address_of(path, line as u32));
+            let mut result = json!({ "verified": address.is_some(), "line": line });
+            match (address, self.line_map(chip8)) {
+                (Some(address), _) => {
+                    addresses.push(address);
+                    result["instructionReference"] = Value::from(format!("{:#x}", address));
+                },
+                (None, Some(_)) => result["message"] = Value::from("No instruction at this line"),
+                (None, None) => result["message"] = Value::from("No line map given when launching, nor in the symbols")
+            }
+            breakpoints.push(result);
+        }
+
+        self.source_breakpoints.insert(String::from(path), addresses);
+        let addresses: Vec<u16> = self.source_breakpoints.values().flatten().copied().collect();
+        let debug = &mut chip8.debug;
+        for address in self.breakpoints.iter().filter(|a| !addresses.contains(a)) {
+            debug.breakpoints.retain(|b| b != address);
+        }
+        self.breakpoints.retain(|a| addresses.contains(a));
+        for address in addresses {
+            if !debug.breakpoints.contains(&address) {
+                self.breakpoints.push(address);
+                debug.breakpoints.push(address);
+            }
+        }
+        json!({ "breakpoints": breakpoints })
+    }
+
+    /// Tells whether a variable can be watched: registers can be watched for
+    /// changes, and rows of memory for accesses.
+    fn data_breakpoint_info(arguments: &Value) -> Value {
+        let reference = arguments["variablesReference"].as_i64().unwrap_or(0);
+        let name = arguments["name"].as_str().unwrap_or("");
+        let row = parse_address(name).filter(|_| reference >= FIRST_PAGE_REFERENCE);
+        let (data_id, description, access_types) = match (reference, row) {
+            (REGISTERS_REFERENCE, _) => (Value::from(name), format!("{} changes", name), vec!["write"]),
+            (_, Some(start)) => {
+                let end = (start + ROW_SIZE).min(MEMSIZE) - 1;
+                (Value::from(format!("{:#05x}-{:#05x}", start, end)), format!("Memory {:#05x}-{:#05x}", start, end), vec!["read", "write", "readWrite"])
+            },
+            _ => (Value::Null, String::from("Only registers and memory can be watched"), Vec::new())
+        };
+        json!({ "dataId": data_id, "description": description, "accessTypes": access_types })
+    }
+
+    /// Replaces the watchpoints set by the editor with its data breakpoints.
+    /// Their conditions are written like the conditions of watchpoints,
+    /// e.g. `value == 2`.
+    fn set_data_breakpoints(&mut self, arguments: &Value, chip8: &mut Chip8) -> Value {
+        let requested = arguments["breakpoints"].as_array().cloned().unwrap_or_default();
+        let mut breakpoints = Vec::new();
+        self.remove_watchpoints(chip8);
+
+        for breakpoint in requested.iter() {
+            let data_id = breakpoint["dataId"].as_str().unwrap_or("");
+            // Registers are named, memory rows are ranges of addresses
+            let mut text = match breakpoint["accessType"].as_str() {
+                _ if parse_address(data_id).is_none() => String::from(data_id),
+                Some("read") => format!("read {}", data_id),
+                Some("readWrite") => format!("access {}", data_id),
+                _ => format!("write {}", data_id)
+            };
+            if let Some(condition) = breakpoint["condition"].as_str().filter(|c| !c.trim().is_empty()) {
+                text = format!("{} if {}", text, condition);
+            }
+            match Watchpoint::parse(&text) {
+                Ok(watchpoint) => {
+                    self.watchpoints.push(watchpoint.clone());
+                    chip8.debug.watchpoints.push(watchpoint);
+                    breakpoints.push(json!({ "verified": true }));
+                },
+                Err(e) => breakpoints.push(json!({ "verified": false, "message": e }))
+            }
+        }
+
+        json!({ "breakpoints": breakpoints })
+    }
+
+    /// Removes the watchpoints set by the editor.
+    fn remove_watchpoints(&mut self, chip8: &mut Chip8) {
+        for watchpoint in self.watchpoints.drain(..) {
+            if let Some(index) = chip8.debug.watchpoints.iter().rposition(|w| *w == watchpoint) {
+                chip8.debug.watchpoints.remove(index);
+            }
+        }
+    }
+
+    /// Frames of the current instruction and of the calls leading to it,
+    /// rebuilt from the return addresses on the stack.
+    fn stack_trace(&self, chip8: &Chip8) -> Value {
+        let calls = chip8.return_addresses().into_iter().map(|address| address.wrapping_sub(2));
+        let addresses: Vec<u16> = std::iter::once(chip8.get_register(Register::PC)).chain(calls).collect();
+
+        let frames: Vec<Value> = addresses.iter().enumerate().map(|(id, address)| {
+            let mut frame = json!({
+                "id": id,
+                "name": chip8.symbols.format(*address),
+                "instructionPointerReference": format!("{:#x}", address),
+                "line": 0,
+                "column": 0
+            });
+            if let Some((file, line)) = self.line_map(chip8).and_then(|map| map.line_of(*address)) {
+                let name = Path::new(file).file_name().map_or_else(|| String::from(file), |n| n.to_string_lossy().into_owned());
+                frame["source"] = json!({ "name": name, "path": file });
+                frame["line"] = Value::from(line);
+                frame["column"] = Value::from(1);
+            }
+            frame
+        }).collect();
+
+        json!({ "totalFrames": frames.len(), "stackFrames": frames })
+    }
+
+    /// Registers, memory pages, or the rows of bytes of a page.
+    fn variables(&self, reference: i64, chip8: &Chip8) -> Result<Vec<Value>, String> {
+        let variables = match reference {
+            REGISTERS_REFERENCE => REGISTER_NAMES.iter().enumerate().map(|(number, name)| {
+                let value = chip8.registers[number];
+                match *name {
+                    "I" | "PC" | "SP" => {
+                        let mut register = variable(name, format!("{:#05x}", value), 0);
+                        register["memoryReference"] = Value::from(format!("{:#x}", value));
+                        register
+                    },
+                    _ => variable(name, format!("{:#04x}", value), 0)
+                }
+            }).collect(),
+            MEMORY_REFERENCE => (0..MEMSIZE.div_ceil(PAGE_SIZE)).map(|page| {
+                let start = page * PAGE_SIZE;
+                let end = (start + PAGE_SIZE).min(MEMSIZE) - 1;
+                variable(&format!("{:#05x}", start), format!("{:#05x}-{:#05x}", start, end), FIRST_PAGE_REFERENCE + page as i64)
+            }).collect(),
+            _ if reference >= FIRST_PAGE_REFERENCE => {
+                let start = reference.checked_sub(FIRST_PAGE_REFERENCE)
+                    .and_then(|page| usize::try_from(page).ok())
+                    .and_then(|page| page.checked_mul(PAGE_SIZE))
+                    .filter(|start| *start < MEMSIZE)
+                    .ok_or("No memory page has this reference.")?;
+                let end = (start + PAGE_SIZE).min(MEMSIZE);
+                (start..end).step_by(ROW_SIZE).map(|row| {
+                    let bytes: Vec<String> = chip8.main_memory[row..(row + ROW_SIZE).min(MEMSIZE)].iter()
+                        .map(|b| format!("{:02x}", b))
+                        .collect();
+                    variable(&format!("{:#05x}", row), bytes.join(" "), 0)
+                }).collect()
+            },
+            _ => Vec::new()
+        };
+        Ok(variables)
+    }
+
+    fn send(&mut self, mut message: Value) -> bool {
+        message["seq"] = Value::from(self.seq);
+        self.seq += 1;
+        let body = message.to_string();
+        let message = format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+
+        let Some(client) = &mut self.client else { return false; };
+        // The socket is non-blocking, messages are small enough to not fill it
+        client.set_nonblocking(false).is_ok()
+            && client.write_all(message.as_bytes()).is_ok()
+            && client.set_nonblocking(true).is_ok()
+    }
+
+    fn send_event(&mut self, event: &str, body: Value) -> bool {
+        let mut message = json!({ "type": "event", "event": event });
+        if !body.is_null() {
+            message["body"] = body;
+        }
+        self.send(message)
+    }
+
+    /// Forgets the editor, removing the breakpoints and watchpoints it set,
+    /// and resumes the emulation.
+    fn detach(&mut self, chip8: &mut Chip8) -> String {
+        self.client = None;
+        self.input.clear();
+        self.events.clear();
+        self.running = false;
+        self.line_map = None;
+        self.source_breakpoints.clear();
+        for address in self.breakpoints.drain(..) {
+            chip8.debug.breakpoints.retain(|b| *b != address);
+        }
+        self.remove_watchpoints(chip8);
+        if chip8.is_stopped() {
+            chip8.resume();
+        }
+        String::from("Editor detached")
+    }
+}
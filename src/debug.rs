@@ -2,31 +2,199 @@ use crate::chip8::Chip8;
 use crate::chip8::Register;
 use crate::chip8::{STACK_SIZE, STACK_START};
 use crate::utils;
-use crate::log_debug;
+use crate::bus::Access;
+use crate::watch::{WatchTarget, Watchpoint};
+use crate::{log_debug, log_info};
 
 
 impl Chip8 {
+    /// Logs the registers, at the info level since users ask for them.
     pub fn reg_dump(&self) {
-        println!("### REG DUMP ###");
+        log_info!(Cpu, "### REG DUMP ###");
         for n in 0..=0xF {
-            println!("V{:#x}: {:#x}", n, self.registers[Chip8::get_vx_i(n)]);
+            log_info!(Cpu, "V{:#x}: {:#x}", n, self.registers[Chip8::get_vx_i(n)]);
         }
-        println!("DT: {:#x}", self.get_register(Register::DT));
-        println!("PC: {:#x}", self.get_register(Register::PC));
-        println!("SP: {:#x}", self.get_register(Register::SP));
-        println!("################");
+        log_info!(Cpu, "I: {}", self.symbols.format(self.get_register(Register::I)));
+        log_info!(Cpu, "DT: {:#x}", self.get_register(Register::DT));
+        log_info!(Cpu, "PC: {}", self.symbols.format(self.get_register(Register::PC)));
+        log_info!(Cpu, "SP: {:#x}", self.get_register(Register::SP));
+        log_info!(Cpu, "################");
         
     }
 
     pub fn stack_dump(&self) {
-        log_debug!("### STCK DUMP ###");
+        log_debug!(Cpu, "### STCK DUMP ###");
         for n in 0..STACK_SIZE {
             if n % 2 != 0 { continue };
             let addr = STACK_START - (n as usize) - 2;
             let data = utils::concat_u8_to_u16(self.main_memory[addr - 1], self.main_memory[addr]);
-            log_debug!("{:#x}: {:#x}", STACK_START - (n as usize), data);
+            log_debug!(Cpu, "{:#x}: {}", STACK_START - (n as usize), self.symbols.format(data));
         }
-        log_debug!("#################");
+        log_debug!(Cpu, "### CALL STACK ###");
+        log_debug!(Cpu, "#0 {}", self.symbols.format(self.get_register(Register::PC)));
+        // Calls are the instructions before the return addresses
+        for (depth, address) in self.return_addresses().iter().enumerate() {
+            log_debug!(Cpu, "#{} {}", depth + 1, self.symbols.format(address.wrapping_sub(2)));
+        }
+        log_debug!(Cpu, "#################");
+
+    }
+}
+
+/// Why the emulation stopped for a debugger.
+#[derive(Clone, Copy, PartialEq)]
+pub enum StopReason {
+    /// Stopped on request, or when the debugger attached.
+    Paused,
+    /// A single instruction was executed.
+    Step,
+    Breakpoint,
+    /// A watchpoint matched, with its index and the memory address accessed,
+    /// if it watches memory.
+    Watchpoint { index: usize, address: Option<u16> },
+    /// Running backwards reached the oldest instruction remembered.
+    HistoryStart
+}
+
+/// Breakpoints and watchpoints set by a debugger, checked around every
+/// instruction executed. While stopped, no time is emulated.
+#[derive(Default)]
+pub struct DebugState {
+    pub breakpoints: Vec<u16>,
+    pub watchpoints: Vec<Watchpoint>,
+    pub stop_reason: Option<StopReason>,
+    /// Address execution resumed from, so a breakpoint or execute
+    /// watchpoint there isn't hit again right away.
+    pub resume_address: Option<u16>,
+    /// Address to stop at when stepping over or out of a subroutine, with
+    /// the lowest stack pointer it can be reached with, so recursive calls
+    /// don't stop early.
+    run_until: Option<(u16, u16)>
+}
+
+impl Chip8 {
+    pub fn is_stopped(&self) -> bool {
+        self.debug.stop_reason.is_some()
+    }
+
+    pub fn stop(&mut self, reason: StopReason) {
+        self.debug.stop_reason = Some(reason);
+        self.debug.run_until = None;
+    }
+
+    /// Resumes the emulation from the current instruction.
+    pub fn resume(&mut self) {
+        self.debug.stop_reason = None;
+        self.debug.resume_address = Some(self.get_register(Register::PC));
+    }
+
+    /// Why the emulation should stop before the next instruction, if it
+    /// has a breakpoint or ends a step, and execution didn't just resume
+    /// from it.
+    pub fn breakpoint_hit(&self) -> Option<StopReason> {
+        let pc = self.get_register(Register::PC);
+        if let Some((address, sp)) = self.debug.run_until {
+            if pc == address && self.get_register(Register::SP) >= sp {
+                return Some(StopReason::Step);
+            }
+        }
+        let resumed_here = self.debug.resume_address == Some(pc);
+        (!resumed_here && self.debug.breakpoints.contains(&pc)).then_some(StopReason::Breakpoint)
+    }
+
+    /// Checks a memory access against the watchpoints, called by the bus.
+    /// Instructions fetched stop the emulation before they run.
+    pub fn watch_access(&mut self, access: Access, address: u16, value: u8) {
+        if self.debug.watchpoints.is_empty() || self.is_stopped() {
+            return;
+        }
+        if access == Access::Execute && self.debug.resume_address == Some(address) {
+            return;
+        }
+        let registers = &self.registers;
+        let hit = self.debug.watchpoints.iter().position(|w| w.matches_access(access, address, registers, value));
+        if let Some(index) = hit {
+            self.stop(StopReason::Watchpoint { index, address: Some(address) });
+        }
+    }
+
+    /// Executes the next instruction, stopping if it matches a watchpoint.
+    pub fn step_watched(&mut self) {
+        let watching_registers = self.debug.watchpoints.iter().any(|w| matches!(w.target, WatchTarget::Register(_)));
+        let before = self.registers;
+        self.step();
+        if !watching_registers || self.is_stopped() {
+            return;
+        }
+        let registers = &self.registers;
+        let hit = self.debug.watchpoints.iter().position(|w| w.matches_change(&before, registers));
+        if let Some(index) = hit {
+            self.stop(StopReason::Watchpoint { index, address: None });
+        }
+    }
 
+    /// Executes a single instruction for a debugger, and stops after it. A
+    /// Chip-8 waiting for a key press stays on the same instruction.
+    pub fn single_step(&mut self) {
+        self.resume();
+        if !self.halted_for_keypress {
+            self.step_watched();
+        }
+        if self.debug.stop_reason.is_none() {
+            self.debug.stop_reason = Some(StopReason::Step);
+        }
+    }
+
+    /// Describes a stop caused by a watchpoint, e.g. to show it to the user.
+    pub fn watchpoint_hit(&self) -> Option<String> {
+        let Some(StopReason::Watchpoint { index, address }) = self.debug.stop_reason else { return None; };
+        let watchpoint = self.debug.watchpoints.get(index)?;
+        let pc = self.get_register(Register::PC);
+        Some(match address {
+            Some(address) => format!("Watchpoint '{}' hit at {} (address {})", watchpoint.text,
+                self.symbols.format(pc), self.symbols.format(address)),
+            None => format!("Watchpoint '{}' hit at {}", watchpoint.text, self.symbols.format(pc))
+        })
+    }
+}
+
+impl Chip8 {
+    /// Steps over subroutine calls: a CALL runs until the subroutine
+    /// returns, other instructions are single-stepped.
+    pub fn step_over(&mut self) {
+        // CALL is 2NNN
+        if self.fetch_next() & 0xF000 == 0x2000 {
+            let pc = self.get_register(Register::PC);
+            let sp = self.get_register(Register::SP);
+            self.resume();
+            self.debug.run_until = Some((pc + 2, sp));
+        } else {
+            self.single_step();
+        }
+    }
+
+    /// Runs until the current subroutine returns. Outside of subroutines,
+    /// single-steps instead.
+    pub fn step_out(&mut self) {
+        match self.return_addresses().first() {
+            Some(&address) => {
+                let sp = self.get_register(Register::SP);
+                self.resume();
+                self.debug.run_until = Some((address, sp + 2));
+            },
+            None => self.single_step()
+        }
+    }
+
+    /// Return addresses of the subroutines called, stored in the stack
+    /// area below STACK_START, the most recent first.
+    pub fn return_addresses(&self) -> Vec<u16> {
+        let sp = self.get_register(Register::SP) as usize;
+        if sp == 0 || sp > STACK_START {
+            return Vec::new();
+        }
+        (sp..STACK_START).step_by(2)
+            .map(|address| utils::concat_u8_to_u16(self.main_memory[address - 1], self.main_memory[address]))
+            .collect()
     }
-}
\ No newline at end of file
+}
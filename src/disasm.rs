@@ -0,0 +1,109 @@
+use crate::symbols::Symbols;
+use crate::utils::nibbles_to_tuple;
+
+/// Groups of instructions, to filter traces.
+#[derive(Clone, Copy, PartialEq)]
+pub enum OpcodeClass {
+    /// Jumps, calls and returns.
+    Flow,
+    /// Conditional skips on registers.
+    Skip,
+    /// Loads of values into registers.
+    Load,
+    /// Arithmetic, logic and random numbers.
+    Alu,
+    /// Loads and stores through I, and changes of I.
+    Memory,
+    Display,
+    Key,
+    Timer,
+    Unknown
+}
+
+const CLASSES: [(&str, OpcodeClass); 9] = [
+    ("flow", OpcodeClass::Flow),
+    ("skip", OpcodeClass::Skip),
+    ("load", OpcodeClass::Load),
+    ("alu", OpcodeClass::Alu),
+    ("memory", OpcodeClass::Memory),
+    ("display", OpcodeClass::Display),
+    ("key", OpcodeClass::Key),
+    ("timer", OpcodeClass::Timer),
+    ("unknown", OpcodeClass::Unknown)
+];
+
+impl OpcodeClass {
+    pub fn from_name(name: &str) -> Option<OpcodeClass> {
+        CLASSES.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, c)| *c)
+    }
+
+    /// Names of the classes, to list them in errors.
+    pub fn names() -> Vec<&'static str> {
+        CLASSES.iter().map(|(n, _)| *n).collect()
+    }
+
+    pub fn of(opcode: u16) -> OpcodeClass {
+        match nibbles_to_tuple(opcode) {
+            (0x0, 0x0, 0xE, 0x0) | (0xD, _, _, _) | (0xF, _, 0x2, 0x9) => OpcodeClass::Display,
+            (0x0, 0x0, 0xE, 0xE) | (0x1, _, _, _) | (0x2, _, _, _) | (0xB, _, _, _) => OpcodeClass::Flow,
+            (0x3, _, _, _) | (0x4, _, _, _) | (0x5, _, _, 0x0) | (0x9, _, _, 0x0) => OpcodeClass::Skip,
+            (0x6, _, _, _) | (0x8, _, _, 0x0) => OpcodeClass::Load,
+            (0x7, _, _, _) | (0x8, _, _, 0x1..=0x7) | (0x8, _, _, 0xE) | (0xC, _, _, _) => OpcodeClass::Alu,
+            (0xA, _, _, _) | (0xF, _, 0x1, 0xE) | (0xF, _, 0x3, 0x3) | (0xF, _, 0x5, 0x5) | (0xF, _, 0x6, 0x5) => OpcodeClass::Memory,
+            (0xE, _, 0x9, 0xE) | (0xE, _, 0xA, 0x1) | (0xF, _, 0x0, 0xA) => OpcodeClass::Key,
+            (0xF, _, 0x0, 0x7) | (0xF, _, 0x1, 0x5) | (0xF, _, 0x1, 0x8) => OpcodeClass::Timer,
+            _ => OpcodeClass::Unknown
+        }
+    }
+}
+
+/// Assembly of an instruction, with the usual Chip-8 mnemonics, e.g.
+/// `LD V3, 0x10`. Unknown opcodes are written as data: `DW 0x5123`.
+pub fn disassemble(opcode: u16) -> String {
+    disassemble_with(opcode, &Symbols::default())
+}
+
+/// Assembly of an instruction, with the labels of the addresses it uses,
+/// e.g. `CALL 0x2a4 <draw>`.
+pub fn disassemble_with(opcode: u16, symbols: &Symbols) -> String {
+    let (n3, x, y, n) = nibbles_to_tuple(opcode);
+    let addr = symbols.format(opcode & 0xFFF);
+    let byte = opcode & 0xFF;
+    match (n3, x, y, n) {
+        (0x0, 0x0, 0xE, 0x0) => String::from("CLS"),
+        (0x0, 0x0, 0xE, 0xE) => String::from("RET"),
+        (0x1, _, _, _) => format!("JP {}", addr),
+        (0x2, _, _, _) => format!("CALL {}", addr),
+        (0x3, _, _, _) => format!("SE V{:X}, {:#04x}", x, byte),
+        (0x4, _, _, _) => format!("SNE V{:X}, {:#04x}", x, byte),
+        (0x5, _, _, 0x0) => format!("SE V{:X}, V{:X}", x, y),
+        (0x6, _, _, _) => format!("LD V{:X}, {:#04x}", x, byte),
+        (0x7, _, _, _) => format!("ADD V{:X}, {:#04x}", x, byte),
+        (0x8, _, _, 0x0) => format!("LD V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x1) => format!("OR V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x2) => format!("AND V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x3) => format!("XOR V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x4) => format!("ADD V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x5) => format!("SUB V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x6) => format!("SHR V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x7) => format!("SUBN V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0xE) => format!("SHL V{:X}, V{:X}", x, y),
+        (0x9, _, _, 0x0) => format!("SNE V{:X}, V{:X}", x, y),
+        (0xA, _, _, _) => format!("LD I, {}", addr),
+        (0xB, _, _, _) => format!("JP V0, {}", addr),
+        (0xC, _, _, _) => format!("RND V{:X}, {:#04x}", x, byte),
+        (0xD, _, _, _) => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        (0xE, _, 0x9, 0xE) => format!("SKP V{:X}", x),
+        (0xE, _, 0xA, 0x1) => format!("SKNP V{:X}", x),
+        (0xF, _, 0x0, 0x7) => format!("LD V{:X}, DT", x),
+        (0xF, _, 0x0, 0xA) => format!("LD V{:X}, K", x),
+        (0xF, _, 0x1, 0x5) => format!("LD DT, V{:X}", x),
+        (0xF, _, 0x1, 0x8) => format!("LD ST, V{:X}", x),
+        (0xF, _, 0x1, 0xE) => format!("ADD I, V{:X}", x),
+        (0xF, _, 0x2, 0x9) => format!("LD F, V{:X}", x),
+        (0xF, _, 0x3, 0x3) => format!("LD B, V{:X}", x),
+        (0xF, _, 0x5, 0x5) => format!("LD [I], V{:X}", x),
+        (0xF, _, 0x6, 0x5) => format!("LD V{:X}, [I]", x),
+        _ => format!("DW {:#06x}", opcode)
+    }
+}
@@ -1,25 +1,224 @@
-use std::io::Write;
+use std::fmt::Write as _;
+use std::io::{self, Write};
 
-use crate::chip8::{DISPLAYW, DISPLAYH, Color};
+use crate::chip8::{DISPLAYW, DISPLAYH};
+use crate::palette::{self, Palette};
+use crate::phosphor::{Ghosting, PhosphorFilter};
+use toml::Value;
 
-pub fn draw_console(framebuffer: &[u8; (DISPLAYW * DISPLAYH) as usize]) {
-    // println!("-------------------------------");
-    print!("{esc}[2J{esc}[1;1H", esc = 27 as char);
-    for i in (0..(DISPLAYW * DISPLAYH)).rev() {
-        if framebuffer[(DISPLAYW * DISPLAYH - 1 - i) as usize] == Color::White as u8 {
-            print!("█");
+/// Each character cell shows two pixels, one on top of the other: the upper
+/// half block is drawn with the top pixel as foreground color and the bottom
+/// pixel as background color.
+const HALF_BLOCK: char = '▀';
+const ROWS: u32 = DISPLAYH / 2;
+
+/// How colors are written to the terminal.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ColorMode {
+    /// 24-bit colors, shown exactly.
+    TrueColor,
+    /// The xterm 256 color palette.
+    Indexed256,
+    /// The 16 basic ANSI colors, supported by every color terminal.
+    Ansi16
+}
+
+impl ColorMode {
+    pub fn from_name(name: &str) -> Option<ColorMode> {
+        match name {
+            "truecolor" => Some(ColorMode::TrueColor),
+            "256" => Some(ColorMode::Indexed256),
+            "16" => Some(ColorMode::Ansi16),
+            _ => None
+        }
+    }
+
+    /// Reads a color mode from the config, where "auto" is None.
+    pub fn from_config(value: &Value) -> Option<Option<ColorMode>> {
+        match value.as_str()? {
+            "auto" => Some(None),
+            name => ColorMode::from_name(name).map(Some)
+        }
+    }
+
+    /// Guesses the colors supported by the terminal from the environment.
+    pub fn detect() -> ColorMode {
+        let colorterm = std::env::var("COLORTERM").unwrap_or_default();
+        let term = std::env::var("TERM").unwrap_or_default();
+        if colorterm.contains("truecolor") || colorterm.contains("24bit") {
+            ColorMode::TrueColor
+        } else if term.contains("256color") {
+            ColorMode::Indexed256
         } else {
-            print!(" ");
+            ColorMode::Ansi16
+        }
+    }
+}
+
+/// Approximate RGB values of the 16 ANSI colors, as shown by xterm.
+const ANSI_COLORS: [[u8; 3]; 16] = [
+    [0, 0, 0], [205, 0, 0], [0, 205, 0], [205, 205, 0],
+    [0, 0, 238], [205, 0, 205], [0, 205, 205], [229, 229, 229],
+    [127, 127, 127], [255, 0, 0], [0, 255, 0], [255, 255, 0],
+    [92, 92, 255], [255, 0, 255], [0, 255, 255], [255, 255, 255]
+];
+
+/// Intensity of each step of the color cube in the xterm 256 color palette.
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+#[derive(Clone, Copy, PartialEq)]
+enum TermColor {
+    Rgb([u8; 3]),
+    Indexed(u8),
+    Ansi(u8)
+}
+
+fn color_distance(a: [u8; 3], b: [u8; 3]) -> u32 {
+    (0..3).map(|i| (a[i] as i32 - b[i] as i32).pow(2) as u32).sum()
+}
+
+impl TermColor {
+    fn from_rgb(color: [f32; 3], mode: ColorMode) -> TermColor {
+        let rgb = color.map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8);
+        match mode {
+            ColorMode::TrueColor => TermColor::Rgb(rgb),
+            ColorMode::Indexed256 => {
+                let nearest_level = |c: u8| (0..6).min_by_key(|i| (CUBE_LEVELS[*i] as i32 - c as i32).abs()).unwrap();
+                let cube = rgb.map(nearest_level);
+                let cube_rgb = cube.map(|i| CUBE_LEVELS[i]);
+                let cube_index = 16 + 36 * cube[0] + 6 * cube[1] + cube[2];
+
+                // The grayscale ramp goes from 8 to 238, in steps of 10
+                let average = (rgb.iter().map(|c| *c as u32).sum::<u32>() / 3) as i32;
+                let gray = ((average - 8 + 5) / 10).clamp(0, 23);
+                let gray_value = (8 + gray * 10) as u8;
+
+                if color_distance(rgb, [gray_value; 3]) < color_distance(rgb, cube_rgb) {
+                    TermColor::Indexed(232 + gray as u8)
+                } else {
+                    TermColor::Indexed(cube_index as u8)
+                }
+            },
+            ColorMode::Ansi16 => {
+                let nearest = (0..16).min_by_key(|i| color_distance(rgb, ANSI_COLORS[*i])).unwrap();
+                TermColor::Ansi(nearest as u8)
+            }
+        }
+    }
+
+    /// Appends the escape sequence that selects this color, as foreground or
+    /// background color.
+    fn write_sgr(&self, out: &mut String, background: bool) {
+        let _ = match *self {
+            TermColor::Rgb([r, g, b]) => write!(out, "\x1b[{};2;{};{};{}m", if background { 48 } else { 38 }, r, g, b),
+            TermColor::Indexed(i) => write!(out, "\x1b[{};5;{}m", if background { 48 } else { 38 }, i),
+            TermColor::Ansi(i) => {
+                let base = match (background, i < 8) {
+                    (false, true) => 30,
+                    (false, false) => 90 - 8,
+                    (true, true) => 40,
+                    (true, false) => 100 - 8
+                };
+                write!(out, "\x1b[{}m", base + i as u32)
+            }
+        };
+    }
+}
+
+/// Draws the screen in a terminal with half block characters, and a status
+/// line below it. Only the characters that changed since the last draw are
+/// written, to avoid flicker and keep the output small over slow connections.
+pub struct ConsoleDisplay {
+    color_mode: ColorMode,
+    palette: Palette,
+    phosphor: PhosphorFilter,
+    /// Colors of the top and bottom pixels of each character last written, or
+    /// None if the character has to be redrawn.
+    cells: Vec<Option<(TermColor, TermColor)>>,
+    status: Option<String>
+}
+
+impl ConsoleDisplay {
+    pub fn new(color_mode: ColorMode) -> ConsoleDisplay {
+        ConsoleDisplay {
+            color_mode,
+            palette: palette::builtin_palettes().remove(0),
+            phosphor: PhosphorFilter::new(Ghosting::Decay { factor: 0.8 }),
+            cells: vec![None; (DISPLAYW * ROWS) as usize],
+            status: None
         }
-        
-        if i % DISPLAYW == 0 {
-            print!("\n");
+    }
+
+    pub fn set_color_mode(&mut self, color_mode: ColorMode) {
+        if color_mode != self.color_mode {
+            self.color_mode = color_mode;
+            self.invalidate();
         }
     }
-    std::io::stdout().flush().expect("Unable to flush stdout.");
 
-    // for i in (0..DISPLAYH).rev() {
-    //     print!("\u{033}");
-    // }
-    // println!("-------------------------------");
-}
\ No newline at end of file
+    pub fn set_palette(&mut self, palette: &Palette) {
+        self.palette = palette.clone();
+    }
+
+    pub fn set_ghosting(&mut self, ghosting: Ghosting) {
+        self.phosphor.set_ghosting(ghosting);
+    }
+
+    /// Makes the next draw write the whole screen, e.g. after the terminal
+    /// was cleared.
+    pub fn invalidate(&mut self) {
+        self.cells.iter_mut().for_each(|c| *c = None);
+        self.status = None;
+    }
+
+    pub fn draw(&mut self, fb: &[u8], frame: u64, status: &str) -> io::Result<()> {
+        let intensities = self.phosphor.apply(fb, frame);
+        let colors: Vec<TermColor> = intensities.chunks_exact(2)
+            .map(|planes| TermColor::from_rgb(self.palette.blend(planes[0], planes[1]), self.color_mode))
+            .collect();
+
+        let mut out = String::new();
+        let mut current_colors = None;
+        for row in 0..ROWS {
+            // Whether the cursor is right after the last character written
+            let mut in_place = false;
+            for x in 0..DISPLAYW {
+                let top = colors[(row * 2 * DISPLAYW + x) as usize];
+                let bottom = colors[((row * 2 + 1) * DISPLAYW + x) as usize];
+                let cell = &mut self.cells[(row * DISPLAYW + x) as usize];
+                if *cell == Some((top, bottom)) {
+                    in_place = false;
+                    continue;
+                }
+                *cell = Some((top, bottom));
+
+                if !in_place {
+                    let _ = write!(out, "\x1b[{};{}H", row + 1, x + 1);
+                    in_place = true;
+                }
+                if current_colors != Some((top, bottom)) {
+                    top.write_sgr(&mut out, false);
+                    bottom.write_sgr(&mut out, true);
+                    current_colors = Some((top, bottom));
+                }
+                out.push(HALF_BLOCK);
+            }
+        }
+
+        if self.status.as_deref() != Some(status) {
+            let visible: String = status.chars().take(DISPLAYW as usize).collect();
+            let _ = write!(out, "\x1b[0m\x1b[{};1H\x1b[2K{}", ROWS + 1, visible);
+            current_colors = None;
+            self.status = Some(String::from(status));
+        }
+
+        if current_colors.is_some() {
+            out.push_str("\x1b[0m");
+        }
+        if out.is_empty() { return Ok(()); }
+
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(out.as_bytes())?;
+        stdout.flush()
+    }
+}
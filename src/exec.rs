@@ -1,17 +1,19 @@
 use std::time::{ Duration, Instant };
 
-use crate::chip8::{ DISPLAYH, DISPLAYW, PROGRAM_START, STACK_START };
+use crate::chip8::{ DISPLAYH, DISPLAYW, FRAME_TIME, MEMSIZE, PROGRAM_START, STACK_SIZE, STACK_START };
 use crate::chip8::Chip8;
 use crate::chip8::Register;
 use crate::chip8::Color;
 use crate::chip8::FONTS;
 use crate::utils;
-use crate::utils::nibbles_to_tuple;
+use crate::disasm;
 use rand::random;
+use crate::{log_debug, log_trace, log_warn};
 
 pub enum InstructionType {
     CLS,
     JP { addr: u16 },
+    JPV { addr: u16 },
     LDV { vx: u8, value: u8  },
     ADDV { vx: u8, value: u8 },
     LDI { value: u16 },
@@ -53,48 +55,136 @@ impl Chip8 {
         // Copy fonts to memory
         self.main_memory[0..FONTS.len()].clone_from_slice(&FONTS);
         self.previous_tick = Instant::now();
-        self.previous_vsync = Instant::now();
     }
 
-    pub fn run_cycles(&mut self) {
-        const INSTRUCTION_TIME_NS: u32 = 1400000;
-        while !self.halted_for_keypress && self.time_since_last_op.as_nanos() > INSTRUCTION_TIME_NS.into() {
-            let next_inst = self.fetch_next();
-            let next_inst_decoded = Chip8::decode_instr(next_inst);         
-            self.advance_pc();
-            self.execute_instruction(&next_inst_decoded);
-            self.time_since_last_op -= Duration::new(0, INSTRUCTION_TIME_NS);
+    /// Returns the wall clock time elapsed since the last call, which is the
+    /// time to emulate when running in real time.
+    pub fn take_elapsed(&mut self) -> Duration {
+        let now = Instant::now();
+        let elapsed: Duration = now - self.previous_tick;
+        self.previous_tick = now;
+        elapsed
+    }
+
+    /// Emulates the given amount of time: runs the instructions that fit in it,
+    /// and updates the timers and frame count. The time doesn't need to match
+    /// the wall clock, e.g. headless runs emulate frames as fast as possible.
+    pub fn run_for(&mut self, elapsed: Duration) {
+        self.run_for_with_frames(elapsed, &mut |_| { });
+    }
+
+    /// Same as `run_for`, calling on_frame at the end of every emulated frame,
+    /// with the state of the machine at that point. Used to record every frame.
+    pub fn run_for_with_frames(&mut self, mut elapsed: Duration, on_frame: &mut dyn FnMut(&Chip8)) {
+        loop {
+            let until_frame_end = FRAME_TIME - self.frame_accumulator;
+            if elapsed < until_frame_end {
+                self.run_slice(elapsed);
+                return;
+            }
+            self.run_slice(until_frame_end);
+            // A debugger stopped the emulation mid-frame, the time left is skipped
+            if self.is_stopped() {
+                return;
+            }
+            elapsed -= until_frame_end;
+            on_frame(self);
         }
+    }
 
+    /// Emulates a slice of time that doesn't extend past the end of the
+    /// current frame.
+    fn run_slice(&mut self, elapsed: Duration) {
+        if self.is_stopped() {
+            return;
+        }
+        self.time_since_last_op += elapsed;
+
+        let instruction_time_ns = self.instruction_time_ns;
+        while !self.halted_for_keypress && !self.waiting_for_vblank && self.time_since_last_op.as_nanos() > instruction_time_ns.into() {
+            if let Some(reason) = self.breakpoint_hit() {
+                self.stop(reason);
+            } else {
+                self.step_watched();
+                self.time_since_last_op -= Duration::new(0, instruction_time_ns);
+            }
+            if self.is_stopped() {
+                // The time left isn't run in a burst once resumed
+                self.time_since_last_op = Duration::new(0, 0);
+                return;
+            }
+        }
+
+        if self.waiting_for_vblank {
+            self.time_since_last_op = Duration::new(0, 0);
+        }
         if self.halted_for_keypress {
             self.time_since_last_op = Duration::new(0, 0);
             for (i, status) in self.keyboard_state.keys.iter().enumerate() {
                 if *status {
+                    log_debug!(Input, "Key {:X} pressed, resuming", i);
                     self.registers[self.halted_keypress_store_reg] = i as u16;
                     self.halted_for_keypress = false;
                     break;
                 }
             }
         }
-        
-        let now = Instant::now();
-        let elapsed: Duration = now - self.previous_tick;
-        self.time_since_last_op += elapsed;
-        self.previous_tick = now;
-        
-        let t_since_last_vsync = now - self.previous_vsync;
-        if t_since_last_vsync.as_millis() > 16 {
-            self.previous_vsync = Instant::now();
+
+        self.frame_accumulator += elapsed;
+        while self.frame_accumulator >= FRAME_TIME {
+            self.frame_accumulator -= FRAME_TIME;
+            self.frame_count += 1;
+            self.waiting_for_vblank = false;
+            self.tick_timers();
+        }
+    }
+
+    /// Fetches, decodes and executes the next instruction.
+    pub fn step(&mut self) {
+        let pc = self.get_register(Register::PC);
+        let next_inst = self.fetch_instruction();
+        // An execute watchpoint stops before the instruction runs
+        if self.is_stopped() {
+            return;
+        }
+        self.debug.resume_address = None;
+        let next_inst_decoded = match Chip8::decode_instr(next_inst) {
+            // BNNN only runs once a cartridge or the config sets how it jumps
+            InstructionType::JPV { .. } if self.quirks.jump.is_none() => InstructionType::UNKNOWN,
+            decoded => decoded
+        };
+        if let InstructionType::UNKNOWN = next_inst_decoded {
+            log_warn!(Cpu, "Unknown instruction {:#06x} at {}", next_inst, self.symbols.format(pc));
+        }
+        self.history.begin(pc, self.instruction_count + 1, &self.registers, self.halted_for_keypress, self.halted_keypress_store_reg);
+        // Only drawing instructions change the screen, which is compared after they run
+        let drawing = self.history.is_recording() && matches!(next_inst_decoded, InstructionType::CLS | InstructionType::DRW { .. });
+        let framebuffer = drawing.then_some(self.framebuffer);
+        self.advance_pc();
+        self.execute_instruction(&next_inst_decoded);
+        self.instruction_count += 1;
+        if let Some(before) = framebuffer {
+            self.history.note_pixels(&before, &self.framebuffer);
         }
+        self.history.end();
+        log_trace!(Cpu, "{} {:04x} {}", self.symbols.format(pc), next_inst, disasm::disassemble_with(next_inst, &self.symbols));
 
-        self.handle_timer(&elapsed);
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(pc, next_inst);
+        }
+        if let Some(tracer) = &mut self.tracer {
+            tracer.record(self.instruction_count, pc, next_inst, &self.registers, &self.symbols);
+            if let InstructionType::UNKNOWN = next_inst_decoded {
+                tracer.dump(&format!("Unknown instruction {:#06x} at {}", next_inst, self.symbols.format(pc)));
+            }
+        }
     }
 
     pub fn fetch_next(&self) -> u16 {
         let nxt_inst_addr: u16 = self.registers[Register::PC as usize];
         utils::concat_u8_to_u16(
-            self.main_memory[nxt_inst_addr as usize],
-            self.main_memory[(nxt_inst_addr + 1) as usize],
+            self.main_memory[nxt_inst_addr as usize % MEMSIZE],
+            self.main_memory[(nxt_inst_addr as usize + 1) % MEMSIZE],
         )
     }
 
@@ -120,6 +210,7 @@ impl Chip8 {
             (0x8, vx, vy, 0xe) => InstructionType::SHL{ vx, vy },
             (0x9, vx, vy, 0x0) => InstructionType::SNER{ vx, vy },
             (0xA, n2, n1, n0) => InstructionType::LDI{ value: utils::concat_nib_to_u16(0, n2, n1, n0) },
+            (0xB, n2, n1, n0) => InstructionType::JPV{ addr: utils::concat_nib_to_u16(0, n2, n1, n0) },
             (0xC, vx, n1, n0) => InstructionType::RND{ vx, value: utils::concat_nib_to_u8(n1, n0)},
             (0xD, vx, vy, bytes) => InstructionType::DRW{ vx, vy, bytes },
             (0xE, vx, 0xA, 0x1) => InstructionType::SKNP{ vx },
@@ -133,12 +224,7 @@ impl Chip8 {
             (0xF, vx, 0x3, 0x3) => InstructionType::LDBCD{ vx },
             (0xF, vx, 0x5, 0x5) => InstructionType::LDVXI{ vx },
             (0xF, vx, 0x6, 0x5) => InstructionType::LDRI{ vx },
-            _ => {
-                let d = nibbles_to_tuple(data);
-                println!("Unknown instruction: {:#x} {:#x} {:#x} {:#x}", d.0, d.1, d.2, d.3);
-                // std::io::stdin().read_line(&mut String::new());
-                InstructionType::UNKNOWN
-            }
+            _ => InstructionType::UNKNOWN
         }
     }
 
@@ -147,6 +233,7 @@ impl Chip8 {
             InstructionType::CLS => self.cls(),
             InstructionType::RET => self.ret(),
             InstructionType::JP{ addr} => self.jmp(*addr),
+            InstructionType::JPV{ addr } => self.jpv(*addr),
             InstructionType::CALL{ addr } => self.call(*addr),
             InstructionType::SKEQV{ vx, value } => self.skpeqv(*vx, *value),
             InstructionType::SER{ vx, vy} => self.ser(*vx, *vy),
@@ -191,6 +278,13 @@ impl Chip8 {
         self.registers[Register::PC as usize] = address;
     }
 
+    /// Jumps to addr plus V0, or plus VX with the jump quirk.
+    pub fn jpv(&mut self, addr: u16) {
+        let vx = if self.quirks.jump == Some(true) { (addr >> 8) as u8 } else { 0 };
+        let offset = self.registers[Chip8::get_vx_i(vx)];
+        self.jmp((addr + offset) & 0xFFF);
+    }
+
     pub fn cls(&mut self) {
         self.framebuffer.iter_mut().for_each(|e| *e = 0);
     }
@@ -214,18 +308,29 @@ impl Chip8 {
 
         let x_coord = self.registers[Chip8::get_vx_i(vx)] % (DISPLAYW as u16);
         let y_coord = self.registers[Chip8::get_vx_i(vy)] % (DISPLAYH as u16);
+        let clip = self.quirks.clip == Some(true);
 
         // Set VF initially to 0. If any pixel drawn clears a pixel that was previously
         // white, VF will be set to 1.
         self.set_register(Register::VF, 0);
+        if bytes > 0 {
+            let rows = self.sprites_drawn.entry(self.registers[Register::I as usize]).or_insert(0);
+            *rows = (*rows).max(bytes);
+        }
         for i in 0..bytes {
-            let i_value = self.registers[Register::I as usize] + i as u16;
-            let data = self.main_memory[i_value as usize];
+            let i_value = self.registers[Register::I as usize].wrapping_add(i as u16);
+            let data = self.read_byte(i_value);
             // log_debug!("vx {} vy {} bytes {} I {:#x} data {:#x}", x_coord, y_coord, bytes, i_value, data);
 
+            if clip && y_coord + i as u16 >= DISPLAYH as u16 {
+                break;
+            }
             let circ_y_coord = (y_coord + i as u16) % DISPLAYH as u16;
 
             for j in 0..8 {
+                if clip && x_coord + j as u16 >= DISPLAYW as u16 {
+                    break;
+                }
                 let circ_x_coord = (x_coord + j as u16) % DISPLAYW as u16;
 
                 let fb_index = Chip8::get_fb_i_from_coord_in_fb(circ_x_coord, circ_y_coord);
@@ -242,14 +347,26 @@ impl Chip8 {
                 }
             }
         }
+        self.waiting_for_vblank = self.quirks.vblank == Some(true);
     }
 
+    /// Calls past the depth of the stack are ignored, since the return
+    /// address would overwrite the memory below it.
     pub fn call(&mut self, addr: u16) {
+        if self.get_register(Register::SP) as usize <= STACK_START - STACK_SIZE as usize {
+            log_warn!(Cpu, "Stack overflow, call to {} ignored", self.symbols.format(addr));
+            return;
+        }
         self.push_stack(self.get_register(Register::PC));
         self.set_register(Register::PC, addr);
     }
 
+    /// Returns with an empty stack are ignored.
     pub fn ret(&mut self) {
+        if self.get_register(Register::SP) as usize >= STACK_START {
+            log_warn!(Cpu, "Return with an empty stack ignored");
+            return;
+        }
         let ret_addr = self.pop_stack();
         self.set_register(Register::PC, ret_addr);
     }
@@ -277,43 +394,40 @@ impl Chip8 {
     pub fn addi(&mut self, vx: u8) {
         let vx_value = self.registers[Chip8::get_vx_i(vx)] as u8;
         let vi_value = self.get_register(Register::I);
-        let sum = vx_value as u16 + vi_value;
+        let sum = (vx_value as u16).wrapping_add(vi_value);
         self.set_register(Register::I, sum);
     }
 
     pub fn set_dt(&mut self, vx: u8) {
         self.set_register(Register::DT, self.registers[Chip8::get_vx_i(vx) & 0xFF]);
+        log_trace!(Timer, "Delay timer set to {}", self.get_register(Register::DT));
     }
 
-    /// handler_timer will decrease DT and ST by 1 at 60hz (1 for every 16 ms that
-    /// passed, to be precise)
-    pub fn handle_timer(&mut self, elapsed: &Duration) {
-        // If DT == 0, timer is disabled
-        if self.get_register(Register::DT) == 0 { 
-            self.timer_accumulator = Duration::new(0, 0);
-            return;
+    /// Counts the timers down, once per emulated frame, so they keep the
+    /// pace of the emulation when it runs faster or pauses.
+    fn tick_timers(&mut self) {
+        let delay_timer = self.get_register(Register::DT);
+        if delay_timer > 0 {
+            self.set_register(Register::DT, delay_timer - 1);
+            if delay_timer == 1 {
+                log_trace!(Timer, "Delay timer reached 0");
+            }
         }
 
-        self.timer_accumulator += *elapsed;
-
-        // TODO: This is the lazy way of doing this
-        // Just to something like dt -= (acummulator / 16)
-        while self.timer_accumulator.as_millis() > 16 {
-            let current_dt = self.get_register(Register::DT);
-            if current_dt > 0 {
-                self.set_register(Register::DT, current_dt - 1);
-                self.timer_accumulator = self.timer_accumulator - Duration::from_millis(16);
-            }
-            else {
-                self.timer_accumulator = Duration::new(0, 0);
-                break;
-            }
+        let sound_timer = self.get_register(Register::Sound);
+        if (sound_timer > 0) != self.last_frame_beeped {
+            log_debug!(Audio, "Beeper {}", if sound_timer > 0 { "on" } else { "off" });
+        }
+        self.last_frame_beeped = sound_timer > 0;
+        if sound_timer > 0 {
+            self.set_register(Register::Sound, sound_timer - 1);
         }
     }
 
     pub fn haltkp(&mut self, vx: u8) {
         self.halted_for_keypress = true;
         self.halted_keypress_store_reg = Chip8::get_vx_i(vx);
+        log_debug!(Input, "Waiting for a key press into V{:X}", vx);
     }
 
     pub fn lddt(&mut self, vx: u8) {
@@ -327,16 +441,19 @@ impl Chip8 {
         let tens = value / 10;
         let ones = value - (tens * 10);
 
-        let i_value = self.get_register(Register::I) as usize;
-        self.main_memory[i_value] = hundreds;
-        self.main_memory[i_value + 1] = tens;
-        self.main_memory[i_value + 2] = ones;
+        let i_value = self.get_register(Register::I);
+        self.write_byte(i_value, hundreds);
+        self.write_byte(i_value.wrapping_add(1), tens);
+        self.write_byte(i_value.wrapping_add(2), ones);
     }
 
     pub fn ldri(&mut self, vx: u8) {
         let i_value = self.get_register(Register::I);
         for offset in 0..=vx {
-            self.registers[Chip8::get_vx_i(offset)] = self.main_memory[(i_value as usize) + (offset as usize)] as u16;
+            self.registers[Chip8::get_vx_i(offset)] = self.read_byte(i_value.wrapping_add(offset as u16)) as u16;
+        }
+        if self.quirks.load_store == Some(false) {
+            self.set_register(Register::I, i_value.wrapping_add(vx as u16 + 1));
         }
     }
 
@@ -367,6 +484,7 @@ impl Chip8 {
         let vy_value = self.registers[Chip8::get_vx_i(vy)];
         let result = (vx_value | vy_value) & 0xFF;
         self.registers[Chip8::get_vx_i(vx)] = result & 0xFF;
+        self.reset_flag_after_logic();
     }
 
     pub fn and(&mut self, vx: u8, vy: u8) {
@@ -374,12 +492,20 @@ impl Chip8 {
         let vy_value = self.registers[Chip8::get_vx_i(vy)];
         let result = (vx_value & vy_value) & 0xFF;
         self.registers[Chip8::get_vx_i(vx)] = result & 0xFF;
+        self.reset_flag_after_logic();
     }
 
     pub fn xor(&mut self, vx: u8, vy: u8) {
         let vx_value = self.registers[Chip8::get_vx_i(vx)];
         let vy_value = self.registers[Chip8::get_vx_i(vy)];
         self.registers[Chip8::get_vx_i(vx)] = (vx_value ^ vy_value) & 0xFF;
+        self.reset_flag_after_logic();
+    }
+
+    fn reset_flag_after_logic(&mut self) {
+        if self.quirks.logic == Some(true) {
+            self.set_register(Register::VF, 0);
+        }
     }
 
     pub fn add(&mut self, vx: u8, vy: u8) {
@@ -404,8 +530,13 @@ impl Chip8 {
         self.set_register(Register::VF, if vx_value > vy_value { 1 } else { 0 });
     }
 
-    pub fn shr(&mut self, vx: u8, _vy: u8) {
-        let vx_value = self.registers[Chip8::get_vx_i(vx)];
+    /// Register shifted by 8XY6 and 8XYE: VX with the shift quirk, else VY.
+    fn shifted_register(&self, vx: u8, vy: u8) -> u16 {
+        self.registers[Chip8::get_vx_i(if self.quirks.shift != Some(false) { vx } else { vy })]
+    }
+
+    pub fn shr(&mut self, vx: u8, vy: u8) {
+        let vx_value = self.shifted_register(vx, vy);
         let lest_significant_bit = utils::get_nth_bit_u16(vx_value, 0);
         self.registers[Chip8::get_vx_i(vx)] = (vx_value >> 1) & 0xFF;
         self.set_register(Register::VF, if lest_significant_bit == 1 { 1 } else { 0 });
@@ -419,15 +550,17 @@ impl Chip8 {
         self.set_register(Register::VF, if vy_value > vx_value { 1 } else { 0 });
     }
 
-    pub fn shl(&mut self, vx: u8, _vy: u8) {
-        let vx_value = self.registers[Chip8::get_vx_i(vx)];
+    pub fn shl(&mut self, vx: u8, vy: u8) {
+        let vx_value = self.shifted_register(vx, vy);
         let most_significant_bit = utils::get_nth_bit_u16(vx_value, 7);
         self.registers[Chip8::get_vx_i(vx)] = (vx_value << 1) & 0xFF;
         self.set_register(Register::VF, if most_significant_bit == 1 { 1 } else { 0 });
     }
 
     // TODO: Sound not yet implemented
-    pub fn ldst(&mut self, _vx: u8) {
+    pub fn ldst(&mut self, vx: u8) {
+        self.set_register(Register::Sound, self.registers[Chip8::get_vx_i(vx)] & 0xFF);
+        log_trace!(Timer, "Sound timer set to {}", self.get_register(Register::Sound));
     }
  
     // TODO: Input not yet implemented
@@ -449,8 +582,191 @@ impl Chip8 {
         let i_value = self.get_register(Register::I);
         for i in 0..(vx + 1) {
             let vx_value = self.registers[Chip8::get_vx_i(i as u8)] as u8;
-            self.main_memory[(i_value + i as u16) as usize] = vx_value; 
+            self.write_byte(i_value.wrapping_add(i as u16), vx_value);
+        }
+        if self.quirks.load_store != Some(true) {
+            self.set_register(Register::I, i_value.wrapping_add(vx as u16 + 1));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::Quirks;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    /// A Chip-8 with the program at PROGRAM_START, and the quirks given.
+    fn with_program(program: &[u16], quirks: Quirks) -> Chip8 {
+        let mut chip8 = Chip8::new();
+        chip8.setup();
+        chip8.quirks = quirks;
+        let bytes: Vec<u8> = program.iter().flat_map(|opcode| opcode.to_be_bytes()).collect();
+        chip8.main_memory[PROGRAM_START..PROGRAM_START + bytes.len()].copy_from_slice(&bytes);
+        chip8
+    }
+
+    fn run(program: &[u16], quirks: Quirks) -> Chip8 {
+        let mut chip8 = with_program(program, quirks);
+        for _ in program {
+            chip8.step();
+        }
+        chip8
+    }
+
+    #[test]
+    fn shifts_vx_unless_the_quirk_is_off() {
+        // V1 = 0x81, V2 = 0x02, V1 = shifted right
+        let program = [0x6181, 0x6202, 0x8126];
+        for (shift, expected, flag) in [(None, 0x40, 1), (Some(true), 0x40, 1), (Some(false), 0x01, 0)] {
+            let chip8 = run(&program, Quirks { shift, ..Quirks::new() });
+            assert_eq!(chip8.get_register(Register::V1), expected);
+            assert_eq!(chip8.get_register(Register::VF), flag);
+        }
+
+        let program = [0x6181, 0x6202, 0x812E];
+        for (shift, expected, flag) in [(None, 0x02, 1), (Some(false), 0x04, 0)] {
+            let chip8 = run(&program, Quirks { shift, ..Quirks::new() });
+            assert_eq!(chip8.get_register(Register::V1), expected);
+            assert_eq!(chip8.get_register(Register::VF), flag);
+        }
+    }
+
+    #[test]
+    fn moves_i_after_loads_and_stores_as_the_quirk_says() {
+        // I = 0x300, store V0-V2, then I = 0x300, load V0-V2
+        let store = [0xA300, 0x6007, 0xF255];
+        let load = [0xA300, 0xF265];
+        for (load_store, after_store, after_load) in [(None, 0x303, 0x300), (Some(true), 0x300, 0x300), (Some(false), 0x303, 0x303)] {
+            let quirks = Quirks { load_store, ..Quirks::new() };
+            let chip8 = run(&store, quirks);
+            assert_eq!(chip8.get_register(Register::I), after_store);
+            assert_eq!(chip8.main_memory[0x300], 7);
+
+            let mut chip8 = with_program(&load, quirks);
+            chip8.main_memory[0x300..0x303].copy_from_slice(&[1, 2, 3]);
+            for _ in load {
+                chip8.step();
+            }
+            assert_eq!(chip8.get_register(Register::I), after_load);
+            assert_eq!(&chip8.registers[0..3], &[1, 2, 3]);
+        }
+    }
+
+    #[test]
+    fn jumps_with_bnnn_only_once_the_quirk_is_set() {
+        // V0 = 0x10, V3 = 0x20, jump to 0x340 plus V0 or V3
+        let program = [0x6010, 0x6320, 0xB340];
+        for (jump, pc) in [(None, 0x206), (Some(false), 0x350), (Some(true), 0x360)] {
+            let chip8 = run(&program, Quirks { jump, ..Quirks::new() });
+            assert_eq!(chip8.get_register(Register::PC), pc);
+        }
+    }
+
+    #[test]
+    fn resets_vf_after_logic_operations_with_the_quirk() {
+        for opcode in [0x8121, 0x8122, 0x8123] {
+            // VF = 5, V1 = 0x0C, V2 = 0x0A, then OR, AND or XOR
+            let program = [0x6F05, 0x610C, 0x620A, opcode];
+            for (logic, flag) in [(None, 5), (Some(false), 5), (Some(true), 0)] {
+                let chip8 = run(&program, Quirks { logic, ..Quirks::new() });
+                assert_eq!(chip8.get_register(Register::VF), flag);
+            }
+        }
+    }
+
+    #[test]
+    fn clips_sprites_at_the_edges_with_the_quirk() {
+        // I = font of 0, at (62, 30): draw 5 rows
+        let program = [0xA000, 0x603E, 0x611E, 0xD015];
+        for (clip, wrapped) in [(None, true), (Some(false), true), (Some(true), false)] {
+            let chip8 = run(&program, Quirks { clip, ..Quirks::new() });
+            let lit = |x, y| chip8.framebuffer[Chip8::get_fb_i_from_coord_in_fb(x, y)] != 0;
+            assert!(lit(62, 30));
+            assert_eq!(lit(0, 30), wrapped);
+            assert_eq!(lit(62, 0), wrapped);
+        }
+    }
+
+    #[test]
+    fn waits_for_the_next_frame_after_drawing_with_the_quirk() {
+        // Draw, then V1 = 1, and loop
+        let program = [0xA000, 0xD005, 0x6101, 0x1206];
+        for (vblank, waits) in [(None, false), (Some(true), true)] {
+            let mut chip8 = with_program(&program, Quirks { vblank, ..Quirks::new() });
+            chip8.instruction_time_ns = 1000;
+            // Time enough for all the instructions, less than a frame
+            chip8.run_slice(Duration::from_micros(10));
+            assert_eq!(chip8.waiting_for_vblank, waits);
+            assert_eq!(chip8.get_register(Register::V1), if waits { 0 } else { 1 });
+
+            // The wait ends with the frame
+            chip8.run_slice(FRAME_TIME);
+            assert!(!chip8.waiting_for_vblank);
+            chip8.run_slice(Duration::from_micros(10));
+            assert_eq!(chip8.get_register(Register::V1), 1);
+        }
+    }
+
+    #[test]
+    fn wraps_memory_accesses_past_the_end_of_memory() {
+        // V0 = 123, I = 0xFFE, store its digits, then draw 3 rows from there
+        let chip8 = run(&[0x607B, 0xAFFE, 0xF033, 0xD003], Quirks::new());
+        assert_eq!(chip8.main_memory[0xFFE], 1);
+        assert_eq!(&chip8.main_memory[0..2], &[2, 3]);
+
+        // Running off the end of memory goes on from its start
+        let mut chip8 = with_program(&[], Quirks::new());
+        chip8.set_register(Register::PC, 0xFFE);
+        chip8.step();
+        assert_eq!(chip8.get_register(Register::PC), 0x1000);
+        chip8.step();
+    }
+
+    #[test]
+    fn flags_no_borrow_only_when_vx_differs() {
+        // V1 = V2 = 5, then 8XY5 or 8XY7
+        for opcode in [0x8125, 0x8127] {
+            let chip8 = run(&[0x6105, 0x6205, opcode], Quirks::new());
+            assert_eq!(chip8.get_register(Register::V1), 0);
+            assert_eq!(chip8.get_register(Register::VF), 0);
+        }
+        let chip8 = run(&[0x6107, 0x6205, 0x8125], Quirks::new());
+        assert_eq!((chip8.get_register(Register::V1), chip8.get_register(Register::VF)), (2, 1));
+        let chip8 = run(&[0x6105, 0x6207, 0x8127], Quirks::new());
+        assert_eq!((chip8.get_register(Register::V1), chip8.get_register(Register::VF)), (2, 1));
+    }
+
+    #[test]
+    fn ignores_calls_past_the_stack_depth_and_returns_with_an_empty_stack() {
+        // Calls itself forever
+        let mut chip8 = with_program(&[0x2200], Quirks::new());
+        for _ in 0..STACK_SIZE / 2 {
+            chip8.step();
+        }
+        assert_eq!(chip8.return_addresses().len(), STACK_SIZE as usize / 2);
+        chip8.step();
+        assert_eq!(chip8.return_addresses().len(), STACK_SIZE as usize / 2);
+        assert_eq!(chip8.get_register(Register::PC), 0x202);
+
+        let chip8 = run(&[0x00EE], Quirks::new());
+        assert_eq!((chip8.get_register(Register::PC), chip8.get_register(Register::SP)), (0x202, STACK_START as u16));
+    }
+
+    /// Roms come from anywhere, so random ones are run with random quirks,
+    /// and must not make the emulator panic.
+    #[test]
+    fn runs_random_programs_without_panicking() {
+        let mut rng = StdRng::seed_from_u64(0x8);
+        for _ in 0..200 {
+            let mut quirk = || [None, Some(false), Some(true)][rng.gen_range(0..3)];
+            let quirks = Quirks { shift: quirk(), load_store: quirk(), jump: quirk(), logic: quirk(), clip: quirk(), vblank: quirk() };
+            let program: Vec<u16> = (0..rng.gen_range(1..64)).map(|_| rng.gen()).collect();
+            let mut chip8 = with_program(&program, quirks);
+            for _ in 0..1000 {
+                chip8.step();
+            }
         }
-        self.set_register(Register::I, (i_value + vx as u16 + 1).into());
     }
 }
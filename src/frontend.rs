@@ -0,0 +1,70 @@
+use crate::config::Settings;
+use crate::input::{Command, InputBackend};
+use crate::menu::Menu;
+use crate::panels::DebugView;
+use crate::palette::Palette;
+use crate::renderer::Image;
+use crate::sprites::SpriteView;
+use crate::log_info;
+
+/// How fast the emulation runs, shown by the frontends.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Speed {
+    Normal,
+    Paused,
+    /// Running this many times faster than normal.
+    FastForward(u32)
+}
+
+/// A way of presenting the emulator to the user, such as a window or the
+/// terminal. Draws the screen, and collects key presses and hotkeys.
+pub trait Frontend: InputBackend {
+    fn should_close(&mut self) -> bool;
+    /// Returns the hotkey commands received since the last call.
+    fn take_commands(&mut self) -> Vec<Command>;
+    /// Applies the settings that can change while running, e.g. when a rom
+    /// with its own overrides is loaded.
+    fn apply_settings(&mut self, settings: &Settings);
+    fn set_palette(&mut self, palette: &Palette);
+    fn draw(&mut self, fb: &[u8], frame: u64);
+    /// Presents the frame drawn, once per loop iteration.
+    fn update(&mut self);
+
+    /// Describes what is running, e.g. the rom name.
+    fn set_status(&mut self, _status: &str) { }
+
+    fn set_speed(&mut self, _speed: Speed) { }
+
+    /// Shows a menu over the screen, or hides it when None. While a menu is
+    /// shown, navigation keys send menu commands instead of Chip-8 keys.
+    /// Frontends that can't show menus never open them.
+    fn show_menu(&mut self, _menu: Option<&Menu>) { }
+
+    /// Shows the debugger panels next to the screen, or hides them when
+    /// None. Frontends that can't show them ignore it.
+    fn show_debugger(&mut self, _view: Option<&DebugView>) { }
+
+    /// Shows the sprite viewer next to the screen, or hides it when None.
+    /// Frontends that can't show it ignore it.
+    fn show_sprites(&mut self, _view: Option<&SpriteView>) { }
+
+    /// Total instructions executed, for frontends that show how many are
+    /// executed per second.
+    fn set_instruction_count(&mut self, _count: u64) { }
+
+    /// Shows a short message to the user.
+    fn show_message(&mut self, message: &str) {
+        log_info!(Render, "{}", message);
+    }
+
+    /// Returns the path of a rom file the user asked to load, if any.
+    fn take_dropped_file(&mut self) -> Option<String> {
+        None
+    }
+
+    /// Reads back the screen as displayed, for frontends that can. Called
+    /// after drawing, before the frame is presented.
+    fn capture_screen(&mut self) -> Option<Image> {
+        None
+    }
+}
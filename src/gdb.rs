@@ -0,0 +1,433 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use crate::chip8::{Chip8, Register, MEMSIZE, STACK_SIZE, STACK_START};
+use crate::debug::StopReason;
+use crate::history;
+use crate::watch::{WatchAccess, WatchTarget, Watchpoint};
+
+/// Registers in the order of the `g` packet, with their size in bytes. The
+/// order matches the `Register` enum. 16 bit registers are little endian.
+const REGISTERS: [(&str, usize); Register::Total as usize] = [
+    ("v0", 1), ("v1", 1), ("v2", 1), ("v3", 1), ("v4", 1), ("v5", 1), ("v6", 1), ("v7", 1),
+    ("v8", 1), ("v9", 1), ("va", 1), ("vb", 1), ("vc", 1), ("vd", 1), ("ve", 1), ("vf", 1),
+    ("i", 2), ("pc", 2), ("sp", 2), ("dt", 1), ("st", 1)
+];
+/// Interrupt sent by the debugger to stop the emulation (Ctrl+C).
+const INTERRUPT: u8 = 0x03;
+/// Largest packet accepted, told to the debugger.
+const PACKET_SIZE: usize = 4096;
+
+/// Debugs the running rom with a debugger speaking the GDB remote serial
+/// protocol, over a local TCP port. A single debugger is served at a time.
+/// The server is polled from the main loop, and never blocks it.
+pub struct GdbServer {
+    listener: TcpListener,
+    port: u16,
+    client: Option<TcpStream>,
+    /// Bytes received that don't make a whole packet yet.
+    input: Vec<u8>,
+    /// Whether the debugger continued, and waits for the emulation to stop.
+    running: bool,
+    /// Breakpoints and watchpoints set by the debugger, removed when it
+    /// detaches. The ones set from the command line or the panels are kept.
+    breakpoints: Vec<u16>,
+    watchpoints: Vec<Watchpoint>
+}
+
+/// Target description, naming the registers for the debugger.
+fn target_xml() -> String {
+    let mut xml = String::from("<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\"><target version=\"1.0\"><feature name=\"org.rusty-chip8.cpu\">");
+    for (number, (name, size)) in REGISTERS.iter().enumerate() {
+        let kind = match *name {
+            "pc" => " type=\"code_ptr\"",
+            "i" | "sp" => " type=\"data_ptr\"",
+            _ => ""
+        };
+        xml += &format!("<reg name=\"{}\" bitsize=\"{}\" regnum=\"{}\"{}/>", name, size * 8, number, kind);
+    }
+    xml + "</feature></target>"
+}
+
+fn parse_hex(text: &str) -> Option<u32> {
+    u32::from_str_radix(text, 16).ok()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// Parses the "address,length" arguments of memory and breakpoint packets.
+fn parse_range(text: &str) -> Option<(u32, u32)> {
+    let (address, length) = text.split_once(',')?;
+    Some((parse_hex(address)?, parse_hex(length)?))
+}
+
+fn register_to_hex(chip8: &Chip8, number: usize) -> String {
+    let value = chip8.registers[number].to_le_bytes();
+    to_hex(&value[..REGISTERS[number].1])
+}
+
+/// Value of a register sent by the debugger, if the register can hold it:
+/// PC must point to an instruction in memory, and SP inside the stack.
+fn register_from_hex(number: usize, bytes: &[u8]) -> Option<u16> {
+    let value = match bytes {
+        [low] => *low as u16,
+        [low, high, ..] => u16::from_le_bytes([*low, *high]),
+        [] => return None
+    };
+    let valid = match number {
+        n if n == Register::PC as usize => valid_pc(value),
+        n if n == Register::SP as usize => {
+            (STACK_START - STACK_SIZE as usize..=STACK_START).contains(&(value as usize)) && value.is_multiple_of(2)
+        },
+        _ => true
+    };
+    valid.then_some(value)
+}
+
+fn valid_pc(pc: u16) -> bool {
+    (pc as usize) < MEMSIZE - 1 && pc.is_multiple_of(2)
+}
+
+/// Reply telling why the emulation stopped. Watchpoints on registers, or on
+/// execution, are reported as breakpoints.
+fn stop_reply(chip8: &Chip8) -> String {
+    if chip8.debug.stop_reason == Some(StopReason::HistoryStart) {
+        return String::from("T05replaylog:begin;");
+    }
+    let Some(StopReason::Watchpoint { index, address: Some(address) }) = chip8.debug.stop_reason else {
+        return String::from("S05");
+    };
+    let kind = match chip8.debug.watchpoints.get(index).map(|w| &w.target) {
+        Some(WatchTarget::Memory { access: WatchAccess::Write, .. }) => "watch",
+        Some(WatchTarget::Memory { access: WatchAccess::Read, .. }) => "rwatch",
+        Some(WatchTarget::Memory { access: WatchAccess::ReadWrite, .. }) => "awatch",
+        _ => return String::from("S05")
+    };
+    format!("T05{}:{:x};", kind, address)
+}
+
+impl GdbServer {
+    pub fn bind(port: u16) -> Result<GdbServer, String> {
+        let listener = TcpListener::bind(("127.0.0.1", port))
+            .and_then(|listener| listener.set_nonblocking(true).map(|_| listener))
+            .map_err(|e| format!("Unable to listen for a debugger on port {}: {}", port, e))?;
+        Ok(GdbServer {
+            listener,
+            port,
+            client: None,
+            input: Vec::new(),
+            running: false,
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new()
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Accepts a debugger, handles the packets received, and tells the
+    /// debugger when the emulation stops. Returns a message to show when a
+    /// debugger attaches or detaches.
+    pub fn poll(&mut self, chip8: &mut Chip8) -> Option<String> {
+        if self.client.is_none() {
+            let (stream, address) = self.listener.accept().ok()?;
+            if stream.set_nonblocking(true).is_err() {
+                return None;
+            }
+            let _ = stream.set_nodelay(true);
+            self.client = Some(stream);
+            self.input.clear();
+            self.running = false;
+            // Debuggers expect the program stopped when they attach
+            chip8.stop(StopReason::Paused);
+            return Some(format!("Debugger attached from {}", address));
+        }
+
+        if !self.receive() {
+            return Some(self.detach(chip8));
+        }
+        let attached = self.handle_input(chip8);
+        if !attached {
+            return Some(self.detach(chip8));
+        }
+
+        if self.running && chip8.is_stopped() {
+            self.running = false;
+            let reply = stop_reply(chip8);
+            if !self.send_packet(&reply) {
+                return Some(self.detach(chip8));
+            }
+        }
+        None
+    }
+
+    /// Reads the bytes available. Returns false when the debugger is gone.
+    fn receive(&mut self) -> bool {
+        let Some(client) = &mut self.client else { return false; };
+        let mut buffer = [0u8; 1024];
+        loop {
+            match client.read(&mut buffer) {
+                Ok(0) => return false,
+                Ok(read) => self.input.extend_from_slice(&buffer[..read]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return true,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => return false
+            }
+        }
+    }
+
+    /// Handles the whole packets received. Returns false when the debugger
+    /// detached, or can't be written to.
+    fn handle_input(&mut self, chip8: &mut Chip8) -> bool {
+        loop {
+            let Some(&first) = self.input.first() else { return true; };
+            match first {
+                INTERRUPT => {
+                    self.input.remove(0);
+                    chip8.stop(StopReason::Paused);
+                },
+                b'$' => {
+                    // "$data#checksum", with a two digit hexadecimal checksum
+                    let Some(end) = self.input.iter().position(|b| *b == b'#') else { return true; };
+                    if self.input.len() < end + 3 {
+                        return true;
+                    }
+                    let packet: Vec<u8> = self.input.drain(..end + 3).collect();
+                    let data = &packet[1..end];
+                    let checksum = std::str::from_utf8(&packet[end + 1..]).ok().and_then(parse_hex);
+                    let expected = data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+                    if checksum != Some(expected as u32) {
+                        if !self.send_raw(b"-") { return false; }
+                        continue;
+                    }
+                    if !self.send_raw(b"+") { return false; }
+
+                    let data = String::from_utf8_lossy(data).into_owned();
+                    if let Some(reply) = self.handle_packet(&data, chip8) {
+                        if !self.send_packet(&reply) { return false; }
+                    }
+                    // Detach and kill end the session, the rom keeps running
+                    if data.starts_with('D') || data == "k" {
+                        return false;
+                    }
+                },
+                // Acknowledgements, and noise between packets
+                _ => { self.input.remove(0); }
+            }
+        }
+    }
+
+    /// Returns the reply to a packet, or None when there is none yet.
+    fn handle_packet(&mut self, packet: &str, chip8: &mut Chip8) -> Option<String> {
+        let (command, arguments) = packet.split_at(packet.chars().next().map_or(0, |c| c.len_utf8()));
+        let reply = match command {
+            "?" => stop_reply(chip8),
+            "g" => (0..REGISTERS.len()).map(|n| register_to_hex(chip8, n)).collect(),
+            "G" => {
+                let Some(bytes) = from_hex(arguments) else { return Some(String::from("E01")); };
+                let mut registers = chip8.registers;
+                let mut offset = 0;
+                for (number, (_, size)) in REGISTERS.iter().enumerate() {
+                    if let Some(value) = bytes.get(offset..offset + size) {
+                        let Some(value) = register_from_hex(number, value) else { return Some(String::from("E01")); };
+                        registers[number] = value;
+                    }
+                    offset += size;
+                }
+                chip8.registers = registers;
+                chip8.history.clear();
+                String::from("OK")
+            },
+            "p" => match parse_hex(arguments).map(|n| n as usize) {
+                Some(number) if number < REGISTERS.len() => register_to_hex(chip8, number),
+                _ => String::from("E01")
+            },
+            "P" => {
+                let register = arguments.split_once('=')
+                    .and_then(|(number, value)| Some((parse_hex(number)? as usize, from_hex(value)?)));
+                match register.filter(|(number, _)| *number < REGISTERS.len())
+                    .and_then(|(number, value)| Some((number, register_from_hex(number, &value)?))) {
+                    Some((number, value)) => {
+                        chip8.registers[number] = value;
+                        chip8.history.clear();
+                        String::from("OK")
+                    },
+                    None => String::from("E01")
+                }
+            },
+            "m" => match parse_range(arguments) {
+                Some((address, length)) if (address as usize) < MEMSIZE => {
+                    let end = (address as usize + length as usize).min(MEMSIZE);
+                    to_hex(&chip8.main_memory[address as usize..end])
+                },
+                _ => String::from("E01")
+            },
+            "M" => {
+                let write = arguments.split_once(':')
+                    .and_then(|(range, data)| Some((parse_range(range)?, from_hex(data)?)));
+                match write {
+                    Some(((address, length), data)) if data.len() == length as usize && address as usize + data.len() <= MEMSIZE => {
+                        chip8.write_memory(address as usize, &data);
+                        String::from("OK")
+                    },
+                    _ => String::from("E01")
+                }
+            },
+            "c" | "s" => {
+                if !arguments.is_empty() {
+                    match parse_hex(arguments).and_then(|address| u16::try_from(address).ok()) {
+                        Some(address) if valid_pc(address) => {
+                            chip8.set_register(Register::PC, address);
+                            chip8.history.clear();
+                        },
+                        _ => return Some(String::from("E01"))
+                    }
+                }
+                if command == "s" {
+                    chip8.single_step();
+                    stop_reply(chip8)
+                } else {
+                    chip8.resume();
+                    self.running = true;
+                    return None;
+                }
+            },
+            // Reverse step (bs) and continue (bc), answered right away since
+            // undoing doesn't wait for the emulation
+            "b" => match arguments {
+                "s" => {
+                    chip8.step_back();
+                    stop_reply(chip8)
+                },
+                "c" => {
+                    chip8.reverse_continue();
+                    stop_reply(chip8)
+                },
+                _ => String::new()
+            },
+            "Z" | "z" => self.handle_breakpoint(command == "Z", arguments, chip8),
+            "H" | "T" => String::from("OK"),
+            "D" => String::from("OK"),
+            "k" => return None,
+            _ => self.handle_query(packet, chip8)
+        };
+        Some(reply)
+    }
+
+    /// Sets (Z) or removes (z) a breakpoint, or a write, read or access
+    /// watchpoint. Only the ones the debugger set can be removed.
+    fn handle_breakpoint(&mut self, set: bool, arguments: &str, chip8: &mut Chip8) -> String {
+        let mut parts = arguments.splitn(3, ',');
+        let kind = parts.next();
+        let address = parts.next().and_then(parse_hex).map(|a| a as u16);
+        let length = parts.next().and_then(|l| parse_hex(l.split(';').next()?)).map(|l| l as u16);
+        let (Some(kind), Some(address), Some(length)) = (kind, address, length) else {
+            return String::from("E01");
+        };
+
+        let debug = &mut chip8.debug;
+        let access = match kind {
+            "0" | "1" => {
+                if !set {
+                    if self.breakpoints.contains(&address) {
+                        self.breakpoints.retain(|b| *b != address);
+                        debug.breakpoints.retain(|b| *b != address);
+                    }
+                } else if !debug.breakpoints.contains(&address) {
+                    self.breakpoints.push(address);
+                    debug.breakpoints.push(address);
+                }
+                return String::from("OK");
+            },
+            "2" => WatchAccess::Write,
+            "3" => WatchAccess::Read,
+            "4" => WatchAccess::ReadWrite,
+            _ => return String::new()
+        };
+
+        let watchpoint = Watchpoint::memory(access, address, length);
+        if set {
+            self.watchpoints.push(watchpoint.clone());
+            debug.watchpoints.push(watchpoint);
+        } else if let Some(index) = self.watchpoints.iter().position(|w| *w == watchpoint) {
+            self.watchpoints.remove(index);
+            if let Some(index) = debug.watchpoints.iter().rposition(|w| *w == watchpoint) {
+                debug.watchpoints.remove(index);
+            }
+        }
+        String::from("OK")
+    }
+
+    fn handle_query(&self, packet: &str, chip8: &Chip8) -> String {
+        if packet.starts_with("qSupported") {
+            return format!("PacketSize={:x};qXfer:features:read+;ReverseStep+;ReverseContinue+", PACKET_SIZE);
+        }
+        // Commands sent with `monitor`, answered with text to print
+        if let Some(arguments) = packet.strip_prefix("qRcmd,") {
+            let command = from_hex(arguments).map(|bytes| String::from_utf8_lossy(&bytes).into_owned()).unwrap_or_default();
+            let text = history::writer_command(chip8, &command)
+                .unwrap_or_else(|| String::from("Commands: writer <address>, the instruction that last wrote an address"));
+            return to_hex(format!("{}\n", text).as_bytes());
+        }
+        if let Some(arguments) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let Some((offset, length)) = parse_range(arguments) else { return String::from("E01"); };
+            let xml = target_xml();
+            let start = (offset as usize).min(xml.len());
+            let end = (start + length as usize).min(xml.len());
+            // 'l' marks the last part, 'm' that there is more
+            let marker = if end == xml.len() { 'l' } else { 'm' };
+            return format!("{}{}", marker, &xml[start..end]);
+        }
+        match packet {
+            "qAttached" => String::from("1"),
+            "qC" => String::from("QC1"),
+            "qfThreadInfo" => String::from("m1"),
+            "qsThreadInfo" => String::from("l"),
+            // Unsupported packets get an empty reply
+            _ => String::new()
+        }
+    }
+
+    fn send_raw(&mut self, data: &[u8]) -> bool {
+        let Some(client) = &mut self.client else { return false; };
+        // The socket is non-blocking, replies are small enough to not fill it
+        client.set_nonblocking(false).is_ok()
+            && client.write_all(data).is_ok()
+            && client.set_nonblocking(true).is_ok()
+    }
+
+    fn send_packet(&mut self, data: &str) -> bool {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        self.send_raw(format!("${}#{:02x}", data, checksum).as_bytes())
+    }
+
+    /// Forgets the debugger, removing the breakpoints and watchpoints it set,
+    /// and resumes the emulation.
+    fn detach(&mut self, chip8: &mut Chip8) -> String {
+        self.client = None;
+        self.input.clear();
+        self.running = false;
+        let debug = &mut chip8.debug;
+        for address in self.breakpoints.drain(..) {
+            debug.breakpoints.retain(|b| *b != address);
+        }
+        for watchpoint in self.watchpoints.drain(..) {
+            if let Some(index) = debug.watchpoints.iter().rposition(|w| *w == watchpoint) {
+                debug.watchpoints.remove(index);
+            }
+        }
+        if chip8.is_stopped() {
+            chip8.resume();
+        }
+        String::from("Debugger detached")
+    }
+}
@@ -0,0 +1,327 @@
+use std::collections::VecDeque;
+use crate::chip8::{Chip8, Register, MEMSIZE};
+use crate::debug::StopReason;
+use crate::disasm;
+use crate::utils;
+
+/// Instructions remembered by default, a couple of minutes of emulation.
+pub const DEFAULT_CAPACITY: usize = 100_000;
+
+/// Last write of an instruction to an address.
+#[derive(Clone, Copy, PartialEq)]
+pub struct MemoryWrite {
+    /// Address of the instruction.
+    pub pc: u16,
+    /// Number of the instruction, counted like `Chip8::instruction_count`.
+    pub instruction: u64,
+    /// Frame the instruction ran in.
+    pub frame: u64
+}
+
+/// State an instruction changed, as it was before it ran.
+struct UndoRecord {
+    registers: [u16; Register::Total as usize],
+    halted_for_keypress: bool,
+    halted_keypress_store_reg: usize,
+    /// Bytes written, with their previous value and last write, in the
+    /// order they were written.
+    memory: Vec<(u16, u8, Option<MemoryWrite>)>,
+    /// Pixels changed, with their previous value.
+    pixels: Vec<(u16, u8)>
+}
+
+/// Undo information of the last instructions executed, so debuggers can
+/// step backwards, and the last write to each address. The oldest
+/// instructions are forgotten once the capacity is reached.
+pub struct History {
+    capacity: usize,
+    records: VecDeque<UndoRecord>,
+    /// Record of the instruction running, while it runs.
+    current: Option<UndoRecord>,
+    /// Address and number of the instruction running, or that last ran.
+    instruction: (u16, u64),
+    writes: Vec<Option<MemoryWrite>>
+}
+
+impl History {
+    /// Remembers up to capacity instructions, none when 0. The last writes
+    /// are tracked either way.
+    pub fn new(capacity: usize) -> History {
+        History {
+            capacity,
+            records: VecDeque::new(),
+            current: None,
+            instruction: (0, 0),
+            writes: vec![None; MEMSIZE]
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Number of instructions that can be undone.
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn last_write(&self, address: u16) -> Option<MemoryWrite> {
+        self.writes.get(address as usize).copied().flatten()
+    }
+
+    /// Starts recording an instruction, before it runs.
+    pub fn begin(&mut self, pc: u16, number: u64, registers: &[u16; Register::Total as usize],
+        halted_for_keypress: bool, halted_keypress_store_reg: usize) {
+        self.instruction = (pc, number);
+        if !self.is_recording() {
+            return;
+        }
+        self.current = Some(UndoRecord {
+            registers: *registers,
+            halted_for_keypress,
+            halted_keypress_store_reg,
+            memory: Vec::new(),
+            pixels: Vec::new()
+        });
+    }
+
+    /// Notes a byte written by the instruction running, called by the bus.
+    pub fn note_write(&mut self, address: u16, previous: u8, frame: u64) {
+        let (pc, instruction) = self.instruction;
+        let write = Some(MemoryWrite { pc, instruction, frame });
+        let last = std::mem::replace(&mut self.writes[address as usize], write);
+        if let Some(record) = &mut self.current {
+            record.memory.push((address, previous, last));
+        }
+    }
+
+    /// Notes the pixels the instruction running changed, from the
+    /// framebuffer before and after it ran.
+    pub fn note_pixels(&mut self, before: &[u8], after: &[u8]) {
+        if let Some(record) = &mut self.current {
+            let changed = before.iter().zip(after.iter()).enumerate().filter(|(_, (b, a))| b != a);
+            record.pixels.extend(changed.map(|(i, (b, _))| (i as u16, *b)));
+        }
+    }
+
+    /// Forgets the instructions remembered, after a debugger changed the
+    /// state: undoing them would mix states with and without the change.
+    pub fn clear(&mut self) {
+        self.records.clear();
+        self.current = None;
+    }
+
+    /// Ends recording the instruction, after it ran.
+    pub fn end(&mut self) {
+        let Some(record) = self.current.take() else { return; };
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+}
+
+impl Chip8 {
+    /// Restores the state before the last instruction remembered. Returns
+    /// false when there is none. Time isn't rewound: timers keep the values
+    /// they had when the instruction ran.
+    pub fn undo_instruction(&mut self) -> bool {
+        let Some(record) = self.history.records.pop_back() else { return false; };
+        for (address, previous, last) in record.memory.iter().rev() {
+            self.main_memory[*address as usize] = *previous;
+            self.history.writes[*address as usize] = *last;
+        }
+        for (index, previous) in record.pixels.iter() {
+            self.framebuffer[*index as usize] = *previous;
+        }
+        self.registers = record.registers;
+        self.halted_for_keypress = record.halted_for_keypress;
+        self.halted_keypress_store_reg = record.halted_keypress_store_reg;
+        self.instruction_count = self.instruction_count.saturating_sub(1);
+        true
+    }
+
+    /// Writes memory for a debugger. The written bytes have no last writer
+    /// anymore, and the history is forgotten.
+    pub fn write_memory(&mut self, address: usize, data: &[u8]) {
+        self.main_memory[address..address + data.len()].copy_from_slice(data);
+        self.history.writes[address..address + data.len()].fill(None);
+        self.history.clear();
+    }
+
+    /// Undoes the last instruction for a debugger, and stops.
+    pub fn step_back(&mut self) {
+        let reason = if self.undo_instruction() { StopReason::Step } else { StopReason::HistoryStart };
+        self.stop(reason);
+    }
+
+    /// Undoes instructions until one with a breakpoint, or the oldest one
+    /// remembered, and stops there.
+    pub fn reverse_continue(&mut self) {
+        loop {
+            if !self.undo_instruction() {
+                self.stop(StopReason::HistoryStart);
+                return;
+            }
+            if self.debug.breakpoints.contains(&self.get_register(Register::PC)) {
+                self.stop(StopReason::Breakpoint);
+                return;
+            }
+        }
+    }
+
+    /// Describes which instruction last wrote an address, e.g. to show it
+    /// to the user.
+    pub fn describe_last_write(&self, address: u16) -> String {
+        if address as usize >= MEMSIZE {
+            return format!("{:#05x} is outside of the memory", address);
+        }
+        let Some(write) = self.history.last_write(address) else {
+            return format!("{} wasn't written by the rom", self.symbols.format(address));
+        };
+        let pc = write.pc as usize;
+        let opcode = utils::concat_u8_to_u16(self.main_memory[pc], self.main_memory[(pc + 1).min(MEMSIZE - 1)]);
+        format!("{} was last written by the instruction at {} ({}), {} instructions ago",
+            self.symbols.format(address), self.symbols.format(write.pc), disasm::disassemble_with(opcode, &self.symbols),
+            self.instruction_count.saturating_sub(write.instruction))
+    }
+}
+
+/// Answers a debugger command asking for the last write to an address,
+/// written `writer 0x300`. Returns None for other commands.
+pub fn writer_command(chip8: &Chip8, command: &str) -> Option<String> {
+    let address = command.trim().strip_prefix("writer")?.trim();
+    let address = match address.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok()?,
+        None => address.parse().ok()?
+    };
+    Some(chip8.describe_last_write(address))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::{DISPLAYH, DISPLAYW, PROGRAM_START};
+
+    const PROGRAM: [u16; 12] = [
+        0x6005, // V0 = 5
+        0xA300, // I = 0x300
+        0xF033, // BCD of V0 at I
+        0x7001, // V0 += 1
+        0xA000, // I = sprite of 0
+        0xD005, // Draw it at (V0, V0)
+        0x2210, // Call 0x210
+        0x1200,
+        0xA302, // I = 0x302
+        0xF055, // Store V0 at I
+        0x00E0, // Clear the screen
+        0x1214  // Loop
+    ];
+
+    type Snapshot = ([u16; Register::Total as usize], [u8; MEMSIZE], [u8; (DISPLAYW * DISPLAYH) as usize]);
+
+    fn with_program(capacity: usize) -> Chip8 {
+        let mut chip8 = Chip8::new();
+        chip8.setup();
+        chip8.history = History::new(capacity);
+        let bytes: Vec<u8> = PROGRAM.iter().flat_map(|opcode| opcode.to_be_bytes()).collect();
+        chip8.main_memory[PROGRAM_START..PROGRAM_START + bytes.len()].copy_from_slice(&bytes);
+        chip8
+    }
+
+    fn snapshot(chip8: &Chip8) -> Snapshot {
+        (chip8.registers, chip8.main_memory, chip8.framebuffer)
+    }
+
+    /// Runs count instructions, returning the state before each of them.
+    fn run(chip8: &mut Chip8, count: usize) -> Vec<Snapshot> {
+        (0..count).map(|_| {
+            let before = snapshot(chip8);
+            chip8.step();
+            before
+        }).collect()
+    }
+
+    #[test]
+    fn undoes_instructions_back_to_each_previous_state() {
+        let mut chip8 = with_program(DEFAULT_CAPACITY);
+        let snapshots = run(&mut chip8, 11);
+        assert!(chip8.framebuffer.iter().all(|pixel| *pixel == 0));
+        assert!(snapshots.iter().any(|(_, _, framebuffer)| framebuffer.iter().any(|pixel| *pixel != 0)));
+        assert_eq!(chip8.history.len(), 11);
+
+        for (count, before) in snapshots.iter().enumerate().rev() {
+            assert!(chip8.undo_instruction());
+            assert!(snapshot(&chip8) == *before, "state before instruction {} differs", count);
+            assert_eq!(chip8.instruction_count, count as u64);
+        }
+        assert!(!chip8.undo_instruction());
+
+        // Running again redoes the same instructions
+        let again = run(&mut chip8, 11);
+        assert!(again == snapshots);
+    }
+
+    #[test]
+    fn forgets_the_oldest_instructions_past_the_capacity() {
+        let mut chip8 = with_program(4);
+        let snapshots = run(&mut chip8, 10);
+        assert_eq!(chip8.history.len(), 4);
+        while chip8.undo_instruction() { }
+        assert!(snapshot(&chip8) == snapshots[6]);
+
+        let mut chip8 = with_program(0);
+        run(&mut chip8, 3);
+        assert!(!chip8.history.is_recording());
+        assert!(!chip8.undo_instruction());
+    }
+
+    #[test]
+    fn reverse_continues_to_a_breakpoint_or_the_oldest_instruction() {
+        let mut chip8 = with_program(DEFAULT_CAPACITY);
+        let snapshots = run(&mut chip8, 11);
+        chip8.debug.breakpoints.push(0x206);
+        chip8.reverse_continue();
+        assert!(chip8.debug.stop_reason == Some(StopReason::Breakpoint));
+        assert!(snapshot(&chip8) == snapshots[3]);
+
+        chip8.step_back();
+        assert!(chip8.debug.stop_reason == Some(StopReason::Step));
+        assert!(snapshot(&chip8) == snapshots[2]);
+
+        chip8.debug.breakpoints.clear();
+        chip8.reverse_continue();
+        assert!(chip8.debug.stop_reason == Some(StopReason::HistoryStart));
+        assert!(snapshot(&chip8) == snapshots[0]);
+        chip8.step_back();
+        assert!(chip8.debug.stop_reason == Some(StopReason::HistoryStart));
+    }
+
+    #[test]
+    fn tracks_the_last_write_to_each_address() {
+        let mut chip8 = with_program(DEFAULT_CAPACITY);
+        run(&mut chip8, 11);
+        let write = |chip8: &Chip8, address| chip8.history.last_write(address).map(|write| (write.pc, write.instruction));
+        assert_eq!(write(&chip8, 0x300), Some((0x204, 3)));
+        assert_eq!(write(&chip8, 0x302), Some((0x212, 9)));
+        assert_eq!(write(&chip8, 0x303), None);
+        assert_eq!(writer_command(&chip8, "writer 0x303").unwrap(), "0x303 wasn't written by the rom");
+        assert!(writer_command(&chip8, "writer 0x302").unwrap().ends_with("2 instructions ago"));
+        assert!(writer_command(&chip8, "step").is_none());
+
+        // Undoing the last write to 0x302 restores the write it replaced
+        for _ in 0..3 {
+            chip8.undo_instruction();
+        }
+        assert_eq!(write(&chip8, 0x302), Some((0x204, 3)));
+
+        // Debugger writes have no writer, and can't be undone
+        chip8.write_memory(0x300, &[7]);
+        assert_eq!(write(&chip8, 0x300), None);
+        assert_eq!(chip8.history.len(), 0);
+    }
+}
@@ -1,4 +1,5 @@
 use crate::chip8::KeyboardKey;
+use crate::log_trace;
 
 pub struct KeyboardState {
     pub keys: [bool; KeyboardKey::Total as usize]
@@ -21,8 +22,53 @@ impl KeyboardState {
     }
 
     pub fn set_key_state(&mut self, key: KeyboardKey, state: bool) {
-        self.keys[key as usize] = state;
+        self.set_key_state_u8(key as u8, state);
     }
+
+    pub fn set_key_state_u8(&mut self, key: u8, state: bool) {
+        if key < KeyboardKey::Total as u8 && self.keys[key as usize] != state {
+            log_trace!(Input, "Key {:X} {}", key, if state { "pressed" } else { "released" });
+            self.keys[key as usize] = state;
+        }
+    }
+}
+
+/// Emulator actions triggered by hotkeys. Input backends queue them, and the
+/// runtime handles them once per frame.
+pub enum Command {
+    NextPalette,
+    PreviousPalette,
+    Screenshot,
+    ToggleRecording,
+    TogglePause,
+    ToggleFastForward,
+    ToggleRomBrowser,
+    ToggleDebugger,
+    /// Executes a single instruction while paused.
+    Step,
+    /// Undoes the last instruction while paused.
+    StepBack,
+    /// Undoes instructions until a breakpoint while paused.
+    ReverseContinue,
+    /// Tells which instruction last wrote an address.
+    ShowLastWrite(u16),
+    /// Sets or removes a breakpoint at an address.
+    ToggleBreakpoint(u16),
+    /// Scrolls the memory view, or the sprite viewer, by a number of rows,
+    /// negative going up.
+    ScrollMemory(i32),
+    ToggleSpriteViewer,
+    SelectSprite(u16),
+    /// Changes the height of the sprites shown by a number of rows.
+    ChangeSpriteHeight(i32),
+    /// Moves the sprite viewer by a number of bytes, to align it on sprites.
+    MoveSprites(i32),
+    /// Saves the sprite selected as a PNG file.
+    ExportSprite,
+    /// Moves the menu selection by a number of items, negative going up.
+    MenuMove(isize),
+    MenuSelect,
+    MenuBack
 }
 
 pub trait InputBackend {
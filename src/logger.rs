@@ -1,13 +1,196 @@
-#[allow(unused_macros)]
-#[cfg(debug_assertions)]
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::Write;
+use std::sync::{Mutex, OnceLock};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::Instant;
+
+/// Environment variable holding the log levels, when `--log` isn't given.
+pub const LOG_ENV: &str = "CHIP8_LOG";
+
+/// How much is logged, from nothing to every detail. Each level includes
+/// the ones before it.
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub enum Level {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace
+}
+
+const LEVEL_NAMES: [(&str, Level); 6] = [
+    ("off", Level::Off),
+    ("error", Level::Error),
+    ("warn", Level::Warn),
+    ("info", Level::Info),
+    ("debug", Level::Debug),
+    ("trace", Level::Trace)
+];
+
+/// Part of the emulator a message comes from.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Category {
+    Cpu,
+    Timer,
+    Input,
+    Render,
+    Rom,
+    Audio,
+    Config,
+    Total
+}
+
+const CATEGORY_NAMES: [&str; Category::Total as usize] = ["cpu", "timer", "input", "render", "rom", "audio", "config"];
+
+impl Level {
+    fn parse(name: &str) -> Result<Level, String> {
+        LEVEL_NAMES.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, l)| *l)
+            .ok_or_else(|| format!("unknown log level '{}'. Levels are off, error, warn, info, debug and trace", name))
+    }
+
+    fn name(&self) -> &'static str {
+        LEVEL_NAMES.iter().find(|(_, l)| l == self).map(|(n, _)| *n).unwrap_or("off")
+    }
+}
+
+/// Level logged for each category, written like `warn,cpu=debug`: a level
+/// alone applies to every category, and `category=level` to one of them.
+#[derive(Clone)]
+pub struct LogFilter {
+    pub levels: [Level; Category::Total as usize]
+}
+
+impl Default for LogFilter {
+    /// Errors, warnings and information of every category.
+    fn default() -> LogFilter {
+        LogFilter { levels: [Level::Info; Category::Total as usize] }
+    }
+}
+
+impl LogFilter {
+    pub fn parse(text: &str) -> Result<LogFilter, String> {
+        let mut filter = LogFilter::default();
+        for item in text.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+            match item.split_once('=') {
+                Some((category, level)) => {
+                    let index = CATEGORY_NAMES.iter().position(|n| n.eq_ignore_ascii_case(category.trim()))
+                        .ok_or_else(|| format!("unknown log category '{}'. Categories are {}", category.trim(), CATEGORY_NAMES.join(", ")))?;
+                    filter.levels[index] = Level::parse(level.trim())?;
+                },
+                None => filter.levels = [Level::parse(item)?; Category::Total as usize]
+            }
+        }
+        Ok(filter)
+    }
+}
+
+static LEVELS: [AtomicU8; Category::Total as usize] = [const { AtomicU8::new(Level::Info as u8) }; Category::Total as usize];
+/// Log file, messages go to stderr without one.
+static OUTPUT: Mutex<Option<File>> = Mutex::new(None);
+static START: OnceLock<Instant> = OnceLock::new();
+/// Messages meant for stderr, held while the terminal frontend draws on the
+/// whole screen. None when messages are written right away.
+static HELD: Mutex<Option<VecDeque<String>>> = Mutex::new(None);
+/// Messages held at most, the oldest ones are dropped past it.
+const MAX_HELD: usize = 1000;
+
+/// Sets the levels logged, and the file messages are written to.
+pub fn init(filter: &LogFilter, path: Option<&str>) -> Result<(), String> {
+    START.get_or_init(Instant::now);
+    for (level, setting) in LEVELS.iter().zip(filter.levels.iter()) {
+        level.store(*setting as u8, Ordering::Relaxed);
+    }
+    if let Some(path) = path {
+        let file = File::create(path).map_err(|e| format!("Unable to create log file {}: {}", path, e))?;
+        *OUTPUT.lock().unwrap() = Some(file);
+    }
+    Ok(())
+}
+
+/// Whether messages of a level and category are logged. The macros check it
+/// before formatting the message.
+pub fn enabled(level: Level, category: Category) -> bool {
+    level != Level::Off && level as u8 <= LEVELS[category as usize].load(Ordering::Relaxed)
+}
+
+pub fn write(level: Level, category: Category, message: fmt::Arguments) {
+    let elapsed = START.get_or_init(Instant::now).elapsed().as_secs_f64();
+    let line = format!("[{:>9.3} {:<5} {:<6}] {}", elapsed, level.name().to_uppercase(), CATEGORY_NAMES[category as usize], message);
+    let mut output = OUTPUT.lock().unwrap();
+    match output.as_mut() {
+        // A failing log file can't be reported anywhere better than stderr
+        Some(file) => if writeln!(file, "{}", line).is_err() {
+            to_stderr(line);
+        },
+        None => to_stderr(line)
+    }
+}
+
+fn to_stderr(line: String) {
+    match HELD.lock().unwrap().as_mut() {
+        Some(held) => {
+            if held.len() == MAX_HELD {
+                held.pop_front();
+            }
+            held.push_back(line);
+        },
+        None => eprintln!("{}", line)
+    }
+}
+
+/// Holds the messages meant for stderr until `release`, so they don't
+/// garble a screen drawn in the terminal. A log file is still written to
+/// right away.
+pub fn hold() {
+    HELD.lock().unwrap().get_or_insert_with(VecDeque::new);
+}
+
+/// Writes the messages held to stderr, and writes the next ones right away.
+pub fn release() {
+    let Some(held) = HELD.lock().unwrap().take() else { return; };
+    if held.len() == MAX_HELD {
+        eprintln!("(earlier messages dropped, only the last {} are shown)", MAX_HELD);
+    }
+    for line in held {
+        eprintln!("{}", line);
+    }
+}
+
+/// Logs a message of a level and category, e.g.
+/// `log_at!(Warn, Rom, "Rom is {} bytes", size)`.
 #[macro_export]
-macro_rules! log_debug {
-    ($($arg:tt)*) => (println!($($arg)*));
+macro_rules! log_at {
+    ($level:ident, $category:ident, $($arg:tt)*) => {
+        if $crate::logger::enabled($crate::logger::Level::$level, $crate::logger::Category::$category) {
+            $crate::logger::write($crate::logger::Level::$level, $crate::logger::Category::$category, format_args!($($arg)*));
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! log_error {
+    ($category:ident, $($arg:tt)*) => ($crate::log_at!(Error, $category, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    ($category:ident, $($arg:tt)*) => ($crate::log_at!(Warn, $category, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! log_info {
+    ($category:ident, $($arg:tt)*) => ($crate::log_at!(Info, $category, $($arg)*));
 }
 
-#[cfg(not(debug_assertions))]
 #[macro_export]
 macro_rules! log_debug {
-    ($($arg:tt)*) => { };
+    ($category:ident, $($arg:tt)*) => ($crate::log_at!(Debug, $category, $($arg)*));
+}
 
-}
\ No newline at end of file
+#[macro_export]
+macro_rules! log_trace {
+    ($category:ident, $($arg:tt)*) => ($crate::log_at!(Trace, $category, $($arg)*));
+}
@@ -14,20 +14,66 @@ mod input;
 mod renderer;
 mod opengl;
 mod shader;
+mod config;
+mod palette;
+mod postfx;
+mod phosphor;
+mod software;
+mod cli;
+mod frontend;
+mod tui;
+mod archive;
+mod screenshot;
+mod recorder;
+mod osd;
+mod menu;
+mod browser;
+mod bus;
+mod gdb;
+mod dap;
+mod symbols;
+mod watch;
+mod disasm;
+mod trace;
+mod panels;
+mod history;
+mod sprites;
+mod profile;
+mod octo;
 
 use std::env;
+use std::process;
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    let mut file_name: Option<String> = None;
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = match cli::Options::parse(&args) {
+        Ok(options) => options,
+        Err(e) => {
+            println!("{}\n\n{}", e, cli::USAGE);
+            process::exit(1);
+        }
+    };
 
-    if args.len() < 2 {
-        println!("No rom file provided. Drag roms to window to load them.");
+    if options.show_help {
+        println!("{}", cli::USAGE);
+        return;
     }
-    else {
-        file_name = Some(String::from(&args[1]));
+
+    if let Err(e) = logger::init(&options.log_filter, options.log_file.as_deref()) {
+        log_error!(Config, "{}", e);
+    }
+
+    if let Some(sprites) = &options.sprites {
+        match sprites::run(sprites) {
+            Ok(message) => println!("{}", message),
+            Err(e) => {
+                log_error!(Rom, "{}", e);
+                process::exit(1);
+            }
+        }
+        return;
     }
 
-    let mut runtime = runtime::Runtime::new(file_name);
+    let mut runtime = runtime::Runtime::new(options);
     runtime.run();
 }
\ No newline at end of file
@@ -0,0 +1,55 @@
+/// A line of a menu. Headers group the items below them, and can't be
+/// selected.
+#[derive(Clone, PartialEq)]
+pub struct MenuItem {
+    pub text: String,
+    pub header: bool
+}
+
+/// A list the user picks an item from, drawn by the frontends over the
+/// screen.
+#[derive(Clone, PartialEq)]
+pub struct Menu {
+    pub title: String,
+    pub items: Vec<MenuItem>,
+    /// Index of the selected item, None when no item can be selected.
+    pub selected: Option<usize>
+}
+
+impl Menu {
+    pub fn new(title: &str) -> Menu {
+        Menu {
+            title: String::from(title),
+            items: Vec::new(),
+            selected: None
+        }
+    }
+
+    pub fn add_header(&mut self, text: &str) {
+        self.items.push(MenuItem { text: String::from(text), header: true });
+    }
+
+    /// Adds a selectable item. The first one added is selected.
+    pub fn add_item(&mut self, text: &str) {
+        if self.selected.is_none() {
+            self.selected = Some(self.items.len());
+        }
+        self.items.push(MenuItem { text: String::from(text), header: false });
+    }
+
+    /// Moves the selection offset items up (negative) or down, skipping
+    /// headers. Stops at the first and last items.
+    pub fn move_selection(&mut self, offset: isize) {
+        let Some(selected) = self.selected else { return; };
+        let target = (selected as isize + offset).clamp(0, self.items.len() as isize - 1) as usize;
+
+        // Closest item to the target, in the direction moved
+        let selectable = |i: &usize| !self.items[*i].header;
+        let found = if offset < 0 {
+            (0..=target).rev().find(selectable).or_else(|| (target..self.items.len()).find(selectable))
+        } else {
+            (target..self.items.len()).find(selectable).or_else(|| (0..=target).rev().find(selectable))
+        };
+        self.selected = found.or(self.selected);
+    }
+}
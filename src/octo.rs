@@ -0,0 +1,1132 @@
+use std::collections::{HashMap, VecDeque};
+use crate::chip8::PROGRAM_START;
+
+/// End of the memory Octo programs can be assembled to, the 64 KB of XO-CHIP.
+const MEMORY_END: usize = 0x10000;
+/// Most tokens macros and string modes can expand to, so a macro calling
+/// itself stops with an error.
+const MAX_EXPANDED_TOKENS: usize = 1 << 20;
+/// Words that can't be used as names of labels, constants or macros.
+const RESERVED: [&str; 25] = [
+    "then", "begin", "key", "-key", ":=", "+=", "-=", "=-", "|=", "&=", "^=", ">>=", "<<=",
+    "==", "!=", "<", ">", "<=", ">=", "{", "}", "hex", "bighex", "random", "-"
+];
+
+#[derive(Clone)]
+struct Token {
+    text: String,
+    line: usize,
+    /// Whether the token was written between quotes.
+    string: bool
+}
+
+/// How a reference to a label defined later is filled in once it is known.
+#[derive(Clone, Copy)]
+enum Patch {
+    /// The 12 bit address of an instruction.
+    Address,
+    /// A 16 bit address, after `i := long` or from `:pointer`.
+    Long,
+    /// The addresses loaded by the two instructions of `:unpack`, with the
+    /// given first digit.
+    Unpack(u8),
+    UnpackLong
+}
+
+struct Reference {
+    name: String,
+    address: usize,
+    patch: Patch,
+    line: usize
+}
+
+/// Blocks of control flow that are still open.
+enum Flow {
+    /// `if ... begin`, with the address of the jump taken when the condition
+    /// doesn't hold.
+    If(usize),
+    /// `else`, with the address of the jump to the `end`.
+    Else(usize),
+    /// `loop`, with its start and the jumps out of it from `while`.
+    Loop { start: usize, exits: Vec<usize> }
+}
+
+#[derive(Clone, Copy)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    Greater,
+    LessEqual,
+    GreaterEqual,
+    Key,
+    NotKey
+}
+
+#[derive(Clone, Copy)]
+enum Operand {
+    Register(u8),
+    Byte(u8)
+}
+
+struct Condition {
+    register: u8,
+    comparison: Comparison,
+    operand: Operand
+}
+
+struct Macro {
+    parameters: Vec<String>,
+    body: Vec<Token>,
+    calls: u32
+}
+
+/// The body of a string mode, expanded for each character of a string that
+/// is in the alphabet.
+struct StringMode {
+    alphabet: Vec<char>,
+    body: Vec<Token>
+}
+
+/// Assembles a program written in Octo, the language of the Octo IDE and of
+/// its cartridges, into the bytes loaded at 0x200. Octo is only implemented
+/// by the JavaScript of its IDE, there is no crate to assemble it.
+pub fn assemble(source: &str) -> Result<Vec<u8>, String> {
+    let mut assembler = Assembler::new(tokenize(source)?);
+    while let Some(token) = assembler.next_token() {
+        assembler.statement(token)?;
+    }
+    assembler.finish()
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    for (index, text) in source.lines().enumerate() {
+        let line = index + 1;
+        let mut chars = text.chars().peekable();
+        loop {
+            while chars.next_if(|c| c.is_whitespace()).is_some() {}
+            match chars.peek() {
+                None | Some('#') => break,
+                Some('"') => {
+                    chars.next();
+                    let mut string = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('"') => break,
+                            Some('\\') => string.push(match chars.next() {
+                                Some('n') => '\n',
+                                Some('r') => '\r',
+                                Some('t') => '\t',
+                                Some('v') => '\x0B',
+                                Some('0') => '\0',
+                                Some(c @ ('\\' | '"')) => c,
+                                _ => return Err(format!("invalid escape in string (line {})", line))
+                            }),
+                            Some(c) => string.push(c),
+                            None => return Err(format!("unterminated string (line {})", line))
+                        }
+                    }
+                    tokens.push(Token { text: string, line, string: true });
+                },
+                Some(_) => {
+                    let mut word = String::new();
+                    while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                        word.push(c);
+                    }
+                    tokens.push(Token { text: word, line, string: false });
+                }
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Numbers are written in decimal, in hexadecimal with `0x` or in binary
+/// with `0b`, and can be negative.
+fn parse_number(text: &str) -> Option<f64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text)
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()?
+    } else if !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value } as f64)
+}
+
+fn parse_register(text: &str) -> Option<u8> {
+    let digit = text.strip_prefix(['v', 'V'])?;
+    if digit.len() != 1 {
+        return None;
+    }
+    u8::from_str_radix(digit, 16).ok()
+}
+
+struct Assembler {
+    tokens: VecDeque<Token>,
+    /// Line of the last token read, for errors.
+    line: usize,
+    memory: Vec<u8>,
+    written: Vec<bool>,
+    here: usize,
+    /// End of the highest address written.
+    end: usize,
+    labels: HashMap<String, usize>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    string_modes: HashMap<String, Vec<StringMode>>,
+    references: Vec<Reference>,
+    flow: Vec<Flow>,
+    expanded_tokens: usize,
+    /// Whether 0x200 holds a jump to main, left out when main comes first.
+    jump_to_main: bool
+}
+
+impl Assembler {
+    fn new(tokens: Vec<Token>) -> Assembler {
+        let mut assembler = Assembler {
+            tokens: VecDeque::from(tokens),
+            line: 1,
+            memory: vec![0; MEMORY_END],
+            written: vec![false; MEMORY_END],
+            here: PROGRAM_START,
+            end: PROGRAM_START,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            string_modes: HashMap::new(),
+            references: Vec::new(),
+            flow: Vec::new(),
+            expanded_tokens: 0,
+            jump_to_main: true
+        };
+        // The jump to main, filled in at the end
+        assembler.memory[PROGRAM_START] = 0x10;
+        assembler.written[PROGRAM_START..PROGRAM_START + 2].fill(true);
+        assembler.here += 2;
+        assembler.end = assembler.here;
+        assembler
+    }
+
+    fn error(&self, message: &str) -> String {
+        format!("{} (line {})", message, self.line)
+    }
+
+    fn next_token(&mut self) -> Option<Token> {
+        let token = self.tokens.pop_front()?;
+        self.line = token.line;
+        Some(token)
+    }
+
+    fn required_token(&mut self) -> Result<Token, String> {
+        self.next_token().ok_or_else(|| self.error("unexpected end of the program"))
+    }
+
+    fn expect(&mut self, text: &str) -> Result<(), String> {
+        let token = self.required_token()?;
+        if token.string || token.text != text {
+            return Err(self.error(&format!("expected '{}', found '{}'", text, token.text)));
+        }
+        Ok(())
+    }
+
+    fn peek_is(&self, text: &str) -> bool {
+        self.tokens.front().is_some_and(|t| !t.string && t.text == text)
+    }
+
+    /// Puts tokens back at the front of the input, to read them next.
+    fn expand(&mut self, tokens: Vec<Token>) -> Result<(), String> {
+        self.expanded_tokens += tokens.len();
+        if self.expanded_tokens > MAX_EXPANDED_TOKENS {
+            return Err(self.error("macros expand to too many tokens"));
+        }
+        for token in tokens.into_iter().rev() {
+            self.tokens.push_front(token);
+        }
+        Ok(())
+    }
+
+    fn byte(&mut self, value: u8) -> Result<(), String> {
+        if self.here >= MEMORY_END {
+            return Err(self.error("the program doesn't fit in memory"));
+        }
+        if self.written[self.here] {
+            return Err(self.error(&format!("address {:#05x} is written twice", self.here)));
+        }
+        self.memory[self.here] = value;
+        self.written[self.here] = true;
+        self.here += 1;
+        self.end = self.end.max(self.here);
+        Ok(())
+    }
+
+    fn opcode(&mut self, opcode: u16) -> Result<(), String> {
+        self.byte((opcode >> 8) as u8)?;
+        self.byte(opcode as u8)
+    }
+
+    /// Sets the target of the jump at address.
+    fn patch_jump(&mut self, address: usize, target: usize) -> Result<(), String> {
+        if target > 0xFFF {
+            return Err(self.error(&format!("can't jump to {:#x}, past 0xFFF", target)));
+        }
+        self.memory[address] = 0x10 | (target >> 8) as u8;
+        self.memory[address + 1] = target as u8;
+        Ok(())
+    }
+
+    /// Emits a jump filled in later, returning its address.
+    fn jump_placeholder(&mut self) -> Result<usize, String> {
+        let address = self.here;
+        self.opcode(0x1000)?;
+        Ok(address)
+    }
+
+    fn is_register(&self, text: &str) -> bool {
+        parse_register(text).is_some() || self.aliases.contains_key(text)
+    }
+
+    fn register(&mut self) -> Result<u8, String> {
+        let token = self.required_token()?;
+        self.aliases.get(&token.text).copied()
+            .or_else(|| parse_register(&token.text).filter(|_| !token.string))
+            .ok_or_else(|| self.error(&format!("expected a register, found '{}'", token.text)))
+    }
+
+    /// Reads the name of something being defined.
+    fn name(&mut self) -> Result<String, String> {
+        let token = self.required_token()?;
+        let valid = !token.string && !token.text.starts_with(':') && !RESERVED.contains(&token.text.as_str())
+            && parse_number(&token.text).is_none() && !self.is_register(&token.text);
+        if !valid {
+            return Err(self.error(&format!("'{}' can't be used as a name", token.text)));
+        }
+        Ok(token.text)
+    }
+
+    fn define_label(&mut self, name: String, address: usize) -> Result<(), String> {
+        if self.labels.contains_key(&name) {
+            return Err(self.error(&format!("label '{}' is defined twice", name)));
+        }
+        // The jump to main is left out when main comes first
+        if name == "main" && self.jump_to_main && self.here == PROGRAM_START + 2 && self.end == self.here {
+            self.written[PROGRAM_START..PROGRAM_START + 2].fill(false);
+            self.jump_to_main = false;
+            self.here = PROGRAM_START;
+            self.end = PROGRAM_START;
+            self.labels.insert(name, PROGRAM_START);
+            return Ok(());
+        }
+        self.labels.insert(name, address);
+        Ok(())
+    }
+
+    /// The value of a number, a constant, a label defined before, or an
+    /// expression between braces. None for other names, e.g. labels defined
+    /// later.
+    fn value(&mut self, token: &Token) -> Result<Option<f64>, String> {
+        if token.string {
+            return Err(self.error(&format!("expected a number, found the string \"{}\"", token.text)));
+        }
+        if token.text == "{" {
+            return self.calc().map(Some);
+        }
+        if let Some(number) = parse_number(&token.text) {
+            return Ok(Some(number));
+        }
+        if let Some(constant) = self.constants.get(&token.text) {
+            return Ok(Some(*constant));
+        }
+        Ok(self.labels.get(&token.text).map(|address| *address as f64))
+    }
+
+    fn number(&mut self) -> Result<i64, String> {
+        let token = self.required_token()?;
+        match self.value(&token)? {
+            Some(value) => Ok(value.floor() as i64),
+            None => Err(self.error(&format!("unknown name '{}'", token.text)))
+        }
+    }
+
+    /// A byte, either signed or unsigned.
+    fn byte_operand(&mut self) -> Result<u8, String> {
+        let value = self.number()?;
+        self.to_byte(value)
+    }
+
+    fn to_byte(&self, value: i64) -> Result<u8, String> {
+        if !(-128..=255).contains(&value) {
+            return Err(self.error(&format!("{} doesn't fit in a byte", value)));
+        }
+        Ok(value as u8)
+    }
+
+    fn nibble_operand(&mut self) -> Result<u8, String> {
+        let value = self.number()?;
+        if !(0..=15).contains(&value) {
+            return Err(self.error(&format!("{} doesn't fit in 4 bits", value)));
+        }
+        Ok(value as u8)
+    }
+
+    /// An address used by the instruction about to be emitted at here. Labels
+    /// defined later are filled in at the end.
+    fn address_operand(&mut self, patch: Patch) -> Result<u16, String> {
+        let token = self.required_token()?;
+        let limit = match patch {
+            Patch::Address | Patch::Unpack(_) => 0xFFF,
+            Patch::Long | Patch::UnpackLong => 0xFFFF
+        };
+        match self.value(&token)? {
+            Some(value) if (0.0..=limit as f64).contains(&value) => Ok(value as u16),
+            Some(value) => Err(self.error(&format!("address {} is out of range", value))),
+            None => {
+                if RESERVED.contains(&token.text.as_str()) || self.is_register(&token.text) {
+                    return Err(self.error(&format!("expected an address, found '{}'", token.text)));
+                }
+                self.references.push(Reference { name: token.text, address: self.here, patch, line: self.line });
+                Ok(0)
+            }
+        }
+    }
+
+    fn statement(&mut self, token: Token) -> Result<(), String> {
+        if token.string {
+            return Err(self.error(&format!("unexpected string \"{}\"", token.text)));
+        }
+        if self.is_register(&token.text) {
+            let register = self.aliases.get(&token.text).copied().or_else(|| parse_register(&token.text)).unwrap_or(0);
+            return self.register_operation(register);
+        }
+
+        match token.text.as_str() {
+            ":" => {
+                let name = self.name()?;
+                self.define_label(name, self.here)
+            },
+            ":next" => {
+                let name = self.name()?;
+                self.define_label(name, self.here + 1)
+            },
+            ":alias" => {
+                let name = self.name()?;
+                let register = self.register()?;
+                self.aliases.insert(name, register);
+                Ok(())
+            },
+            ":const" => {
+                let name = self.name()?;
+                let value = self.number()?;
+                self.constants.insert(name, value as f64);
+                Ok(())
+            },
+            ":calc" => {
+                let name = self.name()?;
+                self.expect("{")?;
+                let value = self.calc()?;
+                self.constants.insert(name, value);
+                Ok(())
+            },
+            ":unpack" => {
+                let patch = if self.peek_is("long") {
+                    self.next_token();
+                    Patch::UnpackLong
+                } else {
+                    Patch::Unpack(self.nibble_operand()?)
+                };
+                let address = self.address_operand(patch)?;
+                let (high, low) = match patch {
+                    Patch::Unpack(nibble) => (nibble << 4 | (address >> 8) as u8, address as u8),
+                    _ => ((address >> 8) as u8, address as u8)
+                };
+                self.opcode(0x6000 | high as u16)?;
+                self.opcode(0x6100 | low as u16)
+            },
+            ":org" => {
+                let address = self.number()?;
+                if !(PROGRAM_START as i64..MEMORY_END as i64).contains(&address) {
+                    return Err(self.error(&format!("can't assemble at {:#x}", address)));
+                }
+                self.here = address as usize;
+                Ok(())
+            },
+            ":byte" => {
+                let value = self.byte_operand()?;
+                self.byte(value)
+            },
+            ":pointer" => {
+                let address = self.address_operand(Patch::Long)?;
+                self.opcode(address)
+            },
+            ":call" => {
+                let address = self.address_operand(Patch::Address)?;
+                self.opcode(0x2000 | address)
+            },
+            ":macro" => self.define_macro(),
+            ":stringmode" => self.define_string_mode(),
+            ":breakpoint" => self.name().map(|_| ()),
+            ":monitor" => {
+                self.required_token()?;
+                self.required_token().map(|_| ())
+            },
+            ":assert" => {
+                let message = match self.tokens.front() {
+                    Some(token) if token.string => self.next_token().map(|t| t.text),
+                    _ => None
+                };
+                self.expect("{")?;
+                if self.calc()? == 0.0 {
+                    return Err(self.error(&format!("assertion failed{}", message.map(|m| format!(": {}", m)).unwrap_or_default())));
+                }
+                Ok(())
+            },
+            "if" => {
+                let condition = self.condition()?;
+                let next = self.required_token()?;
+                match next.text.as_str() {
+                    "then" => self.skip_unless(&condition),
+                    "begin" => {
+                        self.skip_unless(&negate(condition))?;
+                        let jump = self.jump_placeholder()?;
+                        self.flow.push(Flow::If(jump));
+                        Ok(())
+                    },
+                    text => Err(self.error(&format!("expected 'then' or 'begin', found '{}'", text)))
+                }
+            },
+            "else" => match self.flow.pop() {
+                Some(Flow::If(jump)) => {
+                    let end_jump = self.jump_placeholder()?;
+                    self.patch_jump(jump, self.here)?;
+                    self.flow.push(Flow::Else(end_jump));
+                    Ok(())
+                },
+                _ => Err(self.error("'else' without 'if ... begin'"))
+            },
+            "end" => match self.flow.pop() {
+                Some(Flow::If(jump)) | Some(Flow::Else(jump)) => self.patch_jump(jump, self.here),
+                _ => Err(self.error("'end' without 'if ... begin'"))
+            },
+            "loop" => {
+                self.flow.push(Flow::Loop { start: self.here, exits: Vec::new() });
+                Ok(())
+            },
+            "while" => {
+                let condition = self.condition()?;
+                self.skip_unless(&negate(condition))?;
+                let jump = self.jump_placeholder()?;
+                match self.flow.iter_mut().rev().find(|f| matches!(f, Flow::Loop { .. })) {
+                    Some(Flow::Loop { exits, .. }) => {
+                        exits.push(jump);
+                        Ok(())
+                    },
+                    _ => Err(self.error("'while' outside of a loop"))
+                }
+            },
+            "again" => match self.flow.pop() {
+                Some(Flow::Loop { start, exits }) => {
+                    let jump = self.jump_placeholder()?;
+                    self.patch_jump(jump, start)?;
+                    for exit in exits {
+                        self.patch_jump(exit, self.here)?;
+                    }
+                    Ok(())
+                },
+                _ => Err(self.error("'again' without 'loop'"))
+            },
+            "clear" => self.opcode(0x00E0),
+            "return" | ";" => self.opcode(0x00EE),
+            "hires" => self.opcode(0x00FF),
+            "lores" => self.opcode(0x00FE),
+            "exit" => self.opcode(0x00FD),
+            "scroll-left" => self.opcode(0x00FC),
+            "scroll-right" => self.opcode(0x00FB),
+            "scroll-up" => {
+                let rows = self.nibble_operand()?;
+                self.opcode(0x00D0 | rows as u16)
+            },
+            "scroll-down" => {
+                let rows = self.nibble_operand()?;
+                self.opcode(0x00C0 | rows as u16)
+            },
+            "audio" => self.opcode(0xF002),
+            "plane" => {
+                let planes = self.nibble_operand()?;
+                self.opcode(0xF001 | (planes as u16) << 8)
+            },
+            "bcd" => self.register_opcode(0xF033),
+            "saveflags" => self.register_opcode(0xF075),
+            "loadflags" => self.register_opcode(0xF085),
+            "save" | "load" => {
+                let first = self.register()? as u16;
+                if self.peek_is("-") {
+                    self.next_token();
+                    let last = self.register()? as u16;
+                    let kind = if token.text == "save" { 2 } else { 3 };
+                    return self.opcode(0x5000 | first << 8 | last << 4 | kind);
+                }
+                let kind = if token.text == "save" { 0x55 } else { 0x65 };
+                self.opcode(0xF000 | first << 8 | kind)
+            },
+            "sprite" => {
+                let x = self.register()? as u16;
+                let y = self.register()? as u16;
+                let rows = self.nibble_operand()? as u16;
+                self.opcode(0xD000 | x << 8 | y << 4 | rows)
+            },
+            "jump" => {
+                let address = self.address_operand(Patch::Address)?;
+                self.opcode(0x1000 | address)
+            },
+            "jump0" => {
+                let address = self.address_operand(Patch::Address)?;
+                self.opcode(0xB000 | address)
+            },
+            "native" => {
+                let address = self.address_operand(Patch::Address)?;
+                self.opcode(address)
+            },
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let low = match token.text.as_str() {
+                    "delay" => 0x15,
+                    "buzzer" => 0x18,
+                    _ => 0x3A
+                };
+                self.register_opcode(0xF000 | low)
+            },
+            "i" => self.index_operation(),
+            _ => self.data_or_call(token)
+        }
+    }
+
+    /// Emits an instruction using the register read next, as its second digit.
+    fn register_opcode(&mut self, opcode: u16) -> Result<(), String> {
+        let register = self.register()? as u16;
+        self.opcode(opcode | register << 8)
+    }
+
+    fn register_operation(&mut self, x: u8) -> Result<(), String> {
+        let x = x as u16;
+        let operator = self.required_token()?;
+        let operand = self.required_token()?;
+        let operand_register = if operand.string {
+            None
+        } else {
+            self.aliases.get(&operand.text).copied().or_else(|| parse_register(&operand.text))
+        };
+        let alu = |kind: u16| operand_register.map(|y| 0x8000 | x << 8 | (y as u16) << 4 | kind);
+
+        let opcode = match (operator.text.as_str(), operand.text.as_str()) {
+            (":=", "key") => Some(0xF00A | x << 8),
+            (":=", "delay") => Some(0xF007 | x << 8),
+            (":=", "random") => {
+                let mask = self.byte_operand()? as u16;
+                Some(0xC000 | x << 8 | mask)
+            },
+            (":=" | "+=" | "-=", _) if operand_register.is_none() => {
+                self.tokens.push_front(operand.clone());
+                let value = self.byte_operand()?;
+                Some(match operator.text.as_str() {
+                    ":=" => 0x6000 | x << 8 | value as u16,
+                    "+=" => 0x7000 | x << 8 | value as u16,
+                    _ => 0x7000 | x << 8 | value.wrapping_neg() as u16
+                })
+            },
+            (":=", _) => alu(0x0),
+            ("|=", _) => alu(0x1),
+            ("&=", _) => alu(0x2),
+            ("^=", _) => alu(0x3),
+            ("+=", _) => alu(0x4),
+            ("-=", _) => alu(0x5),
+            (">>=", _) => alu(0x6),
+            ("=-", _) => alu(0x7),
+            ("<<=", _) => alu(0xE),
+            (text, _) => return Err(self.error(&format!("unknown operator '{}'", text)))
+        };
+        match opcode {
+            Some(opcode) => self.opcode(opcode),
+            None => Err(self.error(&format!("'{}' needs a register, found '{}'", operator.text, operand.text)))
+        }
+    }
+
+    fn index_operation(&mut self) -> Result<(), String> {
+        let operator = self.required_token()?;
+        match operator.text.as_str() {
+            "+=" => self.register_opcode(0xF01E),
+            ":=" if self.peek_is("hex") => {
+                self.next_token();
+                self.register_opcode(0xF029)
+            },
+            ":=" if self.peek_is("bighex") => {
+                self.next_token();
+                self.register_opcode(0xF030)
+            },
+            ":=" if self.peek_is("long") => {
+                self.next_token();
+                self.opcode(0xF000)?;
+                let address = self.address_operand(Patch::Long)?;
+                self.opcode(address)
+            },
+            ":=" => {
+                let address = self.address_operand(Patch::Address)?;
+                self.opcode(0xA000 | address)
+            },
+            text => Err(self.error(&format!("unknown operator '{}' for i", text)))
+        }
+    }
+
+    /// Anything else is a macro, a string mode, a number or constant emitted
+    /// as a byte, or a call to a label.
+    fn data_or_call(&mut self, token: Token) -> Result<(), String> {
+        if self.macros.contains_key(&token.text) {
+            return self.expand_macro(&token.text);
+        }
+        if self.string_modes.contains_key(&token.text) {
+            return self.expand_string_mode(&token.text);
+        }
+        if let Some(address) = self.labels.get(&token.text) {
+            return self.opcode(0x2000 | (*address & 0xFFF) as u16);
+        }
+        if token.text.starts_with(':') || RESERVED.contains(&token.text.as_str()) {
+            return Err(self.error(&format!("unexpected '{}'", token.text)));
+        }
+        match self.value(&token)? {
+            Some(value) => {
+                let byte = self.to_byte(value.floor() as i64)?;
+                self.byte(byte)
+            },
+            None => {
+                self.tokens.push_front(token);
+                let address = self.address_operand(Patch::Address)?;
+                self.opcode(0x2000 | address)
+            }
+        }
+    }
+
+    fn condition(&mut self) -> Result<Condition, String> {
+        let register = self.register()?;
+        let token = self.required_token()?;
+        let comparison = match token.text.as_str() {
+            "==" => Comparison::Equal,
+            "!=" => Comparison::NotEqual,
+            "<" => Comparison::Less,
+            ">" => Comparison::Greater,
+            "<=" => Comparison::LessEqual,
+            ">=" => Comparison::GreaterEqual,
+            "key" => return Ok(Condition { register, comparison: Comparison::Key, operand: Operand::Byte(0) }),
+            "-key" => return Ok(Condition { register, comparison: Comparison::NotKey, operand: Operand::Byte(0) }),
+            text => return Err(self.error(&format!("unknown comparison '{}'", text)))
+        };
+        let operand = match self.tokens.front() {
+            Some(next) if !next.string && self.is_register(&next.text) => Operand::Register(self.register()?),
+            _ => Operand::Byte(self.byte_operand()?)
+        };
+        Ok(Condition { register, comparison, operand })
+    }
+
+    /// Emits the instructions that skip the next one when the condition
+    /// doesn't hold. Ordered comparisons subtract in VF, and check the borrow.
+    fn skip_unless(&mut self, condition: &Condition) -> Result<(), String> {
+        let x = condition.register as u16;
+        match (condition.comparison, condition.operand) {
+            (Comparison::Key, _) => self.opcode(0xE0A1 | x << 8),
+            (Comparison::NotKey, _) => self.opcode(0xE09E | x << 8),
+            (Comparison::Equal, Operand::Byte(n)) => self.opcode(0x4000 | x << 8 | n as u16),
+            (Comparison::Equal, Operand::Register(y)) => self.opcode(0x9000 | x << 8 | (y as u16) << 4),
+            (Comparison::NotEqual, Operand::Byte(n)) => self.opcode(0x3000 | x << 8 | n as u16),
+            (Comparison::NotEqual, Operand::Register(y)) => self.opcode(0x5000 | x << 8 | (y as u16) << 4),
+            (comparison, operand) => {
+                match operand {
+                    Operand::Byte(n) => self.opcode(0x6F00 | n as u16)?,
+                    Operand::Register(y) => self.opcode(0x8F00 | (y as u16) << 4)?
+                }
+                // VF =- VX leaves VF at 1 when VX >= operand, and VF -= VX
+                // when VX <= operand
+                let (subtraction, skip) = match comparison {
+                    Comparison::Less => (0x8F07, 0x3F01),
+                    Comparison::Greater => (0x8F05, 0x3F01),
+                    Comparison::LessEqual => (0x8F05, 0x4F01),
+                    _ => (0x8F07, 0x4F01)
+                };
+                self.opcode(subtraction | x << 4)?;
+                self.opcode(skip)
+            }
+        }
+    }
+
+    /// Reads the tokens up to the closing brace, the opening one being read.
+    fn block(&mut self) -> Result<Vec<Token>, String> {
+        let mut tokens = Vec::new();
+        let mut depth = 0;
+        loop {
+            let token = self.required_token()?;
+            if !token.string && token.text == "}" {
+                if depth == 0 {
+                    return Ok(tokens);
+                }
+                depth -= 1;
+            } else if !token.string && token.text == "{" {
+                depth += 1;
+            }
+            tokens.push(token);
+        }
+    }
+
+    fn define_macro(&mut self) -> Result<(), String> {
+        let name = self.name()?;
+        let mut parameters = Vec::new();
+        while !self.peek_is("{") {
+            parameters.push(self.name()?);
+        }
+        self.expect("{")?;
+        let body = self.block()?;
+        self.macros.insert(name, Macro { parameters, body, calls: 0 });
+        Ok(())
+    }
+
+    fn expand_macro(&mut self, name: &str) -> Result<(), String> {
+        let count = self.macros[name].parameters.len();
+        let mut arguments = Vec::new();
+        for _ in 0..count {
+            arguments.push(self.required_token()?);
+        }
+        let Some(definition) = self.macros.get_mut(name) else { return Ok(()); };
+        let calls = definition.calls;
+        definition.calls += 1;
+        let body = definition.body.iter().map(|token| {
+            match definition.parameters.iter().position(|p| !token.string && *p == token.text) {
+                Some(index) => arguments[index].clone(),
+                None if !token.string && token.text == "CALLS" => Token { text: calls.to_string(), ..token.clone() },
+                None => token.clone()
+            }
+        }).collect();
+        self.expand(body)
+    }
+
+    fn define_string_mode(&mut self) -> Result<(), String> {
+        let name = self.name()?;
+        let alphabet = self.required_token()?;
+        if !alphabet.string {
+            return Err(self.error("expected the characters of the string mode, between quotes"));
+        }
+        self.expect("{")?;
+        let body = self.block()?;
+        self.string_modes.entry(name).or_default().push(StringMode { alphabet: alphabet.text.chars().collect(), body });
+        Ok(())
+    }
+
+    /// Expands the body of the string mode once for each character of the
+    /// string read next, with `CHAR` the character code, `INDEX` its position
+    /// in the string and `VALUE` its position in the alphabet.
+    fn expand_string_mode(&mut self, name: &str) -> Result<(), String> {
+        let text = self.required_token()?;
+        if !text.string {
+            return Err(self.error(&format!("expected a string after '{}'", name)));
+        }
+        let mut expanded = Vec::new();
+        for (index, c) in text.text.chars().enumerate() {
+            let mode = self.string_modes[name].iter()
+                .find_map(|mode| mode.alphabet.iter().position(|a| *a == c).map(|value| (mode, value)));
+            let Some((mode, value)) = mode else {
+                return Err(self.error(&format!("'{}' is not in string mode '{}'", c, name)));
+            };
+            for token in &mode.body {
+                let text = match token.text.as_str() {
+                    "CHAR" if !token.string => (c as u32).to_string(),
+                    "INDEX" if !token.string => index.to_string(),
+                    "VALUE" if !token.string => value.to_string(),
+                    _ => {
+                        expanded.push(token.clone());
+                        continue;
+                    }
+                };
+                expanded.push(Token { text, ..token.clone() });
+            }
+        }
+        self.expand(expanded)
+    }
+
+    /// Evaluates an expression up to the closing brace, the opening one
+    /// being read. Operators have no precedence and apply from right to left,
+    /// e.g. `2 * 3 + 1` is 8.
+    fn calc(&mut self) -> Result<f64, String> {
+        let value = self.expression()?;
+        self.expect("}")?;
+        Ok(value)
+    }
+
+    fn expression(&mut self) -> Result<f64, String> {
+        let left = self.term()?;
+        if self.peek_is("}") || self.peek_is(")") {
+            return Ok(left);
+        }
+        let operator = self.required_token()?;
+        let right = self.expression()?;
+        let integer = |f: fn(i64, i64) -> i64| f(left as i64, right as i64) as f64;
+        let truth = |b: bool| if b { 1.0 } else { 0.0 };
+        Ok(match operator.text.as_str() {
+            "+" => left + right,
+            "-" => left - right,
+            "*" => left * right,
+            "/" => left / right,
+            "%" => left % right,
+            "&" => integer(|a, b| a & b),
+            "|" => integer(|a, b| a | b),
+            "^" => integer(|a, b| a ^ b),
+            "<<" => integer(|a, b| a.checked_shl(b as u32).unwrap_or(0)),
+            ">>" => integer(|a, b| a.checked_shr(b as u32).unwrap_or(0)),
+            "pow" => left.powf(right),
+            "min" => left.min(right),
+            "max" => left.max(right),
+            "<" => truth(left < right),
+            ">" => truth(left > right),
+            "<=" => truth(left <= right),
+            ">=" => truth(left >= right),
+            "==" => truth(left == right),
+            "!=" => truth(left != right),
+            text => return Err(self.error(&format!("unknown operator '{}'", text)))
+        })
+    }
+
+    fn term(&mut self) -> Result<f64, String> {
+        let token = self.required_token()?;
+        let unary: Option<fn(f64) -> f64> = match token.text.as_str() {
+            "-" => Some(|v| -v),
+            "~" => Some(|v| !(v as i64) as f64),
+            "!" => Some(|v| if v == 0.0 { 1.0 } else { 0.0 }),
+            "sin" => Some(f64::sin),
+            "cos" => Some(f64::cos),
+            "tan" => Some(f64::tan),
+            "exp" => Some(f64::exp),
+            "log" => Some(f64::ln),
+            "abs" => Some(f64::abs),
+            "sqrt" => Some(f64::sqrt),
+            "sign" => Some(f64::signum),
+            "ceil" => Some(f64::ceil),
+            "floor" => Some(f64::floor),
+            _ => None
+        };
+        if let (Some(unary), false) = (unary, token.string) {
+            return Ok(unary(self.term()?));
+        }
+
+        match token.text.as_str() {
+            _ if token.string => Err(self.error(&format!("unexpected string \"{}\"", token.text))),
+            "(" => {
+                let value = self.expression()?;
+                self.expect(")")?;
+                Ok(value)
+            },
+            // Byte of the program assembled so far
+            "@" => {
+                let address = self.term()? as usize;
+                Ok(self.memory.get(address).copied().unwrap_or(0) as f64)
+            },
+            "PI" => Ok(std::f64::consts::PI),
+            "E" => Ok(std::f64::consts::E),
+            "HERE" => Ok(self.here as f64),
+            _ => match self.value(&token)? {
+                Some(value) => Ok(value),
+                None => Err(self.error(&format!("unknown name '{}'", token.text)))
+            }
+        }
+    }
+
+    /// Fills in the references to labels, and returns the program.
+    fn finish(mut self) -> Result<Vec<u8>, String> {
+        if let Some(open) = self.flow.last() {
+            return Err(match open {
+                Flow::If(_) | Flow::Else(_) => String::from("'if ... begin' without 'end'"),
+                Flow::Loop { .. } => String::from("'loop' without 'again'")
+            });
+        }
+
+        if self.jump_to_main {
+            let main = *self.labels.get("main").ok_or("the program has no 'main' label")?;
+            self.patch_jump(PROGRAM_START, main)?;
+        }
+        for reference in &self.references {
+            let Some(value) = self.labels.get(&reference.name).copied() else {
+                return Err(format!("unknown name '{}' (line {})", reference.name, reference.line));
+            };
+            let limit = match reference.patch {
+                Patch::Address | Patch::Unpack(_) => 0xFFF,
+                Patch::Long | Patch::UnpackLong => 0xFFFF
+            };
+            if value > limit {
+                return Err(format!("label '{}' at {:#x} is out of range (line {})", reference.name, value, reference.line));
+            }
+            let address = reference.address;
+            match reference.patch {
+                Patch::Address => {
+                    self.memory[address] |= (value >> 8) as u8;
+                    self.memory[address + 1] = value as u8;
+                },
+                Patch::Long => {
+                    self.memory[address] = (value >> 8) as u8;
+                    self.memory[address + 1] = value as u8;
+                },
+                Patch::Unpack(nibble) => {
+                    self.memory[address + 1] = nibble << 4 | (value >> 8) as u8;
+                    self.memory[address + 3] = value as u8;
+                },
+                Patch::UnpackLong => {
+                    self.memory[address + 1] = (value >> 8) as u8;
+                    self.memory[address + 3] = value as u8;
+                }
+            }
+        }
+        Ok(self.memory[PROGRAM_START..self.end].to_vec())
+    }
+}
+
+fn negate(condition: Condition) -> Condition {
+    let comparison = match condition.comparison {
+        Comparison::Equal => Comparison::NotEqual,
+        Comparison::NotEqual => Comparison::Equal,
+        Comparison::Less => Comparison::GreaterEqual,
+        Comparison::GreaterEqual => Comparison::Less,
+        Comparison::Greater => Comparison::LessEqual,
+        Comparison::LessEqual => Comparison::Greater,
+        Comparison::Key => Comparison::NotKey,
+        Comparison::NotKey => Comparison::Key
+    };
+    Condition { comparison, ..condition }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn assembles_instructions_and_labels() {
+        let program = assemble("
+            # Draws a sprite
+            : main
+                v0 := 5
+                v1 += 0x10
+                i := sprite
+                sprite v0 v1 5
+                loop again
+            : sprite 0xF0 0x90 0xF0 0x90 0xF0
+        ").unwrap();
+        assert_eq!(program, vec![
+            0x60, 0x05, 0x71, 0x10, 0xA2, 0x0A, 0xD0, 0x15, 0x12, 0x08,
+            0xF0, 0x90, 0xF0, 0x90, 0xF0
+        ]);
+
+        // Main is jumped to when it isn't first
+        assert_eq!(assemble(": data 1 2 : main jump data").unwrap(), vec![0x12, 0x04, 0x01, 0x02, 0x12, 0x02]);
+        assert_eq!(assemble(": main draw ; : draw clear return").unwrap(), vec![0x22, 0x04, 0x00, 0xEE, 0x00, 0xE0, 0x00, 0xEE]);
+    }
+
+    #[test]
+    fn assembles_control_flow() {
+        let program = assemble("
+            : main
+                if v0 == 3 then v1 := 1
+                if v2 < v3 begin v4 := 1 else v4 := 2 end
+                loop
+                    while v5 != 0
+                    v5 -= 1
+                again
+        ").unwrap();
+        assert_eq!(program, vec![
+            0x40, 0x03, 0x61, 0x01,
+            0x8F, 0x30, 0x8F, 0x27, 0x4F, 0x01, 0x12, 0x10, 0x64, 0x01, 0x12, 0x12, 0x64, 0x02,
+            0x45, 0x00, 0x12, 0x1A, 0x75, 0xFF, 0x12, 0x12
+        ]);
+    }
+
+    #[test]
+    fn expands_macros_and_constants() {
+        let program = assemble("
+            :alias x v3
+            :const SPEED 2
+            :calc DOUBLE { SPEED * 2 + 1 }
+            :macro move reg amount { reg += amount }
+            :stringmode text \"ABC\" { :byte { VALUE + 1 } }
+            : main
+                move x DOUBLE
+                :unpack 0xA data
+                :next target x := 0
+                i := target
+                text \"CAB\"
+            : data 1 2
+        ").unwrap();
+        assert_eq!(program, vec![
+            0x73, 0x06, 0x60, 0xA2, 0x61, 0x0D, 0x63, 0x00, 0xA2, 0x07, 0x03, 0x01, 0x02, 0x01, 0x02
+        ]);
+    }
+
+    #[test]
+    fn rejects_invalid_programs() {
+        let error = |source: &str| assemble(source).unwrap_err();
+        assert_eq!(error("v0 := 1"), "the program has no 'main' label");
+        assert_eq!(error(": main\n jump nowhere"), "unknown name 'nowhere' (line 2)");
+        assert_eq!(error(": main\n v0 := 256"), "256 doesn't fit in a byte (line 2)");
+        assert_eq!(error(": main loop"), "'loop' without 'again'");
+        assert!(error(": main else").contains("'else' without"));
+        assert!(error(": main : main").contains("defined twice"));
+        assert!(error(": main clear :org 0x200 clear").contains("written twice"));
+        assert!(error(": main v0 |= 5").contains("needs a register"));
+        assert!(error(":macro forever { forever } : main forever").contains("too many tokens"));
+        assert!(error(": main :assert \"too big\" { 1 > 2 }").contains("too big"));
+        assert!(error(": main \"text").contains("unterminated string"));
+    }
+
+    /// Cartridges come from anywhere, so programs with random changes are
+    /// assembled, and must fail with an error rather than a panic.
+    #[test]
+    fn assembles_mutated_programs_without_panicking() {
+        const SOURCE: &str = "
+            :alias x v3
+            :const SPEED 2
+            :calc DOUBLE { SPEED * 2 + 1 }
+            :macro move reg amount { reg += amount }
+            :stringmode text \"ABC\" { :byte { VALUE + 1 } }
+            : main
+                move x DOUBLE
+                :unpack 0xA data
+                :next target x := 0
+                i := target
+                text \"CAB\"
+                if v0 == 3 then v1 := 1
+                if v2 < v3 begin v4 := 1 else v4 := 2 end
+                loop while v5 != 0 v5 -= 1 again
+                i := long data
+                sprite v0 v1 5
+                :org 0x400
+            : data 1 2 :pointer main
+        ";
+        const WORDS: [&str; 24] = [
+            ":", ":org", ":calc", ":macro", ":unpack", ":next", ":pointer", ":byte", "{", "}", "\"", "loop",
+            "again", "begin", "end", "main", "-1", "0xFFFF", "65536", "1e300", "/", "v0", "i :=", "long"
+        ];
+        let mut rng = StdRng::seed_from_u64(0x8);
+        for _ in 0..2000 {
+            let mut words: Vec<&str> = SOURCE.split_whitespace().collect();
+            for _ in 0..rng.gen_range(1..4) {
+                let index = rng.gen_range(0..words.len());
+                match rng.gen_range(0..3) {
+                    0 => { words.remove(index); },
+                    1 => words.insert(index, WORDS[rng.gen_range(0..WORDS.len())]),
+                    _ => words[index] = WORDS[rng.gen_range(0..WORDS.len())]
+                }
+            }
+            let _ = assemble(&words.join(" "));
+        }
+    }
+}
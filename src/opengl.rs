@@ -1,65 +1,153 @@
 use std::mem::{self};
 use std::os::raw::c_void;
+use std::time::{Duration, Instant};
 use gl;
-use crate::renderer::Renderer;
-use crate::shader::{self, ShaderProgram};
+use crate::renderer::{self, Image, Renderer};
+use crate::shader::{self, GlVersion, ShaderProgram};
+use crate::palette::{self, Palette};
+use crate::postfx::{Effects, PostProcessChain};
+use crate::phosphor::{Ghosting, PhosphorFilter};
+use crate::software::SoftwareRenderer;
 use crate::chip8;
+use crate::{log_error, log_info, log_warn};
 
+/// How often user shader files are checked for changes.
+const SHADER_RELOAD_INTERVAL: Duration = Duration::from_millis(500);
+
+/// An RGBA texture with 8 bits per channel, the only format renderable and
+/// filterable in every supported OpenGL version.
 pub struct Texture {
     width: i32,
     height: i32,
     id: u32,
+    /// Staging buffer used to convert the pixels passed to `load`.
     data: Vec<u8>
 }
 
-pub struct OpenGLRenderer {
+/// Two triangles covering the whole viewport, drawn by every pass.
+pub struct ScreenQuad {
     vbo: u32,
-    vao: u32,
+    /// Vertex array holding the quad layout. Not used with OpenGL ES 2.0,
+    /// which lacks vertex arrays, so the layout is set on every bind.
+    vao: Option<u32>
+}
+
+/// An offscreen framebuffer with a color texture attached.
+pub struct RenderTarget {
+    fbo: u32,
+    pub texture: Texture
+}
+
+/// Draws an image over the window, alpha blended, such as the on-screen
+/// display. The image is only uploaded when it changes.
+struct Overlay {
+    texture: Texture,
+    shader: ShaderProgram
+}
+
+/// Draws the screen with a `SoftwareRenderer`, and copies the result to the
+/// window. Only uses OpenGL to upload the pixels and draw them as a texture.
+pub struct SoftwareBlitRenderer {
+    software_renderer: SoftwareRenderer,
+    texture: Texture,
+    quad: ScreenQuad,
+    shader: ShaderProgram,
+    overlay: Overlay
+}
+
+/// A shader pair loaded from disk, recompiled whenever the files change.
+struct UserShader {
+    program: ShaderProgram,
+    /// Whether the last compilation succeeded. If not, the built-in shader is
+    /// used until the files are fixed.
+    compiled: bool
+}
+
+pub struct OpenGLRenderer {
+    gl_version: GlVersion,
+    quad: ScreenQuad,
     screen_shader: shader::ShaderProgram,
-    screen_framebuffer: [f32; (chip8::DISPLAYW * chip8::DISPLAYH) as usize],
-    screen_texture: Texture
+    user_shader: Option<UserShader>,
+    last_shader_check: Instant,
+    start_time: Instant,
+    window_size: (i32, i32),
+    /// Area of the window the screen is drawn to (x, y, width, height).
+    screen_rect: (i32, i32, i32, i32),
+    integer_scaling: bool,
+    palette: Palette,
+    phosphor: PhosphorFilter,
+    screen_texture: Texture,
+    /// Copy of the last frame drawn, for shaders that sample previousFrameTex.
+    previous_frame_texture: Texture,
+    post_process: PostProcessChain,
+    overlay: Overlay
 }
 
 
 impl OpenGLRenderer {
-    pub fn new() -> OpenGLRenderer {
+    pub fn new(gl_version: GlVersion) -> OpenGLRenderer {
 
         let vertex_shader = String::from("
-            #version 450 core
-            layout(location = 0) in vec2 iCoord;
-            out vec2 fScreenCoordN;
+            ATTRIBUTE vec2 iCoord;
+            VARYING vec2 fScreenCoordN;
 
             void main() {
-                gl_Position = vec4(iCoord, 0, 1.0);
+                gl_Position = vec4(iCoord, 0.0, 1.0);
                 fScreenCoordN = iCoord * 0.5 + 0.5;
             }
         ");
 
         let fragment_shader = String::from("
-            #version 450 core 
-            in vec2 fScreenCoordN;
-            out vec4 oColor;
+            VARYING vec2 fScreenCoordN;
             uniform sampler2D screenTex;
+            uniform vec3 palette[4];
             
             void main() {
-                // vec3 black = vec3(0x0A / 255.0, 0x4D / 255.0, 0x68 / 255.0);
-                // vec3 white = vec3(0x00 / 255.0, 0xFF / 255.0, 0xCA / 255.0);
-                vec3 black = vec3(0x39 / 255.0, 0x36 / 255.0, 0x46 / 255.0);
-                vec3 white = vec3(0xF4 / 255.0, 0xEE / 255.0, 0xE0 / 255.0);
-                vec2 invertedCoords = vec2(fScreenCoordN.x, 1 - fScreenCoordN.y);
-                float bufferColor = texture(screenTex, invertedCoords).r;
+                vec2 invertedCoords = vec2(fScreenCoordN.x, 1.0 - fScreenCoordN.y);
+                vec2 planes = TEXTURE(screenTex, invertedCoords).rg;
                 
-                vec3 finalColor = (1 - bufferColor) * black + bufferColor * white;
-                oColor = vec4(vec3(finalColor), 1.0);
+                vec3 secondPlaneOff = mix(palette[0], palette[1], planes.r);
+                vec3 secondPlaneOn = mix(palette[2], palette[3], planes.r);
+                vec3 finalColor = mix(secondPlaneOff, secondPlaneOn, planes.g);
+                FRAG_COLOR = vec4(finalColor, 1.0);
             }
         ");
 
         OpenGLRenderer {
-            vbo: 0,
-            vao: 0,
-            screen_framebuffer: [0.5; (chip8::DISPLAYW * chip8::DISPLAYH) as usize],
-            screen_shader: ShaderProgram::from_text(&vertex_shader, &fragment_shader),
-            screen_texture: Texture::new(chip8::DISPLAYW as i32, chip8::DISPLAYH as i32)
+            gl_version,
+            quad: ScreenQuad::new(gl_version),
+            phosphor: PhosphorFilter::new(Ghosting::Decay { factor: 0.8 }),
+            screen_shader: ShaderProgram::from_text(&vertex_shader, &fragment_shader, gl_version),
+            user_shader: None,
+            last_shader_check: Instant::now(),
+            start_time: Instant::now(),
+            window_size: (0, 0),
+            screen_rect: (0, 0, 0, 0),
+            integer_scaling: false,
+            palette: palette::builtin_palettes().remove(0),
+            screen_texture: Texture::new(chip8::DISPLAYW as i32, chip8::DISPLAYH as i32),
+            previous_frame_texture: Texture::new(1, 1),
+            post_process: PostProcessChain::new(gl_version),
+            overlay: Overlay::new(gl_version)
+        }
+    }
+
+
+    fn reload_changed_shaders(&mut self) {
+        if self.last_shader_check.elapsed() < SHADER_RELOAD_INTERVAL { return; }
+        self.last_shader_check = Instant::now();
+
+        if let Some(user_shader) = &mut self.user_shader {
+            if user_shader.program.files_changed() {
+                user_shader.reload();
+            }
+        }
+    }
+
+    fn active_shader(&self) -> &ShaderProgram {
+        match &self.user_shader {
+            Some(user_shader) if user_shader.compiled => &user_shader.program,
+            _ => &self.screen_shader
         }
     }
 
@@ -68,12 +156,22 @@ impl OpenGLRenderer {
         gl::load_with(|s: &str| f(s))
     }
 
-    pub fn interpolate_to_fb(&mut self, src: &[u8]) {
-        for (i, data) in src.iter().enumerate() {
-            self.screen_framebuffer[i] = if *data == 255 { 1.0 } else {
-                self.screen_framebuffer[i] * 0.8 + (*data as f32 / 255.0) * 0.2
+}
+
+impl UserShader {
+    fn reload(&mut self) {
+        let result = self.program.load_shader_files().and_then(|_| self.program.load());
+        self.compiled = match result {
+            Ok(()) => {
+                log_info!(Render, "Loaded user shaders.");
+                true
+            },
+            Err(e) => {
+                log_error!(Render, "{}", e);
+                log_warn!(Render, "Falling back to the built-in shaders.");
+                false
             }
-        }
+        };
     }
 }
 
@@ -95,22 +193,72 @@ impl Texture {
             gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::NEAREST as i32);
             gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::NEAREST as i32);
 
-            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::MIRRORED_REPEAT as i32);
-            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::MIRRORED_REPEAT as i32);
-            
-            gl::TexImage2D(gl::TEXTURE_2D, 0, gl::RED as i32, self.width, self.height, 
-                0, gl::RED, gl::FLOAT, std::ptr::null());
+            // OpenGL ES 2.0 only allows clamping for textures whose size is not a power of two
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as i32);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as i32);
         }
+        self.allocate();
     }
 
-    pub fn load(&mut self, data: &[f32]) {
+    /// Uses linear filtering, instead of nearest, when sampling the texture.
+    pub fn set_linear_filter(&mut self) {
+        unsafe {
+            self.bind();
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+        }
+    }
+
+    pub fn size(&self) -> (i32, i32) {
+        (self.width, self.height)
+    }
+
+    fn allocate(&mut self) {
+        unsafe {
+            self.bind();
+            gl::TexImage2D(gl::TEXTURE_2D, 0, gl::RGBA as i32, self.width, self.height,
+                0, gl::RGBA, gl::UNSIGNED_BYTE, std::ptr::null());
+        }
+    }
+
+    pub fn resize(&mut self, width: i32, height: i32) {
+        if (width, height) == (self.width, self.height) { return; }
+        self.width = width;
+        self.height = height;
+        self.allocate();
+    }
+
+    /// Replaces the texture contents with RGBA8 pixels.
+    pub fn load_rgba8(&mut self, data: &[u8]) {
+        assert!(data.len() >= (self.width * self.height * 4) as usize);
+        unsafe {
+            self.bind();
+            gl::TexSubImage2D(gl::TEXTURE_2D, 0, 0, 0, self.width, self.height,
+                gl::RGBA, gl::UNSIGNED_BYTE, data.as_ptr() as *const c_void);
+        }
+    }
+
+    /// Copies the area of the bound framebuffer starting at (x, y) to the texture.
+    pub fn copy_from_framebuffer(&mut self, x: i32, y: i32) {
         unsafe {
             self.bind();
-            gl::TexSubImage2D(gl::TEXTURE_2D, 0, 0, 0, self.width, self.height, 
-                gl::RED, gl::FLOAT, data.as_ptr() as *const c_void);
+            gl::CopyTexSubImage2D(gl::TEXTURE_2D, 0, 0, 0, x, y, self.width, self.height);
         }
     }
 
+    /// Replaces the texture contents with two channels per pixel, from 0 to
+    /// 1, stored in the red and green channels.
+    pub fn load(&mut self, data: &[f32]) {
+        let mut pixels = mem::take(&mut self.data);
+        pixels.clear();
+        pixels.extend(data.chunks(2).flat_map(|rg| {
+            let channel = |v: f32| (v.clamp(0.0, 1.0) * 255.0).round() as u8;
+            [channel(rg[0]), channel(rg[1]), 0, 255]
+        }));
+        self.load_rgba8(&pixels);
+        self.data = pixels;
+    }
+
     pub fn bind(&mut self) {
         unsafe {
             gl::BindTexture(gl::TEXTURE_2D, self.id);
@@ -118,43 +266,276 @@ impl Texture {
     }
 }
 
-struct Vertex2D {
-    x: f32,
-    y: f32
+impl RenderTarget {
+    pub fn new() -> RenderTarget {
+        RenderTarget {
+            fbo: 0,
+            texture: Texture::new(1, 1)
+        }
+    }
+
+    pub fn init(&mut self) {
+        self.texture.init();
+        self.texture.set_linear_filter();
+        unsafe {
+            gl::GenFramebuffers(1, &mut self.fbo);
+            gl::BindFramebuffer(gl::FRAMEBUFFER, self.fbo);
+            gl::FramebufferTexture2D(gl::FRAMEBUFFER, gl::COLOR_ATTACHMENT0, gl::TEXTURE_2D, self.texture.id, 0);
+            if gl::CheckFramebufferStatus(gl::FRAMEBUFFER) != gl::FRAMEBUFFER_COMPLETE {
+                log_error!(Render, "Offscreen framebuffer is incomplete.");
+            }
+            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+        }
+    }
+
+    pub fn resize(&mut self, width: i32, height: i32) {
+        self.texture.resize(width, height);
+    }
+
+    pub fn size(&self) -> (i32, i32) {
+        self.texture.size()
+    }
+
+    /// Directs drawing to this target, covering the whole texture.
+    pub fn bind(&self) {
+        unsafe {
+            gl::BindFramebuffer(gl::FRAMEBUFFER, self.fbo);
+            gl::Viewport(0, 0, self.texture.width, self.texture.height);
+        }
+    }
+
+    /// Directs drawing back to the window, in the given viewport (x, y, width, height).
+    pub fn bind_default(viewport: (i32, i32, i32, i32)) {
+        unsafe {
+            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+            gl::Viewport(viewport.0, viewport.1, viewport.2, viewport.3);
+        }
+    }
 }
 
-impl Renderer for OpenGLRenderer {
+impl ScreenQuad {
+    pub fn new(gl_version: GlVersion) -> ScreenQuad {
+        ScreenQuad {
+            vbo: 0,
+            vao: if gl_version == GlVersion::Es20 { None } else { Some(0) }
+        }
+    }
+
+    pub fn init(&mut self) {
+        const SCREEN_QUAD_N_VERTICES: usize = 6;
+        let screen_quad_vertices: [Vertex2D; SCREEN_QUAD_N_VERTICES] = [
+            Vertex2D{ x: -1.0, y:  1.0 },
+            Vertex2D{ x:  1.0, y:  1.0 },
+            Vertex2D{ x: -1.0, y: -1.0 },
+            Vertex2D{ x:  1.0, y:  1.0 },
+            Vertex2D{ x: -1.0, y: -1.0 },
+            Vertex2D{ x:  1.0, y: -1.0 },
+        ];
+
+        unsafe {
+            gl::GenBuffers(1, &mut self.vbo);
+            gl::BindBuffer(gl::ARRAY_BUFFER, self.vbo);
+            gl::BufferData(gl::ARRAY_BUFFER, mem::size_of::<[Vertex2D; SCREEN_QUAD_N_VERTICES]>() as isize,
+                screen_quad_vertices.as_ptr() as *const c_void, gl::STATIC_DRAW);
+
+            if let Some(vao) = &mut self.vao {
+                gl::GenVertexArrays(1, vao);
+                gl::BindVertexArray(*vao);
+                ScreenQuad::set_layout();
+            }
+        }
+    }
+
+    fn set_layout() {
+        unsafe {
+            gl::VertexAttribPointer(0, 2, gl::FLOAT, gl::FALSE, 0, std::ptr::null());
+            gl::EnableVertexAttribArray(0);
+        }
+    }
+
+    pub fn bind(&self) {
+        unsafe {
+            match self.vao {
+                Some(vao) => gl::BindVertexArray(vao),
+                None => {
+                    gl::BindBuffer(gl::ARRAY_BUFFER, self.vbo);
+                    ScreenQuad::set_layout();
+                }
+            }
+        }
+    }
+
+    pub fn draw(&self) {
+        unsafe { gl::DrawArrays(gl::TRIANGLES, 0, 6); }
+    }
+}
+
+impl SoftwareBlitRenderer {
+    pub fn new(gl_version: GlVersion) -> SoftwareBlitRenderer {
+        SoftwareBlitRenderer {
+            software_renderer: SoftwareRenderer::new(1, 1),
+            texture: Texture::new(1, 1),
+            quad: ScreenQuad::new(gl_version),
+            shader: ShaderProgram::from_text(BLIT_VERTEX_SHADER, BLIT_FRAGMENT_SHADER, gl_version),
+            overlay: Overlay::new(gl_version)
+        }
+    }
+}
+
+impl Overlay {
+    fn new(gl_version: GlVersion) -> Overlay {
+        Overlay {
+            texture: Texture::new(1, 1),
+            shader: ShaderProgram::from_text(BLIT_VERTEX_SHADER, OVERLAY_FRAGMENT_SHADER, gl_version)
+        }
+    }
+
     fn init(&mut self) {
+        if let Err(e) = self.shader.load() {
+            panic!("Unable to load the built-in shaders. {}", e);
+        }
+        self.texture.init();
+    }
+
+    /// Draws the image from the top left corner of a window of the given
+    /// height. Leaves the viewport covering the image.
+    fn draw(&mut self, quad: &ScreenQuad, image: &Image, scale: u32, changed: bool, window_height: i32) {
+        if image.width == 0 || image.height == 0 { return; }
+        if changed {
+            self.texture.resize(image.width as i32, image.height as i32);
+            self.texture.load_rgba8(&image.pixels);
+        }
+
+        let (width, height) = ((image.width * scale) as i32, (image.height * scale) as i32);
         unsafe {
+            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+            gl::Viewport(0, window_height - height, width, height);
+            gl::Enable(gl::BLEND);
+            gl::BlendFunc(gl::ONE, gl::ONE_MINUS_SRC_ALPHA);
+        }
+        self.shader.bind();
+        self.shader.set_uniform_i32("sourceTex", 0);
+        quad.bind();
+        unsafe { gl::ActiveTexture(gl::TEXTURE0); }
+        self.texture.bind();
+        quad.draw();
+        unsafe { gl::Disable(gl::BLEND); }
+    }
+}
 
-            self.screen_shader.load();
+/// Draws a texture over the whole viewport. The texture rows go from top to
+/// bottom, so the image is flipped.
+const BLIT_VERTEX_SHADER: &str = "
+    ATTRIBUTE vec2 iCoord;
+    VARYING vec2 fTexCoord;
 
-            gl::GenBuffers(1, &mut self.vbo); 
-            gl::GenVertexArrays(1, &mut self.vao);
-            gl::BindVertexArray(self.vao);
-            gl::BindBuffer(gl::ARRAY_BUFFER, self.vbo);
+    void main() {
+        gl_Position = vec4(iCoord, 0.0, 1.0);
+        fTexCoord = vec2(iCoord.x * 0.5 + 0.5, 0.5 - iCoord.y * 0.5);
+    }
+";
 
-            const SCREEN_QUAD_N_VERTICES: usize = 6;
-            let screen_quad_vertices: [Vertex2D; SCREEN_QUAD_N_VERTICES] = [
-                Vertex2D{ x: -1.0, y:  1.0 },
-                Vertex2D{ x:  1.0, y:  1.0 },
-                Vertex2D{ x: -1.0, y: -1.0 },
-                Vertex2D{ x:  1.0, y:  1.0 },
-                Vertex2D{ x: -1.0, y: -1.0 },
-                Vertex2D{ x:  1.0, y: -1.0 },
-            ];
-
-            gl::BufferData(gl::ARRAY_BUFFER, mem::size_of::<[Vertex2D; SCREEN_QUAD_N_VERTICES]>() as isize, 
-                screen_quad_vertices.as_ptr() as *const c_void, gl::STATIC_DRAW);
+const BLIT_FRAGMENT_SHADER: &str = "
+    VARYING vec2 fTexCoord;
+    uniform sampler2D sourceTex;
+
+    void main() {
+        FRAG_COLOR = vec4(TEXTURE(sourceTex, fTexCoord).rgb, 1.0);
+    }
+";
+
+const OVERLAY_FRAGMENT_SHADER: &str = "
+    VARYING vec2 fTexCoord;
+    uniform sampler2D sourceTex;
+
+    void main() {
+        FRAG_COLOR = TEXTURE(sourceTex, fTexCoord);
+    }
+";
 
-                gl::VertexAttribPointer(0, 2, gl::FLOAT, gl::FALSE, 0, std::ptr::null());
-                gl::EnableVertexAttribArray(0);
+impl Renderer for SoftwareBlitRenderer {
+    fn init(&mut self) {
+        if let Err(e) = self.shader.load() {
+            panic!("Unable to load the built-in shaders. {}", e);
+        }
+        self.software_renderer.init();
+        self.texture.init();
+        self.quad.init();
+        self.overlay.init();
+    }
 
+    fn set_clear_color(&mut self, r: f32, g: f32, b: f32, a: f32) {
+        self.software_renderer.set_clear_color(r, g, b, a);
+    }
 
+    fn set_palette(&mut self, palette: &Palette) {
+        self.software_renderer.set_palette(palette);
+    }
+
+    fn set_viewport(&mut self, width: i32, height: i32) {
+        self.software_renderer.set_viewport(width, height);
+        self.texture.resize(width.max(1), height.max(1));
+        unsafe { gl::Viewport(0, 0, width, height); }
+    }
+
+    fn set_ghosting(&mut self, ghosting: Ghosting) {
+        self.software_renderer.set_ghosting(ghosting);
+    }
+
+    fn set_integer_scaling(&mut self, integer_scaling: bool) {
+        self.software_renderer.set_integer_scaling(integer_scaling);
+    }
+
+    fn clear(&mut self) {
+        self.software_renderer.clear();
+    }
+
+    fn read_pixels(&mut self) -> Option<Image> {
+        self.software_renderer.read_pixels()
+    }
+
+    fn draw_overlay(&mut self, overlay: &Image, scale: u32, changed: bool) {
+        let (width, height) = self.software_renderer.size();
+        self.overlay.draw(&self.quad, overlay, scale, changed, height as i32);
+        unsafe { gl::Viewport(0, 0, width as i32, height as i32); }
+    }
+
+    fn draw_screen(&mut self, fb: &[u8], frame: u64) {
+        self.software_renderer.draw_screen(fb, frame);
+        let (width, height) = self.software_renderer.size();
+        if width == 0 || height == 0 { return; }
+
+        self.texture.load_rgba8(self.software_renderer.pixels());
+        self.shader.bind();
+        self.shader.set_uniform_i32("sourceTex", 0);
+        self.quad.bind();
+        unsafe { gl::ActiveTexture(gl::TEXTURE0); }
+        self.texture.bind();
+        self.quad.draw();
+    }
+}
+
+struct Vertex2D {
+    x: f32,
+    y: f32
+}
+
+impl Renderer for OpenGLRenderer {
+    fn init(&mut self) {
+        unsafe {
+
+            if let Err(e) = self.screen_shader.load() {
+                panic!("Unable to load the built-in shaders. {}", e);
+            }
+
+            self.quad.init();
             self.screen_texture.init();
+            self.previous_frame_texture.init();
+            self.post_process.init();
+            self.overlay.init();
 
             while gl::GetError() != gl::NO_ERROR {
-                println!("Error!");
+                log_error!(Render, "OpenGL error while initializing the renderer.");
                 panic!();
             }
         }
@@ -172,13 +553,106 @@ impl Renderer for OpenGLRenderer {
         }
     }
 
-    fn draw_screen(&mut self, fb: &[u8]) {
+    fn set_palette(&mut self, palette: &Palette) {
+        self.palette = palette.clone();
+    }
+
+    fn set_viewport(&mut self, width: i32, height: i32) {
+        self.window_size = (width, height);
+        self.screen_rect = renderer::screen_rect(width, height, self.integer_scaling);
+        let (x, y, screen_width, screen_height) = self.screen_rect;
+        self.post_process.resize(screen_width, screen_height);
+        unsafe { gl::Viewport(x, y, screen_width, screen_height); }
+    }
+
+    fn set_integer_scaling(&mut self, integer_scaling: bool) {
+        self.integer_scaling = integer_scaling;
+        let (width, height) = self.window_size;
+        self.set_viewport(width, height);
+    }
+
+    fn set_ghosting(&mut self, ghosting: Ghosting) {
+        self.phosphor.set_ghosting(ghosting);
+    }
+
+    /// Uses the shaders in the given files to draw the screen, instead of the
+    /// built-in ones. Passing None switches back to the built-in shaders.
+    fn set_user_shaders(&mut self, paths: Option<(&str, &str)>) {
+        self.user_shader = paths.map(|(vs_path, fs_path)| {
+            let mut user_shader = UserShader {
+                program: ShaderProgram::from_files(vs_path, fs_path, self.gl_version),
+                compiled: false
+            };
+            user_shader.reload();
+            user_shader
+        });
+    }
+
+    fn set_effects(&mut self, effects: &Effects) {
+        self.post_process.set_effects(effects);
+    }
+
+    /// Reads the screen area of the window back from the GPU.
+    fn read_pixels(&mut self) -> Option<Image> {
+        let (x, y, width, height) = self.screen_rect;
+        if width <= 0 || height <= 0 { return None; }
+
+        let row_size = (width * 4) as usize;
+        let mut pixels = vec![0u8; row_size * height as usize];
+        unsafe {
+            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+            gl::PixelStorei(gl::PACK_ALIGNMENT, 4);
+            gl::ReadPixels(x, y, width, height, gl::RGBA, gl::UNSIGNED_BYTE, pixels.as_mut_ptr() as *mut c_void);
+        }
+
+        // OpenGL rows go from bottom to top
+        let flipped = pixels.chunks_exact(row_size).rev().flatten().copied().collect();
+        Some(Image { width: width as u32, height: height as u32, pixels: flipped })
+    }
+
+    fn draw_overlay(&mut self, overlay: &Image, scale: u32, changed: bool) {
+        self.overlay.draw(&self.quad, overlay, scale, changed, self.window_size.1);
+        let (x, y, width, height) = self.screen_rect;
+        unsafe { gl::Viewport(x, y, width, height); }
+    }
+
+    fn draw_screen(&mut self, fb: &[u8], frame: u64) {
+        self.reload_changed_shaders();
+        let intensities = self.phosphor.apply(fb, frame);
+        self.screen_texture.load(intensities);
+        self.clear();
+
+        let post_processing = self.post_process.is_active();
+        if post_processing {
+            self.post_process.begin();
+        }
+
+        let (x, y, width, height) = self.screen_rect;
+        let shader = self.active_shader();
+        shader.bind();
+        shader.set_uniform_i32("screenTex", 0);
+        shader.set_uniform_i32("previousFrameTex", 1);
+        shader.set_uniform_vec3_array("palette", &self.palette.colors);
+        shader.set_uniform_f32("time", self.start_time.elapsed().as_secs_f32());
+        shader.set_uniform_vec2("resolution", [width as f32, height as f32]);
+        let uses_previous_frame = shader.has_uniform("previousFrameTex");
+
+        self.quad.bind();
         unsafe {
-            self.screen_shader.bind();
-            self.interpolate_to_fb(fb);
-            self.screen_texture.load(&self.screen_framebuffer);
-            self.clear();
-            gl::DrawArrays(gl::TRIANGLES, 0, 6);
+            gl::ActiveTexture(gl::TEXTURE1);
+            self.previous_frame_texture.bind();
+            gl::ActiveTexture(gl::TEXTURE0);
+            self.screen_texture.bind();
+        }
+        self.quad.draw();
+
+        if post_processing {
+            self.post_process.finish(self.screen_rect);
+        }
+
+        if uses_previous_frame {
+            self.previous_frame_texture.resize(width, height);
+            self.previous_frame_texture.copy_from_framebuffer(x, y);
         }
     }
 
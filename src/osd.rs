@@ -0,0 +1,638 @@
+use std::time::{Duration, Instant};
+use crate::chip8::Register;
+use crate::config::Settings;
+use crate::frontend::Speed;
+use crate::menu::Menu;
+use crate::panels::{DebugView, PanelTarget};
+use crate::renderer::Image;
+use crate::sprites::{SpriteView, SHEET_COLUMNS};
+
+const GLYPH_WIDTH: u32 = 5;
+const GLYPH_HEIGHT: u32 = 7;
+/// Glyphs with the spacing between them.
+const CELL_WIDTH: u32 = GLYPH_WIDTH + 1;
+const LINE_HEIGHT: u32 = GLYPH_HEIGHT + 4;
+/// Space between the text and the edges of its background box.
+const PADDING: u32 = 1;
+/// Space between the boxes and the window edges.
+const MARGIN: u32 = 2;
+const MAX_MESSAGES: usize = 4;
+const MESSAGE_DURATION: Duration = Duration::from_secs(3);
+
+const TEXT_COLOR: [u8; 4] = [255, 255, 255, 255];
+const INDICATOR_COLOR: [u8; 4] = [255, 210, 64, 255];
+const BOX_COLOR: [u8; 4] = [0, 0, 0, 160];
+const PANEL_COLOR: [u8; 4] = [0, 0, 0, 220];
+const SELECTION_COLOR: [u8; 4] = [255, 255, 255, 255];
+const SELECTED_TEXT_COLOR: [u8; 4] = [0, 0, 0, 255];
+const HINT_COLOR: [u8; 4] = [160, 160, 160, 255];
+/// Opaque, as the screen isn't drawn under the debugger panels.
+const DEBUGGER_COLOR: [u8; 4] = [16, 16, 16, 255];
+const BREAKPOINT_COLOR: [u8; 4] = [255, 96, 96, 255];
+const WRITE_COLOR: [u8; 4] = [150, 40, 40, 255];
+/// Width of the debugger panels, in font pixels: 30 characters and margins.
+const DEBUGGER_WIDTH: u32 = 30 * CELL_WIDTH + LINE_HEIGHT;
+/// Size of the pixels of the sprite viewer, in font pixels.
+const SPRITE_PIXEL: u32 = 2;
+/// Space between the sprites of the sprite viewer.
+const SPRITE_GAP: u32 = 4;
+const SPRITE_OFF_COLOR: [u8; 4] = [48, 48, 48, 255];
+/// Most instructions shown in the disassembly panel.
+const DISASSEMBLY_ROWS: u32 = 9;
+/// Chip-8 keys as laid out on the COSMAC VIP keypad.
+const KEYPAD: [[usize; 4]; 4] = [[0x1, 0x2, 0x3, 0xC], [0x4, 0x5, 0x6, 0xD], [0x7, 0x8, 0x9, 0xE], [0xA, 0x0, 0xB, 0xF]];
+
+/// 5x7 font, one byte per row with the leftmost pixel in the highest bit.
+/// Covers printable ASCII, with lowercase letters drawn as uppercase: glyphs
+/// from ' ' to '`', followed by '{' to '~'.
+const FONT: [u8; 7 * 69] = [
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // space
+    0x20, 0x20, 0x20, 0x20, 0x20, 0x00, 0x20, // !
+    0x50, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00, // "
+    0x50, 0x50, 0xF8, 0x50, 0xF8, 0x50, 0x50, // #
+    0x20, 0x78, 0xA0, 0x70, 0x28, 0xF0, 0x20, // $
+    0xC0, 0xC8, 0x10, 0x20, 0x40, 0x98, 0x18, // %
+    0x60, 0x90, 0xA0, 0x40, 0xA8, 0x90, 0x68, // &
+    0x20, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, // '
+    0x10, 0x20, 0x40, 0x40, 0x40, 0x20, 0x10, // (
+    0x40, 0x20, 0x10, 0x10, 0x10, 0x20, 0x40, // )
+    0x00, 0x20, 0xA8, 0x70, 0xA8, 0x20, 0x00, // *
+    0x00, 0x20, 0x20, 0xF8, 0x20, 0x20, 0x00, // +
+    0x00, 0x00, 0x00, 0x00, 0x60, 0x20, 0x40, // ,
+    0x00, 0x00, 0x00, 0xF8, 0x00, 0x00, 0x00, // -
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x60, 0x60, // .
+    0x00, 0x08, 0x10, 0x20, 0x40, 0x80, 0x00, // /
+    0x70, 0x88, 0x98, 0xA8, 0xC8, 0x88, 0x70, // 0
+    0x20, 0x60, 0x20, 0x20, 0x20, 0x20, 0x70, // 1
+    0x70, 0x88, 0x08, 0x10, 0x20, 0x40, 0xF8, // 2
+    0xF8, 0x10, 0x20, 0x10, 0x08, 0x88, 0x70, // 3
+    0x10, 0x30, 0x50, 0x90, 0xF8, 0x10, 0x10, // 4
+    0xF8, 0x80, 0xF0, 0x08, 0x08, 0x88, 0x70, // 5
+    0x30, 0x40, 0x80, 0xF0, 0x88, 0x88, 0x70, // 6
+    0xF8, 0x08, 0x10, 0x20, 0x40, 0x40, 0x40, // 7
+    0x70, 0x88, 0x88, 0x70, 0x88, 0x88, 0x70, // 8
+    0x70, 0x88, 0x88, 0x78, 0x08, 0x10, 0x60, // 9
+    0x00, 0x60, 0x60, 0x00, 0x60, 0x60, 0x00, // :
+    0x00, 0x60, 0x60, 0x00, 0x60, 0x20, 0x40, // ;
+    0x10, 0x20, 0x40, 0x80, 0x40, 0x20, 0x10, // <
+    0x00, 0x00, 0xF8, 0x00, 0xF8, 0x00, 0x00, // =
+    0x40, 0x20, 0x10, 0x08, 0x10, 0x20, 0x40, // >
+    0x70, 0x88, 0x08, 0x10, 0x20, 0x00, 0x20, // ?
+    0x70, 0x88, 0x08, 0x68, 0xA8, 0xA8, 0x70, // @
+    0x70, 0x88, 0x88, 0xF8, 0x88, 0x88, 0x88, // A
+    0xF0, 0x88, 0x88, 0xF0, 0x88, 0x88, 0xF0, // B
+    0x70, 0x88, 0x80, 0x80, 0x80, 0x88, 0x70, // C
+    0xE0, 0x90, 0x88, 0x88, 0x88, 0x90, 0xE0, // D
+    0xF8, 0x80, 0x80, 0xF0, 0x80, 0x80, 0xF8, // E
+    0xF8, 0x80, 0x80, 0xF0, 0x80, 0x80, 0x80, // F
+    0x70, 0x88, 0x80, 0xB8, 0x88, 0x88, 0x78, // G
+    0x88, 0x88, 0x88, 0xF8, 0x88, 0x88, 0x88, // H
+    0x70, 0x20, 0x20, 0x20, 0x20, 0x20, 0x70, // I
+    0x38, 0x10, 0x10, 0x10, 0x10, 0x90, 0x60, // J
+    0x88, 0x90, 0xA0, 0xC0, 0xA0, 0x90, 0x88, // K
+    0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0xF8, // L
+    0x88, 0xD8, 0xA8, 0xA8, 0x88, 0x88, 0x88, // M
+    0x88, 0x88, 0xC8, 0xA8, 0x98, 0x88, 0x88, // N
+    0x70, 0x88, 0x88, 0x88, 0x88, 0x88, 0x70, // O
+    0xF0, 0x88, 0x88, 0xF0, 0x80, 0x80, 0x80, // P
+    0x70, 0x88, 0x88, 0x88, 0xA8, 0x90, 0x68, // Q
+    0xF0, 0x88, 0x88, 0xF0, 0xA0, 0x90, 0x88, // R
+    0x78, 0x80, 0x80, 0x70, 0x08, 0x08, 0xF0, // S
+    0xF8, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20, // T
+    0x88, 0x88, 0x88, 0x88, 0x88, 0x88, 0x70, // U
+    0x88, 0x88, 0x88, 0x88, 0x88, 0x50, 0x20, // V
+    0x88, 0x88, 0x88, 0xA8, 0xA8, 0xA8, 0x50, // W
+    0x88, 0x88, 0x50, 0x20, 0x50, 0x88, 0x88, // X
+    0x88, 0x88, 0x50, 0x20, 0x20, 0x20, 0x20, // Y
+    0xF8, 0x08, 0x10, 0x20, 0x40, 0x80, 0xF8, // Z
+    0x70, 0x40, 0x40, 0x40, 0x40, 0x40, 0x70, // [
+    0x00, 0x80, 0x40, 0x20, 0x10, 0x08, 0x00, // backslash
+    0x70, 0x10, 0x10, 0x10, 0x10, 0x10, 0x70, // ]
+    0x20, 0x50, 0x88, 0x00, 0x00, 0x00, 0x00, // ^
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xF8, // _
+    0x40, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, // `
+    0x10, 0x20, 0x20, 0x40, 0x20, 0x20, 0x10, // {
+    0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20, // |
+    0x40, 0x20, 0x20, 0x10, 0x20, 0x20, 0x40, // }
+    0x00, 0x00, 0x40, 0xA8, 0x10, 0x00, 0x00, // ~
+];
+
+/// Width of a line of text, in font pixels.
+fn text_width(text: &str) -> u32 {
+    (text.chars().count() as u32 * CELL_WIDTH).saturating_sub(1)
+}
+
+fn glyph(c: char) -> &'static [u8] {
+    let index = match c.to_ascii_uppercase() {
+        c @ ' '..='`' => c as usize - ' ' as usize,
+        c @ '{'..='~' => c as usize - '{' as usize + 65,
+        _ => '?' as usize - ' ' as usize
+    };
+    &FONT[index * 7..index * 7 + 7]
+}
+
+/// Something drawn on the display, with its top left corner at x, y.
+#[derive(Clone, PartialEq)]
+enum Shape {
+    Rect { x: u32, y: u32, width: u32, height: u32, color: [u8; 4] },
+    Text { x: u32, y: u32, text: String, color: [u8; 4] }
+}
+
+/// Text on a box, so it stays readable over the screen.
+fn label(shapes: &mut Vec<Shape>, x: u32, y: u32, text: &str, color: [u8; 4]) {
+    let (width, height) = (text_width(text) + 2 * PADDING, GLYPH_HEIGHT + 2 * PADDING);
+    shapes.push(Shape::Rect { x, y, width, height, color: BOX_COLOR });
+    shapes.push(Shape::Text { x: x + PADDING, y: y + PADDING, text: String::from(text), color });
+}
+
+/// Cuts text to at most width font pixels.
+fn fit_text(text: &str, width: u32) -> String {
+    let max_chars = ((width + 1) / CELL_WIDTH) as usize;
+    text.chars().take(max_chars).collect()
+}
+
+/// On-screen display drawn over the window: notifications, a frames and
+/// instructions per second counter, pause and fast-forward indicators,
+/// menus, and the debugger panels.
+/// It is drawn with a bitmap font at a low resolution, then scaled up, and
+/// only redrawn when its contents change.
+pub struct Osd {
+    show_messages: bool,
+    show_fps: bool,
+    show_indicators: bool,
+    /// Window pixels per font pixel.
+    scale: u32,
+    /// Size of the window, in window pixels.
+    window_size: (u32, u32),
+    messages: Vec<(String, Instant)>,
+    menu: Option<Menu>,
+    debugger: Option<DebugView>,
+    sprites: Option<SpriteView>,
+    /// Parts of the panels that can be clicked, as their left, top, width
+    /// and height, to find the one clicked.
+    click_targets: Vec<(u32, u32, u32, u32, PanelTarget)>,
+    speed: Speed,
+    fps: u32,
+    ips: u64,
+    frames_presented: u32,
+    instructions: u64,
+    counter_start: Instant,
+    counter_start_instructions: u64,
+    shapes: Vec<Shape>,
+    image: Image,
+    changed: bool
+}
+
+impl Osd {
+    pub fn new() -> Osd {
+        Osd {
+            show_messages: true,
+            show_fps: false,
+            show_indicators: true,
+            scale: 2,
+            window_size: (0, 0),
+            messages: Vec::new(),
+            menu: None,
+            debugger: None,
+            sprites: None,
+            click_targets: Vec::new(),
+            speed: Speed::Normal,
+            fps: 0,
+            ips: 0,
+            frames_presented: 0,
+            instructions: 0,
+            counter_start: Instant::now(),
+            counter_start_instructions: 0,
+            shapes: Vec::new(),
+            image: Image { width: 0, height: 0, pixels: Vec::new() },
+            changed: true
+        }
+    }
+
+    pub fn apply_settings(&mut self, settings: &Settings) {
+        self.show_messages = settings.osd_messages;
+        self.show_fps = settings.osd_fps;
+        self.show_indicators = settings.osd_indicators;
+        if settings.osd_scale != self.scale {
+            self.scale = settings.osd_scale;
+            self.changed = true;
+        }
+        if !self.show_messages {
+            self.messages.clear();
+        }
+    }
+
+    pub fn resize(&mut self, width: u32, height: u32) {
+        if (width, height) != self.window_size {
+            self.window_size = (width, height);
+            self.changed = true;
+        }
+    }
+
+    /// Shows a notification for a few seconds, unless messages are disabled.
+    pub fn show_message(&mut self, message: &str) {
+        if !self.show_messages { return; }
+        if self.messages.len() == MAX_MESSAGES {
+            self.messages.remove(0);
+        }
+        self.messages.push((String::from(message), Instant::now()));
+    }
+
+    /// Shows a menu over the screen, or hides it when None.
+    pub fn set_menu(&mut self, menu: Option<&Menu>) {
+        self.menu = menu.cloned();
+    }
+
+    /// Shows the debugger panels on the right of the window, or hides them
+    /// when None.
+    pub fn set_debugger(&mut self, view: Option<&DebugView>) {
+        self.debugger = view.cloned();
+    }
+
+    /// Shows the sprite viewer in place of the debugger panels, or hides it
+    /// when None.
+    pub fn set_sprites(&mut self, view: Option<&SpriteView>) {
+        self.sprites = view.cloned();
+    }
+
+    /// Whether the debugger panels or the sprite viewer are shown.
+    pub fn shows_debugger(&self) -> bool {
+        self.debugger.is_some() || self.sprites.is_some()
+    }
+
+    /// Width of the debugger panels in window pixels, 0 when hidden. They
+    /// take at most half of the window, and the screen is drawn in the rest.
+    pub fn debugger_width(&self) -> u32 {
+        if !self.shows_debugger() { return 0; }
+        (DEBUGGER_WIDTH * self.scale).min(self.window_size.0 / 2)
+    }
+
+    /// Left edge of the debugger panels, in font pixels.
+    fn debugger_x(&self) -> u32 {
+        (self.window_size.0 - self.debugger_width()) / self.scale
+    }
+
+    /// Instruction, byte of memory, sprite or button shown at a point of the
+    /// window, in window pixels, if the panels show one there.
+    pub fn target_at(&self, x: u32, y: u32) -> Option<PanelTarget> {
+        if !self.shows_debugger() || x / self.scale < self.debugger_x() {
+            return None;
+        }
+        let (x, y) = (x / self.scale, y / self.scale);
+        self.click_targets.iter()
+            .find(|(left, top, width, height, _)| (*left..*left + *width).contains(&x) && (*top..*top + *height).contains(&y))
+            .map(|(_, _, _, _, target)| *target)
+    }
+
+    pub fn set_speed(&mut self, speed: Speed) {
+        self.speed = speed;
+    }
+
+    /// Total instructions executed by the emulator, used to count the
+    /// instructions per second.
+    pub fn set_instruction_count(&mut self, count: u64) {
+        // The count restarts when a new rom is loaded
+        if count < self.instructions {
+            self.counter_start_instructions = 0;
+        }
+        self.instructions = count;
+    }
+
+    /// Counts a frame shown, and updates the counters once per second.
+    pub fn frame_presented(&mut self) {
+        self.frames_presented += 1;
+        let elapsed = self.counter_start.elapsed();
+        if elapsed >= Duration::from_secs(1) {
+            self.fps = (self.frames_presented as f64 / elapsed.as_secs_f64()).round() as u32;
+            let instructions = self.instructions - self.counter_start_instructions;
+            self.ips = (instructions as f64 / elapsed.as_secs_f64()).round() as u64;
+            self.frames_presented = 0;
+            self.counter_start = Instant::now();
+            self.counter_start_instructions = self.instructions;
+        }
+    }
+
+    /// Size of the display image, in font pixels. Rounded up, so it covers
+    /// the whole window once scaled.
+    fn size(&self) -> (u32, u32) {
+        let (width, height) = self.window_size;
+        (width.div_ceil(self.scale), height.div_ceil(self.scale))
+    }
+
+    /// Places what is shown: the menu, if any, messages on the top left,
+    /// newest last, and the counter and indicators on the top right.
+    fn layout(&mut self) -> Vec<Shape> {
+        self.messages.retain(|(_, shown_at)| shown_at.elapsed() < MESSAGE_DURATION);
+
+        let mut shapes = Vec::new();
+        let (mut width, _) = self.size();
+        self.click_targets = match (&self.sprites, &self.debugger) {
+            (Some(view), _) => self.layout_sprites(&mut shapes, view),
+            (None, Some(view)) => self.layout_debugger(&mut shapes, view),
+            (None, None) => Vec::new()
+        };
+        if self.shows_debugger() {
+            // Indicators stay on the screen side
+            width = self.debugger_x();
+        }
+        match &self.menu {
+            Some(menu) => self.layout_menu(&mut shapes, menu),
+            None => {
+                let mut right_lines = Vec::new();
+                if self.show_fps {
+                    right_lines.push((format!("{} FPS  {} IPS", self.fps, self.ips), TEXT_COLOR));
+                }
+                if self.show_indicators {
+                    match self.speed {
+                        Speed::Normal => { },
+                        Speed::Paused => right_lines.push((String::from("PAUSED"), INDICATOR_COLOR)),
+                        Speed::FastForward(speed) => right_lines.push((format!(">> {}X", speed), INDICATOR_COLOR))
+                    }
+                }
+
+                for (i, (text, color)) in right_lines.iter().enumerate() {
+                    let box_width = text_width(text) + 2 * PADDING;
+                    label(&mut shapes, width.saturating_sub(MARGIN + box_width), MARGIN + i as u32 * LINE_HEIGHT, text, *color);
+                }
+            }
+        }
+
+        // Messages go over the menu
+        for (i, (message, _)) in self.messages.iter().enumerate() {
+            label(&mut shapes, MARGIN, MARGIN + i as u32 * LINE_HEIGHT, message, TEXT_COLOR);
+        }
+        shapes
+    }
+
+    /// Places a menu on a panel covering most of the display. The list
+    /// scrolls to keep the selected item visible.
+    fn layout_menu(&self, shapes: &mut Vec<Shape>, menu: &Menu) {
+        let (width, height) = self.size();
+        let panel_x = MARGIN * 4;
+        let panel_y = MARGIN * 4;
+        let panel_width = width.saturating_sub(panel_x * 2);
+        let panel_height = height.saturating_sub(panel_y * 2);
+        shapes.push(Shape::Rect { x: panel_x, y: panel_y, width: panel_width, height: panel_height, color: PANEL_COLOR });
+
+        let text_x = panel_x + LINE_HEIGHT;
+        let text_width = panel_width.saturating_sub(2 * LINE_HEIGHT);
+        let title_y = panel_y + LINE_HEIGHT / 2;
+        shapes.push(Shape::Text { x: text_x, y: title_y, text: fit_text(&menu.title, text_width), color: TEXT_COLOR });
+
+        let hint = "ENTER: SELECT  ESC: CLOSE";
+        let hint_y = (panel_y + panel_height).saturating_sub(LINE_HEIGHT);
+        shapes.push(Shape::Text { x: text_x, y: hint_y, text: fit_text(hint, text_width), color: HINT_COLOR });
+
+        let list_y = title_y + LINE_HEIGHT * 2;
+        let rows = (hint_y.saturating_sub(list_y + LINE_HEIGHT) / LINE_HEIGHT) as usize;
+        if rows == 0 { return; }
+        let selected = menu.selected.unwrap_or(0);
+        let first = selected.saturating_sub(rows / 2).min(menu.items.len().saturating_sub(rows));
+
+        for (row, (i, item)) in menu.items.iter().enumerate().skip(first).take(rows).enumerate() {
+            let y = list_y + row as u32 * LINE_HEIGHT;
+            if item.header {
+                shapes.push(Shape::Text { x: text_x, y, text: fit_text(&item.text, text_width), color: INDICATOR_COLOR });
+                continue;
+            }
+
+            let item_x = text_x + CELL_WIDTH * 2;
+            let item_width = text_width.saturating_sub(CELL_WIDTH * 2);
+            let mut color = TEXT_COLOR;
+            if menu.selected == Some(i) {
+                shapes.push(Shape::Rect {
+                    x: item_x - 2, y: y - 2, width: item_width + 3, height: GLYPH_HEIGHT + 4, color: SELECTION_COLOR
+                });
+                color = SELECTED_TEXT_COLOR;
+            }
+            shapes.push(Shape::Text { x: item_x, y, text: fit_text(&item.text, item_width), color });
+        }
+    }
+
+    /// Places the debugger panels in a column on the right of the display:
+    /// registers, keypad and call stack, the instructions around PC, and
+    /// memory in the space left. Returns the parts that can be clicked.
+    fn layout_debugger(&self, shapes: &mut Vec<Shape>, view: &DebugView) -> Vec<(u32, u32, u32, u32, PanelTarget)> {
+        let (width, height) = self.size();
+        let panel_x = self.debugger_x();
+        shapes.push(Shape::Rect { x: panel_x, y: 0, width: width - panel_x, height, color: DEBUGGER_COLOR });
+
+        let x = panel_x + LINE_HEIGHT / 2;
+        let text_width = (width - x).saturating_sub(LINE_HEIGHT / 2);
+        let text = |shapes: &mut Vec<Shape>, x: u32, y: u32, text: &str, color: [u8; 4]| {
+            shapes.push(Shape::Text { x, y, text: fit_text(text, text_width.saturating_sub(x - panel_x - LINE_HEIGHT / 2)), color });
+        };
+        let highlight = |shapes: &mut Vec<Shape>, x: u32, y: u32, chars: u32, color: [u8; 4]| {
+            shapes.push(Shape::Rect { x: x - 1, y: y - 2, width: chars * CELL_WIDTH + 1, height: GLYPH_HEIGHT + 4, color });
+        };
+        let mut y = LINE_HEIGHT / 2;
+
+        text(shapes, x, y, "REGISTERS", INDICATOR_COLOR);
+        y += LINE_HEIGHT;
+        for row in 0..4 {
+            let line: Vec<String> = (row * 4..row * 4 + 4)
+                .map(|n| format!("V{:X} {:02X}", n, view.registers[n]))
+                .collect();
+            text(shapes, x, y, &line.join("  "), TEXT_COLOR);
+            y += LINE_HEIGHT;
+        }
+        let register = |register: Register| view.registers[register as usize];
+        text(shapes, x, y, &format!("I {:03X}  PC {:03X}  SP {:03X}", register(Register::I), register(Register::PC), register(Register::SP)), TEXT_COLOR);
+        y += LINE_HEIGHT;
+        text(shapes, x, y, &format!("DT {:02X}  ST {:02X}", register(Register::DT), register(Register::Sound)), TEXT_COLOR);
+        y += LINE_HEIGHT * 3 / 2;
+
+        let stack_x = x + CELL_WIDTH * 10;
+        text(shapes, x, y, "KEYPAD", INDICATOR_COLOR);
+        text(shapes, stack_x, y, "CALL STACK", INDICATOR_COLOR);
+        y += LINE_HEIGHT;
+        for (row, keys) in KEYPAD.iter().enumerate() {
+            let row_y = y + row as u32 * LINE_HEIGHT;
+            for (column, key) in keys.iter().enumerate() {
+                let key_x = x + column as u32 * CELL_WIDTH * 2;
+                if view.keys[*key] {
+                    highlight(shapes, key_x, row_y, 1, SELECTION_COLOR);
+                    text(shapes, key_x, row_y, &format!("{:X}", key), SELECTED_TEXT_COLOR);
+                } else {
+                    text(shapes, key_x, row_y, &format!("{:X}", key), HINT_COLOR);
+                }
+            }
+        }
+        // The deepest calls are left out when the stack doesn't fit
+        let shown = if view.call_stack.len() > KEYPAD.len() { KEYPAD.len() - 1 } else { view.call_stack.len() };
+        for (row, (address, label)) in view.call_stack.iter().take(shown).enumerate() {
+            let line = match label {
+                Some(label) => format!("{:03X} {}", address, label),
+                None => format!("{:03X}", address)
+            };
+            text(shapes, stack_x, y + row as u32 * LINE_HEIGHT, &line, TEXT_COLOR);
+        }
+        if view.call_stack.is_empty() {
+            text(shapes, stack_x, y, "EMPTY", HINT_COLOR);
+        } else if shown < view.call_stack.len() {
+            text(shapes, stack_x, y + shown as u32 * LINE_HEIGHT, &format!("+{} MORE", view.call_stack.len() - shown), HINT_COLOR);
+        }
+        y += LINE_HEIGHT * (KEYPAD.len() as u32) + LINE_HEIGHT / 2;
+
+        // The disassembly takes half of the lines left, memory the rest
+        let lines = height.saturating_sub(y + LINE_HEIGHT / 2) / LINE_HEIGHT;
+        let disassembly_rows = (lines.saturating_sub(2) / 2).clamp(1, DISASSEMBLY_ROWS) as usize;
+        text(shapes, x, y, "DISASSEMBLY", INDICATOR_COLOR);
+        y += LINE_HEIGHT;
+        let current = view.disassembly.iter().position(|line| line.current).unwrap_or(0);
+        let first = current.saturating_sub(disassembly_rows / 2).min(view.disassembly.len().saturating_sub(disassembly_rows));
+        let mut targets = Vec::new();
+        for line in view.disassembly.iter().skip(first).take(disassembly_rows) {
+            let marker = if line.breakpoint { '*' } else { ' ' };
+            let content = format!("{} {:03X} {:04X} {}", marker, line.address, line.opcode, line.text);
+            let color = if line.current {
+                highlight(shapes, x, y, (text_width + 1) / CELL_WIDTH, SELECTION_COLOR);
+                SELECTED_TEXT_COLOR
+            } else if line.breakpoint {
+                BREAKPOINT_COLOR
+            } else {
+                TEXT_COLOR
+            };
+            text(shapes, x, y, &content, color);
+            targets.push((panel_x, y - 2, width - panel_x, LINE_HEIGHT, PanelTarget::Instruction(line.address)));
+            y += LINE_HEIGHT;
+        }
+        y += LINE_HEIGHT / 2;
+
+        if y + LINE_HEIGHT * 2 > height {
+            return targets;
+        }
+        text(shapes, x, y, "MEMORY", INDICATOR_COLOR);
+        y += LINE_HEIGHT;
+        let memory_rows = (height.saturating_sub(y + LINE_HEIGHT / 2) / LINE_HEIGHT) as usize;
+        for row in view.memory.iter().take(memory_rows) {
+            text(shapes, x, y, &format!("{:03X}:", row.address), HINT_COLOR);
+            for (i, (byte, written)) in row.bytes.iter().zip(row.written.iter()).enumerate() {
+                let byte_x = x + (5 + i as u32 * 3) * CELL_WIDTH;
+                if *written {
+                    highlight(shapes, byte_x, y, 2, WRITE_COLOR);
+                }
+                text(shapes, byte_x, y, &format!("{:02X}", byte), TEXT_COLOR);
+                targets.push((byte_x - 1, y - 2, CELL_WIDTH * 3, LINE_HEIGHT, PanelTarget::Memory(row.address + i as u16)));
+            }
+            y += LINE_HEIGHT;
+        }
+        targets
+    }
+
+    /// Places the sprite viewer in the column of the debugger panels: the
+    /// height and alignment buttons, the sprites in a grid, framed when the
+    /// rom drew them, and the sprite selected. Returns the parts that can be
+    /// clicked.
+    fn layout_sprites(&self, shapes: &mut Vec<Shape>, view: &SpriteView) -> Vec<(u32, u32, u32, u32, PanelTarget)> {
+        let (width, height) = self.size();
+        let panel_x = self.debugger_x();
+        shapes.push(Shape::Rect { x: panel_x, y: 0, width: width - panel_x, height, color: DEBUGGER_COLOR });
+
+        let x = panel_x + LINE_HEIGHT / 2;
+        let text_width = (width - x).saturating_sub(LINE_HEIGHT / 2);
+        let text = |shapes: &mut Vec<Shape>, y: u32, text: &str, color: [u8; 4]| {
+            shapes.push(Shape::Text { x, y, text: fit_text(text, text_width), color });
+        };
+        let mut targets = Vec::new();
+        let mut y = LINE_HEIGHT / 2;
+
+        text(shapes, y, &format!("SPRITES FROM {:03X}", view.start), INDICATOR_COLOR);
+        y += LINE_HEIGHT;
+        // The buttons are the - and + signs around what they change
+        text(shapes, y, &format!("HEIGHT - {:>2} +  ALIGN - +", view.height), TEXT_COLOR);
+        let buttons = [(7, PanelTarget::SpriteHeight(-1)), (12, PanelTarget::SpriteHeight(1)),
+            (21, PanelTarget::SpriteOffset(-1)), (23, PanelTarget::SpriteOffset(1))];
+        for (column, target) in buttons {
+            targets.push((x + column * CELL_WIDTH - CELL_WIDTH / 2, y - 2, CELL_WIDTH * 2, LINE_HEIGHT, target));
+        }
+        y += LINE_HEIGHT * 3 / 2;
+
+        // The grid takes the lines left above the selected sprite
+        let (cell_width, pitch_x, pitch_y) = (8 * SPRITE_PIXEL, 8 * SPRITE_PIXEL + SPRITE_GAP, view.height as u32 * SPRITE_PIXEL + SPRITE_GAP);
+        let columns = (text_width / pitch_x).clamp(1, SHEET_COLUMNS as u32);
+        let rows = height.saturating_sub(y + LINE_HEIGHT * 3) / pitch_y;
+        for (index, cell) in view.cells.iter().take((rows * columns) as usize).enumerate() {
+            let cell_x = x + 1 + (index as u32 % columns) * pitch_x;
+            let cell_y = y + (index as u32 / columns) * pitch_y;
+            let cell_height = cell.rows.len() as u32 * SPRITE_PIXEL;
+            let selected = view.selected.is_some_and(|(address, _)| address == cell.address);
+            let frame = if selected { Some(SELECTION_COLOR) } else if cell.drawn { Some(INDICATOR_COLOR) } else { None };
+            if let Some(color) = frame {
+                shapes.push(Shape::Rect { x: cell_x - 1, y: cell_y - 1, width: cell_width + 2, height: cell_height + 2, color });
+            }
+            shapes.push(Shape::Rect { x: cell_x, y: cell_y, width: cell_width, height: cell_height, color: SPRITE_OFF_COLOR });
+            for (row, byte) in cell.rows.iter().enumerate() {
+                for column in (0..8).filter(|column| byte & (0x80 >> column) != 0) {
+                    shapes.push(Shape::Rect { x: cell_x + column * SPRITE_PIXEL, y: cell_y + row as u32 * SPRITE_PIXEL,
+                        width: SPRITE_PIXEL, height: SPRITE_PIXEL, color: TEXT_COLOR });
+                }
+            }
+            targets.push((cell_x - SPRITE_GAP / 2, cell_y - SPRITE_GAP / 2, pitch_x, pitch_y, PanelTarget::Sprite(cell.address)));
+        }
+        y += rows * pitch_y + LINE_HEIGHT / 2;
+
+        match view.selected {
+            Some((address, drawn)) => {
+                let description = match drawn {
+                    Some(sprite) => format!("{:03X}  DRAWN FROM I={:03X}, {} ROWS", address, sprite.address, sprite.rows),
+                    None => format!("{:03X}  NOT DRAWN YET", address)
+                };
+                text(shapes, y, &description, TEXT_COLOR);
+                y += LINE_HEIGHT;
+                text(shapes, y, "EXPORT PNG", INDICATOR_COLOR);
+                targets.push((x - 1, y - 2, CELL_WIDTH * 10 + 1, LINE_HEIGHT, PanelTarget::ExportSprite));
+            },
+            None => text(shapes, y, "CLICK A SPRITE TO SELECT IT", HINT_COLOR)
+        }
+        targets
+    }
+
+    pub fn scale(&self) -> u32 {
+        self.scale
+    }
+
+    /// Returns the image to draw over the window, or None when there is
+    /// nothing to show. The flag tells whether the image changed since the
+    /// last call. Pixels are premultiplied by their alpha.
+    pub fn render(&mut self) -> Option<(&Image, bool)> {
+        let shapes = self.layout();
+        if shapes != self.shapes || self.changed {
+            self.shapes = shapes;
+            self.changed = false;
+            if self.shapes.is_empty() { return None; }
+            self.draw();
+            return Some((&self.image, true));
+        }
+
+        if self.shapes.is_empty() { None } else { Some((&self.image, false)) }
+    }
+
+    fn draw(&mut self) {
+        let (width, height) = self.size();
+        self.image.width = width;
+        self.image.height = height;
+        self.image.pixels.clear();
+        self.image.pixels.resize((width * height * 4) as usize, 0);
+
+        for shape in &self.shapes {
+            match shape {
+                Shape::Rect { x, y, width, height, color } => self.image.fill_rect(*x, *y, *width, *height, *color),
+                Shape::Text { x, y, text, color } => draw_text(&mut self.image, *x, *y, text, *color)
+            }
+        }
+    }
+}
+
+fn draw_text(image: &mut Image, x: u32, y: u32, text: &str, color: [u8; 4]) {
+    for (i, c) in text.chars().enumerate() {
+        let glyph_x = x + i as u32 * CELL_WIDTH;
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for column in 0..GLYPH_WIDTH {
+                if bits & (0x80 >> column) != 0 {
+                    image.fill_rect(glyph_x + column, y + row as u32, 1, 1, color);
+                }
+            }
+        }
+    }
+}
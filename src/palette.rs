@@ -0,0 +1,72 @@
+use crate::config::parse_color;
+use toml::Table;
+
+/// Colors used to draw the screen. Color 0 is the background and color 1 the
+/// foreground. Colors 2 and 3 are only used when more than one bitplane is
+/// drawn: 2 for pixels set only in the second plane, 3 for pixels set in both.
+#[derive(Clone)]
+pub struct Palette {
+    pub name: String,
+    pub colors: [[f32; 3]; 4]
+}
+
+impl Palette {
+    pub fn from_hex(name: &str, colors: [u32; 4]) -> Palette {
+        Palette {
+            name: String::from(name),
+            colors: colors.map(|rgb| [
+                ((rgb >> 16) & 0xFF) as f32 / 255.0,
+                ((rgb >> 8) & 0xFF) as f32 / 255.0,
+                (rgb & 0xFF) as f32 / 255.0
+            ])
+        }
+    }
+
+    /// Palette of a background and a foreground color, with the bitplane
+    /// colors derived from them.
+    pub fn from_two_colors(name: &str, background: [f32; 3], foreground: [f32; 3]) -> Palette {
+        let half = [0, 1, 2].map(|i| (background[i] + foreground[i]) * 0.5);
+        Palette { name: String::from(name), colors: [background, foreground, half, foreground] }
+    }
+
+    /// Color of a pixel given the intensity of its two bitplanes. Matches the
+    /// blending done by the screen shader.
+    pub fn blend(&self, first_plane: f32, second_plane: f32) -> [f32; 3] {
+        let mix = |a: [f32; 3], b: [f32; 3], t: f32| [0, 1, 2].map(|i| a[i] + (b[i] - a[i]) * t);
+        let second_plane_off = mix(self.colors[0], self.colors[1], first_plane);
+        let second_plane_on = mix(self.colors[2], self.colors[3], first_plane);
+        mix(second_plane_off, second_plane_on, second_plane)
+    }
+
+    /// Builds a palette from a `[palettes.<name>]` config section:
+    ///
+    /// ```toml
+    /// [palettes.mine]
+    /// colors = ["#000000", "#FFFFFF", "#FF0000", "#00FF00"]
+    /// ```
+    ///
+    /// When only two colors are given, the bitplane colors are derived from them.
+    pub fn from_config(name: &str, table: &Table) -> Option<Palette> {
+        let colors = table.get("colors")?.as_array()?;
+        let colors: Option<Vec<[f32; 3]>> = colors.iter().map(parse_color).collect();
+        let colors = colors?;
+
+        match colors.len() {
+            2 => Some(Palette::from_two_colors(name, colors[0], colors[1])),
+            4 => Some(Palette { name: String::from(name), colors: [colors[0], colors[1], colors[2], colors[3]] }),
+            _ => None
+        }
+    }
+}
+
+pub fn builtin_palettes() -> Vec<Palette> {
+    vec![
+        Palette::from_hex("default", [0x393646, 0xF4EEE0, 0x6D5D6E, 0x4F4557]),
+        Palette::from_hex("teal", [0x0A4D68, 0x00FFCA, 0x088395, 0x05BFDB]),
+        Palette::from_hex("octo", [0x996600, 0xFFCC00, 0xFF6600, 0x662200]),
+        Palette::from_hex("gameboy", [0x0F380F, 0x9BBC0F, 0x306230, 0x8BAC0F]),
+        Palette::from_hex("amber", [0x1A0F00, 0xFFB000, 0x805800, 0xFFD280]),
+        Palette::from_hex("green", [0x001A00, 0x33FF33, 0x118811, 0xAAFFAA]),
+        Palette::from_hex("mono", [0x000000, 0xFFFFFF, 0x555555, 0xAAAAAA])
+    ]
+}
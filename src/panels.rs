@@ -0,0 +1,113 @@
+use crate::chip8::{Chip8, KeyboardKey, Register, MEMSIZE};
+use crate::disasm;
+use crate::utils;
+
+/// Bytes shown per row of the memory view.
+pub const MEMORY_ROW_SIZE: u16 = 8;
+/// Rows of memory captured, frontends show as many as fit.
+const MEMORY_ROWS: u16 = 48;
+/// Instructions captured before and after PC.
+const DISASSEMBLY_CONTEXT: u16 = 8;
+/// Frames a byte stays highlighted after the rom writes it.
+const WRITE_HIGHLIGHT_FRAMES: u64 = 30;
+
+/// Part of the panels that can be clicked.
+#[derive(Clone, Copy, PartialEq)]
+pub enum PanelTarget {
+    /// The instruction at an address, in the disassembly.
+    Instruction(u16),
+    /// A byte of the memory view.
+    Memory(u16),
+    /// The sprite at an address, in the sprite viewer.
+    Sprite(u16),
+    /// Changes the height of the sprites by a number of rows.
+    SpriteHeight(i32),
+    /// Moves the sprite viewer by a number of bytes.
+    SpriteOffset(i32),
+    ExportSprite
+}
+
+#[derive(Clone, PartialEq)]
+pub struct DisassemblyLine {
+    pub address: u16,
+    pub opcode: u16,
+    pub text: String,
+    /// Whether this is the instruction at PC.
+    pub current: bool,
+    pub breakpoint: bool
+}
+
+#[derive(Clone, PartialEq)]
+pub struct MemoryRow {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    /// Whether each byte was written recently.
+    pub written: Vec<bool>
+}
+
+/// State of the Chip-8 shown by the debugger panels, which the frontends
+/// draw next to the screen: registers, the instructions around PC, the
+/// call stack, the keypad and a page of memory.
+#[derive(Clone, PartialEq)]
+pub struct DebugView {
+    pub registers: [u16; Register::Total as usize],
+    pub disassembly: Vec<DisassemblyLine>,
+    /// Return addresses with their label, the most recent first.
+    pub call_stack: Vec<(u16, Option<String>)>,
+    pub keys: [bool; KeyboardKey::Total as usize],
+    pub memory: Vec<MemoryRow>
+}
+
+impl DebugView {
+    /// Captures the state of the Chip-8, with the memory view starting at
+    /// memory_start.
+    pub fn capture(chip8: &Chip8, memory_start: u16) -> DebugView {
+        let pc = chip8.get_register(Register::PC);
+        // Instructions are aligned on PC, which may be odd
+        let first = pc - (pc / 2).min(DISASSEMBLY_CONTEXT) * 2;
+        let disassembly = (first..=pc.saturating_add(DISASSEMBLY_CONTEXT * 2)).step_by(2)
+            .take_while(|address| (*address as usize) + 1 < MEMSIZE)
+            .map(|address| {
+                let opcode = utils::concat_u8_to_u16(chip8.main_memory[address as usize], chip8.main_memory[address as usize + 1]);
+                DisassemblyLine {
+                    address,
+                    opcode,
+                    text: disasm::disassemble_with(opcode, &chip8.symbols),
+                    current: address == pc,
+                    breakpoint: chip8.debug.breakpoints.contains(&address)
+                }
+            })
+            .collect();
+
+        let memory = (0..MEMORY_ROWS)
+            .map(|row| memory_start as usize + (row * MEMORY_ROW_SIZE) as usize)
+            .take_while(|address| *address < MEMSIZE)
+            .map(|address| {
+                let end = (address + MEMORY_ROW_SIZE as usize).min(MEMSIZE);
+                MemoryRow {
+                    address: address as u16,
+                    bytes: chip8.main_memory[address..end].to_vec(),
+                    written: (address..end)
+                        .map(|a| chip8.history.last_write(a as u16)
+                            .is_some_and(|write| chip8.frame_count.saturating_sub(write.frame) < WRITE_HIGHLIGHT_FRAMES))
+                        .collect()
+                }
+            })
+            .collect();
+
+        DebugView {
+            registers: chip8.registers,
+            disassembly,
+            call_stack: chip8.return_addresses().into_iter().map(|address| (address, chip8.symbols.label(address))).collect(),
+            keys: chip8.keyboard_state.keys,
+            memory
+        }
+    }
+}
+
+/// Start of the memory view after scrolling it by a number of rows,
+/// negative going up. Stays within the memory.
+pub fn scroll_memory(start: u16, rows: i32) -> u16 {
+    let last_row = (MEMSIZE as i32 - 1) / MEMORY_ROW_SIZE as i32 * MEMORY_ROW_SIZE as i32;
+    (start as i32 + rows * MEMORY_ROW_SIZE as i32).clamp(0, last_row) as u16
+}
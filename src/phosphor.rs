@@ -0,0 +1,163 @@
+use std::collections::VecDeque;
+use crate::chip8;
+use crate::config;
+use crate::renderer;
+use toml::Table;
+
+const SCREEN_PIXELS: usize = (chip8::DISPLAYW * chip8::DISPLAYH) as usize;
+
+/// How pixels that were just turned off keep being displayed. Games draw by
+/// XORing sprites, so moving sprites are off for part of the time, and
+/// flicker when shown as is.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Ghosting {
+    /// Pixels are shown exactly as in the framebuffer.
+    Off,
+    /// Pixels fade out, keeping `factor` of their intensity every frame.
+    Decay { factor: f32 },
+    /// Pixels are averaged over the last `frames` frames.
+    Blend { frames: usize },
+    /// Pixels on in either the current or the previous frame are shown.
+    Or
+}
+
+impl Ghosting {
+    /// Reads the ghosting mode from the `[ghosting]` config section:
+    ///
+    /// ```toml
+    /// [ghosting]
+    /// mode = "decay"  # "off", "decay", "blend" or "or"
+    /// factor = 0.8    # Used by "decay"
+    /// frames = 3      # Used by "blend"
+    /// ```
+    pub fn from_config(table: &Table, current: Ghosting) -> Option<Ghosting> {
+        let factor = match table.get("factor") {
+            Some(v) => config::read_float(v).filter(|f| (0.0..1.0).contains(f))? as f32,
+            None => if let Ghosting::Decay { factor } = current { factor } else { 0.8 }
+        };
+        let frames = match table.get("frames") {
+            Some(v) => v.as_integer().filter(|f| (1..=60).contains(f))? as usize,
+            None => if let Ghosting::Blend { frames } = current { frames } else { 3 }
+        };
+
+        let mode = match table.get("mode") {
+            Some(v) => v.as_str()?,
+            None => match current {
+                Ghosting::Off => "off",
+                Ghosting::Decay { .. } => "decay",
+                Ghosting::Blend { .. } => "blend",
+                Ghosting::Or => "or"
+            }
+        };
+
+        match mode {
+            "off" => Some(Ghosting::Off),
+            "decay" => Some(Ghosting::Decay { factor }),
+            "blend" => Some(Ghosting::Blend { frames }),
+            "or" => Some(Ghosting::Or),
+            _ => None
+        }
+    }
+}
+
+/// Computes the displayed intensity of each pixel from the framebuffer.
+///
+/// The filter advances in emulated frames (60 per second, see
+/// `Chip8::frame_count`) instead of once per draw, so ghosting looks the same
+/// regardless of the display refresh rate or the emulation speed.
+pub struct PhosphorFilter {
+    ghosting: Ghosting,
+    /// Plane masks of the framebuffer at the end of the previous frames, most
+    /// recent first.
+    history: VecDeque<Vec<u8>>,
+    /// Plane masks of the framebuffer the last time the filter was applied.
+    last_planes: Vec<u8>,
+    /// Intensity each plane decayed to since the previous frames, interleaved.
+    decayed: Vec<f32>,
+    /// Intensity of each plane to be displayed, interleaved.
+    output: Vec<f32>,
+    last_frame: Option<u64>
+}
+
+impl PhosphorFilter {
+    pub fn new(ghosting: Ghosting) -> PhosphorFilter {
+        PhosphorFilter {
+            ghosting,
+            history: VecDeque::new(),
+            last_planes: vec![0; SCREEN_PIXELS],
+            decayed: vec![0.0; SCREEN_PIXELS * 2],
+            output: vec![0.0; SCREEN_PIXELS * 2],
+            last_frame: None
+        }
+    }
+
+    pub fn set_ghosting(&mut self, ghosting: Ghosting) {
+        if ghosting != self.ghosting {
+            *self = PhosphorFilter::new(ghosting);
+        }
+    }
+
+    fn history_len(&self) -> usize {
+        match self.ghosting {
+            Ghosting::Off | Ghosting::Decay { .. } => 0,
+            Ghosting::Blend { frames } => frames - 1,
+            Ghosting::Or => 1
+        }
+    }
+
+    /// Returns the intensity, from 0 to 1, of both bitplanes of each pixel
+    /// (interleaved), for the framebuffer as of the given emulated frame.
+    pub fn apply(&mut self, fb: &[u8], frame: u64) -> &[f32] {
+        let planes: Vec<u8> = fb.iter().map(|p| renderer::pixel_planes(*p)).collect();
+        let elapsed_frames = match self.last_frame {
+            Some(last) => frame.saturating_sub(last),
+            None => 1
+        };
+
+        if elapsed_frames > 0 {
+            self.last_frame = Some(frame);
+            self.advance_frames(elapsed_frames);
+        }
+
+        for (i, mask) in planes.iter().enumerate() {
+            for plane in 0..2 {
+                let bit = 1 << plane;
+                let on = if mask & bit != 0 { 1.0 } else { 0.0 };
+                self.output[i * 2 + plane] = match self.ghosting {
+                    Ghosting::Off => on,
+                    Ghosting::Decay { .. } => f32::max(on, self.decayed[i * 2 + plane]),
+                    Ghosting::Or => if self.history.iter().any(|h| h[i] & bit != 0) { 1.0 } else { on },
+                    Ghosting::Blend { frames } => {
+                        let on_frames = self.history.iter().filter(|h| h[i] & bit != 0).count() as f32;
+                        (on + on_frames) / frames as f32
+                    }
+                };
+            }
+        }
+
+        self.last_planes = planes;
+        &self.output
+    }
+
+    /// Moves the frames that ended into the history. The framebuffer as last
+    /// seen is taken as the final state of the frame that ended.
+    fn advance_frames(&mut self, elapsed_frames: u64) {
+        if let Ghosting::Decay { factor } = self.ghosting {
+            let decay = factor.powi(elapsed_frames.min(i32::MAX as u64) as i32);
+            for (i, mask) in self.last_planes.iter().enumerate() {
+                for plane in 0..2 {
+                    let value = &mut self.decayed[i * 2 + plane];
+                    let on = if mask & (1 << plane) != 0 { 1.0 } else { *value };
+                    *value = on * decay;
+                }
+            }
+        }
+
+        let history_len = self.history_len();
+        if history_len == 0 { return; }
+        for _ in 0..elapsed_frames.min(history_len as u64) {
+            self.history.push_front(self.last_planes.clone());
+        }
+        self.history.truncate(history_len);
+    }
+}
@@ -0,0 +1,305 @@
+use crate::opengl::RenderTarget;
+use crate::shader::{GlVersion, ShaderProgram};
+use toml::Table;
+use crate::chip8;
+use crate::config;
+use crate::log_warn;
+
+/// Post-processing effects applied to the screen, configured in the
+/// `[effects]` section of the config file. All effects are disabled by default.
+#[derive(Clone)]
+pub struct Effects {
+    pub scanlines: bool,
+    pub scanline_intensity: f32,
+    pub pixel_grid: bool,
+    pub pixel_grid_intensity: f32,
+    pub bloom: bool,
+    pub bloom_intensity: f32,
+    /// Distance between bloom samples, in window pixels.
+    pub bloom_radius: f32,
+    pub curvature: bool,
+    pub curvature_amount: f32,
+    pub vignette: bool,
+    pub vignette_intensity: f32
+}
+
+impl Effects {
+    pub fn new() -> Effects {
+        Effects {
+            scanlines: false,
+            scanline_intensity: 0.3,
+            pixel_grid: false,
+            pixel_grid_intensity: 0.4,
+            bloom: false,
+            bloom_intensity: 0.5,
+            bloom_radius: 2.0,
+            curvature: false,
+            curvature_amount: 0.1,
+            vignette: false,
+            vignette_intensity: 0.4
+        }
+    }
+
+    pub fn apply(&mut self, table: &Table) {
+        for (key, value) in table {
+            let applied = match key.as_str() {
+                "scanlines" => value.as_bool().map(|v| self.scanlines = v),
+                "scanline_intensity" => config::read_float(value).map(|v| self.scanline_intensity = v as f32),
+                "pixel_grid" => value.as_bool().map(|v| self.pixel_grid = v),
+                "pixel_grid_intensity" => config::read_float(value).map(|v| self.pixel_grid_intensity = v as f32),
+                "bloom" => value.as_bool().map(|v| self.bloom = v),
+                "bloom_intensity" => config::read_float(value).map(|v| self.bloom_intensity = v as f32),
+                "bloom_radius" => config::read_float(value).map(|v| self.bloom_radius = v as f32),
+                "curvature" => value.as_bool().map(|v| self.curvature = v),
+                "curvature_amount" => config::read_float(value).map(|v| self.curvature_amount = v as f32),
+                "vignette" => value.as_bool().map(|v| self.vignette = v),
+                "vignette_intensity" => config::read_float(value).map(|v| self.vignette_intensity = v as f32),
+                _ => {
+                    log_warn!(Config, "Unknown effect setting '{}'.", key);
+                    Some(())
+                }
+            };
+
+            if applied.is_none() {
+                log_warn!(Config, "Invalid value for effect setting '{}'.", key);
+            }
+        }
+    }
+
+    pub fn any_enabled(&self) -> bool {
+        Effect::ALL.iter().any(|e| e.enabled(self))
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Effect {
+    PixelGrid,
+    Scanlines,
+    Bloom,
+    Curvature,
+    Vignette
+}
+
+impl Effect {
+    /// Effects in the order they are applied. Curvature comes after the
+    /// effects that draw lines, so the lines are warped along with the image.
+    const ALL: [Effect; 5] = [Effect::PixelGrid, Effect::Scanlines, Effect::Bloom, Effect::Curvature, Effect::Vignette];
+
+    fn enabled(&self, effects: &Effects) -> bool {
+        match self {
+            Effect::PixelGrid => effects.pixel_grid,
+            Effect::Scanlines => effects.scanlines,
+            Effect::Bloom => effects.bloom,
+            Effect::Curvature => effects.curvature,
+            Effect::Vignette => effects.vignette
+        }
+    }
+
+    fn uniforms(&self, effects: &Effects) -> Vec<(&'static str, f32)> {
+        match self {
+            Effect::PixelGrid => vec![("intensity", effects.pixel_grid_intensity)],
+            Effect::Scanlines => vec![("intensity", effects.scanline_intensity)],
+            Effect::Bloom => vec![("intensity", effects.bloom_intensity), ("radius", effects.bloom_radius)],
+            Effect::Curvature => vec![("amount", effects.curvature_amount)],
+            Effect::Vignette => vec![("intensity", effects.vignette_intensity)]
+        }
+    }
+
+    fn fragment_shader(&self) -> &'static str {
+        match self {
+            Effect::PixelGrid => PIXEL_GRID_SHADER,
+            Effect::Scanlines => SCANLINES_SHADER,
+            Effect::Bloom => BLOOM_SHADER,
+            Effect::Curvature => CURVATURE_SHADER,
+            Effect::Vignette => VIGNETTE_SHADER
+        }
+    }
+}
+
+struct EffectPass {
+    effect: Effect,
+    shader: ShaderProgram
+}
+
+/// Runs the enabled effects over the rendered screen. The screen is drawn to
+/// an offscreen target, then each effect reads the output of the previous one
+/// and the last effect draws to the window.
+pub struct PostProcessChain {
+    passes: Vec<EffectPass>,
+    targets: [RenderTarget; 2],
+    effects: Effects
+}
+
+impl PostProcessChain {
+    pub fn new(gl_version: GlVersion) -> PostProcessChain {
+        PostProcessChain {
+            passes: Effect::ALL.iter().map(|effect| EffectPass {
+                effect: *effect,
+                shader: ShaderProgram::from_text(PASS_VERTEX_SHADER, effect.fragment_shader(), gl_version)
+            }).collect(),
+            targets: [RenderTarget::new(), RenderTarget::new()],
+            effects: Effects::new()
+        }
+    }
+
+    pub fn init(&mut self) {
+        for pass in &mut self.passes {
+            if let Err(e) = pass.shader.load() {
+                panic!("Unable to load post-processing shaders. {}", e);
+            }
+        }
+        for target in &mut self.targets {
+            target.init();
+        }
+    }
+
+    pub fn set_effects(&mut self, effects: &Effects) {
+        self.effects = effects.clone();
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.effects.any_enabled()
+    }
+
+    pub fn resize(&mut self, width: i32, height: i32) {
+        for target in &mut self.targets {
+            target.resize(width.max(1), height.max(1));
+        }
+    }
+
+    /// Redirects drawing to the chain input. Must be followed by a call to
+    /// `finish` once the screen is drawn.
+    pub fn begin(&mut self) {
+        self.targets[0].bind();
+        unsafe { gl::Clear(gl::COLOR_BUFFER_BIT); }
+    }
+
+    /// Applies the enabled effects and draws the result to the window. The
+    /// screen quad must be bound.
+    pub fn finish(&mut self, viewport: (i32, i32, i32, i32)) {
+        let enabled: Vec<&EffectPass> = self.passes.iter()
+            .filter(|p| p.effect.enabled(&self.effects))
+            .collect();
+
+        for (i, pass) in enabled.iter().enumerate() {
+            let source = i % 2;
+            let is_last = i + 1 == enabled.len();
+            if is_last {
+                RenderTarget::bind_default(viewport);
+            } else {
+                self.targets[1 - source].bind();
+            }
+
+            let (width, height) = self.targets[source].size();
+            pass.shader.bind();
+            pass.shader.set_uniform_i32("sourceTex", 0);
+            pass.shader.set_uniform_vec2("resolution", [width as f32, height as f32]);
+            pass.shader.set_uniform_vec2("screenSize", [chip8::DISPLAYW as f32, chip8::DISPLAYH as f32]);
+            for (name, value) in pass.effect.uniforms(&self.effects) {
+                pass.shader.set_uniform_f32(name, value);
+            }
+
+            unsafe {
+                gl::ActiveTexture(gl::TEXTURE0);
+                self.targets[source].texture.bind();
+                gl::DrawArrays(gl::TRIANGLES, 0, 6);
+            }
+        }
+    }
+}
+
+const PASS_VERTEX_SHADER: &str = "
+    ATTRIBUTE vec2 iCoord;
+    VARYING vec2 fTexCoord;
+
+    void main() {
+        gl_Position = vec4(iCoord, 0.0, 1.0);
+        fTexCoord = iCoord * 0.5 + 0.5;
+    }
+";
+
+/// Darkens the borders between Chip-8 pixels.
+const PIXEL_GRID_SHADER: &str = "
+    VARYING vec2 fTexCoord;
+    uniform sampler2D sourceTex;
+    uniform vec2 screenSize;
+    uniform float intensity;
+
+    void main() {
+        vec3 color = TEXTURE(sourceTex, fTexCoord).rgb;
+        vec2 distanceToCenter = abs(fract(fTexCoord * screenSize) - 0.5) * 2.0;
+        float border = smoothstep(0.7, 1.0, max(distanceToCenter.x, distanceToCenter.y));
+        FRAG_COLOR = vec4(color * (1.0 - intensity * border), 1.0);
+    }
+";
+
+/// Darkens the space between Chip-8 pixel rows.
+const SCANLINES_SHADER: &str = "
+    VARYING vec2 fTexCoord;
+    uniform sampler2D sourceTex;
+    uniform vec2 screenSize;
+    uniform float intensity;
+
+    void main() {
+        vec3 color = TEXTURE(sourceTex, fTexCoord).rgb;
+        float row = fract(fTexCoord.y * screenSize.y);
+        float line = 0.5 + 0.5 * cos(row * 6.2831853);
+        FRAG_COLOR = vec4(color * (1.0 - intensity * line), 1.0);
+    }
+";
+
+/// Adds a blurred copy of the image on top of itself.
+const BLOOM_SHADER: &str = "
+    VARYING vec2 fTexCoord;
+    uniform sampler2D sourceTex;
+    uniform vec2 resolution;
+    uniform float intensity;
+    uniform float radius;
+
+    void main() {
+        vec3 color = TEXTURE(sourceTex, fTexCoord).rgb;
+        vec3 glow = vec3(0.0);
+        float totalWeight = 0.0;
+        for (int x = -3; x <= 3; x++) {
+            for (int y = -3; y <= 3; y++) {
+                vec2 offset = vec2(float(x), float(y)) * radius / resolution;
+                float weight = exp(-float(x * x + y * y) / 8.0);
+                glow += TEXTURE(sourceTex, fTexCoord + offset).rgb * weight;
+                totalWeight += weight;
+            }
+        }
+        FRAG_COLOR = vec4(color + glow / totalWeight * intensity, 1.0);
+    }
+";
+
+/// Warps the image as if displayed on a curved CRT screen.
+const CURVATURE_SHADER: &str = "
+    VARYING vec2 fTexCoord;
+    uniform sampler2D sourceTex;
+    uniform float amount;
+
+    void main() {
+        vec2 centered = fTexCoord * 2.0 - 1.0;
+        centered += centered * (centered.yx * centered.yx) * amount;
+        vec2 coord = centered * 0.5 + 0.5;
+        if (coord.x < 0.0 || coord.x > 1.0 || coord.y < 0.0 || coord.y > 1.0) {
+            FRAG_COLOR = vec4(0.0, 0.0, 0.0, 1.0);
+        } else {
+            FRAG_COLOR = vec4(TEXTURE(sourceTex, coord).rgb, 1.0);
+        }
+    }
+";
+
+/// Darkens the corners of the image.
+const VIGNETTE_SHADER: &str = "
+    VARYING vec2 fTexCoord;
+    uniform sampler2D sourceTex;
+    uniform float intensity;
+
+    void main() {
+        vec3 color = TEXTURE(sourceTex, fTexCoord).rgb;
+        vec2 centered = fTexCoord - 0.5;
+        float vignette = smoothstep(0.2, 0.75, length(centered));
+        FRAG_COLOR = vec4(color * (1.0 - intensity * vignette), 1.0);
+    }
+";
@@ -0,0 +1,262 @@
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use crate::chip8::{Chip8, MEMSIZE, PROGRAM_START};
+use crate::disasm;
+use crate::sprites;
+use crate::utils;
+
+/// Addresses listed in the hot spots of the report.
+const HOT_SPOTS: usize = 20;
+/// Loops listed in the report.
+const HOT_LOOPS: usize = 10;
+/// Calls followed at most. Roms that call without returning drop the
+/// outermost ones.
+const MAX_DEPTH: usize = 64;
+
+/// Instructions run by a subroutine, counted from its calls.
+#[derive(Clone, Copy, Default)]
+pub struct SubroutineStats {
+    pub calls: u64,
+    /// Instructions run by the subroutine and the ones it called.
+    pub inclusive: u64,
+    /// Instructions run by the subroutine itself.
+    pub exclusive: u64
+}
+
+/// A call not returned from yet.
+struct Frame {
+    entry: u16,
+    /// Instructions run before the call.
+    start: u64
+}
+
+/// A backward jump, and the instructions run between its target and itself.
+struct Loop {
+    start: u16,
+    end: u16,
+    iterations: u64,
+    instructions: u64
+}
+
+/// Counts how many times each address is executed, and the instructions
+/// run by each subroutine, following `CALL` and `RET`.
+pub struct Profiler {
+    rom_size: usize,
+    counts: Vec<u64>,
+    total: u64,
+    subroutines: BTreeMap<u16, SubroutineStats>,
+    /// Calls not returned from yet, the innermost last.
+    frames: Vec<Frame>,
+    /// Instructions run outside of subroutines.
+    top_level: u64
+}
+
+fn percent(count: u64, total: u64) -> f64 {
+    if total == 0 { 0.0 } else { count as f64 * 100.0 / total as f64 }
+}
+
+impl Profiler {
+    /// Profiles a rom of rom_size bytes, whose code is reported on.
+    pub fn new(rom_size: usize) -> Profiler {
+        Profiler {
+            rom_size,
+            counts: vec![0; MEMSIZE],
+            total: 0,
+            subroutines: BTreeMap::new(),
+            frames: Vec::new(),
+            top_level: 0
+        }
+    }
+
+    /// Times the instruction at an address was executed.
+    pub fn count(&self, address: u16) -> u64 {
+        self.counts.get(address as usize).copied().unwrap_or(0)
+    }
+
+    /// Counts an instruction that just ran.
+    pub fn record(&mut self, pc: u16, opcode: u16) {
+        self.total += 1;
+        self.counts[pc as usize] += 1;
+        match self.frames.last() {
+            Some(frame) => self.subroutines.entry(frame.entry).or_default().exclusive += 1,
+            None => self.top_level += 1
+        }
+
+        if opcode & 0xF000 == 0x2000 {
+            let entry = opcode & 0x0FFF;
+            self.subroutines.entry(entry).or_default().calls += 1;
+            if self.frames.len() == MAX_DEPTH {
+                self.frames.remove(0);
+            }
+            self.frames.push(Frame { entry, start: self.total });
+        } else if opcode == 0x00EE {
+            if let Some(frame) = self.frames.pop() {
+                // Recursive calls are counted by the outermost one
+                if !self.frames.iter().any(|f| f.entry == frame.entry) {
+                    self.subroutines.entry(frame.entry).or_default().inclusive += self.total - frame.start;
+                }
+            }
+        }
+    }
+
+    /// Subroutine statistics, including the calls not returned from yet.
+    fn subroutine_stats(&self) -> BTreeMap<u16, SubroutineStats> {
+        let mut stats = self.subroutines.clone();
+        for (depth, frame) in self.frames.iter().enumerate() {
+            if !self.frames[..depth].iter().any(|f| f.entry == frame.entry) {
+                stats.entry(frame.entry).or_default().inclusive += self.total - frame.start;
+            }
+        }
+        stats
+    }
+
+    /// Backward jumps executed, the ones running the most instructions first.
+    fn hot_loops(&self, memory: &[u8]) -> Vec<Loop> {
+        let mut loops: Vec<Loop> = (0..MEMSIZE - 1)
+            .filter(|pc| self.counts[*pc] > 0)
+            .filter_map(|pc| {
+                let opcode = utils::concat_u8_to_u16(memory[pc], memory[pc + 1]);
+                let target = (opcode & 0x0FFF) as usize;
+                (opcode & 0xF000 == 0x1000 && target <= pc).then(|| Loop {
+                    start: target as u16,
+                    end: pc as u16,
+                    iterations: self.counts[pc],
+                    instructions: self.counts[target..=pc].iter().sum()
+                })
+            })
+            .collect();
+        loops.sort_by_key(|l| Reverse(l.instructions));
+        loops
+    }
+
+    /// Whether a byte of the rom is part of an instruction executed.
+    fn covered(&self, address: usize) -> bool {
+        self.counts[address] > 0 || (address > 0 && self.counts[address - 1] > 0)
+    }
+
+    /// Ranges of the rom never executed, from start to end included.
+    fn never_executed(&self) -> Vec<(usize, usize)> {
+        let mut ranges: Vec<(usize, usize)> = Vec::new();
+        for address in PROGRAM_START..(PROGRAM_START + self.rom_size).min(MEMSIZE) {
+            if self.covered(address) {
+                continue;
+            }
+            match ranges.last_mut() {
+                Some((_, end)) if *end + 1 == address => *end = address,
+                _ => ranges.push((address, address))
+            }
+        }
+        ranges
+    }
+
+    /// Describes where instructions were spent: the hot spots and loops, the
+    /// instructions of each subroutine, and the code never executed.
+    pub fn report(&self, chip8: &Chip8) -> String {
+        let memory = &chip8.main_memory;
+        let symbols = &chip8.symbols;
+        let disassemble = |address: usize| disasm::disassemble_with(utils::concat_u8_to_u16(memory[address], memory[(address + 1).min(MEMSIZE - 1)]), symbols);
+        let end = (PROGRAM_START + self.rom_size).min(MEMSIZE);
+        let covered = (PROGRAM_START..end).filter(|a| self.covered(*a)).count();
+        let mut report = String::new();
+
+        let _ = writeln!(report, "Profile of {} instructions. {} of the {} bytes of the rom were executed ({:.1}%).",
+            self.total, covered, self.rom_size, percent(covered as u64, self.rom_size as u64));
+
+        let _ = writeln!(report, "\nHot spots:\n     count       %  address  instruction");
+        let mut addresses: Vec<usize> = (0..MEMSIZE).filter(|a| self.counts[*a] > 0).collect();
+        addresses.sort_by(|a, b| self.counts[*b].cmp(&self.counts[*a]).then(a.cmp(b)));
+        for address in addresses.iter().take(HOT_SPOTS) {
+            let count = self.counts[*address];
+            let _ = writeln!(report, "{:>10} {:>6.2}%  {:#05x}    {}{}", count, percent(count, self.total), address, disassemble(*address),
+                symbols.label(*address as u16).map_or_else(String::new, |label| format!("  ; {}", label)));
+        }
+
+        let _ = writeln!(report, "\nHot loops (from the target of a backward jump to the jump):\n  instructions       %  iterations  addresses");
+        for l in self.hot_loops(memory).iter().take(HOT_LOOPS) {
+            let _ = writeln!(report, "{:>14} {:>6.2}%  {:>10}  {:#05x}-{:#05x}{}",
+                l.instructions, percent(l.instructions, self.total), l.iterations, l.start, l.end,
+                symbols.label(l.start).map_or_else(String::new, |label| format!("  ; {}", label)));
+        }
+
+        let _ = writeln!(report, "\nSubroutines (inclusive counts the subroutines they call):\n   address      calls   inclusive       %   exclusive       %");
+        let _ = writeln!(report, "top level  {:>10}  {:>10} {:>6.2}%  {:>10} {:>6.2}%", "-",
+            self.total, 100.0, self.top_level, percent(self.top_level, self.total));
+        let mut subroutines: Vec<(u16, SubroutineStats)> = self.subroutine_stats().into_iter().collect();
+        subroutines.sort_by(|a, b| b.1.inclusive.cmp(&a.1.inclusive).then(a.0.cmp(&b.0)));
+        for (address, stats) in subroutines.iter() {
+            let _ = writeln!(report, "    {:#05x}  {:>10}  {:>10} {:>6.2}%  {:>10} {:>6.2}%{}", address, stats.calls,
+                stats.inclusive, percent(stats.inclusive, self.total), stats.exclusive, percent(stats.exclusive, self.total),
+                symbols.label(*address).map_or_else(String::new, |label| format!("  ; {}", label)));
+        }
+
+        let _ = writeln!(report, "\nNever executed:");
+        for (start, end) in self.never_executed() {
+            let sprites = (start..=end).any(|a| sprites::drawn_at(&chip8.sprites_drawn, a as u16).is_some());
+            let _ = writeln!(report, "  {:#05x}-{:#05x}  {:>4} bytes{}{}", start, end, end - start + 1,
+                if sprites { "  holds sprites drawn" } else { "" },
+                symbols.label(start as u16).map_or_else(String::new, |label| format!("  ; {}", label)));
+        }
+        report
+    }
+
+    /// Disassembles the rom with the times each instruction was executed,
+    /// marking the subroutines, loops and sprites.
+    pub fn listing(&self, chip8: &Chip8) -> String {
+        let memory = &chip8.main_memory;
+        let symbols = &chip8.symbols;
+        let subroutines = self.subroutine_stats();
+        let loops = self.hot_loops(memory);
+        let mut listing = String::new();
+        let _ = writeln!(listing, "; {} instructions executed\n;      count        %  addr  opcode  instruction", self.total);
+
+        let end = (PROGRAM_START + self.rom_size).min(MEMSIZE);
+        let mut address = PROGRAM_START;
+        while address < end {
+            // Subroutines without a label are named after their address
+            let label = symbols.name_at(address as u16);
+            match subroutines.get(&(address as u16)) {
+                Some(stats) => {
+                    let _ = writeln!(listing, "\n{}:  ; {} calls, {} instructions ({:.2}%), {} in itself ({:.2}%)",
+                        label.map_or_else(|| format!("sub_{:03x}", address), String::from), stats.calls,
+                        stats.inclusive, percent(stats.inclusive, self.total), stats.exclusive, percent(stats.exclusive, self.total));
+                },
+                None => if let Some(label) = label {
+                    let _ = writeln!(listing, "{}:", label);
+                }
+            }
+            for l in loops.iter().filter(|l| l.start as usize == address) {
+                let _ = writeln!(listing, "; loop to {:#05x}: {} iterations, {} instructions ({:.2}%)",
+                    l.end, l.iterations, l.instructions, percent(l.instructions, self.total));
+            }
+
+            // Code may be aligned on odd addresses, after data of odd size
+            if self.counts[address] == 0 && address + 1 < end && self.counts[address + 1] > 0 {
+                let _ = writeln!(listing, "           -        -  {:03x}  {:02x}      ; data", address, memory[address]);
+                address += 1;
+                continue;
+            }
+            let opcode = utils::concat_u8_to_u16(memory[address], memory[(address + 1).min(MEMSIZE - 1)]);
+            let count = self.counts[address];
+            if count > 0 {
+                let _ = writeln!(listing, "{:>12} {:>7.2}%  {:03x}  {:04x}    {}", count, percent(count, self.total), address, opcode, disasm::disassemble_with(opcode, symbols));
+            } else {
+                let sprite = sprites::drawn_at(&chip8.sprites_drawn, address as u16).is_some();
+                let _ = writeln!(listing, "           -        -  {:03x}  {:04x}    {}", address, opcode,
+                    if sprite { String::from("; sprite") } else { disasm::disassemble_with(opcode, symbols) });
+            }
+            address += 2;
+        }
+        listing
+    }
+
+    /// Writes the report to path, and the annotated disassembly next to it,
+    /// with `.lst` appended. Returns a message telling where.
+    pub fn save(&self, path: &str, chip8: &Chip8) -> Result<String, String> {
+        let listing_path = format!("{}.lst", path);
+        fs::write(path, self.report(chip8)).map_err(|e| format!("Unable to write profile {}: {}", path, e))?;
+        fs::write(&listing_path, self.listing(chip8)).map_err(|e| format!("Unable to write profile {}: {}", listing_path, e))?;
+        Ok(format!("Profile saved: {} and {}", path, listing_path))
+    }
+}
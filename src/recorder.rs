@@ -0,0 +1,262 @@
+use std::borrow::Cow;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use crate::chip8::{DISPLAYH, DISPLAYW};
+use crate::palette::Palette;
+use crate::renderer;
+use crate::utils;
+
+/// Frames are emulated at 60 hz, see `chip8::FRAME_TIME`.
+const FRAME_RATE: u64 = 60;
+const SAMPLE_RATE: u32 = 44100;
+const SAMPLES_PER_FRAME: u32 = SAMPLE_RATE / FRAME_RATE as u32;
+/// Pitch of the beeper.
+const BEEP_FREQUENCY: u32 = 440;
+const BEEP_AMPLITUDE: i16 = i16::MAX / 4;
+/// Most viewers show frames with delays below 2 hundredths of a second for
+/// much longer, so shorter frames are dropped from GIFs instead.
+const MIN_GIF_DELAY: u64 = 2;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum RecordingFormat {
+    /// Animated GIF, with repeated frames merged. No audio.
+    Gif,
+    /// Uncompressed Y4M video and WAV audio, with every frame, to be encoded
+    /// or muxed later, e.g. with `ffmpeg -i video.y4m -i audio.wav out.mp4`.
+    Raw
+}
+
+impl RecordingFormat {
+    pub fn from_name(name: &str) -> Option<RecordingFormat> {
+        match name {
+            "gif" => Some(RecordingFormat::Gif),
+            "raw" => Some(RecordingFormat::Raw),
+            _ => None
+        }
+    }
+}
+
+/// A frame waiting to be written to a GIF, until it is known how long it is
+/// shown for.
+struct PendingFrame {
+    pixels: Vec<u8>,
+    /// Emulated frame at which it started being shown.
+    start_frame: u64
+}
+
+enum Encoder {
+    Gif {
+        writer: gif::Encoder<BufWriter<File>>,
+        pending: Option<PendingFrame>
+    },
+    Raw {
+        video: BufWriter<File>,
+        audio: WavWriter
+    }
+}
+
+/// Records every emulated frame, and the beeper, to files.
+pub struct Recorder {
+    encoder: Encoder,
+    palette: Palette,
+    scale: u32,
+    frames: u64,
+    paths: Vec<PathBuf>
+}
+
+fn centiseconds(frame: u64) -> u64 {
+    (frame * 100 + FRAME_RATE / 2) / FRAME_RATE
+}
+
+fn create_file(path: &Path) -> Result<BufWriter<File>, String> {
+    File::create(path)
+        .map(BufWriter::new)
+        .map_err(|e| format!("Unable to create {}: {}", path.display(), e))
+}
+
+impl Recorder {
+    /// Starts recording to new files in dir, named after the rom and the
+    /// current time. Each Chip-8 pixel is recorded as a square of scale by
+    /// scale pixels, drawn with the palette colors.
+    pub fn start(dir: &str, name: &str, format: RecordingFormat, palette: &Palette, scale: u32) -> Result<Recorder, String> {
+        fs::create_dir_all(dir).map_err(|e| format!("Unable to create recording directory {}: {}", dir, e))?;
+        let scale = scale.max(1);
+        let (width, height) = (DISPLAYW * scale, DISPLAYH * scale);
+        let base_path = Path::new(dir).join(format!("{}-{}", name, utils::timestamp()));
+
+        let (encoder, paths) = match format {
+            RecordingFormat::Gif => {
+                let path = base_path.with_extension("gif");
+                let colors = palette.colors.map(|color| color.map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8));
+                let mut writer = gif::Encoder::new(create_file(&path)?, width as u16, height as u16, &colors.concat())
+                    .map_err(|e| format!("Unable to write {}: {}", path.display(), e))?;
+                writer.set_repeat(gif::Repeat::Infinite).map_err(|e| format!("Unable to write {}: {}", path.display(), e))?;
+                (Encoder::Gif { writer, pending: None }, vec![path])
+            },
+            RecordingFormat::Raw => {
+                let video_path = base_path.with_extension("y4m");
+                let audio_path = base_path.with_extension("wav");
+                let mut video = create_file(&video_path)?;
+                // 4:4:4 chroma, so pixel colors are kept exactly
+                writeln!(video, "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C444", width, height, FRAME_RATE)
+                    .map_err(|e| format!("Unable to write {}: {}", video_path.display(), e))?;
+                let audio = WavWriter::new(create_file(&audio_path)?)
+                    .map_err(|e| format!("Unable to write {}: {}", audio_path.display(), e))?;
+                (Encoder::Raw { video, audio }, vec![video_path, audio_path])
+            }
+        };
+
+        Ok(Recorder {
+            encoder,
+            palette: palette.clone(),
+            scale,
+            frames: 0,
+            paths
+        })
+    }
+
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    /// Color index (bitplane mask) of each pixel, scaled up.
+    fn scaled_pixels(&self, fb: &[u8]) -> Vec<u8> {
+        let (width, height) = (DISPLAYW * self.scale, DISPLAYH * self.scale);
+        let mut pixels = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            let row = ((y / self.scale) * DISPLAYW) as usize;
+            pixels.extend((0..width).map(|x| renderer::pixel_planes(fb[row + (x / self.scale) as usize])));
+        }
+        pixels
+    }
+
+    /// Records the framebuffer at the end of an emulated frame, and whether
+    /// the beeper was sounding during it.
+    pub fn add_frame(&mut self, fb: &[u8], beeping: bool) -> io::Result<()> {
+        let pixels = self.scaled_pixels(fb);
+        let frame = self.frames;
+        self.frames += 1;
+
+        match &mut self.encoder {
+            Encoder::Gif { writer, pending } => {
+                let start_frame = match pending {
+                    Some(p) if p.pixels == pixels => return Ok(()),
+                    Some(p) => {
+                        let delay = centiseconds(frame) - centiseconds(p.start_frame);
+                        if delay < MIN_GIF_DELAY {
+                            // Replaced before being shown long enough
+                            p.start_frame
+                        } else {
+                            write_gif_frame(writer, self.scale, &p.pixels, delay)?;
+                            frame
+                        }
+                    },
+                    None => frame
+                };
+                *pending = Some(PendingFrame { pixels, start_frame });
+            },
+            Encoder::Raw { video, audio } => {
+                video.write_all(b"FRAME\n")?;
+                let yuv = self.palette.colors.map(rgb_to_yuv);
+                for channel in [0, 1, 2] {
+                    let plane: Vec<u8> = pixels.iter().map(|p| yuv[*p as usize][channel]).collect();
+                    video.write_all(&plane)?;
+                }
+                audio.write_frame(beeping)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the remaining data and closes the files. Returns the paths of
+    /// the files written.
+    pub fn finish(self) -> io::Result<Vec<PathBuf>> {
+        match self.encoder {
+            Encoder::Gif { mut writer, pending } => {
+                if let Some(p) = pending {
+                    let delay = centiseconds(self.frames) - centiseconds(p.start_frame);
+                    write_gif_frame(&mut writer, self.scale, &p.pixels, delay.max(MIN_GIF_DELAY))?;
+                }
+                writer.into_inner()?.flush()?;
+            },
+            Encoder::Raw { mut video, audio } => {
+                video.flush()?;
+                audio.finish()?;
+            }
+        }
+        Ok(self.paths)
+    }
+}
+
+/// Writes a GIF frame, split in several when the delay is too long for a
+/// single one. Pixels are palette indices, and frames are left in place.
+fn write_gif_frame(writer: &mut gif::Encoder<BufWriter<File>>, scale: u32, pixels: &[u8], mut delay: u64) -> io::Result<()> {
+    let (width, height) = ((DISPLAYW * scale) as u16, (DISPLAYH * scale) as u16);
+    let mut frame = gif::Frame { width, height, buffer: Cow::Borrowed(pixels), ..gif::Frame::default() };
+    while delay > u16::MAX as u64 {
+        frame.delay = u16::MAX;
+        writer.write_frame(&frame).map_err(io::Error::other)?;
+        delay -= u16::MAX as u64;
+    }
+    frame.delay = delay as u16;
+    writer.write_frame(&frame).map_err(io::Error::other)
+}
+
+/// Converts a color to BT.601 studio range YCbCr, as expected by Y4M readers.
+fn rgb_to_yuv(color: [f32; 3]) -> [u8; 3] {
+    let [r, g, b] = color.map(|c| c.clamp(0.0, 1.0));
+    let y = 16.0 + 65.481 * r + 128.553 * g + 24.966 * b;
+    let u = 128.0 - 37.797 * r - 74.203 * g + 112.0 * b;
+    let v = 128.0 + 112.0 * r - 93.786 * g - 18.214 * b;
+    [y, u, v].map(|c| c.round() as u8)
+}
+
+/// Writes 16 bit mono PCM audio. The sizes in the header are filled in when
+/// finished.
+struct WavWriter {
+    output: BufWriter<File>,
+    samples: u32,
+    /// Position in the square wave period, in samples.
+    phase: u32
+}
+
+impl WavWriter {
+    fn new(mut output: BufWriter<File>) -> io::Result<WavWriter> {
+        output.write_all(b"RIFF\0\0\0\0WAVEfmt ")?;
+        output.write_all(&16u32.to_le_bytes())?;
+        // PCM, mono
+        output.write_all(&1u16.to_le_bytes())?;
+        output.write_all(&1u16.to_le_bytes())?;
+        output.write_all(&SAMPLE_RATE.to_le_bytes())?;
+        output.write_all(&(SAMPLE_RATE * 2).to_le_bytes())?;
+        // Bytes per sample, bits per sample
+        output.write_all(&2u16.to_le_bytes())?;
+        output.write_all(&16u16.to_le_bytes())?;
+        output.write_all(b"data\0\0\0\0")?;
+        Ok(WavWriter { output, samples: 0, phase: 0 })
+    }
+
+    /// Writes a frame worth of samples: a square wave while beeping, and
+    /// silence otherwise.
+    fn write_frame(&mut self, beeping: bool) -> io::Result<()> {
+        let period = SAMPLE_RATE / BEEP_FREQUENCY;
+        let mut samples = Vec::with_capacity(SAMPLES_PER_FRAME as usize * 2);
+        for _ in 0..SAMPLES_PER_FRAME {
+            let sample = if !beeping { 0 } else if self.phase < period / 2 { BEEP_AMPLITUDE } else { -BEEP_AMPLITUDE };
+            samples.extend(sample.to_le_bytes());
+            self.phase = (self.phase + 1) % period;
+        }
+        self.samples += SAMPLES_PER_FRAME;
+        self.output.write_all(&samples)
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        let data_size = self.samples * 2;
+        self.output.seek(SeekFrom::Start(4))?;
+        self.output.write_all(&(36 + data_size).to_le_bytes())?;
+        self.output.seek(SeekFrom::Start(40))?;
+        self.output.write_all(&data_size.to_le_bytes())?;
+        self.output.flush()
+    }
+}
@@ -1,6 +1,148 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use crate::chip8::{Color, DISPLAYH, DISPLAYW};
+use crate::palette::Palette;
+use crate::phosphor::Ghosting;
+use crate::postfx::Effects;
+use toml::Value;
+
+/// Renderer implementation used to draw the screen, selected at startup.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Backend {
+    /// Shader based renderer, supporting custom shaders and effects.
+    OpenGL,
+    /// CPU renderer. Only palettes, ghosting and scaling are supported.
+    Software
+}
+
+impl Backend {
+    pub fn from_name(name: &str) -> Option<Backend> {
+        match name {
+            "opengl" => Some(Backend::OpenGL),
+            "software" => Some(Backend::Software),
+            _ => None
+        }
+    }
+
+    pub fn from_config(value: &Value) -> Option<Backend> {
+        value.as_str().and_then(Backend::from_name)
+    }
+}
+
+/// RGBA pixels, 8 bits per channel, with rows from top to bottom.
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>
+}
+
+impl Image {
+    /// Sets the pixels of a rectangle to color. The parts outside the image
+    /// are left out.
+    pub fn fill_rect(&mut self, x: u32, y: u32, width: u32, height: u32, color: [u8; 4]) {
+        let (x_end, y_end) = ((x + width).min(self.width), (y + height).min(self.height));
+        for row in y.min(y_end)..y_end {
+            for column in x.min(x_end)..x_end {
+                let i = ((row * self.width + column) * 4) as usize;
+                self.pixels[i..i + 4].copy_from_slice(&color);
+            }
+        }
+    }
+
+    /// Saves the image as a PNG file.
+    pub fn write_png(&self, path: &Path) -> io::Result<()> {
+        self.encode_png(BufWriter::new(File::create(path)?))
+    }
+
+    /// The alpha channel is dropped, since the screen is always opaque.
+    fn encode_png<W: Write>(&self, output: W) -> io::Result<()> {
+        let rgb: Vec<u8> = self.pixels.chunks_exact(4)
+            .take((self.width * self.height) as usize)
+            .flat_map(|pixel| &pixel[..3])
+            .copied()
+            .collect();
+        let mut encoder = png::Encoder::new(output, self.width, self.height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&rgb)?;
+        Ok(writer.finish()?)
+    }
+}
+
 pub trait Renderer {
     fn init(&mut self);
     fn set_clear_color(&mut self, r: f32, g: f32, b: f32, a: f32);
+    fn set_palette(&mut self, palette: &Palette);
+    fn set_viewport(&mut self, width: i32, height: i32);
+    fn set_ghosting(&mut self, ghosting: Ghosting);
+    /// Restricts the screen scale to whole multiples of the Chip-8 resolution.
+    fn set_integer_scaling(&mut self, integer_scaling: bool);
     fn clear(&mut self);
-    fn draw_screen(&mut self, fb: &[u8]);
-}
\ No newline at end of file
+    /// Draws the framebuffer. frame is the number of the emulated frame,
+    /// used to time ghosting effects.
+    fn draw_screen(&mut self, fb: &[u8], frame: u64);
+
+    /// Custom shaders are only supported by shader based renderers, others
+    /// ignore them.
+    fn set_user_shaders(&mut self, _paths: Option<(&str, &str)>) { }
+    /// Post-processing effects are only supported by shader based renderers,
+    /// others ignore them.
+    fn set_effects(&mut self, _effects: &Effects) { }
+    /// Reads back the screen as last drawn, including the effects applied,
+    /// for renderers that support it. Must be called before the frame is
+    /// presented.
+    fn read_pixels(&mut self) -> Option<Image> { None }
+    /// Draws an image over the window, such as the on-screen display, from
+    /// its top left corner, with each pixel covering scale by scale window
+    /// pixels. Pixels are premultiplied by their alpha, and blended over what
+    /// was drawn. changed tells whether the image differs from the last one.
+    fn draw_overlay(&mut self, _overlay: &Image, _scale: u32, _changed: bool) { }
+}
+
+/// Returns the bitplanes set in a framebuffer pixel, as a two bit mask.
+/// Pixels drawn by the single plane instructions are `Color::White`, and map
+/// to the first plane. Any other value is read as a plane mask.
+pub fn pixel_planes(pixel: u8) -> u8 {
+    if pixel == Color::White as u8 { 0b01 } else { pixel & 0b11 }
+}
+
+/// Area of the window the screen is drawn to, as (x, y, width, height). The
+/// screen keeps its aspect ratio and is centered, leaving borders on the sides
+/// that don't fit. With integer scaling, the scale is rounded down to a whole
+/// number, unless the window is smaller than the Chip-8 resolution.
+pub fn screen_rect(window_width: i32, window_height: i32, integer_scaling: bool) -> (i32, i32, i32, i32) {
+    let (screen_width, screen_height) = (DISPLAYW as f32, DISPLAYH as f32);
+    let mut scale = f32::min(window_width as f32 / screen_width, window_height as f32 / screen_height);
+    if integer_scaling && scale >= 1.0 {
+        scale = scale.floor();
+    }
+
+    let width = (screen_width * scale).round() as i32;
+    let height = (screen_height * scale).round() as i32;
+    ((window_width - width) / 2, (window_height - height) / 2, width, height)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_png_images() {
+        let (width, height) = (13, 7);
+        let mut pixels: Vec<u8> = (0..width * height * 4).map(|i| (i * 7 % 251) as u8).collect();
+        // Pixels past the end of the image are left out
+        pixels.extend([255; 8]);
+        let image = Image { width, height, pixels };
+        let mut png = Vec::new();
+        image.encode_png(&mut png).unwrap();
+
+        let mut reader = png::Decoder::new(png.as_slice()).read_info().unwrap();
+        let mut rgb = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut rgb).unwrap();
+        assert_eq!((info.width, info.height, info.color_type), (width, height, png::ColorType::Rgb));
+        let expected: Vec<u8> = image.pixels.chunks_exact(4).take((width * height) as usize).flat_map(|p| p[..3].to_vec()).collect();
+        assert_eq!(&rgb[..info.buffer_size()], &expected[..]);
+    }
+}
@@ -1,5 +1,336 @@
+use std::fs;
+use std::path::Path;
+use crate::archive::{self, Archive};
 use crate::chip8;
-use crate::chip8::Chip8;
+use crate::chip8::{Chip8, FRAME_TIME};
+use crate::octo;
+use toml::{Table, Value};
+use crate::utils;
+use crate::{log_debug, log_info};
+
+/// Largest rom that fits in memory, after the interpreter area.
+pub const MAX_ROM_SIZE: usize = chip8::MEMSIZE - chip8::PROGRAM_START;
+
+/// Machine a rom was written for, as told by its file extension.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Platform {
+    Chip8,
+    SuperChip,
+    XoChip
+}
+
+impl Platform {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Platform::Chip8 => "CHIP-8",
+            Platform::SuperChip => "SUPER-CHIP",
+            Platform::XoChip => "XO-CHIP"
+        }
+    }
+}
+
+/// Extensions of rom files, with the platform of each.
+const ROM_EXTENSIONS: [(&str, Platform); 4] = [
+    ("ch8", Platform::Chip8),
+    ("c8", Platform::Chip8),
+    ("sc8", Platform::SuperChip),
+    ("xo8", Platform::XoChip)
+];
+/// Extensions also used for roms, without telling the platform. Files with
+/// these extensions, or none, are checked not to be text before running them.
+const GENERIC_EXTENSIONS: [&str; 2] = ["rom", "bin"];
+const ARCHIVE_EXTENSION: &str = "zip";
+/// Octo saves its cartridges as GIF images.
+const CARTRIDGE_EXTENSION: &str = "gif";
+const GIF_SIGNATURE: &[u8] = b"GIF8";
+/// Palette made of the colors of a cartridge.
+const CARTRIDGE_PALETTE: &str = "cartridge";
+/// Options of Octo cartridges for each quirk setting.
+const CARTRIDGE_QUIRKS: [(&str, &str); 6] = [
+    ("shiftQuirks", "shift"),
+    ("loadStoreQuirks", "load_store"),
+    ("jumpQuirks", "jump"),
+    ("logicQuirks", "logic"),
+    ("clipQuirks", "clip"),
+    ("vBlankQuirks", "vblank")
+];
+/// Options of Octo cartridges for the colors of the background, of pixels set
+/// in the first bitplane, in the second, and in both.
+const CARTRIDGE_COLORS: [&str; 4] = ["backgroundColor", "fillColor", "fillColor2", "blendColor"];
+
+/// A rom read from disk or from an archive, ready to be loaded.
+pub struct RomFile {
+    /// Path of the rom. Files inside archives are written as if the archive
+    /// was a directory, e.g. `roms.zip/tetris.ch8`.
+    pub path: String,
+    pub data: Vec<u8>,
+    pub platform: Platform,
+    /// Settings that come with the rom, e.g. the options of Octo cartridges.
+    /// The rom sections of the config file override them.
+    pub settings: Table
+}
+
+/// What a path given to the emulator holds.
+pub enum Opened {
+    Rom(RomFile),
+    /// A directory or an archive with several roms, to choose from.
+    Choice { location: String, paths: Vec<String> }
+}
+
+fn extension(path: &str) -> Option<String> {
+    Path::new(path).extension().map(|e| e.to_string_lossy().to_ascii_lowercase())
+}
+
+/// Whether a file name has a rom extension. Hidden files and the resource
+/// forks added to archives made on macOS are left out.
+pub fn is_rom_name(name: &str) -> bool {
+    let hidden = Path::new(name).components()
+        .any(|c| c.as_os_str().to_string_lossy().starts_with('.') || c.as_os_str() == "__MACOSX");
+    let rom_extension = extension(name).is_some_and(|ext| {
+        ROM_EXTENSIONS.iter().any(|(e, _)| *e == ext) || GENERIC_EXTENSIONS.contains(&ext.as_str()) || ext == CARTRIDGE_EXTENSION
+    });
+    !hidden && rom_extension
+}
+
+pub fn is_archive_name(name: &str) -> bool {
+    extension(name).is_some_and(|ext| ext == ARCHIVE_EXTENSION)
+}
+
+/// Title shown for a rom: its file name, without the extension, and with
+/// underscores and dashes turned into spaces.
+pub fn title(path: &str) -> String {
+    let stem = Path::new(path).file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| String::from(path));
+    let title = stem.replace(['_', '-'], " ");
+    let words: Vec<&str> = title.split_whitespace().collect();
+    if words.is_empty() { stem } else { words.join(" ") }
+}
+
+/// Splits a path to a file inside an archive, e.g. `roms.zip/tetris.ch8`,
+/// into the archive path and the name of the file in it.
+fn split_archive_path(path: &str) -> Option<(String, String)> {
+    let full_path = Path::new(path);
+    let archive = full_path.ancestors().skip(1).find(|a| a.is_file())?;
+    let name: Vec<String> = full_path.strip_prefix(archive).ok()?
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    Some((archive.to_string_lossy().into_owned(), name.join("/")))
+}
+
+/// Whether a rom path, possibly inside an archive, can still be opened.
+pub fn exists(path: &str) -> bool {
+    Path::new(path).is_file() || split_archive_path(path).is_some()
+}
+
+/// Absolute form of a rom path, also for files inside archives, so it can
+/// be opened from anywhere.
+pub fn absolute_path(path: &str) -> String {
+    if let Ok(absolute) = fs::canonicalize(path) {
+        return absolute.to_string_lossy().into_owned();
+    }
+    match split_archive_path(path) {
+        Some((archive, name)) => match fs::canonicalize(archive) {
+            Ok(archive) => format!("{}/{}", archive.to_string_lossy(), name),
+            Err(_) => String::from(path)
+        },
+        None => String::from(path)
+    }
+}
+
+/// Opens a rom file, a file inside a zip archive, an archive, or a
+/// directory. Archives with a single rom open it directly.
+pub fn open(path: &str) -> Result<Opened, String> {
+    let file_path = Path::new(path);
+    if file_path.is_dir() {
+        let paths = list_directory(path)?;
+        if paths.is_empty() {
+            return Err(format!("No roms found in {}", path));
+        }
+        return Ok(Opened::Choice { location: String::from(path), paths });
+    }
+
+    if !file_path.exists() {
+        if let Some((archive, name)) = split_archive_path(path) {
+            let data = Archive::open(&archive)?.read(&name, MAX_ROM_SIZE)?;
+            return check(path, data).map(Opened::Rom);
+        }
+    }
+
+    let data = utils::read_file_to_u8(path).map_err(|e| format!("Unable to read rom file {}: {}", path, e))?;
+    if data.starts_with(&archive::SIGNATURE) {
+        return open_archive(path, data);
+    }
+    check(path, data).map(Opened::Rom)
+}
+
+fn open_archive(path: &str, data: Vec<u8>) -> Result<Opened, String> {
+    let mut archive = Archive::from_bytes(data).map_err(|e| format!("Unable to read archive {}: {}", path, e))?;
+    let names: Vec<String> = archive.file_names().into_iter().filter(|name| is_rom_name(name)).collect();
+    match names.as_slice() {
+        [] => Err(format!("No roms found in {}", path)),
+        [name] => {
+            let rom_path = format!("{}/{}", path, name);
+            check(&rom_path, archive.read(name, MAX_ROM_SIZE)?).map(Opened::Rom)
+        },
+        _ => Ok(Opened::Choice {
+            location: String::from(path),
+            paths: names.iter().map(|name| format!("{}/{}", path, name)).collect()
+        })
+    }
+}
+
+/// Roms and archives in a directory, sorted by title.
+pub fn list_directory(dir: &str) -> Result<Vec<String>, String> {
+    let entries = fs::read_dir(dir).map_err(|e| format!("Unable to read {}: {}", dir, e))?;
+    let mut paths = Vec::new();
+    for entry in entries {
+        let path = entry.map_err(|e| format!("Unable to read {}: {}", dir, e))?.path();
+        let Some(name) = path.file_name().map(|n| n.to_string_lossy().into_owned()) else { continue; };
+        let listed = (is_rom_name(&name) || (is_archive_name(&name) && !name.starts_with('.'))) && path.is_file();
+        if listed {
+            paths.push(path.to_string_lossy().into_owned());
+        }
+    }
+    paths.sort_by_key(|path| title(path).to_ascii_lowercase());
+    Ok(paths)
+}
+
+/// Makes sure the data is a rom before running it, and tells its platform
+/// from the extension.
+fn check(path: &str, data: Vec<u8>) -> Result<RomFile, String> {
+    let name = Path::new(path).file_name().map_or_else(|| String::from(path), |n| n.to_string_lossy().into_owned());
+    let extension = extension(path);
+    let known_platform = extension.as_ref().and_then(|ext| ROM_EXTENSIONS.iter().find(|(e, _)| e == ext)).map(|(_, p)| *p);
+
+    if data.starts_with(GIF_SIGNATURE) {
+        return read_cartridge(path, &name, &data);
+    }
+    if extension.as_deref() == Some(CARTRIDGE_EXTENSION) {
+        return Err(format!("{} is not an Octo cartridge: it is not a GIF image.", name));
+    }
+
+    let platform = match (known_platform, &extension) {
+        (Some(platform), _) => platform,
+        (None, Some(ext)) if !GENERIC_EXTENSIONS.contains(&ext.as_str()) => {
+            return Err(format!("{} is not a rom. Roms are .ch8, .c8, .sc8 or .xo8 files, or .zip archives of them.", name));
+        },
+        _ => {
+            // Programs are binary, while text files are only printable characters
+            let text = data.iter().all(|b| b.is_ascii_graphic() || b.is_ascii_whitespace());
+            if text && !data.is_empty() {
+                return Err(format!("{} is not a rom: it contains text.", name));
+            }
+            Platform::Chip8
+        }
+    };
+
+    check_size(&name, &data)?;
+    Ok(RomFile { path: String::from(path), data, platform, settings: Table::new() })
+}
+
+fn check_size(name: &str, data: &[u8]) -> Result<(), String> {
+    if data.is_empty() {
+        return Err(format!("{} is not a rom: it is empty.", name));
+    }
+    if data.len() > MAX_ROM_SIZE {
+        return Err(format!("{} is not a rom: it is too large ({} bytes, at most {} fit in memory).", name, data.len(), MAX_ROM_SIZE));
+    }
+    Ok(())
+}
+
+/// Reads an Octo cartridge: a GIF image whose color indices hold, in their
+/// low 4 bits, the length of a JSON document in 4 bytes (big endian) then the
+/// document, high half of each byte first. The document has the source of the
+/// program, which is assembled, and the options it runs with, which become
+/// the settings of the rom.
+fn read_cartridge(path: &str, name: &str, data: &[u8]) -> Result<RomFile, String> {
+    let nibbles = cartridge_nibbles(data).map_err(|e| format!("Unable to read cartridge {}: {}", name, e))?;
+    let bytes: Vec<u8> = nibbles.chunks_exact(2).map(|pair| pair[0] << 4 | pair[1]).collect();
+
+    let not_cartridge = |reason: &str| format!("{} is not an Octo cartridge: {}.", name, reason);
+    let length = bytes.get(..4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize)
+        .ok_or_else(|| not_cartridge("the image is too small"))?;
+    let document = bytes.get(4..).and_then(|b| b.get(..length))
+        .ok_or_else(|| not_cartridge("the image is smaller than the data it holds"))?;
+    let document = String::from_utf8(document.to_vec()).map_err(|_| not_cartridge("its data is not text"))?;
+    let document: serde_json::Value = serde_json::from_str(&document).map_err(|e| not_cartridge(&e.to_string()))?;
+    let source = document["program"].as_str().ok_or_else(|| not_cartridge("it holds no program"))?;
+
+    let program = octo::assemble(source).map_err(|e| format!("Unable to assemble the program of {}: {}", name, e))?;
+    check_size(name, &program)?;
+    let options = &document["options"];
+    log_info!(Rom, "Cartridge {}: {} bytes of source, assembled to {} bytes", name, source.len(), program.len());
+
+    // Octo tells the platform by the memory the program may use
+    let platform = match options["maxSize"].as_i64() {
+        Some(size) if size > 3583 => Platform::XoChip,
+        Some(size) if size > 3232 => Platform::SuperChip,
+        _ => Platform::Chip8
+    };
+    Ok(RomFile { path: String::from(path), data: program, platform, settings: cartridge_settings(options) })
+}
+
+/// Low 4 bits of the color indices of every frame of a cartridge.
+fn cartridge_nibbles(data: &[u8]) -> Result<Vec<u8>, gif::DecodingError> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = options.read_info(data)?;
+    let mut nibbles = Vec::new();
+    while let Some(frame) = decoder.read_next_frame()? {
+        nibbles.extend(frame.buffer.iter().map(|p| p & 0x0F));
+    }
+    Ok(nibbles)
+}
+
+/// Settings from the options of a cartridge. Options the emulator doesn't
+/// have, like the screen rotation, are left out.
+fn cartridge_settings(options: &serde_json::Value) -> Table {
+    let mut settings = Table::new();
+    // Octo runs a number of instructions per frame
+    if let Some(tickrate) = options["tickrate"].as_i64().filter(|t| *t > 0) {
+        let instruction_time_ns = (FRAME_TIME.as_nanos() as i64 / tickrate).max(1);
+        settings.insert(String::from("instruction_time_ns"), Value::Integer(instruction_time_ns));
+    }
+
+    let quirks: Table = CARTRIDGE_QUIRKS.iter()
+        .filter_map(|(option, quirk)| options[*option].as_bool().map(|v| (String::from(*quirk), Value::Boolean(v))))
+        .collect();
+    if !quirks.is_empty() {
+        settings.insert(String::from("quirks"), Value::Table(quirks));
+    }
+
+    let colors: Option<Vec<Value>> = CARTRIDGE_COLORS.iter()
+        .map(|option| options[*option].as_str().map(|c| Value::String(String::from(c))))
+        .collect();
+    if let Some(colors) = colors {
+        let palette = Table::from_iter([(String::from("colors"), Value::Array(colors))]);
+        let palettes = Table::from_iter([(String::from(CARTRIDGE_PALETTE), Value::Table(palette))]);
+        settings.insert(String::from("palettes"), Value::Table(palettes));
+        settings.insert(String::from("palette"), Value::String(String::from(CARTRIDGE_PALETTE)));
+    }
+    if let Some(color) = options["quietColor"].as_str() {
+        settings.insert(String::from("border_color"), Value::String(String::from(color)));
+    }
+
+    // Host keys of each Chip-8 key, named like in the browser ("x", "ArrowUp", " ")
+    let keys: Table = (0..16).filter_map(|key| {
+        let names = &options["keys"][format!("{:X}", key).as_str()];
+        let name = names.as_str().or_else(|| names.as_array()?.iter().find_map(|n| n.as_str()))?;
+        let name = match name {
+            " " => "Space",
+            name => name.strip_prefix("Arrow").unwrap_or(name)
+        };
+        Some((format!("{:X}", key), Value::String(String::from(name))))
+    }).collect();
+    if !keys.is_empty() {
+        settings.insert(String::from("keys"), Value::Table(keys));
+    }
+
+    log_debug!(Rom, "Cartridge settings: {}", settings.keys().cloned().collect::<Vec<_>>().join(", "));
+    settings
+}
 
 impl Chip8 {
     /// Loads the respective bytes into the program area
@@ -7,7 +338,7 @@ impl Chip8 {
     /// 
     /// It will panic if rom_bytes.len() > 0xDFF
     pub fn load_rom_to_memory(&mut self, rom_bytes: &Vec<u8>) {
-        println!("Rom size: {} bytes", rom_bytes.len());
+        log_info!(Rom, "Rom size: {} bytes", rom_bytes.len());
         // Assure rom size can fit in memory
         if rom_bytes.len() > chip8::MEMSIZE - chip8::PROGRAM_START {
             panic!("Rom is too large.");
@@ -17,4 +348,153 @@ impl Chip8 {
         self.main_memory[chip8::PROGRAM_START..(rom_bytes.len() + chip8::PROGRAM_START)]
             .clone_from_slice(rom_bytes);
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    /// A cartridge holding a document, after its length.
+    fn cartridge(document: &str) -> Vec<u8> {
+        let mut bytes = (document.len() as u32).to_be_bytes().to_vec();
+        bytes.extend(document.as_bytes());
+        cartridge_image(&bytes)
+    }
+
+    /// An image laid out like the cartridges Octo saves: the label is drawn
+    /// with the high 4 bits of the color indices, each 16 indices having the
+    /// same color, and the bytes are held in the low 4 bits.
+    fn cartridge_image(bytes: &[u8]) -> Vec<u8> {
+        let nibbles: Vec<u8> = bytes.iter().flat_map(|b| [b >> 4, b & 0x0F]).collect();
+
+        let width = 128;
+        let height = nibbles.len().div_ceil(width).max(64);
+        let label = [[0xF8, 0xF8, 0xF0], [0x26, 0x26, 0x26], [0xFF, 0xCC, 0x00], [0x99, 0x66, 0x00]];
+        let colors: Vec<[u8; 3]> = (0..256).map(|i| label[i >> 4 & 3]).collect();
+        let pixels: Vec<u8> = (0..width * height)
+            .map(|i| ((i / width / 8 + i % width / 8) % 4) as u8)
+            .zip(nibbles.iter().copied().chain(std::iter::repeat(0)))
+            .map(|(color, nibble)| color << 4 | nibble)
+            .collect();
+
+        let mut encoder = gif::Encoder::new(Vec::new(), width as u16, height as u16, &colors.concat()).unwrap();
+        encoder.write_frame(&gif::Frame::from_indexed_pixels(width as u16, height as u16, pixels, None)).unwrap();
+        encoder.into_inner().unwrap()
+    }
+
+    const DOCUMENT: &str = r##"{
+        "program": "# Moves a dot\n:alias x v1\n: main\n  i := dot\n  loop\n    sprite x x 1\n    x += 1\n  again\n: dot 0x80\n",
+        "options": {
+            "tickrate": 100,
+            "fillColor": "#FFCC00",
+            "fillColor2": "#FF6600",
+            "blendColor": "#662200",
+            "backgroundColor": "#996600",
+            "buzzColor": "#FFAA00",
+            "quietColor": "#000000",
+            "shiftQuirks": false,
+            "loadStoreQuirks": true,
+            "vfOrderQuirks": false,
+            "clipQuirks": true,
+            "jumpQuirks": false,
+            "logicQuirks": false,
+            "vBlankQuirks": false,
+            "screenRotation": 0,
+            "maxSize": 3216,
+            "touchInputMode": "none",
+            "fontStyle": "octo",
+            "keys": { "5": ["ArrowUp", "w"], "6": " " }
+        }
+    }"##;
+
+    #[test]
+    fn loads_cartridges() {
+        let rom = check("games/dot.gif", cartridge(DOCUMENT)).unwrap();
+        assert_eq!(rom.path, "games/dot.gif");
+        assert!(rom.platform == Platform::Chip8);
+        assert_eq!(rom.data, vec![0xA2, 0x08, 0xD1, 0x11, 0x71, 0x01, 0x12, 0x02, 0x80]);
+
+        let settings = &rom.settings;
+        assert_eq!(settings["instruction_time_ns"].as_integer(), Some(166666));
+        let quirks = settings["quirks"].as_table().unwrap();
+        let quirks: Vec<(&str, bool)> = quirks.iter().map(|(k, v)| (k.as_str(), v.as_bool().unwrap())).collect();
+        assert_eq!(quirks, vec![("clip", true), ("jump", false), ("load_store", true), ("logic", false), ("shift", false), ("vblank", false)]);
+        let palette = settings["palettes"].as_table().unwrap()[CARTRIDGE_PALETTE].as_table().unwrap();
+        let colors: Vec<&str> = palette["colors"].as_array().unwrap().iter().map(|c| c.as_str().unwrap()).collect();
+        assert_eq!(colors, vec!["#996600", "#FFCC00", "#FF6600", "#662200"]);
+        assert_eq!(settings["palette"].as_str(), Some(CARTRIDGE_PALETTE));
+        assert_eq!(settings["border_color"].as_str(), Some("#000000"));
+        let keys = settings["keys"].as_table().unwrap();
+        assert_eq!((keys["5"].as_str(), keys["6"].as_str()), (Some("Up"), Some("Space")));
+        assert_eq!(settings.len(), 6);
+
+        // Options are optional, and the memory size tells the platform
+        let rom = check("xo.gif", cartridge(r#"{"program": ": main clear", "options": {"maxSize": 65024}}"#)).unwrap();
+        assert!(rom.platform == Platform::XoChip);
+        assert!(rom.settings.is_empty());
+    }
+
+    #[test]
+    fn rejects_broken_cartridges() {
+        let error = |path: &str, data: Vec<u8>| check(path, data).err().unwrap();
+        assert!(error("a.gif", b"not an image".to_vec()).contains("not a GIF image"));
+        assert!(error("a.gif", b"GIF89a".to_vec()).starts_with("Unable to read cartridge a.gif"));
+        assert!(error("a.gif", cartridge("{\"options\": {}}")).contains("holds no program"));
+        assert!(error("a.gif", cartridge("{\"program\": ")).contains("not an Octo cartridge"));
+        assert!(error("a.gif", cartridge(r#"{"program": ": main jump nowhere"}"#)).contains("unknown name 'nowhere'"));
+        assert!(error("a.gif", cartridge(r#"{"program": ": main"}"#)).contains("it is empty"));
+
+        // A length larger than the image
+        let mut bytes = 2u32.to_be_bytes().to_vec();
+        bytes[0] |= 0xF0;
+        bytes.extend(b"{}");
+        assert!(error("a.gif", cartridge_image(&bytes)).contains("smaller than the data it holds"));
+    }
+
+    /// Inserts, removes or changes a few random bytes.
+    fn mutate(rng: &mut StdRng, data: &[u8]) -> Vec<u8> {
+        let mut data = data.to_vec();
+        for _ in 0..rng.gen_range(1..4) {
+            let index = rng.gen_range(0..data.len());
+            match rng.gen_range(0..3) {
+                0 => { data.remove(index); },
+                1 => data.insert(index, rng.gen()),
+                _ => data[index] = rng.gen()
+            }
+        }
+        data
+    }
+
+    /// Cartridges come from anywhere, so ones with random changes are
+    /// opened, and must be rejected with an error rather than a panic.
+    #[test]
+    fn opens_mutated_cartridges_without_panicking() {
+        let image = cartridge(DOCUMENT);
+        let mut rng = StdRng::seed_from_u64(0x8);
+        for _ in 0..300 {
+            let document = mutate(&mut rng, DOCUMENT.as_bytes());
+            let _ = check("dot.gif", cartridge(&String::from_utf8_lossy(&document)));
+            let _ = check("dot.gif", mutate(&mut rng, &image));
+        }
+    }
+
+    /// Archives come from anywhere too, and hold files that are opened
+    /// once extracted.
+    #[test]
+    fn opens_mutated_archives_without_panicking() {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        writer.start_file("dot.gif", SimpleFileOptions::default()).unwrap();
+        writer.write_all(&cartridge(DOCUMENT)).unwrap();
+        let archive = writer.finish().unwrap().into_inner();
+        assert!(matches!(open_archive("roms.zip", archive.clone()), Ok(Opened::Rom(_))));
+
+        let mut rng = StdRng::seed_from_u64(0x8);
+        for _ in 0..300 {
+            let _ = open_archive("roms.zip", mutate(&mut rng, &archive));
+        }
+    }
+}
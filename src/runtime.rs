@@ -1,74 +1,695 @@
-use crate::{chip8::Chip8, utils};
+use std::path::Path;
+use std::time::Duration;
+use crate::chip8::{Chip8, Register};
+use crate::trace::Tracer;
+use crate::profile::Profiler;
+use crate::symbols::Symbols;
+use crate::browser::RomBrowser;
+use crate::chip8::{FRAME_TIME, PROGRAM_START};
+use crate::debug::StopReason;
+use crate::cli::Options;
+use crate::config::{Config, RomId, Settings};
+use crate::frontend::{Frontend, Speed};
+use crate::dap::DapServer;
+use crate::gdb::GdbServer;
+use crate::history::History;
+use crate::panels::{self, DebugView};
+use crate::renderer::{Image, Renderer};
+use crate::rom::{self, Opened, Platform, RomFile};
+use crate::recorder::{Recorder, RecordingFormat};
+use crate::screenshot;
+use crate::software::SoftwareRenderer;
+use crate::sprites::{self, SpriteViewer};
+use toml::Value;
+use crate::tui::TuiFrontend;
 use crate::window::Window;
-use crate::input::InputBackend;
+use crate::input::{Command, KeyboardState};
+use crate::{log_error, log_info, log_warn};
 
 pub struct Runtime {
     chip8: Chip8,
+    options: Options,
+    config: Config,
+    settings: Settings,
     current_rom_path: Option<String>,
+    current_rom_id: Option<RomId>,
     rom_loaded: bool,
-    paused: bool
+    paused: bool,
+    fast_forward: bool,
+    config_changed: bool,
+    /// Set by the screenshot hotkey. The screenshot is taken once the frame
+    /// is drawn.
+    screenshot_requested: bool,
+    recorder: Option<Recorder>,
+    /// The rom browser, while it is open. Emulation stops meanwhile.
+    browser: Option<RomBrowser>,
+    gdb: Option<GdbServer>,
+    dap: Option<DapServer>,
+    /// Start of the memory shown by the debugger panels, while they are
+    /// shown.
+    memory_view: Option<u16>,
+    /// The sprite viewer, while it is shown in place of the debugger panels.
+    sprite_viewer: Option<SpriteViewer>
 }
 
 impl Runtime {
-    pub fn new(rom_path: Option<String>) -> Runtime {
-        Runtime {
+    pub fn new(options: Options) -> Runtime {
+        let config = Config::load();
+        let rom_path = options.rom_path.clone();
+
+        let mut runtime = Runtime {
             chip8: Chip8::new(),
+            options,
+            config,
+            settings: Settings::new(),
             current_rom_path: rom_path,
+            current_rom_id: None,
             rom_loaded: false,
-            paused: false
+            paused: false,
+            fast_forward: false,
+            config_changed: false,
+            screenshot_requested: false,
+            recorder: None,
+            browser: None,
+            gdb: None,
+            dap: None,
+            memory_view: None,
+            sprite_viewer: None
+        };
+        runtime.settings = runtime.resolve_settings();
+        runtime
+    }
+
+    /// Settings for the current rom, with the command line options applied.
+    fn resolve_settings(&self) -> Settings {
+        let mut settings = self.config.settings(self.current_rom_id.as_ref());
+        if let Some(renderer) = self.options.renderer {
+            settings.renderer = renderer;
         }
+        settings
     }
 
     pub fn run(&mut self) {
         self.chip8.setup();
+        self.chip8.debug.watchpoints = self.options.watchpoints.clone();
+        self.chip8.history = History::new(self.options.history);
+        if let Some(trace) = self.options.trace.clone() {
+            match Tracer::create(trace) {
+                Ok(tracer) => {
+                    log_info!(Cpu, "Tracing instructions to {}", tracer.path());
+                    self.chip8.tracer = Some(tracer);
+                },
+                Err(e) => log_error!(Cpu, "{}", e)
+            }
+        }
 
-        if let Some(file_path) = &self.current_rom_path {
-            let file_path_copy = file_path.clone();
-            self.load_from_from_file(&file_path_copy);
+        if self.current_rom_path.is_none() && self.settings.resume_last_rom {
+            self.current_rom_path = self.config.recent_roms().into_iter().find(|p| rom::exists(p));
+        }
+        if self.current_rom_path.is_none() && !self.options.headless && !self.options.tui {
+            log_info!(Rom, "No rom file provided. Pick one in the rom browser (F1), or drag roms to the window to load them.");
+        }
+
+        if let Some(path) = self.current_rom_path.clone() {
+            match rom::open(&path) {
+                Ok(Opened::Rom(rom)) => {
+                    if let Some(warning) = self.load_rom(rom) {
+                        log_warn!(Rom, "{}", warning);
+                    }
+                },
+                Ok(Opened::Choice { location, paths }) => {
+                    log_info!(Rom, "{} holds several roms: {}", location, paths.iter().map(|p| rom::title(p)).collect::<Vec<_>>().join(", "));
+                    self.browser = Some(RomBrowser::with_choice(&location, &paths));
+                },
+                Err(e) => log_error!(Rom, "{}", e)
+            }
         }
 
-        self.run_main_loop();
+        if self.options.headless {
+            self.run_headless();
+        } else if self.options.tui {
+            self.run_tui();
+        } else {
+            self.run_main_loop();
+        }
+        self.save_profile();
+    }
+
+    /// Writes the profile of the rom running, when profiling.
+    fn save_profile(&mut self) {
+        let (Some(path), Some(profiler)) = (&self.options.profile, self.chip8.profiler.take()) else { return; };
+        match profiler.save(path, &self.chip8) {
+            Ok(message) => println!("{}", message),
+            Err(e) => log_error!(Cpu, "{}", e)
+        }
     }
 
-    pub fn load_from_from_file(&mut self, file_path: &str) -> bool {
-        let data = utils::read_file_to_u8(&file_path);
-        match data {
-            Ok(data) => {
-                self.chip8.load_rom_to_memory(&data);
-                self.rom_loaded = true;
-                true
+    /// Loads the symbols of the rom loaded: the ones given in the command
+    /// line for the first rom, then the ones set in the config file.
+    fn load_symbols(&mut self, rom_size: usize) {
+        let Some(path) = self.options.symbols.take().or_else(|| self.settings.symbols.clone()) else { return; };
+        match Symbols::load(&path) {
+            Ok(mut symbols) => {
+                log_info!(Rom, "Symbols: {} labels loaded from {}", symbols.len(), path);
+                symbols.end = Some((PROGRAM_START + rom_size) as u16);
+                self.chip8.symbols = symbols;
             },
+            Err(e) => log_error!(Rom, "{}", e)
+        }
+    }
+
+    /// Loads a rom into the memory of the Chip-8, which callers reset first.
+    /// Returns a warning when the rom was written for another platform.
+    fn load_rom(&mut self, rom: RomFile) -> Option<String> {
+        self.chip8.load_rom_to_memory(&rom.data);
+        if self.options.profile.is_some() {
+            self.chip8.profiler = Some(Profiler::new(rom.data.len()));
+        }
+        self.rom_loaded = true;
+        let rom_id = RomId::of(&rom);
+        log_info!(Rom, "Rom hash: {}", rom_id.hash);
+        self.current_rom_id = Some(rom_id);
+        self.config.add_recent_rom(&rom::absolute_path(&rom.path));
+        self.config_changed = true;
+        self.settings = self.resolve_settings();
+        self.chip8.instruction_time_ns = self.settings.instruction_time_ns;
+        self.chip8.quirks = self.settings.quirks;
+        self.load_symbols(rom.data.len());
+        self.current_rom_path = Some(rom.path);
+
+        match rom.platform {
+            Platform::Chip8 => None,
+            platform => Some(format!("{} is a {} rom. Only CHIP-8 instructions are supported, so it may not run correctly.",
+                self.file_name(), platform.name()))
+        }
+    }
+
+    pub fn run_main_loop(&mut self) {
+        let mut w = match Window::new(&self.settings) {
+            Ok(w) => w,
             Err(e) => {
-                println!("Error: Unable to road rom file: {}. {}", file_path, e);
-                self.rom_loaded = false;
-                false
+                log_error!(Render, "{}", e);
+                return;
             }
+        };
+        w.init(&self.settings);
+        self.run_frontend(&mut w);
+
+        self.save_window_size(w.get_size());
+        self.save_config();
+    }
+
+    /// Runs in the terminal, drawing the screen with text characters.
+    pub fn run_tui(&mut self) {
+        if !self.rom_loaded {
+            log_error!(Rom, "A rom is needed to run in the terminal.");
+            return;
         }
+
+        let mut tui = match TuiFrontend::new(&self.settings) {
+            Ok(tui) => tui,
+            Err(e) => {
+                log_error!(Render, "{}", e);
+                return;
+            }
+        };
+        self.run_frontend(&mut tui);
+
+        // Restores the terminal before anything else is printed
+        drop(tui);
+        self.save_config();
     }
 
-    pub fn run_main_loop(&mut self) {
-        let mut w = Window::new(1200, 600);
-        w.init();
+    fn run_frontend(&mut self, frontend: &mut dyn Frontend) {
+        if let Some(format) = self.options.record {
+            let result = self.start_recording(format);
+            self.show_result(frontend, result);
+        }
+        if let Some(port) = self.options.gdb_port {
+            let result = GdbServer::bind(port)
+                .map(|gdb| { self.gdb = Some(gdb); format!("Waiting for a debugger on port {}", port) });
+            self.show_result(frontend, result);
+        }
+        if let Some(port) = self.options.dap_port {
+            let result = DapServer::bind(port)
+                .map(|dap| { self.dap = Some(dap); format!("Waiting for an editor on port {}", port) });
+            self.show_result(frontend, result);
+        }
+        frontend.set_status(&self.status());
+        frontend.set_speed(self.speed());
+        if let Some(browser) = &self.browser {
+            frontend.show_menu(Some(browser.menu()));
+        } else if !self.rom_loaded {
+            self.open_browser(frontend);
+        }
 
         loop {
-            w.process_input(&mut self.chip8.keyboard_state);
-            if w.should_close() { break };
-            if w.has_drag_and_drop() {
-                // Restart chip8 internal state
+            frontend.process_input(&mut self.chip8.keyboard_state);
+            if frontend.should_close() { break };
+            if let Some(file_path) = frontend.take_dropped_file() {
+                self.open_rom(frontend, &file_path);
+            }
+
+            for command in frontend.take_commands() {
+                self.handle_command(frontend, command);
+            }
+
+            let stopped = self.chip8.is_stopped();
+            if let Some(message) = self.gdb.as_mut().and_then(|gdb| gdb.poll(&mut self.chip8)) {
+                frontend.show_message(&message);
+            }
+            if let Some(message) = self.dap.as_mut().and_then(|dap| dap.poll(&mut self.chip8)) {
+                frontend.show_message(&message);
+            }
+            if let Some(path) = self.dap.as_mut().and_then(|dap| dap.take_launch()) {
+                if self.browser.is_some() {
+                    self.close_browser(frontend);
+                }
+                self.open_rom(frontend, &path);
+            }
+
+            // Time passed while paused is skipped
+            let elapsed = self.chip8.take_elapsed();
+            if self.rom_loaded && !self.paused && self.browser.is_none() {
+                let speed = if self.fast_forward { self.settings.fast_forward_speed } else { 1 };
+                if let Err(e) = self.emulate(elapsed * speed) {
+                    frontend.show_message(&format!("Error: {}", e));
+                    frontend.set_status(&self.status());
+                }
+            }
+            // Stopped by the debugger or a watchpoint, or resumed
+            if self.chip8.is_stopped() != stopped {
+                frontend.set_speed(self.speed());
+                if let Some(message) = self.chip8.watchpoint_hit() {
+                    frontend.show_message(&message);
+                }
+                if self.chip8.debug.stop_reason == Some(StopReason::Breakpoint) {
+                    frontend.show_message(&format!("Breakpoint hit at {}", self.chip8.symbols.format(self.chip8.get_register(Register::PC))));
+                }
+            }
+            if let Some(start) = self.memory_view {
+                frontend.show_debugger(Some(&DebugView::capture(&self.chip8, start)));
+            }
+            if let Some(viewer) = &self.sprite_viewer {
+                frontend.show_sprites(Some(&viewer.capture(&self.chip8)));
+            }
+            frontend.set_instruction_count(self.chip8.instruction_count);
+            frontend.draw(&self.chip8.framebuffer, self.chip8.frame_count);
+            if self.screenshot_requested {
+                self.screenshot_requested = false;
+                let displayed = if self.settings.screenshot_display { frontend.capture_screen() } else { None };
+                let result = self.save_screenshot(displayed.as_ref());
+                self.show_result(frontend, result);
+            }
+            frontend.update();
+        }
+
+        if let Some(result) = self.stop_recording() {
+            self.show_result(frontend, result);
+        }
+    }
+
+    /// Restarts the Chip-8 with a new rom, if it can be loaded. Archives and
+    /// directories holding several roms open the browser to pick one.
+    fn open_rom(&mut self, frontend: &mut dyn Frontend, path: &str) {
+        match rom::open(path) {
+            Ok(Opened::Rom(rom)) => {
+                // The profile of the previous rom is written before it is replaced
+                self.save_profile();
+                // Restart chip8 internal state, keeping the debugger's breakpoints, the trace
+                // and the size of the history
+                let debug = std::mem::take(&mut self.chip8.debug);
+                let tracer = self.chip8.tracer.take();
+                let history = self.chip8.history.capacity();
                 self.chip8 = Chip8::new();
+                self.chip8.debug = debug;
+                self.chip8.tracer = tracer;
+                self.chip8.history = History::new(history);
                 self.chip8.setup();
-                // Load new rom, if possible
-                self.rom_loaded = false;
-                self.current_rom_path = Some(w.get_drag_and_drop());
-                self.load_from_from_file(&w.get_drag_and_drop());
-                w.clear_drag_and_drop();
+                match self.load_rom(rom) {
+                    Some(warning) => frontend.show_message(&format!("Loaded {}. {}", self.file_name(), warning)),
+                    None => frontend.show_message(&format!("Loaded {}", self.file_name()))
+                }
+                frontend.apply_settings(&self.settings);
+                frontend.set_status(&self.status());
+                frontend.set_speed(self.speed());
+            },
+            Ok(Opened::Choice { location, paths }) => {
+                let browser = RomBrowser::with_choice(&location, &paths);
+                frontend.show_menu(Some(browser.menu()));
+                self.browser = Some(browser);
+                self.chip8.keyboard_state = KeyboardState::new();
+            },
+            // The running rom, if any, keeps running
+            Err(e) => frontend.show_message(&format!("Error: {}", e))
+        }
+    }
+
+    fn open_browser(&mut self, frontend: &mut dyn Frontend) {
+        let browser = RomBrowser::new(&self.config.recent_roms(), self.settings.rom_dir.as_deref());
+        frontend.show_menu(Some(browser.menu()));
+        self.browser = Some(browser);
+        // Keys held now would stay pressed, their release going to the menu
+        self.chip8.keyboard_state = KeyboardState::new();
+    }
+
+    fn close_browser(&mut self, frontend: &mut dyn Frontend) {
+        self.browser = None;
+        frontend.show_menu(None);
+    }
+
+    fn show_result(&self, frontend: &mut dyn Frontend, result: Result<String, String>) {
+        match result {
+            Ok(message) => frontend.show_message(&message),
+            Err(e) => frontend.show_message(&format!("Error: {}", e))
+        }
+    }
+
+    /// File name of the rom running, or a placeholder when there is none.
+    fn file_name(&self) -> &str {
+        match (&self.current_rom_id, self.rom_loaded) {
+            (Some(rom_id), true) => rom_id.file_name.as_str(),
+            _ => "No rom loaded"
+        }
+    }
+
+    /// Short description of what is running, shown by the frontends.
+    fn status(&self) -> String {
+        let recording = if self.recorder.is_some() { " | recording" } else { "" };
+        format!("{} | palette: {}{}", self.file_name(), self.settings.palette, recording)
+    }
+
+    fn speed(&self) -> Speed {
+        if self.paused || self.chip8.is_stopped() {
+            Speed::Paused
+        } else if self.fast_forward {
+            Speed::FastForward(self.settings.fast_forward_speed)
+        } else {
+            Speed::Normal
+        }
+    }
+
+    /// Runs the rom for the number of frames given in the command line,
+    /// without a window. Frames are emulated as fast as possible, and drawn
+    /// with the software renderer.
+    pub fn run_headless(&mut self) {
+        if !self.rom_loaded {
+            log_error!(Rom, "A rom is needed to run in headless mode.");
+            return;
+        }
+
+        if let Some(format) = self.options.record {
+            match self.start_recording(format) {
+                Ok(message) => println!("{}", message),
+                Err(e) => log_error!(Render, "{}", e)
             }
+        }
+
+        let mut renderer = SoftwareRenderer::new(self.settings.window_width, self.settings.window_height);
+        let [r, g, b] = self.settings.border_color;
+        renderer.set_clear_color(r, g, b, 1.0);
+        renderer.set_palette(self.settings.current_palette());
+        renderer.set_ghosting(self.settings.ghosting);
+        renderer.set_integer_scaling(self.settings.integer_scaling);
+
+        for _ in 0..self.options.frames {
+            if let Err(e) = self.emulate(FRAME_TIME) {
+                log_error!(Render, "{}", e);
+            }
+            renderer.draw_screen(&self.chip8.framebuffer, self.chip8.frame_count);
+            if let Some(message) = self.chip8.watchpoint_hit() {
+                println!("{}", message);
+                break;
+            }
+        }
+
+        println!("Ran {} frames.", self.chip8.frame_count);
+        if let Some(result) = self.stop_recording() {
+            match result {
+                Ok(message) => println!("{}", message),
+                Err(e) => log_error!(Render, "{}", e)
+            }
+        }
+        if self.options.screenshot {
+            let displayed = if self.settings.screenshot_display { renderer.read_pixels() } else { None };
+            match self.save_screenshot(displayed.as_ref()) {
+                Ok(message) => println!("{}", message),
+                Err(e) => log_error!(Render, "{}", e)
+            }
+        }
+    }
+
+    /// Emulates the given time. While recording, every frame emulated is
+    /// recorded, and recording stops if writing fails.
+    fn emulate(&mut self, elapsed: Duration) -> Result<(), String> {
+        let recorder = match &mut self.recorder {
+            Some(recorder) => recorder,
+            None => {
+                self.chip8.run_for(elapsed);
+                return Ok(());
+            }
+        };
+
+        let mut result = Ok(());
+        let mut on_frame = |chip8: &Chip8| {
+            if result.is_ok() {
+                result = recorder.add_frame(&chip8.framebuffer, chip8.last_frame_beeped);
+            }
+        };
+        self.chip8.run_for_with_frames(elapsed, &mut on_frame);
+
+        if let Err(e) = result {
+            let _ = self.stop_recording();
+            return Err(format!("Recording stopped, unable to write it: {}", e));
+        }
+        Ok(())
+    }
+
+    /// Name used for the files saved while a rom runs.
+    fn file_base_name(&self) -> String {
+        match &self.current_rom_id {
+            Some(rom_id) => Path::new(&rom_id.file_name).file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_else(|| rom_id.file_name.clone()),
+            None => String::from("chip8")
+        }
+    }
+
+    fn start_recording(&mut self, format: RecordingFormat) -> Result<String, String> {
+        let recorder = Recorder::start(&self.settings.recording_dir, &self.file_base_name(), format,
+            self.settings.current_palette(), self.settings.recording_scale)?;
+        let message = format!("Recording to {}", recorder.paths()[0].display());
+        self.recorder = Some(recorder);
+        Ok(message)
+    }
+
+    /// Stops recording, if recording. Returns a message describing the files
+    /// written.
+    fn stop_recording(&mut self) -> Option<Result<String, String>> {
+        let recorder = self.recorder.take()?;
+        Some(match recorder.finish() {
+            Ok(paths) => {
+                let paths: Vec<String> = paths.iter().map(|p| p.display().to_string()).collect();
+                Ok(format!("Recording saved: {}", paths.join(", ")))
+            },
+            Err(e) => Err(format!("Unable to finish recording: {}", e))
+        })
+    }
 
-            if self.rom_loaded {
-                self.chip8.run_cycles();
+    /// Saves the current screen, as configured in the settings. Returns a
+    /// message describing the files written.
+    fn save_screenshot(&self, displayed: Option<&Image>) -> Result<String, String> {
+        let name = self.file_base_name();
+        let paths = screenshot::save(&self.settings.screenshot_dir, &name, &self.chip8.framebuffer,
+            self.settings.current_palette(), self.settings.screenshot_scale, displayed)?;
+        Ok(format!("Screenshot saved: {}", paths[0].display()))
+    }
+
+    /// Saves the sprite selected in the sprite viewer, with the screenshots.
+    fn save_sprite(&self) -> Result<String, String> {
+        let Some((address, height)) = self.sprite_viewer.as_ref().and_then(|v| Some((v.selected?, v.height))) else {
+            return Err(String::from("No sprite selected"));
+        };
+        let end = (address as usize + height as usize).min(self.chip8.main_memory.len());
+        let path = sprites::save(&self.settings.screenshot_dir, &self.file_base_name(), address,
+            &self.chip8.main_memory[address as usize..end], self.settings.current_palette(), self.settings.screenshot_scale)?;
+        Ok(format!("Sprite saved: {}", path.display()))
+    }
+
+    fn handle_command(&mut self, frontend: &mut dyn Frontend, command: Command) {
+        match command {
+            Command::Screenshot => self.screenshot_requested = true,
+            Command::ToggleRecording => {
+                let result = match self.stop_recording() {
+                    Some(result) => result,
+                    None => self.start_recording(self.settings.recording_format)
+                };
+                self.show_result(frontend, result);
+                frontend.set_status(&self.status());
+            },
+            Command::ToggleRomBrowser => {
+                if self.browser.is_some() {
+                    self.close_browser(frontend);
+                } else {
+                    self.open_browser(frontend);
+                }
+            },
+            Command::MenuMove(offset) => {
+                if let Some(browser) = &mut self.browser {
+                    browser.move_selection(offset);
+                    frontend.show_menu(Some(browser.menu()));
+                }
+            },
+            Command::MenuSelect => {
+                let selected = self.browser.as_ref().and_then(|b| b.selected_path()).map(String::from);
+                if let Some(path) = selected {
+                    self.close_browser(frontend);
+                    self.open_rom(frontend, &path);
+                }
+            },
+            Command::MenuBack => self.close_browser(frontend),
+            Command::ToggleDebugger => {
+                self.memory_view = match self.memory_view {
+                    Some(_) => None,
+                    None => Some(PROGRAM_START as u16)
+                };
+                if self.memory_view.is_none() {
+                    frontend.show_debugger(None);
+                } else if self.sprite_viewer.take().is_some() {
+                    frontend.show_sprites(None);
+                }
+            },
+            Command::ToggleSpriteViewer => {
+                self.sprite_viewer = match self.sprite_viewer {
+                    Some(_) => None,
+                    None => Some(SpriteViewer::new())
+                };
+                if self.sprite_viewer.is_none() {
+                    frontend.show_sprites(None);
+                } else if self.memory_view.take().is_some() {
+                    frontend.show_debugger(None);
+                }
+            },
+            Command::ScrollMemory(rows) => {
+                if let Some(viewer) = &mut self.sprite_viewer {
+                    viewer.scroll(rows);
+                } else if let Some(start) = &mut self.memory_view {
+                    *start = panels::scroll_memory(*start, rows);
+                }
+            },
+            Command::SelectSprite(address) => {
+                if let Some(viewer) = &mut self.sprite_viewer {
+                    viewer.selected = Some(address);
+                }
+            },
+            Command::ChangeSpriteHeight(rows) => {
+                if let Some(viewer) = &mut self.sprite_viewer {
+                    viewer.change_height(rows);
+                }
+            },
+            Command::MoveSprites(bytes) => {
+                if let Some(viewer) = &mut self.sprite_viewer {
+                    viewer.move_start(bytes);
+                }
+            },
+            Command::ExportSprite => {
+                let result = self.save_sprite();
+                self.show_result(frontend, result);
+            },
+            Command::ToggleBreakpoint(address) => {
+                let breakpoints = &mut self.chip8.debug.breakpoints;
+                match breakpoints.iter().position(|b| *b == address) {
+                    Some(index) => {
+                        breakpoints.remove(index);
+                        frontend.show_message(&format!("Breakpoint removed at {}", self.chip8.symbols.format(address)));
+                    },
+                    None => {
+                        breakpoints.push(address);
+                        frontend.show_message(&format!("Breakpoint set at {}", self.chip8.symbols.format(address)));
+                    }
+                }
+            },
+            // Debuggers step the emulation themselves
+            Command::Step if !self.rom_loaded || self.gdb.is_some() || self.dap.is_some() => { },
+            Command::Step if self.speed() != Speed::Paused => frontend.show_message("Pause the emulation to step"),
+            Command::Step => {
+                self.chip8.single_step();
+                if let Some(message) = self.chip8.watchpoint_hit() {
+                    frontend.show_message(&message);
+                }
+                frontend.set_speed(self.speed());
+            },
+            Command::StepBack | Command::ReverseContinue if !self.rom_loaded || self.gdb.is_some() || self.dap.is_some() => { },
+            Command::StepBack | Command::ReverseContinue if self.speed() != Speed::Paused => frontend.show_message("Pause the emulation to step back"),
+            Command::StepBack | Command::ReverseContinue if !self.chip8.history.is_recording() => frontend.show_message("The history is disabled (--history 0)"),
+            Command::StepBack | Command::ReverseContinue => {
+                if matches!(command, Command::StepBack) {
+                    self.chip8.step_back();
+                } else {
+                    self.chip8.reverse_continue();
+                }
+                match self.chip8.debug.stop_reason {
+                    Some(StopReason::HistoryStart) => frontend.show_message("No older instruction in the history"),
+                    Some(StopReason::Breakpoint) => frontend.show_message(&format!("Breakpoint hit at {}", self.chip8.symbols.format(self.chip8.get_register(Register::PC)))),
+                    _ => { }
+                }
+                frontend.set_speed(self.speed());
+            },
+            Command::ShowLastWrite(address) => frontend.show_message(&self.chip8.describe_last_write(address)),
+            // A watchpoint stopped the emulation, without a debugger to resume it
+            Command::TogglePause if self.chip8.is_stopped() && self.gdb.is_none() && self.dap.is_none() => {
+                self.chip8.resume();
+                self.paused = false;
+                frontend.show_message("Resumed");
+                frontend.set_speed(self.speed());
+            },
+            Command::TogglePause => {
+                self.paused = !self.paused;
+                frontend.show_message(if self.paused { "Paused" } else { "Resumed" });
+                frontend.set_speed(self.speed());
+            },
+            Command::ToggleFastForward => {
+                self.fast_forward = !self.fast_forward;
+                let message = if self.fast_forward {
+                    format!("Speed: {}x", self.settings.fast_forward_speed)
+                } else {
+                    String::from("Speed: normal")
+                };
+                frontend.show_message(&message);
+                frontend.set_speed(self.speed());
+            },
+            Command::NextPalette | Command::PreviousPalette => {
+                let offset = if let Command::NextPalette = command { 1 } else { -1 };
+                let palette = self.settings.cycle_palette(offset);
+                frontend.show_message(&format!("Palette: {}", palette.name));
+                frontend.set_palette(palette);
+                frontend.set_status(&self.status());
+                // Palettes are remembered per rom
+                let name = Value::String(self.settings.palette.clone());
+                self.config.set(self.current_rom_id.as_ref(), "palette", name);
+                self.config_changed = true;
             }
-            w.draw(&self.chip8.framebuffer);
-            w.update();
         }
-    }    
-}
\ No newline at end of file
+    }
+
+    /// Remembers the window size for the next run, if the user resized it.
+    fn save_window_size(&mut self, (width, height): (u32, u32)) {
+        if width == self.settings.window_width && height == self.settings.window_height {
+            return;
+        }
+
+        self.config.set(None, "window_width", Value::Integer(width as i64));
+        self.config.set(None, "window_height", Value::Integer(height as i64));
+        self.config_changed = true;
+    }
+
+    /// Writes settings changed at runtime back to the config file.
+    fn save_config(&mut self) {
+        if !self.config_changed { return; }
+
+        if let Err(e) = self.config.save() {
+            log_error!(Config, "Unable to save config file. {}", e);
+        }
+        self.config_changed = false;
+    }
+}
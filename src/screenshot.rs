@@ -0,0 +1,52 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::chip8::{DISPLAYH, DISPLAYW};
+use crate::palette::Palette;
+use crate::renderer::{self, Image};
+use crate::utils;
+
+/// Draws the framebuffer with the palette colors, each Chip-8 pixel becoming
+/// a square of scale by scale pixels. Ghosting and effects are not applied.
+pub fn render(fb: &[u8], palette: &Palette, scale: u32) -> Image {
+    let scale = scale.max(1);
+    let (width, height) = (DISPLAYW * scale, DISPLAYH * scale);
+    let mut pixels = Vec::with_capacity((width * height * 4) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            let planes = renderer::pixel_planes(fb[((y / scale) * DISPLAYW + x / scale) as usize]);
+            let color = palette.blend((planes & 1) as f32, (planes >> 1) as f32);
+            pixels.extend(color.map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8));
+            pixels.push(255);
+        }
+    }
+    Image { width, height, pixels }
+}
+
+/// Saves the screen as PNG files in dir, named after the rom and the current
+/// time: the framebuffer at native resolution, the framebuffer scaled up, and
+/// the screen as displayed, with shaders and effects, when given. Returns
+/// the paths of the files written.
+pub fn save(dir: &str, name: &str, fb: &[u8], palette: &Palette, scale: u32, displayed: Option<&Image>)
+    -> Result<Vec<PathBuf>, String> {
+    fs::create_dir_all(dir).map_err(|e| format!("Unable to create screenshot directory {}: {}", dir, e))?;
+
+    let base_name = format!("{}-{}", name, utils::timestamp());
+    let native = render(fb, palette, 1);
+    let scaled = render(fb, palette, scale);
+    let mut images = vec![
+        (format!("{}.png", base_name), &native),
+        (format!("{}-{}x.png", base_name, scale.max(1)), &scaled)
+    ];
+    if let Some(displayed) = displayed {
+        images.push((format!("{}-display.png", base_name), displayed));
+    }
+
+    let mut paths = Vec::new();
+    for (file_name, image) in images {
+        let path = Path::new(dir).join(file_name);
+        image.write_png(&path)
+            .map_err(|e| format!("Unable to write screenshot {}: {}", path.display(), e))?;
+        paths.push(path);
+    }
+    Ok(paths)
+}
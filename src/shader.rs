@@ -1,31 +1,119 @@
-use std::{fs, ffi::{CStr, CString}};
+use std::{fs, ffi::CString, time::SystemTime};
+
+/// OpenGL flavors the emulator can run on, from the most to the least capable.
+/// Each one has its own shading language version, so shaders are written
+/// without a `#version` directive, using the macros defined by the preludes
+/// below, and the prelude of the current context is prepended to them:
+///
+/// - `ATTRIBUTE`: vertex shader input
+/// - `VARYING`: vertex shader output, and fragment shader input
+/// - `TEXTURE`: texture sampling function
+/// - `FRAG_COLOR`: fragment shader output
+#[derive(Clone, Copy, PartialEq)]
+pub enum GlVersion {
+    Core45,
+    Core33,
+    Es30,
+    Es20
+}
+
+impl GlVersion {
+    /// Versions in the order they are tried when creating the context.
+    pub const ALL: [GlVersion; 4] = [GlVersion::Core45, GlVersion::Core33, GlVersion::Es30, GlVersion::Es20];
+
+    pub fn from_name(name: &str) -> Option<GlVersion> {
+        match name {
+            "4.5" => Some(GlVersion::Core45),
+            "3.3" => Some(GlVersion::Core33),
+            "es3.0" => Some(GlVersion::Es30),
+            "es2.0" => Some(GlVersion::Es20),
+            _ => None
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            GlVersion::Core45 => "OpenGL 4.5 core",
+            GlVersion::Core33 => "OpenGL 3.3 core",
+            GlVersion::Es30 => "OpenGL ES 3.0",
+            GlVersion::Es20 => "OpenGL ES 2.0"
+        }
+    }
+
+    pub fn is_es(&self) -> bool {
+        matches!(self, GlVersion::Es30 | GlVersion::Es20)
+    }
+
+    pub fn vertex_prelude(&self) -> &'static str {
+        match self {
+            GlVersion::Core45 => "#version 450 core\n#define ATTRIBUTE in\n#define VARYING out\n",
+            GlVersion::Core33 => "#version 330 core\n#define ATTRIBUTE in\n#define VARYING out\n",
+            GlVersion::Es30 => "#version 300 es\n#define ATTRIBUTE in\n#define VARYING out\n",
+            GlVersion::Es20 => "#version 100\n#define ATTRIBUTE attribute\n#define VARYING varying\n"
+        }
+    }
+
+    pub fn fragment_prelude(&self) -> &'static str {
+        match self {
+            GlVersion::Core45 => "#version 450 core\n#define VARYING in\n#define TEXTURE texture\n\
+                out vec4 oColor;\n#define FRAG_COLOR oColor\n",
+            GlVersion::Core33 => "#version 330 core\n#define VARYING in\n#define TEXTURE texture\n\
+                out vec4 oColor;\n#define FRAG_COLOR oColor\n",
+            GlVersion::Es30 => "#version 300 es\nprecision mediump float;\n#define VARYING in\n\
+                #define TEXTURE texture\nout vec4 oColor;\n#define FRAG_COLOR oColor\n",
+            GlVersion::Es20 => "#version 100\nprecision mediump float;\n#define VARYING varying\n\
+                #define TEXTURE texture2D\n#define FRAG_COLOR gl_FragColor\n"
+        }
+    }
+}
+
+/// Name of the vertex position attribute. It is bound to location 0 in all
+/// programs, since not all shading language versions support layout qualifiers.
+pub const POSITION_ATTRIBUTE: &str = "iCoord";
 
 pub struct ShaderProgram {
     vs_path: String,
     fs_path: String,
     id: u32,
     vs_content: String,
-    fs_content: String
+    fs_content: String,
+    /// Modification times of the shader files when they were last read. Used
+    /// to detect changes to the files.
+    files_mtime: (Option<SystemTime>, Option<SystemTime>),
+    /// Prepended to shaders that don't start with a `#version` directive.
+    version: GlVersion
 }
 
 impl ShaderProgram {
-    pub fn from_files (vs_file_path: &str, fs_file_path: &str) -> ShaderProgram {
-        ShaderProgram { 
-            vs_path: String::from(vs_file_path), 
-            fs_path: String::from(fs_file_path), 
-            id: 0, 
-            vs_content: String::new(), 
-            fs_content: String::new()
+    pub fn from_files (vs_file_path: &str, fs_file_path: &str, version: GlVersion) -> ShaderProgram {
+        ShaderProgram {
+            vs_path: String::from(vs_file_path),
+            fs_path: String::from(fs_file_path),
+            id: 0,
+            vs_content: String::new(),
+            fs_content: String::new(),
+            files_mtime: (None, None),
+            version
         }
     }
 
-    pub fn from_text(vs_content: &str, fs_content: &str) -> ShaderProgram {
-        ShaderProgram { 
-            vs_path: String::new(), 
-            fs_path: String::new(), 
-            id: 0, 
+    pub fn from_text(vs_content: &str, fs_content: &str, version: GlVersion) -> ShaderProgram {
+        ShaderProgram {
+            vs_path: String::new(),
+            fs_path: String::new(),
+            id: 0,
             vs_content: vs_content.to_string(),
-            fs_content: fs_content.to_string() 
+            fs_content: fs_content.to_string(),
+            files_mtime: (None, None),
+            version
+        }
+    }
+
+    fn with_prelude(source: &str, prelude: &str) -> String {
+        if source.trim_start().starts_with("#version") {
+            String::from(source)
+        } else {
+            format!("{}{}", prelude, source)
         }
     }
 
@@ -33,68 +121,151 @@ impl ShaderProgram {
         unsafe { gl::UseProgram(self.id); }
     }
 
-    pub fn load_shader_files(&mut self) -> bool {
-        if self.vs_path.is_empty() || self.fs_path.is_empty() {
-            println!("Unable to load shaders. At least of the shader paths is missing.");
-            return false;
-        }
-        
-        self.vs_content = fs::read_to_string(&self.vs_path).unwrap();
-        self.fs_content = fs::read_to_string(&self.fs_path).unwrap();
+    pub fn is_loaded(&self) -> bool {
+        self.id != 0
+    }
+
+    fn uniform_location(&self, name: &str) -> i32 {
+        let name_cstring = CString::new(name).unwrap();
+        unsafe { gl::GetUniformLocation(self.id, name_cstring.as_ptr()) }
+    }
 
-        true
+    /// Whether the program uses the uniform. Unused uniforms are removed by
+    /// the shader compiler.
+    pub fn has_uniform(&self, name: &str) -> bool {
+        self.uniform_location(name) != -1
     }
 
-    pub fn load(&mut self) -> bool {
+    /// Sets a vec3 uniform. The program must be bound.
+    pub fn set_uniform_vec3(&self, name: &str, value: [f32; 3]) {
+        unsafe { gl::Uniform3f(self.uniform_location(name), value[0], value[1], value[2]); }
+    }
+
+    /// Sets a vec3 array uniform. The program must be bound.
+    pub fn set_uniform_vec3_array(&self, name: &str, values: &[[f32; 3]]) {
         unsafe {
-            const LOG_SIZE: usize = 200;
+            gl::Uniform3fv(self.uniform_location(name), values.len() as i32, values.as_ptr() as *const f32);
+        }
+    }
 
-            let vs_id = gl::CreateShader(gl::VERTEX_SHADER);
-            let fs_id = gl::CreateShader(gl::FRAGMENT_SHADER);
-            self.id = gl::CreateProgram();
+    /// Sets a vec2 uniform. The program must be bound.
+    pub fn set_uniform_vec2(&self, name: &str, value: [f32; 2]) {
+        unsafe { gl::Uniform2f(self.uniform_location(name), value[0], value[1]); }
+    }
 
-            let vs_cstring = CString::new(self.vs_content.as_bytes()).unwrap();
-            let fs_cstring = CString::new(self.fs_content.as_bytes()).unwrap();
-            gl::ShaderSource(vs_id, 1, &vs_cstring.as_c_str().as_ptr(), std::ptr::null());
-            gl::ShaderSource(fs_id, 1, &fs_cstring.as_c_str().as_ptr(), std::ptr::null());
+    /// Sets a float uniform. The program must be bound.
+    pub fn set_uniform_f32(&self, name: &str, value: f32) {
+        unsafe { gl::Uniform1f(self.uniform_location(name), value); }
+    }
 
-            gl::CompileShader(vs_id);
-            let mut vs_compile_status = 0;
-            gl::GetShaderiv(vs_id, gl::COMPILE_STATUS, &mut vs_compile_status);
+    /// Sets an int (or sampler) uniform. The program must be bound.
+    pub fn set_uniform_i32(&self, name: &str, value: i32) {
+        unsafe { gl::Uniform1i(self.uniform_location(name), value); }
+    }
 
-            if vs_compile_status as u8 != gl::TRUE {
+    fn files_mtime(&self) -> (Option<SystemTime>, Option<SystemTime>) {
+        let mtime = |path: &str| fs::metadata(path).and_then(|m| m.modified()).ok();
+        (mtime(&self.vs_path), mtime(&self.fs_path))
+    }
 
-                let mut log: [i8; LOG_SIZE] = [0; LOG_SIZE];
-                gl::GetShaderInfoLog(vs_id, LOG_SIZE as i32, std::ptr::null_mut(), log.as_mut_ptr());
+    /// Whether any of the shader files was modified since they were last read.
+    pub fn files_changed(&self) -> bool {
+        !self.vs_path.is_empty() && self.files_mtime() != self.files_mtime
+    }
 
-                let log_c_str = CStr::from_ptr(log.as_ptr()).to_str().unwrap();
-                println!("Unable to compile vertex shader!");
-                println!("Shader error log: {}", log_c_str);
+    pub fn load_shader_files(&mut self) -> Result<(), String> {
+        if self.vs_path.is_empty() || self.fs_path.is_empty() {
+            return Err(String::from("Unable to load shaders. At least one of the shader paths is missing."));
+        }
 
-                return false;
-            } 
+        // Read the timestamps first, so a write during the read is caught next time
+        self.files_mtime = self.files_mtime();
+        self.vs_content = fs::read_to_string(&self.vs_path)
+            .map_err(|e| format!("Unable to read vertex shader {}: {}", self.vs_path, e))?;
+        self.fs_content = fs::read_to_string(&self.fs_path)
+            .map_err(|e| format!("Unable to read fragment shader {}: {}", self.fs_path, e))?;
 
-            gl::CompileShader(fs_id);
-            let mut fs_compile_status = 0;
-            gl::GetShaderiv(fs_id, gl::COMPILE_STATUS, &mut fs_compile_status);
-            
-            if fs_compile_status as u8 != gl::TRUE {
-                let mut shader_log: [i8; LOG_SIZE] = [0; LOG_SIZE];
-                gl::GetShaderInfoLog(fs_id, LOG_SIZE as i32, std::ptr::null_mut(), shader_log.as_mut_ptr());
+        Ok(())
+    }
 
-                let shader_log_cstr = CStr::from_ptr(shader_log.as_ptr());
-                println!("Unable to compile fragment shader!");
-                println!("Shader error log: {}", shader_log_cstr.to_str().unwrap());
+    fn shader_log(shader_id: u32) -> String {
+        unsafe {
+            let mut log_length = 0;
+            gl::GetShaderiv(shader_id, gl::INFO_LOG_LENGTH, &mut log_length);
+            let mut log = vec![0u8; log_length.max(1) as usize];
+            gl::GetShaderInfoLog(shader_id, log_length, std::ptr::null_mut(), log.as_mut_ptr() as *mut i8);
+            String::from_utf8_lossy(&log).trim_end_matches('\0').trim_end().to_string()
+        }
+    }
 
-                return false;
-            }
+    fn program_log(program_id: u32) -> String {
+        unsafe {
+            let mut log_length = 0;
+            gl::GetProgramiv(program_id, gl::INFO_LOG_LENGTH, &mut log_length);
+            let mut log = vec![0u8; log_length.max(1) as usize];
+            gl::GetProgramInfoLog(program_id, log_length, std::ptr::null_mut(), log.as_mut_ptr() as *mut i8);
+            String::from_utf8_lossy(&log).trim_end_matches('\0').trim_end().to_string()
+        }
+    }
+
+    fn compile_shader(kind: gl::types::GLenum, source: &str, name: &str) -> Result<u32, String> {
+        unsafe {
+            let shader_id = gl::CreateShader(kind);
+            let source_cstring = CString::new(source.as_bytes())
+                .map_err(|_| format!("Unable to compile {} shader: source contains a null byte.", name))?;
+            gl::ShaderSource(shader_id, 1, &source_cstring.as_c_str().as_ptr(), std::ptr::null());
+            gl::CompileShader(shader_id);
 
-            gl::AttachShader(self.id, vs_id);
-            gl::AttachShader(self.id, fs_id);
-            gl::LinkProgram(self.id);
+            let mut compile_status = 0;
+            gl::GetShaderiv(shader_id, gl::COMPILE_STATUS, &mut compile_status);
+            if compile_status as u8 != gl::TRUE {
+                let log = ShaderProgram::shader_log(shader_id);
+                gl::DeleteShader(shader_id);
+                return Err(format!("Unable to compile {} shader!\nShader error log:\n{}", name, log));
+            }
 
-            return true;
+            Ok(shader_id)
         }
+    }
+
+    /// Compiles and links the program. On failure the previously loaded
+    /// program, if any, is kept, and the compiler log is returned.
+    pub fn load(&mut self) -> Result<(), String> {
+        unsafe {
+            let vs_source = ShaderProgram::with_prelude(&self.vs_content, self.version.vertex_prelude());
+            let fs_source = ShaderProgram::with_prelude(&self.fs_content, self.version.fragment_prelude());
+            let vs_id = ShaderProgram::compile_shader(gl::VERTEX_SHADER, &vs_source, "vertex")?;
+            let fs_id = match ShaderProgram::compile_shader(gl::FRAGMENT_SHADER, &fs_source, "fragment") {
+                Ok(id) => id,
+                Err(e) => {
+                    gl::DeleteShader(vs_id);
+                    return Err(e);
+                }
+            };
 
+            let program_id = gl::CreateProgram();
+            gl::AttachShader(program_id, vs_id);
+            gl::AttachShader(program_id, fs_id);
+            let position_cstring = CString::new(POSITION_ATTRIBUTE).unwrap();
+            gl::BindAttribLocation(program_id, 0, position_cstring.as_ptr());
+            gl::LinkProgram(program_id);
+            gl::DeleteShader(vs_id);
+            gl::DeleteShader(fs_id);
+
+            let mut link_status = 0;
+            gl::GetProgramiv(program_id, gl::LINK_STATUS, &mut link_status);
+            if link_status as u8 != gl::TRUE {
+                let log = ShaderProgram::program_log(program_id);
+                gl::DeleteProgram(program_id);
+                return Err(format!("Unable to link shader program!\nProgram error log:\n{}", log));
+            }
+
+            if self.id != 0 {
+                gl::DeleteProgram(self.id);
+            }
+            self.id = program_id;
+
+            Ok(())
+        }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,103 @@
+use crate::chip8::{DISPLAYH, DISPLAYW};
+use crate::palette::{self, Palette};
+use crate::phosphor::{Ghosting, PhosphorFilter};
+use crate::renderer::{self, Image, Renderer};
+
+/// Renders the screen on the CPU, to an RGBA pixel buffer. The buffer rows go
+/// from top to bottom. Used for screenshots, headless runs, and to display
+/// the screen without relying on shaders.
+pub struct SoftwareRenderer {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+    clear_color: [u8; 4],
+    palette: Palette,
+    phosphor: PhosphorFilter,
+    integer_scaling: bool
+}
+
+impl SoftwareRenderer {
+    pub fn new(width: u32, height: u32) -> SoftwareRenderer {
+        SoftwareRenderer {
+            width,
+            height,
+            pixels: vec![0; (width * height * 4) as usize],
+            clear_color: [0, 0, 0, 255],
+            palette: palette::builtin_palettes().remove(0),
+            phosphor: PhosphorFilter::new(Ghosting::Decay { factor: 0.8 }),
+            integer_scaling: false
+        }
+    }
+
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+}
+
+fn to_rgba8(color: [f32; 3]) -> [u8; 4] {
+    let channel = |c: f32| (c.clamp(0.0, 1.0) * 255.0).round() as u8;
+    [channel(color[0]), channel(color[1]), channel(color[2]), 255]
+}
+
+impl Renderer for SoftwareRenderer {
+    fn init(&mut self) {
+        self.pixels = vec![0; (self.width * self.height * 4) as usize];
+    }
+
+    fn read_pixels(&mut self) -> Option<Image> {
+        Some(Image { width: self.width, height: self.height, pixels: self.pixels.clone() })
+    }
+
+    fn set_clear_color(&mut self, r: f32, g: f32, b: f32, a: f32) {
+        let [r, g, b, _] = to_rgba8([r, g, b]);
+        self.clear_color = [r, g, b, (a.clamp(0.0, 1.0) * 255.0).round() as u8];
+    }
+
+    fn set_palette(&mut self, palette: &Palette) {
+        self.palette = palette.clone();
+    }
+
+    fn set_viewport(&mut self, width: i32, height: i32) {
+        self.width = width.max(0) as u32;
+        self.height = height.max(0) as u32;
+        self.init();
+    }
+
+    fn set_ghosting(&mut self, ghosting: Ghosting) {
+        self.phosphor.set_ghosting(ghosting);
+    }
+
+    fn set_integer_scaling(&mut self, integer_scaling: bool) {
+        self.integer_scaling = integer_scaling;
+    }
+
+    fn clear(&mut self) {
+        for pixel in self.pixels.chunks_exact_mut(4) {
+            pixel.copy_from_slice(&self.clear_color);
+        }
+    }
+
+    fn draw_screen(&mut self, fb: &[u8], frame: u64) {
+        self.clear();
+
+        let intensities = self.phosphor.apply(fb, frame);
+        let colors: Vec<[u8; 4]> = intensities.chunks_exact(2)
+            .map(|planes| to_rgba8(self.palette.blend(planes[0], planes[1])))
+            .collect();
+
+        let (x0, y0, width, height) = renderer::screen_rect(self.width as i32, self.height as i32, self.integer_scaling);
+        for y in 0..height {
+            let screen_y = (y as u32 * DISPLAYH / height as u32) as usize;
+            let row_start = (((y0 + y) as u32 * self.width + x0 as u32) * 4) as usize;
+            let row = &mut self.pixels[row_start..row_start + (width * 4) as usize];
+            for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+                let screen_x = (x as u32 * DISPLAYW / width as u32) as usize;
+                pixel.copy_from_slice(&colors[screen_y * DISPLAYW as usize + screen_x]);
+            }
+        }
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::chip8::{Chip8, FRAME_TIME, MEMSIZE, PROGRAM_START};
+use crate::config::{Config, RomId};
+use crate::history::History;
+use crate::palette::Palette;
+use crate::renderer::Image;
+use crate::rom::{self, Opened};
+use crate::utils;
+
+/// Most rows of a sprite, drawn by `DXYF`.
+pub const MAX_HEIGHT: u8 = 15;
+/// Sprites per row, in the viewer and in the sheets exported.
+pub const SHEET_COLUMNS: u16 = 8;
+/// Rows of sprites captured, frontends show as many as fit.
+const VIEW_ROWS: u16 = 64;
+const GAP_COLOR: [u8; 4] = [40, 40, 40, 255];
+/// Frames sprites the rom drew in the sheets exported.
+const DRAWN_COLOR: [u8; 4] = [255, 210, 64, 255];
+
+/// A sprite drawn by the rom, covering an address.
+#[derive(Clone, Copy, PartialEq)]
+pub struct DrawnSprite {
+    /// Address in I when it was drawn.
+    pub address: u16,
+    /// Most rows drawn from this address.
+    pub rows: u8
+}
+
+/// Sprite drawn by the rom that covers an address, if any. Sprites are
+/// recorded by `Chip8::drw`, with the address in I and the rows drawn.
+pub fn drawn_at(drawn: &BTreeMap<u16, u8>, address: u16) -> Option<DrawnSprite> {
+    drawn.range(address.saturating_sub(MAX_HEIGHT as u16 - 1)..=address)
+        .filter(|(start, rows)| address < **start + **rows as u16)
+        .map(|(start, rows)| DrawnSprite { address: *start, rows: *rows })
+        .next_back()
+}
+
+#[derive(Clone, PartialEq)]
+pub struct SpriteCell {
+    pub address: u16,
+    /// Bytes of the sprite, one per row. Shorter at the end of the memory.
+    pub rows: Vec<u8>,
+    /// Whether the rom drew some of its bytes.
+    pub drawn: bool
+}
+
+/// Memory shown by the sprite viewer, as 8 pixel wide sprites of the same
+/// height in a grid.
+#[derive(Clone, PartialEq)]
+pub struct SpriteView {
+    pub start: u16,
+    pub height: u8,
+    pub cells: Vec<SpriteCell>,
+    /// Address of the sprite selected, and the sprite drawn there, if any.
+    pub selected: Option<(u16, Option<DrawnSprite>)>
+}
+
+/// Where the sprite viewer is in memory, and the sprite selected.
+pub struct SpriteViewer {
+    pub start: u16,
+    pub height: u8,
+    pub selected: Option<u16>
+}
+
+impl SpriteViewer {
+    pub fn new() -> SpriteViewer {
+        SpriteViewer { start: PROGRAM_START as u16, height: 8, selected: None }
+    }
+
+    pub fn capture(&self, chip8: &Chip8) -> SpriteView {
+        let cells = (0..VIEW_ROWS * SHEET_COLUMNS)
+            .map(|cell| self.start as usize + cell as usize * self.height as usize)
+            .take_while(|address| *address < MEMSIZE)
+            .map(|address| {
+                let end = (address + self.height as usize).min(MEMSIZE);
+                SpriteCell {
+                    address: address as u16,
+                    rows: chip8.main_memory[address..end].to_vec(),
+                    drawn: (address..end).any(|a| drawn_at(&chip8.sprites_drawn, a as u16).is_some())
+                }
+            })
+            .collect();
+        SpriteView {
+            start: self.start,
+            height: self.height,
+            cells,
+            selected: self.selected.map(|address| (address, drawn_at(&chip8.sprites_drawn, address)))
+        }
+    }
+
+    /// Moves the start by a number of bytes, negative going up, e.g. to align
+    /// the grid on the sprites of the rom.
+    pub fn move_start(&mut self, bytes: i32) {
+        self.start = (self.start as i32 + bytes).clamp(0, MEMSIZE as i32 - 1) as u16;
+    }
+
+    /// Scrolls by a number of rows of sprites, negative going up.
+    pub fn scroll(&mut self, rows: i32) {
+        self.move_start(rows * SHEET_COLUMNS as i32 * self.height as i32);
+    }
+
+    pub fn change_height(&mut self, rows: i32) {
+        self.height = (self.height as i32 + rows).clamp(1, MAX_HEIGHT as i32) as u8;
+    }
+}
+
+/// Color of a sprite pixel, with the palette the screen uses.
+fn pixel_color(palette: &Palette, on: bool) -> [u8; 4] {
+    let [r, g, b] = palette.blend(if on { 1.0 } else { 0.0 }, 0.0).map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8);
+    [r, g, b, 255]
+}
+
+/// Draws a sprite, each pixel becoming a square of scale by scale pixels.
+pub fn render_sprite(rows: &[u8], palette: &Palette, scale: u32) -> Image {
+    let scale = scale.max(1);
+    let mut image = Image { width: 8 * scale, height: rows.len() as u32 * scale, pixels: Vec::new() };
+    image.pixels.resize((image.width * image.height * 4) as usize, 0);
+    draw_sprite(&mut image, 0, 0, rows, palette, scale);
+    image
+}
+
+fn draw_sprite(image: &mut Image, x: u32, y: u32, rows: &[u8], palette: &Palette, scale: u32) {
+    for (row, byte) in rows.iter().enumerate() {
+        for column in 0..8 {
+            let color = pixel_color(palette, byte & (0x80 >> column) != 0);
+            image.fill_rect(x + column * scale, y + row as u32 * scale, scale, scale, color);
+        }
+    }
+}
+
+/// Draws the memory from start to end included as sprites of a height, in
+/// rows of `SHEET_COLUMNS`. Sprites the rom drew are framed.
+pub fn render_sheet(memory: &[u8], (start, end): (u16, u16), height: u8, drawn: &BTreeMap<u16, u8>,
+    palette: &Palette, scale: u32) -> Image {
+    let scale = scale.max(1);
+    let bytes = &memory[start as usize..=(end as usize).min(memory.len() - 1)];
+    let sprites = bytes.len().div_ceil(height as usize) as u32;
+    let columns = sprites.clamp(1, SHEET_COLUMNS as u32);
+    // Each sprite has a frame of one pixel around it
+    let (cell_width, cell_height) = ((8 + 2) * scale, (height as u32 + 2) * scale);
+    let mut image = Image { width: columns * cell_width, height: sprites.div_ceil(columns).max(1) * cell_height, pixels: Vec::new() };
+    image.pixels.resize((image.width * image.height * 4) as usize, 0);
+    image.fill_rect(0, 0, image.width, image.height, GAP_COLOR);
+
+    for (index, rows) in bytes.chunks(height as usize).enumerate() {
+        let (x, y) = ((index as u32 % columns) * cell_width, (index as u32 / columns) * cell_height);
+        let address = start as usize + index * height as usize;
+        if (address..address + rows.len()).any(|a| drawn_at(drawn, a as u16).is_some()) {
+            image.fill_rect(x, y, cell_width, (rows.len() as u32 + 2) * scale, DRAWN_COLOR);
+        }
+        draw_sprite(&mut image, x + scale, y + scale, rows, palette, scale);
+    }
+    image
+}
+
+/// Saves a sprite as a PNG file in dir, named after the rom, the address of
+/// the sprite and the current time. Returns the path of the file written.
+pub fn save(dir: &str, name: &str, address: u16, rows: &[u8], palette: &Palette, scale: u32) -> Result<PathBuf, String> {
+    fs::create_dir_all(dir).map_err(|e| format!("Unable to create screenshot directory {}: {}", dir, e))?;
+    let image = render_sprite(rows, palette, scale);
+    let path = Path::new(dir).join(format!("{}-sprite-{:03x}-{}.png", name, address, utils::timestamp()));
+    image.write_png(&path)
+        .map_err(|e| format!("Unable to write sprite {}: {}", path.display(), e))?;
+    Ok(path)
+}
+
+/// Options of the `sprites` command.
+pub struct SpriteOptions {
+    pub rom_path: String,
+    /// Memory drawn, from start to end included. The rom when None.
+    pub range: Option<(u16, u16)>,
+    pub height: u8,
+    /// Frames the rom runs first, to find the sprites it draws.
+    pub frames: u64,
+    pub scale: u32,
+    /// PNG file written, named after the rom when None.
+    pub output: Option<String>
+}
+
+/// Runs the `sprites` command: runs the rom without a window, then draws a
+/// range of memory as a sheet of sprites, framing the ones the rom drew.
+/// Returns a message telling where the sheet was saved.
+pub fn run(options: &SpriteOptions) -> Result<String, String> {
+    let rom = match rom::open(&options.rom_path)? {
+        Opened::Rom(rom) => rom,
+        Opened::Choice { location, paths } => return Err(format!("{} holds several roms, pick one of: {}",
+            location, paths.iter().map(|p| rom::title(p)).collect::<Vec<_>>().join(", ")))
+    };
+    let settings = Config::load().settings(Some(&RomId::of(&rom)));
+
+    let mut chip8 = Chip8::new();
+    chip8.history = History::new(0);
+    chip8.setup();
+    chip8.load_rom_to_memory(&rom.data);
+    chip8.instruction_time_ns = settings.instruction_time_ns;
+    chip8.quirks = settings.quirks;
+    for _ in 0..options.frames {
+        chip8.run_for(FRAME_TIME);
+    }
+
+    let range = options.range.unwrap_or((PROGRAM_START as u16, (PROGRAM_START + rom.data.len().max(1) - 1) as u16));
+    if range.1 as usize >= MEMSIZE {
+        return Err(format!("The range ends outside of the memory, which ends at {:#05x}.", MEMSIZE - 1));
+    }
+    let sheet = render_sheet(&chip8.main_memory, range, options.height, &chip8.sprites_drawn, settings.current_palette(), options.scale);
+    let path = match &options.output {
+        Some(path) => PathBuf::from(path),
+        None => {
+            let stem = Path::new(&rom.path).file_stem().map_or_else(|| String::from("rom"), |s| s.to_string_lossy().into_owned());
+            PathBuf::from(format!("{}-sprites.png", stem))
+        }
+    };
+    sheet.write_png(&path)
+        .map_err(|e| format!("Unable to write sprites {}: {}", path.display(), e))?;
+
+    if chip8.sprites_drawn.is_empty() {
+        println!("No sprite drawn in {} frames.", options.frames);
+    } else {
+        let drawn: Vec<String> = chip8.sprites_drawn.iter().map(|(address, rows)| format!("{:#05x} ({} rows)", address, rows)).collect();
+        println!("Sprites drawn in {} frames: {}", options.frames, drawn.join(", "));
+    }
+    Ok(format!("Sprites {:#05x}-{:#05x} saved: {}", range.0, range.1, path.display()))
+}
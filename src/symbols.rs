@@ -0,0 +1,187 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use serde_json::Value;
+
+/// Source line of each instruction of a rom, read from a line map file
+/// written by the assembler. Each line of the file maps an address to a
+/// source line, e.g. `0x202 game.8o:14`, and lines starting with `#` are
+/// comments. Relative source paths are relative to the line map file.
+pub struct LineMap {
+    lines: BTreeMap<u16, (String, u32)>
+}
+
+/// Path used to compare source files, which debuggers may give in a
+/// different form than the line map.
+fn normalize_path(path: &Path) -> String {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()).to_string_lossy().into_owned()
+}
+
+fn parse_address(text: &str) -> Option<u16> {
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok()
+    }
+}
+
+impl LineMap {
+    pub fn load(path: &str) -> Result<LineMap, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Unable to read line map {}: {}", path, e))?;
+        let directory = Path::new(path).parent().unwrap_or(Path::new(""));
+        LineMap::parse(&text, directory).map_err(|e| format!("Invalid line map {}: {}", path, e))
+    }
+
+    pub fn parse(text: &str, directory: &Path) -> Result<LineMap, String> {
+        let mut map = LineMap { lines: BTreeMap::new() };
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = || format!("line {}: expected \"<address> <file>:<line>\"", number + 1);
+            let (address, location) = line.split_once(char::is_whitespace).ok_or_else(error)?;
+            let address = parse_address(address).ok_or_else(error)?;
+            map.insert(address, location.trim(), directory).ok_or_else(error)?;
+        }
+        Ok(map)
+    }
+
+    /// Maps an address to a source location written `<file>:<line>`.
+    fn insert(&mut self, address: u16, location: &str, directory: &Path) -> Option<()> {
+        let (file, source_line) = location.rsplit_once(':')?;
+        let source_line = source_line.parse().ok()?;
+        self.lines.insert(address, (normalize_path(&directory.join(file)), source_line));
+        Some(())
+    }
+
+    /// Source file and line of the instruction at an address.
+    pub fn line_of(&self, address: u16) -> Option<(&str, u32)> {
+        self.lines.get(&address).map(|(file, line)| (file.as_str(), *line))
+    }
+
+    /// Address of the first instruction of a source line.
+    pub fn address_of(&self, file: &str, line: u32) -> Option<u16> {
+        let file = normalize_path(Path::new(file));
+        self.lines.iter()
+            .find(|(_, (f, l))| *f == file && *l == line)
+            .map(|(address, _)| *address)
+    }
+}
+
+/// Labels of a rom, and optionally the source line of its instructions,
+/// read from a symbol file written by the assembler:
+/// - `.json` files hold the labels like Octo, as an object of addresses
+///   named `labels`, and optionally the source lines as an object named
+///   `lines`, e.g. `{"labels": {"main": 512}, "lines": {"0x200": "game.8o:3"}}`.
+/// - Other files have a label per line, e.g. `main 0x200` or `0x200 main`, and may map
+///   addresses to source lines like line maps, e.g. `0x200 game.8o:3`.
+#[derive(Default)]
+pub struct Symbols {
+    labels: BTreeMap<u16, String>,
+    pub line_map: Option<LineMap>,
+    /// End of the rom: addresses from there on have no label.
+    pub end: Option<u16>
+}
+
+/// Address written as a number or a string, e.g. `512` or `"0x200"`.
+fn json_address(value: &Value) -> Option<u16> {
+    match value.as_str() {
+        Some(text) => parse_address(text),
+        None => value.as_u64().and_then(|n| u16::try_from(n).ok())
+    }
+}
+
+impl Symbols {
+    pub fn load(path: &str) -> Result<Symbols, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Unable to read symbols {}: {}", path, e))?;
+        let directory = Path::new(path).parent().unwrap_or(Path::new(""));
+        let symbols = if path.to_ascii_lowercase().ends_with(".json") {
+            Symbols::parse_json(&text, directory)
+        } else {
+            Symbols::parse(&text, directory)
+        };
+        symbols.map_err(|e| format!("Invalid symbols {}: {}", path, e))
+    }
+
+    pub fn parse(text: &str, directory: &Path) -> Result<Symbols, String> {
+        let mut symbols = Symbols::default();
+        let mut line_map = LineMap { lines: BTreeMap::new() };
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = || format!("line {}: expected \"<label> <address>\" or \"<address> <file>:<line>\"", number + 1);
+            let (first, second) = line.split_once(char::is_whitespace).ok_or_else(error)?;
+            let second = second.trim();
+            match (parse_address(first), parse_address(second)) {
+                (_, Some(address)) => { symbols.labels.insert(address, String::from(first)); },
+                (Some(address), None) if second.contains(':') => line_map.insert(address, second, directory).ok_or_else(error)?,
+                (Some(address), None) => { symbols.labels.insert(address, String::from(second)); },
+                (None, None) => return Err(error())
+            }
+        }
+        if !line_map.lines.is_empty() {
+            symbols.line_map = Some(line_map);
+        }
+        Ok(symbols)
+    }
+
+    pub fn parse_json(text: &str, directory: &Path) -> Result<Symbols, String> {
+        let value: Value = serde_json::from_str(text).map_err(|e| e.to_string())?;
+        let mut symbols = Symbols::default();
+        if let Value::Object(labels) = &value["labels"] {
+            for (name, address) in labels {
+                let address = json_address(address).ok_or_else(|| format!("invalid address of label {}", name))?;
+                symbols.labels.insert(address, name.clone());
+            }
+        }
+        if let Value::Object(lines) = &value["lines"] {
+            let mut line_map = LineMap { lines: BTreeMap::new() };
+            for (address, location) in lines {
+                let (Some(address), Some(location)) = (parse_address(address), location.as_str()) else {
+                    return Err(format!("invalid line of address {}, expected \"<file>:<line>\"", address));
+                };
+                line_map.insert(address, location, directory)
+                    .ok_or_else(|| format!("invalid line of address {:#05x}, expected \"<file>:<line>\"", address))?;
+            }
+            symbols.line_map = Some(line_map);
+        }
+        Ok(symbols)
+    }
+
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    /// Label at exactly an address, if any.
+    pub fn name_at(&self, address: u16) -> Option<&str> {
+        self.labels.get(&address).map(String::as_str)
+    }
+
+    /// Address written as the closest label before it and the offset from
+    /// it, e.g. `draw+0x4`, or None when no label comes before it.
+    pub fn label(&self, address: u16) -> Option<String> {
+        if self.end.is_some_and(|end| address >= end) {
+            return None;
+        }
+        let (start, name) = self.labels.range(..=address).next_back()?;
+        Some(match address - start {
+            0 => name.clone(),
+            offset => format!("{}+{:#x}", name, offset)
+        })
+    }
+
+    /// Address to show to the user, with its label when there is one, e.g.
+    /// `0x204 <draw+0x4>`.
+    pub fn format(&self, address: u16) -> String {
+        match self.label(address) {
+            Some(label) => format!("{:#05x} <{}>", address, label),
+            None => format!("{:#05x}", address)
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::thread;
+use crate::chip8::Register;
+use crate::disasm::{self, OpcodeClass};
+use crate::symbols::Symbols;
+use crate::log_error;
+
+/// Which instructions are traced, and where.
+#[derive(Clone)]
+pub struct TraceOptions {
+    pub path: String,
+    /// Addresses of the instructions traced, from start to end included.
+    pub range: Option<(u16, u16)>,
+    /// Classes of the instructions traced, all when empty.
+    pub classes: Vec<OpcodeClass>,
+    /// Keep only this many instructions, and write them when an error
+    /// occurs, instead of writing every instruction.
+    pub last: Option<usize>
+}
+
+/// Writes a line per instruction executed to a file: the instruction
+/// count, PC, opcode, disassembly, and the registers after it ran.
+pub struct Tracer {
+    options: TraceOptions,
+    output: BufWriter<File>,
+    /// Last instructions traced, when only the last ones are kept.
+    history: VecDeque<String>,
+    /// Set once writing failed, so the error is only shown once.
+    failed: bool
+}
+
+fn format_line(count: u64, pc: u16, opcode: u16, registers: &[u16], symbols: &Symbols) -> String {
+    let v: Vec<String> = registers[Register::V0 as usize..=Register::VF as usize].iter()
+        .map(|value| format!("{:02x}", value))
+        .collect();
+    format!("{:>10} {:03x} {:04x} {:<18} V={} I={:03x} SP={:03x} DT={:02x} ST={:02x}",
+        count, pc, opcode, disasm::disassemble_with(opcode, symbols), v.join(" "),
+        registers[Register::I as usize], registers[Register::SP as usize],
+        registers[Register::DT as usize], registers[Register::Sound as usize])
+}
+
+impl Tracer {
+    pub fn create(options: TraceOptions) -> Result<Tracer, String> {
+        let file = File::create(&options.path).map_err(|e| format!("Unable to create trace {}: {}", options.path, e))?;
+        let mut tracer = Tracer {
+            options,
+            output: BufWriter::new(file),
+            history: VecDeque::new(),
+            failed: false
+        };
+        tracer.write("#      count  pc opcode instruction    registers after the instruction");
+        Ok(tracer)
+    }
+
+    pub fn path(&self) -> &str {
+        &self.options.path
+    }
+
+    fn traces(&self, pc: u16, opcode: u16) -> bool {
+        self.options.range.is_none_or(|(start, end)| (start..=end).contains(&pc))
+            && (self.options.classes.is_empty() || self.options.classes.contains(&OpcodeClass::of(opcode)))
+    }
+
+    fn write(&mut self, line: &str) {
+        if self.failed {
+            return;
+        }
+        if let Err(e) = writeln!(self.output, "{}", line) {
+            log_error!(Cpu, "Unable to write trace {}: {}", self.options.path, e);
+            self.failed = true;
+        }
+    }
+
+    /// Traces an instruction that just ran, if it passes the filters.
+    pub fn record(&mut self, count: u64, pc: u16, opcode: u16, registers: &[u16], symbols: &Symbols) {
+        if !self.traces(pc, opcode) {
+            return;
+        }
+        let line = format_line(count, pc, opcode, registers, symbols);
+        match self.options.last {
+            Some(last) => {
+                if self.history.len() >= last {
+                    self.history.pop_front();
+                }
+                self.history.push_back(line);
+            },
+            None => self.write(&line)
+        }
+    }
+
+    /// Notes an error in the trace, after the instructions kept that led
+    /// to it.
+    pub fn dump(&mut self, reason: &str) {
+        let history: Vec<String> = self.history.drain(..).collect();
+        for line in history.iter() {
+            self.write(line);
+        }
+        self.write(&format!("# {}", reason));
+        let _ = self.output.flush();
+    }
+}
+
+impl Drop for Tracer {
+    /// The instructions leading to a crash are written before the emulator
+    /// exits.
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.dump("The emulator crashed");
+        }
+        let _ = self.output.flush();
+    }
+}
@@ -0,0 +1,321 @@
+use std::io::{self, Read, Write};
+use std::process::{self, Stdio};
+use std::time::{Duration, Instant};
+
+use crate::chip8::FRAME_TIME;
+use crate::config::Settings;
+use crate::display::{ColorMode, ConsoleDisplay};
+use crate::frontend::{Frontend, Speed};
+use crate::input::{Command, InputBackend, KeyboardState};
+use crate::palette::Palette;
+use crate::log_error;
+use crate::logger;
+
+/// Terminals only report key presses, repeated while the key is held, and
+/// never releases. A key is considered held for this long after each press.
+const KEY_HOLD: Duration = Duration::from_millis(200);
+
+/// Keys as read from the terminal.
+#[derive(Clone, Copy, PartialEq)]
+enum TermKey {
+    /// A printable character, or enter, tab or backspace. Letters are lowercase.
+    Char(char),
+    Up,
+    Down,
+    Left,
+    Right,
+    F3,
+    ShiftF3,
+    F5,
+    F6,
+    F9,
+    F12,
+    Escape,
+    /// Ctrl+C. Signals are disabled while in raw mode, so it arrives as a key.
+    Interrupt
+}
+
+/// Converts a key name, as written in the config file, to the key read from
+/// the terminal. Same names as `window::key_from_name`.
+fn term_key_from_name(name: &str) -> Option<TermKey> {
+    let lower = name.to_ascii_lowercase();
+    let mut chars = lower.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return if c.is_ascii_graphic() { Some(TermKey::Char(c)) } else { None };
+    }
+
+    // The keypad sends plain digits
+    if let Some(digit) = lower.strip_prefix("kp").filter(|d| d.len() == 1 && d.chars().all(|c| c.is_ascii_digit())) {
+        return digit.chars().next().map(TermKey::Char);
+    }
+
+    match lower.as_str() {
+        "space" => Some(TermKey::Char(' ')),
+        "enter" => Some(TermKey::Char('\n')),
+        "tab" => Some(TermKey::Char('\t')),
+        "backspace" => Some(TermKey::Char('\x7f')),
+        "up" => Some(TermKey::Up),
+        "down" => Some(TermKey::Down),
+        "left" => Some(TermKey::Left),
+        "right" => Some(TermKey::Right),
+        _ => None
+    }
+}
+
+/// Splits the bytes read from the terminal into keys. Escape sequences that
+/// don't map to a known key are skipped.
+fn decode_keys(bytes: &[u8]) -> Vec<TermKey> {
+    let mut keys = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let byte = bytes[i];
+        i += 1;
+        match byte {
+            0x03 => keys.push(TermKey::Interrupt),
+            0x1b if i < bytes.len() && (bytes[i] == b'[' || bytes[i] == b'O') => {
+                // Escape sequences end with a byte in the 0x40..=0x7E range
+                let start = i;
+                i += 1;
+                while i < bytes.len() && !(0x40..=0x7E).contains(&bytes[i]) {
+                    i += 1;
+                }
+                i = (i + 1).min(bytes.len());
+                let key = match &bytes[start..i] {
+                    b"[A" | b"OA" => Some(TermKey::Up),
+                    b"[B" | b"OB" => Some(TermKey::Down),
+                    b"[C" | b"OC" => Some(TermKey::Right),
+                    b"[D" | b"OD" => Some(TermKey::Left),
+                    b"OR" | b"[13~" => Some(TermKey::F3),
+                    b"[1;2R" | b"[13;2~" => Some(TermKey::ShiftF3),
+                    b"[15~" => Some(TermKey::F5),
+                    b"[17~" => Some(TermKey::F6),
+                    b"[20~" => Some(TermKey::F9),
+                    b"[24~" => Some(TermKey::F12),
+                    _ => None
+                };
+                keys.extend(key);
+            },
+            0x1b => keys.push(TermKey::Escape),
+            b'\r' | b'\n' => keys.push(TermKey::Char('\n')),
+            0x08 | 0x7f => keys.push(TermKey::Char('\x7f')),
+            b'\t' | 0x20..=0x7e => keys.push(TermKey::Char(byte.to_ascii_lowercase() as char)),
+            _ => { }
+        }
+    }
+    keys
+}
+
+fn stty(args: &[&str]) -> Result<String, String> {
+    let output = process::Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .output()
+        .map_err(|e| format!("Unable to run stty: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("stty failed: {}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Switches the terminal to raw mode and to the alternate screen, so input is
+/// read key by key without echo and the screen can be drawn anywhere. Log
+/// messages meant for stderr are held meanwhile. The previous state is
+/// restored when dropped, and the messages are written.
+struct RawMode {
+    saved_state: String
+}
+
+impl RawMode {
+    fn enable() -> Result<RawMode, String> {
+        let saved_state = stty(&["-g"])?;
+        // Reads return immediately, even when no key was pressed
+        stty(&["-icanon", "-echo", "-isig", "-ixon", "min", "0", "time", "0"])?;
+
+        // Alternate screen, hidden cursor, cleared screen
+        print!("\x1b[?1049h\x1b[?25l\x1b[2J");
+        let _ = io::stdout().flush();
+        logger::hold();
+        Ok(RawMode { saved_state })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        print!("\x1b[0m\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+        if let Err(e) = stty(&[&self.saved_state]) {
+            log_error!(Render, "Unable to restore the terminal: {}", e);
+        }
+        logger::release();
+    }
+}
+
+/// Runs the emulator in the terminal: the screen is drawn with text
+/// characters, and the keypad is read from the keyboard. Works over SSH.
+pub struct TuiFrontend {
+    /// Restores the terminal when the frontend is dropped.
+    raw_mode: RawMode,
+    display: ConsoleDisplay,
+    key_map: [Option<TermKey>; 16],
+    /// Time at which each Chip-8 key is released, if no new press arrives.
+    key_release: [Option<Instant>; 16],
+    commands: Vec<Command>,
+    should_close: bool,
+    status: String,
+    speed: Speed,
+    message: Option<(String, Instant)>,
+    last_update: Instant,
+    frames_drawn: u32,
+    fps_start: Instant,
+    fps: u32
+}
+
+/// How long messages replace the status line.
+const MESSAGE_DURATION: Duration = Duration::from_secs(2);
+
+impl TuiFrontend {
+    pub fn new(settings: &Settings) -> Result<TuiFrontend, String> {
+        let raw_mode = RawMode::enable()
+            .map_err(|e| format!("Unable to set up the terminal. {}", e))?;
+        let color_mode = settings.terminal_colors.unwrap_or_else(ColorMode::detect);
+
+        let mut tui = TuiFrontend {
+            raw_mode,
+            display: ConsoleDisplay::new(color_mode),
+            key_map: [None; 16],
+            key_release: [None; 16],
+            commands: Vec::new(),
+            should_close: false,
+            status: String::new(),
+            speed: Speed::Normal,
+            message: None,
+            last_update: Instant::now(),
+            frames_drawn: 0,
+            fps_start: Instant::now(),
+            fps: 0
+        };
+        tui.apply_settings(settings);
+        Ok(tui)
+    }
+
+    fn handle_key(&mut self, key: TermKey) {
+        match key {
+            TermKey::Escape | TermKey::Interrupt => self.should_close = true,
+            TermKey::F3 => self.commands.push(Command::NextPalette),
+            TermKey::ShiftF3 => self.commands.push(Command::PreviousPalette),
+            TermKey::F5 => self.commands.push(Command::TogglePause),
+            TermKey::F6 => self.commands.push(Command::ToggleFastForward),
+            TermKey::F9 => self.commands.push(Command::ToggleRecording),
+            TermKey::F12 => self.commands.push(Command::Screenshot),
+            _ => {
+                if let Some(chip8_key) = self.key_map.iter().position(|k| *k == Some(key)) {
+                    self.key_release[chip8_key] = Some(Instant::now() + KEY_HOLD);
+                }
+            }
+        }
+    }
+
+    fn status_line(&mut self) -> String {
+        if let Some((message, shown_at)) = &self.message {
+            if shown_at.elapsed() < MESSAGE_DURATION {
+                return message.clone();
+            }
+            self.message = None;
+        }
+        let speed = match self.speed {
+            Speed::Normal => String::new(),
+            Speed::Paused => String::from("PAUSED | "),
+            Speed::FastForward(speed) => format!(">> {}x | ", speed)
+        };
+        format!("{}{} | {} fps | F3: palette  F5: pause  F6: fast forward  F9: record  F12: screenshot  Esc: quit",
+            speed, self.status, self.fps)
+    }
+}
+
+impl InputBackend for TuiFrontend {
+    fn process_input(&mut self, keyboard_state: &mut KeyboardState) {
+        let mut buffer = [0u8; 64];
+        loop {
+            match io::stdin().lock().read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    for key in decode_keys(&buffer[..n]) {
+                        self.handle_key(key);
+                    }
+                }
+            }
+        }
+
+        let now = Instant::now();
+        for (key, release) in self.key_release.iter_mut().enumerate() {
+            let held = matches!(release, Some(time) if *time > now);
+            if !held {
+                *release = None;
+            }
+            keyboard_state.set_key_state_u8(key as u8, held);
+        }
+    }
+}
+
+impl Frontend for TuiFrontend {
+    fn should_close(&mut self) -> bool {
+        self.should_close
+    }
+
+    fn take_commands(&mut self) -> Vec<Command> {
+        std::mem::take(&mut self.commands)
+    }
+
+    fn apply_settings(&mut self, settings: &Settings) {
+        self.display.set_color_mode(settings.terminal_colors.unwrap_or_else(ColorMode::detect));
+        self.display.set_palette(settings.current_palette());
+        self.display.set_ghosting(settings.ghosting);
+
+        for (mapped, name) in self.key_map.iter_mut().zip(settings.key_map.iter()) {
+            *mapped = term_key_from_name(name);
+        }
+    }
+
+    fn set_palette(&mut self, palette: &Palette) {
+        self.display.set_palette(palette);
+    }
+
+    fn draw(&mut self, fb: &[u8], frame: u64) {
+        let status = self.status_line();
+        if self.display.draw(fb, frame, &status).is_err() {
+            // The terminal is gone, e.g. the SSH connection dropped
+            self.should_close = true;
+        }
+    }
+
+    /// Waits until the next frame is due. Terminals have no vsync, and drawing
+    /// more than once per emulated frame would only waste bandwidth.
+    fn update(&mut self) {
+        let elapsed = self.last_update.elapsed();
+        if elapsed < FRAME_TIME {
+            std::thread::sleep(FRAME_TIME - elapsed);
+        }
+        self.last_update = Instant::now();
+
+        self.frames_drawn += 1;
+        if self.fps_start.elapsed() >= Duration::from_secs(1) {
+            self.fps = self.frames_drawn;
+            self.frames_drawn = 0;
+            self.fps_start = Instant::now();
+        }
+    }
+
+    fn set_status(&mut self, status: &str) {
+        self.status = String::from(status);
+    }
+
+    fn set_speed(&mut self, speed: Speed) {
+        self.speed = speed;
+    }
+
+    /// Messages are shown in the status line for a while, since printing them
+    /// would scroll the screen.
+    fn show_message(&mut self, message: &str) {
+        self.message = Some((String::from(message), Instant::now()));
+    }
+}
@@ -1,9 +1,10 @@
 use std::fs;
 use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
 use crate::log_debug;
 
 pub fn read_file_to_u8(file_path: &str) -> io::Result<Vec<u8>> {
-    log_debug!("Reading file: {}", file_path);
+    log_debug!(Rom, "Reading file: {}", file_path);
     fs::read(file_path)
 }
 
@@ -51,3 +52,36 @@ pub fn nibbles_to_tuple(data: u16) -> (u8, u8, u8, u8) {
         get_nth_nibble(data, 0),
     )
 }
+
+/// 64 bit FNV-1a hash. Used to identify roms by their contents.
+pub fn fnv1a_64(data: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+    data.iter().fold(OFFSET_BASIS, |hash, byte| (hash ^ *byte as u64).wrapping_mul(PRIME))
+}
+
+/// Current UTC date and time as YYYYMMDD-HHMMSS-mmm, sortable and safe to
+/// use in file names.
+pub fn timestamp() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = now.as_secs();
+    let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+    let time = seconds % 86400;
+    format!("{:04}{:02}{:02}-{:02}{:02}{:02}-{:03}", year, month, day,
+        time / 3600, time / 60 % 60, time % 60, now.subsec_millis())
+}
+
+/// Converts days since 1970-01-01 to a (year, month, day) date, using Howard
+/// Hinnant's algorithm.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month as u32, day as u32)
+}
@@ -0,0 +1,239 @@
+use crate::bus::Access;
+use crate::chip8::Register;
+
+const REGISTER_NAMES: [&str; Register::Total as usize] = [
+    "v0", "v1", "v2", "v3", "v4", "v5", "v6", "v7", "v8", "v9", "va", "vb", "vc", "vd", "ve", "vf",
+    "i", "pc", "sp", "dt", "st"
+];
+
+/// Accesses a memory watchpoint stops on.
+#[derive(Clone, Copy, PartialEq)]
+pub enum WatchAccess {
+    Read,
+    Write,
+    /// Reads and writes.
+    ReadWrite,
+    /// Running an instruction, before it runs.
+    Execute
+}
+
+impl WatchAccess {
+    fn includes(&self, access: Access) -> bool {
+        matches!((self, access),
+            (WatchAccess::Read | WatchAccess::ReadWrite, Access::Read)
+            | (WatchAccess::Write | WatchAccess::ReadWrite, Access::Write)
+            | (WatchAccess::Execute, Access::Execute))
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            WatchAccess::Read => "read",
+            WatchAccess::Write => "write",
+            WatchAccess::ReadWrite => "access",
+            WatchAccess::Execute => "execute"
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub enum WatchTarget {
+    /// Accesses to the memory from start to end, included.
+    Memory { access: WatchAccess, start: u16, end: u16 },
+    /// Changes of a register.
+    Register(usize)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Operand {
+    Register(usize),
+    /// The byte read or written, or the new value of the watched register.
+    Value
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual
+}
+
+#[derive(Clone, PartialEq)]
+pub struct Condition {
+    operand: Operand,
+    comparison: Comparison,
+    value: u16
+}
+
+impl Condition {
+    fn holds(&self, registers: &[u16], value: u16) -> bool {
+        let operand = match self.operand {
+            Operand::Register(register) => registers[register],
+            Operand::Value => value
+        };
+        match self.comparison {
+            Comparison::Equal => operand == self.value,
+            Comparison::NotEqual => operand != self.value,
+            Comparison::Less => operand < self.value,
+            Comparison::LessOrEqual => operand <= self.value,
+            Comparison::Greater => operand > self.value,
+            Comparison::GreaterOrEqual => operand >= self.value
+        }
+    }
+}
+
+/// Stops the emulation when the rom accesses some memory or changes a
+/// register, optionally only when a condition holds. Written as text, e.g.
+/// `write to 0x3f0`, `read 0x300-0x30f if v0 == 2`, `execute 0x2a4`,
+/// `v3` (any change) or `v3 == 0x10` (changes to 0x10).
+#[derive(Clone, PartialEq)]
+pub struct Watchpoint {
+    pub target: WatchTarget,
+    pub condition: Option<Condition>,
+    /// How the watchpoint was written, shown when it stops the emulation.
+    pub text: String
+}
+
+fn parse_register(word: &str) -> Option<usize> {
+    REGISTER_NAMES.iter().position(|name| name.eq_ignore_ascii_case(word))
+}
+
+fn parse_number(word: &str) -> Result<u16, String> {
+    let lower = word.to_ascii_lowercase();
+    let parsed = match lower.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => lower.parse()
+    };
+    parsed.map_err(|_| format!("invalid number '{}'", word))
+}
+
+/// Splits the text in words, numbers and comparison operators.
+fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens: Vec<String> = Vec::new();
+    let mut previous_kind = None;
+    for c in text.chars() {
+        let kind = if c.is_ascii_alphanumeric() || c == '_' {
+            Some(0)
+        } else if "=!<>".contains(c) {
+            Some(1)
+        } else if c.is_whitespace() {
+            None
+        } else {
+            // Other symbols, such as range dashes, stand alone
+            Some(2)
+        };
+        match (kind, tokens.last_mut()) {
+            (Some(k), Some(last)) if previous_kind == Some(k) && k != 2 => last.push(c),
+            (Some(_), _) => tokens.push(c.to_string()),
+            (None, _) => { }
+        }
+        previous_kind = kind;
+    }
+    tokens
+}
+
+fn parse_condition(tokens: &[String]) -> Result<Condition, String> {
+    let [operand, comparison, value] = tokens else {
+        return Err(String::from("expected a condition like 'v0 == 0x10'"));
+    };
+    let operand = match parse_register(operand) {
+        Some(register) => Operand::Register(register),
+        None if operand.eq_ignore_ascii_case("value") => Operand::Value,
+        None => return Err(format!("unknown register '{}'", operand))
+    };
+    let comparison = match comparison.as_str() {
+        "==" | "=" => Comparison::Equal,
+        "!=" => Comparison::NotEqual,
+        "<" => Comparison::Less,
+        "<=" => Comparison::LessOrEqual,
+        ">" => Comparison::Greater,
+        ">=" => Comparison::GreaterOrEqual,
+        _ => return Err(format!("unknown comparison '{}'", comparison))
+    };
+    Ok(Condition { operand, comparison, value: parse_number(value)? })
+}
+
+impl Watchpoint {
+    /// Watches the accesses to a memory range, without condition.
+    pub fn memory(access: WatchAccess, start: u16, length: u16) -> Watchpoint {
+        let end = start.saturating_add(length.max(1) - 1);
+        let text = if end == start {
+            format!("{} {:#05x}", access.name(), start)
+        } else {
+            format!("{} {:#05x}-{:#05x}", access.name(), start, end)
+        };
+        Watchpoint { target: WatchTarget::Memory { access, start, end }, condition: None, text }
+    }
+
+    pub fn parse(text: &str) -> Result<Watchpoint, String> {
+        let tokens = tokenize(text);
+        let (target, condition) = match tokens.iter().position(|t| t.eq_ignore_ascii_case("if")) {
+            Some(index) => (&tokens[..index], Some(parse_condition(&tokens[index + 1..])?)),
+            None => (&tokens[..], None)
+        };
+
+        let Some(first) = target.first() else { return Err(String::from("empty watchpoint")); };
+        let access = match first.to_ascii_lowercase().as_str() {
+            "read" => Some(WatchAccess::Read),
+            "write" => Some(WatchAccess::Write),
+            "access" => Some(WatchAccess::ReadWrite),
+            "execute" | "exec" => Some(WatchAccess::Execute),
+            _ => None
+        };
+
+        let (target, condition) = match (access, parse_register(first)) {
+            (Some(access), _) => {
+                // "write to 0x3f0", "read from 0x300-0x30f"
+                let range: Vec<&String> = target[1..].iter()
+                    .filter(|t| !["to", "from", "at"].contains(&t.to_ascii_lowercase().as_str()))
+                    .collect();
+                let (start, end) = match range.as_slice() {
+                    [address] => (parse_number(address)?, parse_number(address)?),
+                    [start, dash, end] if dash.as_str() == "-" => (parse_number(start)?, parse_number(end)?),
+                    _ => return Err(format!("expected an address or a range after '{}'", first))
+                };
+                if end < start {
+                    return Err(String::from("the range ends before it starts"));
+                }
+                (WatchTarget::Memory { access, start, end }, condition)
+            },
+            (None, Some(register)) => match (&target[1..], condition) {
+                ([], condition) => (WatchTarget::Register(register), condition),
+                // "v3 == 0x10" is a change of v3 to 0x10
+                ([comparison, value], None) => {
+                    let tokens = [first.clone(), comparison.clone(), value.clone()];
+                    (WatchTarget::Register(register), Some(parse_condition(&tokens)?))
+                },
+                _ => return Err(format!("unexpected text after '{}'", first))
+            },
+            (None, None) => return Err(format!("expected read, write, access, execute or a register, found '{}'", first))
+        };
+
+        Ok(Watchpoint { target, condition, text: String::from(text.trim()) })
+    }
+
+    /// Whether a memory access stops the emulation.
+    pub fn matches_access(&self, access: Access, address: u16, registers: &[u16], value: u8) -> bool {
+        match self.target {
+            WatchTarget::Memory { access: watched, start, end } => {
+                watched.includes(access) && (start..=end).contains(&address)
+                    && self.condition.as_ref().is_none_or(|c| c.holds(registers, value as u16))
+            },
+            WatchTarget::Register(_) => false
+        }
+    }
+
+    /// Whether the change of registers made by an instruction stops the
+    /// emulation.
+    pub fn matches_change(&self, before: &[u16], after: &[u16]) -> bool {
+        match self.target {
+            WatchTarget::Register(register) => {
+                before[register] != after[register]
+                    && self.condition.as_ref().is_none_or(|c| c.holds(after, after[register]))
+            },
+            WatchTarget::Memory { .. } => false
+        }
+    }
+}
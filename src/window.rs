@@ -1,11 +1,27 @@
 extern crate glfw;
 use gl;
-use glfw::{Action, Context, Key, WindowEvent };
-use crate::input::{InputBackend, KeyboardState};
-use crate::renderer::Renderer;
-use crate::chip8::KeyboardKey;
+use glfw::{Action, Context, GamepadButton, Key, WindowEvent };
+use crate::browser;
+use crate::input::{Command, InputBackend, KeyboardState};
+use crate::palette::Palette;
+use crate::renderer::{Backend, Image, Renderer};
+use crate::config::Settings;
+use crate::frontend::{Frontend, Speed};
+use crate::menu::Menu;
+use crate::osd::Osd;
+use crate::panels::{DebugView, PanelTarget};
+use crate::sprites::SpriteView;
+use crate::shader::GlVersion;
+use std::ffi::CStr;
+use crate::{log_info, log_warn};
 use std::sync::mpsc::{ Receiver };
-use crate::opengl::{self, OpenGLRenderer };
+use crate::opengl::{self, OpenGLRenderer, SoftwareBlitRenderer };
+
+/// Gamepad buttons that open and navigate the rom browser.
+const GAMEPAD_BUTTONS: [GamepadButton; 7] = [
+    GamepadButton::ButtonStart, GamepadButton::ButtonDpadUp, GamepadButton::ButtonDpadDown,
+    GamepadButton::ButtonLeftBumper, GamepadButton::ButtonRightBumper, GamepadButton::ButtonA, GamepadButton::ButtonB
+];
 
 pub struct Window {
     width: u32,
@@ -14,104 +30,379 @@ pub struct Window {
     glfw: glfw::Glfw,
     window: glfw::Window,
     events: Receiver<(f64, WindowEvent)>,
-    opengl_renderer: opengl::OpenGLRenderer,
+    renderer: Box<dyn Renderer>,
+    key_map: [Option<Key>; 16],
+    commands: Vec<Command>,
+    /// Position and size of the window before switching to fullscreen, or
+    /// None when windowed.
+    windowed_geometry: Option<(i32, i32, u32, u32)>,
+    osd: Osd,
+    /// Whether a menu is shown, so navigation keys drive it.
+    menu_open: bool,
+    /// State of `GAMEPAD_BUTTONS` when last polled, to detect presses.
+    gamepad_buttons: [bool; GAMEPAD_BUTTONS.len()],
 
     drag_and_drop: Option<String>
 }
 
 impl Window {
-    pub fn new(width: u32, height: u32) -> Window {
-        let mut glfw = glfw::init(glfw::FAIL_ON_ERRORS).unwrap();
-        glfw.window_hint(glfw::WindowHint::ContextVersion(4, 5));
-        let (mut window, events) = glfw
-            .create_window(width, height, "Chip-8", glfw::WindowMode::Windowed)
-            .expect("Failed do create window.");
+    /// Creates the window with the most capable OpenGL context available, or
+    /// the one set in the settings.
+    pub fn new(settings: &Settings) -> Result<Window, String> {
+        let (width, height) = (settings.window_width, settings.window_height);
+        let mut glfw = glfw::init(glfw::LOG_ERRORS)
+            .map_err(|e| format!("Unable to initialize GLFW: {:?}", e))?;
+
+        let versions = match settings.gl_version {
+            Some(version) => vec![version],
+            None => GlVersion::ALL.to_vec()
+        };
+        let mut created = None;
+        for version in versions.iter() {
+            if let Some((window, events)) = Window::create_window(&mut glfw, width, height, *version) {
+                created = Some((window, events, *version));
+                break;
+            }
+            log_warn!(Render, "Unable to create an {} context.", version.description());
+        }
+
+        let (mut window, events, gl_version) = created.ok_or_else(|| {
+            let tried: Vec<&str> = versions.iter().map(|v| v.description()).collect();
+            format!("Failed to create window: none of the OpenGL versions tried is supported ({}).",
+                tried.join(", "))
+        })?;
+        let context_version = window.get_context_version();
+        log_info!(Render, "Created an {} context (version {}.{}.{}).", gl_version.description(),
+            context_version.major, context_version.minor, context_version.patch);
 
         window.set_key_polling(true);
         window.set_drag_and_drop_polling(true);
         window.set_framebuffer_size_polling(true);
+        window.set_size_polling(true);
+        window.set_mouse_button_polling(true);
+        window.set_scroll_polling(true);
         window.make_current();
     
-        Window {
+        Ok(Window {
             width,
             height,
             glfw,
             window,
             events,
-            opengl_renderer: OpenGLRenderer::new(),
+            renderer: match settings.renderer {
+                Backend::OpenGL => Box::new(OpenGLRenderer::new(gl_version)),
+                Backend::Software => Box::new(SoftwareBlitRenderer::new(gl_version))
+            },
+            key_map: [None; 16],
+            commands: Vec::new(),
+            windowed_geometry: None,
+            osd: Osd::new(),
+            menu_open: false,
+            gamepad_buttons: [false; GAMEPAD_BUTTONS.len()],
             drag_and_drop: None
+        })
+    }
+
+    fn create_window(glfw: &mut glfw::Glfw, width: u32, height: u32, version: GlVersion)
+        -> Option<(glfw::Window, Receiver<(f64, WindowEvent)>)> {
+        use glfw::{ClientApiHint, OpenGlProfileHint, WindowHint};
+
+        glfw.default_window_hints();
+        match version {
+            GlVersion::Core45 | GlVersion::Core33 => {
+                let (major, minor) = if version == GlVersion::Core45 { (4, 5) } else { (3, 3) };
+                glfw.window_hint(WindowHint::ContextVersion(major, minor));
+                glfw.window_hint(WindowHint::OpenGlProfile(OpenGlProfileHint::Core));
+                glfw.window_hint(WindowHint::OpenGlForwardCompat(true));
+            },
+            GlVersion::Es30 | GlVersion::Es20 => {
+                let major = if version == GlVersion::Es30 { 3 } else { 2 };
+                glfw.window_hint(WindowHint::ClientApi(ClientApiHint::OpenGlEs));
+                glfw.window_hint(WindowHint::ContextVersion(major, 0));
+            }
         }
+
+        glfw.create_window(width, height, "Chip-8", glfw::WindowMode::Windowed)
     }
 
-    pub fn init(&mut self) {
+    pub fn init(&mut self, settings: &Settings) {
         gl::load_with(|s| self.glfw.get_proc_address_raw(s));
         opengl::OpenGLRenderer::load_procs(|s: &str| self.glfw.get_proc_address_raw(s));
-        self.opengl_renderer.init();
-        self.opengl_renderer.set_clear_color(0.2, 0.2, 0.2, 1.0);
+        unsafe {
+            let renderer = gl::GetString(gl::RENDERER);
+            if !renderer.is_null() {
+                log_info!(Render, "OpenGL renderer: {}", CStr::from_ptr(renderer as *const _).to_string_lossy());
+            }
+        }
+        self.renderer.init();
+        self.resize_viewport();
+        self.apply_settings(settings);
         self.window.make_current();
 
+        if settings.fullscreen {
+            self.toggle_fullscreen();
+        }
     }
 
-    pub fn update(&mut self) {
-        self.window.swap_buffers()
+    /// Size of the window when windowed, even if it is currently fullscreen.
+    pub fn get_size(&self) -> (u32, u32) {
+        (self.width, self.height)
     }
 
-    pub fn draw(&mut self, fb: &[u8]) {
-        self.opengl_renderer.draw_screen(fb);
+    pub fn is_fullscreen(&self) -> bool {
+        self.windowed_geometry.is_some()
     }
 
-    pub fn should_close(&mut self) -> bool {
-        self.window.should_close()
+    /// Switches between fullscreen, on the primary monitor, and windowed mode.
+    /// The window position and size are restored when leaving fullscreen.
+    pub fn toggle_fullscreen(&mut self) {
+        if let Some((x, y, width, height)) = self.windowed_geometry.take() {
+            self.window.set_monitor(glfw::WindowMode::Windowed, x, y, width, height, None);
+            return;
+        }
+
+        let (x, y) = self.window.get_pos();
+        let geometry = (x, y, self.width, self.height);
+        let window = &mut self.window;
+        let switched = self.glfw.with_primary_monitor(|_, monitor| {
+            let monitor = monitor?;
+            let mode = monitor.get_video_mode()?;
+            window.set_monitor(glfw::WindowMode::FullScreen(monitor), 0, 0,
+                mode.width, mode.height, Some(mode.refresh_rate));
+            Some(())
+        });
+
+        match switched {
+            Some(()) => self.windowed_geometry = Some(geometry),
+            None => log_warn!(Render, "Unable to switch to fullscreen: no monitor found.")
+        }
+    }
+
+    /// Draws the screen in the window, leaving room on the right for the
+    /// debugger panels when they are shown.
+    fn resize_viewport(&mut self) {
+        let (width, height) = self.window.get_framebuffer_size();
+        self.osd.resize(width as u32, height as u32);
+        let panels = self.osd.debugger_width() as i32;
+        self.renderer.set_viewport(width - panels, height);
+    }
+
+    /// Position of the cursor in framebuffer pixels, which differ from
+    /// window coordinates on high density displays.
+    fn cursor_position(&self) -> (u32, u32) {
+        let (x, y) = self.window.get_cursor_pos();
+        let (width, height) = self.window.get_size();
+        let (fb_width, fb_height) = self.window.get_framebuffer_size();
+        let x = x * fb_width as f64 / width.max(1) as f64;
+        let y = y * fb_height as f64 / height.max(1) as f64;
+        (x.max(0.0) as u32, y.max(0.0) as u32)
     }
 
-    pub fn set_key_state(keyboard_state: &mut KeyboardState, key: KeyboardKey, action: Action) {
+    /// Queues the commands of the gamepad buttons pressed since the last
+    /// call. Only the first gamepad is used.
+    fn process_gamepad(&mut self) {
+        let state = self.glfw.get_joystick(glfw::JoystickId::Joystick1).get_gamepad_state();
+        for (button, was_pressed) in GAMEPAD_BUTTONS.iter().zip(self.gamepad_buttons.iter_mut()) {
+            let pressed = matches!(&state, Some(s) if s.get_button_state(*button) == Action::Press);
+            let newly_pressed = pressed && !*was_pressed;
+            *was_pressed = pressed;
+            if !newly_pressed { continue; }
+
+            let command = match button {
+                GamepadButton::ButtonStart => Some(Command::ToggleRomBrowser),
+                _ if !self.menu_open => None,
+                GamepadButton::ButtonDpadUp => Some(Command::MenuMove(-1)),
+                GamepadButton::ButtonDpadDown => Some(Command::MenuMove(1)),
+                GamepadButton::ButtonLeftBumper => Some(Command::MenuMove(-browser::PAGE_SIZE)),
+                GamepadButton::ButtonRightBumper => Some(Command::MenuMove(browser::PAGE_SIZE)),
+                GamepadButton::ButtonA => Some(Command::MenuSelect),
+                GamepadButton::ButtonB => Some(Command::MenuBack),
+                _ => None
+            };
+            self.commands.extend(command);
+        }
+    }
+
+    pub fn set_key_state(keyboard_state: &mut KeyboardState, key: u8, action: Action) {
         if action == Action::Press {
-            keyboard_state.set_key_state(key, true);
+            keyboard_state.set_key_state_u8(key, true);
         }
         else if action == Action::Release {
-            keyboard_state.set_key_state(key, false);
+            keyboard_state.set_key_state_u8(key, false);
+        }
+    }
+}
+
+impl Frontend for Window {
+    /// Applies the settings that can change while the window is open. Window
+    /// size is only used when the window is created.
+    fn apply_settings(&mut self, settings: &Settings) {
+        let [r, g, b] = settings.border_color;
+        self.renderer.set_clear_color(r, g, b, 1.0);
+        self.renderer.set_integer_scaling(settings.integer_scaling);
+        self.set_palette(settings.current_palette());
+
+        let user_shaders = match (&settings.vertex_shader, &settings.fragment_shader) {
+            (Some(vs), Some(fs)) => Some((vs.as_str(), fs.as_str())),
+            (None, None) => None,
+            _ => {
+                log_warn!(Render, "Both vertex_shader and fragment_shader must be set to use custom shaders.");
+                None
+            }
+        };
+        self.renderer.set_user_shaders(user_shaders);
+        self.renderer.set_effects(&settings.effects);
+        self.renderer.set_ghosting(settings.ghosting);
+        self.osd.apply_settings(settings);
+        // The debugger panels scale with the display
+        self.resize_viewport();
+
+        for (mapped, name) in self.key_map.iter_mut().zip(settings.key_map.iter()) {
+            *mapped = key_from_name(name);
+            if mapped.is_none() {
+                log_warn!(Input, "Unknown key name in key map: {}", name);
+            }
         }
     }
 
-    pub fn has_drag_and_drop(&self) -> bool {
-        self.drag_and_drop.is_some()
+    fn set_palette(&mut self, palette: &Palette) {
+        self.renderer.set_palette(palette);
+    }
+
+    fn take_commands(&mut self) -> Vec<Command> {
+        std::mem::take(&mut self.commands)
+    }
+
+    /// Draws the on-screen display and presents the frame. The display is
+    /// drawn here, after screenshots are read back, so it is left out of them.
+    fn update(&mut self) {
+        let scale = self.osd.scale();
+        if let Some((image, changed)) = self.osd.render() {
+            self.renderer.draw_overlay(image, scale, changed);
+        }
+        self.window.swap_buffers();
+        self.osd.frame_presented();
+    }
+
+    fn draw(&mut self, fb: &[u8], frame: u64) {
+        self.renderer.draw_screen(fb, frame);
+    }
+
+    fn should_close(&mut self) -> bool {
+        self.window.should_close()
+    }
+
+    fn take_dropped_file(&mut self) -> Option<String> {
+        self.drag_and_drop.take()
+    }
+
+    fn capture_screen(&mut self) -> Option<Image> {
+        self.renderer.read_pixels()
+    }
+
+    fn set_status(&mut self, status: &str) {
+        self.window.set_title(&format!("Chip-8 - {}", status));
+    }
+
+    /// Messages are shown on screen, unless disabled, and always logged.
+    fn show_message(&mut self, message: &str) {
+        self.osd.show_message(message);
+        log_info!(Render, "{}", message);
+    }
+
+    fn set_speed(&mut self, speed: Speed) {
+        self.osd.set_speed(speed);
+    }
+
+    fn show_menu(&mut self, menu: Option<&Menu>) {
+        self.osd.set_menu(menu);
+        self.menu_open = menu.is_some();
     }
 
-    pub fn clear_drag_and_drop(&mut self) {
-        self.drag_and_drop = None;
+    fn set_instruction_count(&mut self, count: u64) {
+        self.osd.set_instruction_count(count);
     }
 
-    pub fn get_drag_and_drop(&mut self) -> String {
-        self.drag_and_drop.clone().unwrap().clone()
+    fn show_debugger(&mut self, view: Option<&DebugView>) {
+        let shown = self.osd.shows_debugger();
+        self.osd.set_debugger(view);
+        if self.osd.shows_debugger() != shown {
+            self.resize_viewport();
+        }
     }
 
+    fn show_sprites(&mut self, view: Option<&SpriteView>) {
+        let shown = self.osd.shows_debugger();
+        self.osd.set_sprites(view);
+        if self.osd.shows_debugger() != shown {
+            self.resize_viewport();
+        }
+    }
 }
 
 impl InputBackend for Window {
     fn process_input(&mut self, keyboard_state: &mut KeyboardState) {
         self.glfw.poll_events();
-        for (_, event) in glfw::flush_messages(&self.events) {
+        self.process_gamepad();
+        let events: Vec<(f64, WindowEvent)> = glfw::flush_messages(&self.events).collect();
+        for (_, event) in events {
+            if let glfw::WindowEvent::Key(key, _, Action::Press | Action::Repeat, _) = event {
+                if let Some(command) = menu_command(key).filter(|_| self.menu_open) {
+                    self.commands.push(command);
+                    continue;
+                }
+            }
+
             match event {
-                glfw::WindowEvent::Key(key, _, action, _) => {
-                    match key {
-                        Key::R => { Window::set_key_state(keyboard_state, KeyboardKey::One,   action); },
-                        Key::T => { Window::set_key_state(keyboard_state, KeyboardKey::Two,   action); },
-                        Key::Y => { Window::set_key_state(keyboard_state, KeyboardKey::Three, action); },
-                        Key::F => { Window::set_key_state(keyboard_state, KeyboardKey::Four,  action); },
-                        Key::G => { Window::set_key_state(keyboard_state, KeyboardKey::Five,  action); },
-                        Key::H => { Window::set_key_state(keyboard_state, KeyboardKey::Six,   action); },
-                        Key::V => { Window::set_key_state(keyboard_state, KeyboardKey::Seven, action); },
-                        Key::B => { Window::set_key_state(keyboard_state, KeyboardKey::Eight, action); },
-                        Key::N => { Window::set_key_state(keyboard_state, KeyboardKey::Nine,  action); },
-                        Key::M => { Window::set_key_state(keyboard_state, KeyboardKey::E,     action); },
-                        Key::U => { Window::set_key_state(keyboard_state, KeyboardKey::C,     action); },
-                        Key::J => { Window::set_key_state(keyboard_state, KeyboardKey::D,     action); },
-                        Key::W => { Window::set_key_state(keyboard_state, KeyboardKey::A,     action); },
-                        Key::E => { Window::set_key_state(keyboard_state, KeyboardKey::Zero,  action); },
-                        Key::S => { Window::set_key_state(keyboard_state, KeyboardKey::B,     action); },
-                        Key::D => { Window::set_key_state(keyboard_state, KeyboardKey::F,     action); },
-                        Key::Escape => { self.window.set_should_close(true); }
-                        _ => { }
+                glfw::WindowEvent::Key(Key::Escape, _, Action::Press, _) => {
+                    self.window.set_should_close(true);
+                },
+                glfw::WindowEvent::Key(Key::F1, _, Action::Press, _) => {
+                    self.commands.push(Command::ToggleRomBrowser);
+                },
+                glfw::WindowEvent::Key(Key::F11, _, Action::Press, _) => {
+                    self.toggle_fullscreen();
+                },
+                glfw::WindowEvent::Key(Key::F5, _, Action::Press, modifiers) => {
+                    self.commands.push(if modifiers.contains(glfw::Modifiers::Shift) {
+                        Command::ReverseContinue
+                    } else {
+                        Command::TogglePause
+                    });
+                },
+                glfw::WindowEvent::Key(Key::F6, _, Action::Press, _) => {
+                    self.commands.push(Command::ToggleFastForward);
+                },
+                glfw::WindowEvent::Key(Key::F7, _, Action::Press, _) => {
+                    self.commands.push(Command::ToggleDebugger);
+                },
+                glfw::WindowEvent::Key(Key::F8, _, Action::Press, _) => {
+                    self.commands.push(Command::ToggleSpriteViewer);
+                },
+                glfw::WindowEvent::Key(Key::F10, _, Action::Press | Action::Repeat, modifiers) => {
+                    self.commands.push(if modifiers.contains(glfw::Modifiers::Shift) {
+                        Command::StepBack
+                    } else {
+                        Command::Step
+                    });
+                },
+                glfw::WindowEvent::Key(Key::F9, _, Action::Press, _) => {
+                    self.commands.push(Command::ToggleRecording);
+                },
+                glfw::WindowEvent::Key(Key::F12, _, Action::Press, _) => {
+                    self.commands.push(Command::Screenshot);
+                },
+                glfw::WindowEvent::Key(Key::F3, _, Action::Press, modifiers) => {
+                    self.commands.push(if modifiers.contains(glfw::Modifiers::Shift) {
+                        Command::PreviousPalette
+                    } else {
+                        Command::NextPalette
+                    });
+                },
+                // Keys pressed in a menu don't reach the Chip-8
+                glfw::WindowEvent::Key(key, _, action, _) if !self.menu_open || action == Action::Release => {
+                    if let Some(chip8_key) = self.key_map.iter().position(|k| *k == Some(key)) {
+                        Window::set_key_state(keyboard_state, chip8_key as u8, action);
                     }
                 },
                 glfw::WindowEvent::FileDrop(path) => {
@@ -123,8 +414,31 @@ impl InputBackend for Window {
                                 .unwrap()));
                     }
                 },
-                glfw::WindowEvent::FramebufferSize(width, height) => {
-                    unsafe{ gl::Viewport(0, 0, width, height); }
+                glfw::WindowEvent::FramebufferSize(_, _) => self.resize_viewport(),
+                // Clicking an instruction of the debugger sets or removes a
+                // breakpoint, clicking a byte tells what last wrote it
+                glfw::WindowEvent::MouseButton(glfw::MouseButtonLeft, Action::Press, _) => {
+                    let (x, y) = self.cursor_position();
+                    let command = match self.osd.target_at(x, y) {
+                        Some(PanelTarget::Instruction(address)) => Command::ToggleBreakpoint(address),
+                        Some(PanelTarget::Memory(address)) => Command::ShowLastWrite(address),
+                        Some(PanelTarget::Sprite(address)) => Command::SelectSprite(address),
+                        Some(PanelTarget::SpriteHeight(rows)) => Command::ChangeSpriteHeight(rows),
+                        Some(PanelTarget::SpriteOffset(bytes)) => Command::MoveSprites(bytes),
+                        Some(PanelTarget::ExportSprite) => Command::ExportSprite,
+                        None => continue
+                    };
+                    self.commands.push(command);
+                },
+                glfw::WindowEvent::Scroll(_, offset) if self.osd.shows_debugger() => {
+                    let rows = (-offset * 2.0).round() as i32;
+                    if rows != 0 {
+                        self.commands.push(Command::ScrollMemory(rows));
+                    }
+                },
+                glfw::WindowEvent::Size(width, height) if !self.is_fullscreen() => {
+                    self.width = width as u32;
+                    self.height = height as u32;
                 }
                 _ => { }
             }
@@ -132,3 +446,68 @@ impl InputBackend for Window {
     }
 }
 
+
+
+/// Command of a key used to navigate menus.
+fn menu_command(key: Key) -> Option<Command> {
+    match key {
+        Key::Up => Some(Command::MenuMove(-1)),
+        Key::Down => Some(Command::MenuMove(1)),
+        Key::PageUp => Some(Command::MenuMove(-browser::PAGE_SIZE)),
+        Key::PageDown => Some(Command::MenuMove(browser::PAGE_SIZE)),
+        Key::Enter | Key::KpEnter => Some(Command::MenuSelect),
+        Key::Escape | Key::Backspace => Some(Command::MenuBack),
+        _ => None
+    }
+}
+
+/// Converts a key name, as written in the config file, to a GLFW key.
+/// Letters and digits map to themselves (e.g. "R", "4"), keypad keys are
+/// prefixed with "Kp" (e.g. "Kp4").
+pub fn key_from_name(name: &str) -> Option<Key> {
+    const LETTERS: [Key; 26] = [
+        Key::A, Key::B, Key::C, Key::D, Key::E, Key::F, Key::G, Key::H, Key::I,
+        Key::J, Key::K, Key::L, Key::M, Key::N, Key::O, Key::P, Key::Q, Key::R,
+        Key::S, Key::T, Key::U, Key::V, Key::W, Key::X, Key::Y, Key::Z
+    ];
+    const DIGITS: [Key; 10] = [
+        Key::Num0, Key::Num1, Key::Num2, Key::Num3, Key::Num4,
+        Key::Num5, Key::Num6, Key::Num7, Key::Num8, Key::Num9
+    ];
+    const KEYPAD: [Key; 10] = [
+        Key::Kp0, Key::Kp1, Key::Kp2, Key::Kp3, Key::Kp4,
+        Key::Kp5, Key::Kp6, Key::Kp7, Key::Kp8, Key::Kp9
+    ];
+
+    let upper = name.to_ascii_uppercase();
+    let mut chars = upper.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return match c {
+            'A'..='Z' => Some(LETTERS[(c as u8 - b'A') as usize]),
+            '0'..='9' => Some(DIGITS[(c as u8 - b'0') as usize]),
+            ',' => Some(Key::Comma),
+            '.' => Some(Key::Period),
+            '/' => Some(Key::Slash),
+            ';' => Some(Key::Semicolon),
+            '-' => Some(Key::Minus),
+            '=' => Some(Key::Equal),
+            _ => None
+        };
+    }
+
+    if let Some(digit) = upper.strip_prefix("KP").and_then(|d| d.parse::<usize>().ok()) {
+        return KEYPAD.get(digit).copied();
+    }
+
+    match upper.as_str() {
+        "SPACE" => Some(Key::Space),
+        "ENTER" => Some(Key::Enter),
+        "TAB" => Some(Key::Tab),
+        "BACKSPACE" => Some(Key::Backspace),
+        "UP" => Some(Key::Up),
+        "DOWN" => Some(Key::Down),
+        "LEFT" => Some(Key::Left),
+        "RIGHT" => Some(Key::Right),
+        _ => None
+    }
+}